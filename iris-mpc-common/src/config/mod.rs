@@ -55,6 +55,12 @@ pub struct Config {
     #[serde(default)]
     pub init_db_size: usize,
 
+    /// JSON file with the seed and size of the random DB to initialize, see
+    /// `TestVectorSeed`. Overrides `init_db_size`, so the client of an
+    /// end-to-end test can read the same file.
+    #[serde(default)]
+    pub test_vector_seed_file: Option<String>,
+
     #[serde(default)]
     pub max_db_size: usize,
 
@@ -81,6 +87,17 @@ pub struct Config {
 
     #[serde(default = "default_shutdown_last_results_sync_timeout_secs")]
     pub shutdown_last_results_sync_timeout_secs: u64,
+
+    /// How long to wait for the NCCL comms to all peers to be established
+    /// before giving up at startup. The server then exits, since the pending
+    /// NCCL init cannot be cancelled and retried in-process.
+    #[serde(default = "default_nccl_connect_timeout_secs")]
+    pub nccl_connect_timeout_secs: u64,
+
+    /// If set, every published result carries an HMAC of its body keyed with
+    /// this secret.
+    #[serde(default)]
+    pub result_hmac_key: Option<ResultHmacKey>,
 }
 
 fn default_processing_timeout_secs() -> u64 {
@@ -103,6 +120,10 @@ fn default_shutdown_last_results_sync_timeout_secs() -> u64 {
     10
 }
 
+fn default_nccl_connect_timeout_secs() -> u64 {
+    300
+}
+
 impl Config {
     pub fn load_config(prefix: &str) -> eyre::Result<Config> {
         let settings = config::Config::builder();
@@ -134,6 +155,16 @@ impl Config {
     }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ResultHmacKey(pub String);
+
+impl fmt::Debug for ResultHmacKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("********")
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Default)]
 pub struct DbConfig {
     pub url: String,
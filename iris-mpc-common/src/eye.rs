@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub enum Eye {
+    #[default]
+    Left  = 0,
+    Right = 1,
+}
+
+impl Display for Eye {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Eye::Left => write!(f, "left"),
+            Eye::Right => write!(f, "right"),
+        }
+    }
+}
+
+impl FromStr for Eye {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "left" => Ok(Eye::Left),
+            "right" => Ok(Eye::Right),
+            _ => Err(format!("Invalid eye: {}", s)),
+        }
+    }
+}
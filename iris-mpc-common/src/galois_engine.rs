@@ -3,15 +3,19 @@ pub type CompactGaloisRingShares = Vec<Vec<u8>>;
 pub mod degree4 {
     use crate::{
         galois::degree4::{basis, GaloisRingElement, ShamirGaloisRingShare},
-        iris_db::iris::IrisCodeArray,
+        id::PartyID,
+        iris_db::iris::{IrisCode, IrisCodeArray},
         IRIS_CODE_LENGTH, MASK_CODE_LENGTH,
     };
     use base64::{prelude::BASE64_STANDARD, Engine};
     use rand::{CryptoRng, Rng};
     use serde::{Deserialize, Serialize};
     use serde_big_array::BigArray;
+    use std::mem;
 
     const CODE_COLS: usize = 200;
+    /// Size of an encoded [`GaloisRingIrisCodeShare`].
+    const SHARE_BYTES: usize = mem::size_of::<u64>() + IRIS_CODE_LENGTH * mem::size_of::<u16>();
 
     fn preprocess_coefs(id: usize, coefs: &mut [u16]) {
         let lagrange_coeffs = ShamirGaloisRingShare::deg_2_lagrange_polys_at_zero();
@@ -259,6 +263,41 @@ pub mod degree4 {
             shares
         }
 
+        /// Inverse of [`Self::encode_iris_code`]. Only two of the shares are
+        /// needed, the third one is ignored. Code bits outside of the mask are
+        /// not part of the encoding and come back as zero.
+        pub fn reconstruct(shares: &[Self; 3]) -> IrisCode {
+            let party = |share: &Self| PartyID::try_from(share.id - 1).expect("valid share id");
+            let (a, b) = (&shares[0], &shares[1]);
+            let lagrange_a =
+                ShamirGaloisRingShare::deg_1_lagrange_polys_at_zero(party(a), party(b));
+            let lagrange_b =
+                ShamirGaloisRingShare::deg_1_lagrange_polys_at_zero(party(b), party(a));
+
+            let mut iris = IrisCode {
+                code: IrisCodeArray::ZERO,
+                mask: IrisCodeArray::ZERO,
+            };
+            for i in (0..IRIS_CODE_LENGTH).step_by(4) {
+                let [y_a, y_b] = [a, b].map(|share| {
+                    GaloisRingElement::from_coefs([
+                        share.coefs[i],
+                        share.coefs[i + 1],
+                        share.coefs[i + 2],
+                        share.coefs[i + 3],
+                    ])
+                });
+                let element = (y_a * lagrange_a + y_b * lagrange_b).to_basis_A();
+                for (j, value) in element.coefs.into_iter().enumerate() {
+                    // Each bit is encoded as `mask - 2 * (code & mask)`
+                    let bit = Self::remap_index(i + j);
+                    iris.mask.set_bit(bit, value != 0);
+                    iris.code.set_bit(bit, value == u16::MAX);
+                }
+            }
+            iris
+        }
+
         pub fn preprocess_iris_code_query_share(&mut self) {
             preprocess_coefs(self.id, &mut self.coefs);
         }
@@ -305,14 +344,46 @@ pub mod degree4 {
             result
         }
 
+        /// Encodes the share in its wire format: the id as a little-endian
+        /// `u64`, followed by the coefs as little-endian `u16`s, independent of
+        /// the byte order of the host. This is the same layout `bincode`
+        /// produces.
+        pub fn to_bytes(&self) -> Vec<u8> {
+            let mut bytes = Vec::with_capacity(SHARE_BYTES);
+            bytes.extend_from_slice(&(self.id as u64).to_le_bytes());
+            for coef in self.coefs {
+                bytes.extend_from_slice(&coef.to_le_bytes());
+            }
+            bytes
+        }
+
+        /// Inverse of [`Self::to_bytes`].
+        pub fn from_bytes(bytes: &[u8]) -> eyre::Result<Self> {
+            if bytes.len() != SHARE_BYTES {
+                eyre::bail!(
+                    "Invalid length for a share: expected {} bytes, got {}",
+                    SHARE_BYTES,
+                    bytes.len()
+                );
+            }
+            let (id, coef_bytes) = bytes.split_at(mem::size_of::<u64>());
+            let mut coefs = [0u16; IRIS_CODE_LENGTH];
+            for (coef, chunk) in coefs.iter_mut().zip(coef_bytes.chunks_exact(2)) {
+                *coef = u16::from_le_bytes([chunk[0], chunk[1]]);
+            }
+            Ok(Self {
+                id: u64::from_le_bytes(id.try_into().expect("8 bytes")).try_into()?,
+                coefs,
+            })
+        }
+
         pub fn to_base64(&self) -> String {
-            let as_vec_u8 = bincode::serialize(&self).expect("to serialize");
-            BASE64_STANDARD.encode::<Vec<u8>>(as_vec_u8)
+            BASE64_STANDARD.encode(self.to_bytes())
         }
 
         pub fn from_base64(s: &str) -> eyre::Result<Self> {
             let decoded_bytes = BASE64_STANDARD.decode(s)?;
-            Ok(bincode::deserialize(&decoded_bytes)?)
+            Self::from_bytes(&decoded_bytes)
         }
     }
 
@@ -320,7 +391,7 @@ pub mod degree4 {
     mod tests {
         use crate::{
             galois_engine::degree4::{GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare},
-            iris_db::iris::IrisCodeArray,
+            iris_db::iris::{IrisCode, IrisCodeArray},
             MASK_CODE_LENGTH,
         };
         use float_eq::assert_float_eq;
@@ -463,6 +534,52 @@ pub mod degree4 {
             assert_float_eq!(dist_15, min_dist, abs <= 1e-6);
         }
 
+        #[test]
+        fn reconstruct_iris_code() {
+            let rng = &mut thread_rng();
+            for _ in 0..10 {
+                let iris = IrisCode::random_rng(rng);
+                let shares = GaloisRingIrisCodeShare::encode_iris_code(&iris.code, &iris.mask, rng);
+                let reconstructed = GaloisRingIrisCodeShare::reconstruct(&shares);
+                assert_eq!(reconstructed.mask, iris.mask);
+                assert_eq!(reconstructed.code, iris.code & iris.mask);
+
+                // Any two of the shares are enough
+                let [s0, s1, s2] = shares;
+                let shares = [s2, s0, s1];
+                assert_eq!(GaloisRingIrisCodeShare::reconstruct(&shares), reconstructed);
+            }
+        }
+
+        #[test]
+        fn share_bytes_are_little_endian() {
+            let mut rng = thread_rng();
+            let code = IrisCodeArray::random_rng(&mut rng);
+            let share = GaloisRingIrisCodeShare::encode_mask_code(&code, &mut rng)[1].clone();
+
+            // Encode with an explicit byte order, independent of the host
+            let mut le_bytes = (share.id as u64).to_le_bytes().to_vec();
+            le_bytes.extend(share.coefs.iter().flat_map(|coef| coef.to_le_bytes()));
+            assert_eq!(share.to_bytes(), le_bytes);
+            assert_eq!(bincode::serialize(&share).unwrap(), le_bytes);
+            assert_eq!(
+                GaloisRingIrisCodeShare::from_bytes(&le_bytes).unwrap(),
+                share
+            );
+
+            // Big-endian bytes decode to byte-swapped coefs
+            let mut be_bytes = (share.id as u64).to_le_bytes().to_vec();
+            be_bytes.extend(share.coefs.iter().flat_map(|coef| coef.to_be_bytes()));
+            let decoded = GaloisRingIrisCodeShare::from_bytes(&be_bytes).unwrap();
+            assert!(decoded
+                .coefs
+                .iter()
+                .zip(share.coefs)
+                .all(|(&decoded, coef)| decoded == coef.swap_bytes()));
+
+            assert!(GaloisRingIrisCodeShare::from_bytes(&le_bytes[1..]).is_err());
+        }
+
         #[test]
         fn base64_shares() {
             let mut rng = thread_rng();
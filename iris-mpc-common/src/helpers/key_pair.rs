@@ -33,9 +33,9 @@ pub enum SharesDecodingError {
     ParsingKeyError,
     #[error("Sealed box open error")]
     SealedBoxOpenError,
-    #[error("Public key not found error")]
-    PreviousKeyNotFound,
     #[error("Previous key not found error")]
+    PreviousKeyNotFound,
+    #[error("Public key not found error")]
     PublicKeyNotFound,
     #[error("Private key not found error")]
     PrivateKeyNotFound,
@@ -57,6 +57,11 @@ pub enum SharesDecodingError {
     ),
     #[error("Upload share file error")]
     UploadS3Error,
+    #[error("Unsupported {field}: {version}")]
+    UnsupportedVersion {
+        field:   &'static str,
+        version: String,
+    },
 }
 
 #[derive(Clone, Debug)]
@@ -118,13 +123,15 @@ impl SharesEncryptionKeyPairs {
         }
     }
 
+    /// An empty (or whitespace-only) previous key means that no previous key
+    /// is configured.
     pub fn from_b64_private_key_strings(
         current_sk_b64_string: String,
         previous_sk_b64_string: String,
     ) -> Result<Self, SharesDecodingError> {
         let current_key_pair =
             SharesEncryptionKeyPair::from_b64_private_key_string(current_sk_b64_string)?;
-        if previous_sk_b64_string.is_empty() {
+        if previous_sk_b64_string.trim().is_empty() {
             return Ok(SharesEncryptionKeyPairs {
                 current_key_pair,
                 previous_key_pair: None,
@@ -138,6 +145,25 @@ impl SharesEncryptionKeyPairs {
             previous_key_pair: Some(previous_key_pair),
         })
     }
+
+    /// Opens `code` with the current key, falling back to the previous key if
+    /// one is configured. Fails with
+    /// [`SharesDecodingError::PreviousKeyNotFound`] if the current key cannot
+    /// open it and there is no previous key.
+    pub fn open_sealed_box(&self, code: &[u8]) -> Result<Vec<u8>, SharesDecodingError> {
+        match self.current_key_pair.open_sealed_box_slice(code) {
+            Ok(bytes) => Ok(bytes),
+            Err(_) => match &self.previous_key_pair {
+                Some(key_pair) => key_pair.open_sealed_box_slice(code),
+                None => {
+                    tracing::debug!(
+                        "Current key failed to open the share and no previous key is configured"
+                    );
+                    Err(SharesDecodingError::PreviousKeyNotFound)
+                }
+            },
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -177,7 +203,11 @@ impl SharesEncryptionKeyPair {
     }
 
     pub fn open_sealed_box(&self, code: Vec<u8>) -> Result<Vec<u8>, SharesDecodingError> {
-        let decrypted = sealedbox::open(&code, &self.pk, &self.sk);
+        self.open_sealed_box_slice(&code)
+    }
+
+    pub fn open_sealed_box_slice(&self, code: &[u8]) -> Result<Vec<u8>, SharesDecodingError> {
+        let decrypted = sealedbox::open(code, &self.pk, &self.sk);
         match decrypted {
             Ok(bytes) => Ok(bytes),
             Err(_) => Err(SharesDecodingError::SealedBoxOpenError),
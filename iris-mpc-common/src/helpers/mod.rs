@@ -2,9 +2,11 @@ pub mod aws;
 pub mod aws_sigv4;
 pub mod key_pair;
 pub mod kms_dh;
+pub mod runtime;
 pub mod sha256;
 pub mod shutdown_handler;
 pub mod smpc_request;
+pub mod sqs;
 pub mod sqs_s3_helper;
 pub mod sync;
 pub mod task_monitor;
@@ -0,0 +1,43 @@
+//! Tokio runtime setup shared by the binaries.
+
+use std::env;
+use tokio::runtime::{Builder, Runtime};
+
+/// Environment variable overriding the number of tokio worker threads.
+pub const WORKER_THREADS_ENV: &str = "WORKER_THREADS";
+
+fn parse_worker_threads(threads: Option<&str>) -> eyre::Result<Option<usize>> {
+    let Some(threads) = threads else {
+        return Ok(None);
+    };
+    let threads: usize = threads.parse()?;
+    eyre::ensure!(threads > 0, "{} must be positive", WORKER_THREADS_ENV);
+    Ok(Some(threads))
+}
+
+/// Builds a multi-threaded runtime with `WORKER_THREADS` worker threads, or
+/// `default` if it is not set. Without either, tokio picks one per core.
+pub fn multi_thread_runtime(default: Option<usize>) -> eyre::Result<Runtime> {
+    let threads = parse_worker_threads(env::var(WORKER_THREADS_ENV).ok().as_deref())?;
+    let mut builder = Builder::new_multi_thread();
+    if let Some(threads) = threads.or(default) {
+        builder.worker_threads(threads);
+    }
+    Ok(builder.enable_all().build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_worker_threads;
+
+    #[test]
+    fn test_parse_worker_threads() {
+        assert_eq!(parse_worker_threads(None).unwrap(), None);
+        assert_eq!(parse_worker_threads(Some("4")).unwrap(), Some(4));
+        assert_eq!(
+            parse_worker_threads(Some("0")).unwrap_err().to_string(),
+            "WORKER_THREADS must be positive"
+        );
+        assert!(parse_worker_threads(Some("many")).is_err());
+    }
+}
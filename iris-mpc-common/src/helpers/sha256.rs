@@ -1,5 +1,10 @@
 use sha2::{Digest, Sha256};
+use sha3::Sha3_256;
 
 pub fn calculate_sha256<T: AsRef<[u8]>>(data: T) -> String {
     hex::encode(Sha256::digest(data.as_ref()))
 }
+
+pub fn calculate_sha3_256<T: AsRef<[u8]>>(data: T) -> String {
+    hex::encode(Sha3_256::digest(data.as_ref()))
+}
@@ -1,4 +1,7 @@
-use super::{key_pair::SharesDecodingError, sha256::calculate_sha256};
+use super::{
+    key_pair::SharesDecodingError,
+    sha256::{calculate_sha256, calculate_sha3_256},
+};
 use crate::helpers::key_pair::SharesEncryptionKeyPairs;
 use aws_sdk_sns::types::MessageAttributeValue;
 use aws_sdk_sqs::{
@@ -7,10 +10,15 @@ use aws_sdk_sqs::{
 };
 use base64::{engine::general_purpose::STANDARD, Engine};
 use eyre::Report;
+use hmac::{Hmac, Mac};
 use reqwest::Client;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::Value;
-use std::{collections::HashMap, sync::LazyLock};
+use sha2::Sha256;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::LazyLock,
+};
 use thiserror::Error;
 use tokio_retry::{
     strategy::{jitter, FixedInterval},
@@ -109,13 +117,36 @@ pub const SMPC_MESSAGE_TYPE_ATTRIBUTE: &str = "message_type";
 pub const IDENTITY_DELETION_MESSAGE_TYPE: &str = "identity_deletion";
 pub const CIRCUIT_BREAKER_MESSAGE_TYPE: &str = "circuit_breaker";
 pub const UNIQUENESS_MESSAGE_TYPE: &str = "uniqueness";
+pub const RESULT_HMAC_ATTRIBUTE: &str = "result_hmac";
+
+/// Computes the hex encoded HMAC-SHA256 of a result message body.
+pub fn compute_result_hmac(key: &[u8], body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Checks in constant time that `hmac` is the hex encoded HMAC-SHA256 of
+/// `body` under `key`.
+pub fn verify_result_hmac(key: &[u8], body: &str, hmac: &str) -> bool {
+    let Ok(expected) = hex::decode(hmac) else {
+        return false;
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+    mac.verify_slice(&expected).is_ok()
+}
 
+/// A uniqueness check of one signup. The shares behind `s3_presigned_url`
+/// hold both eyes, and each eye is matched against the DB of that eye, so a
+/// request is not tied to a single eye.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UniquenessRequest {
     pub batch_size:              Option<usize>,
     pub signup_id:               String,
     pub s3_presigned_url:        String,
-    pub iris_shares_file_hashes: [String; 3],
+    /// One hash per party, see [`UniquenessRequest::check_party_count`].
+    pub iris_shares_file_hashes: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -167,6 +198,30 @@ impl ReceiveRequestError {
     }
 }
 
+/// Why a decrypted iris share was rejected by
+/// [`UniquenessRequest::validate_iris_share`].
+#[derive(Error, Debug)]
+pub enum ValidationError {
+    #[error("Iris share hash mismatch for party {party}: expected {expected}, got {got}")]
+    HashMismatch {
+        party:    usize,
+        expected: String,
+        got:      String,
+    },
+
+    #[error("No iris share hash for party {0}")]
+    UnknownParty(usize),
+
+    #[error("Expected iris share hashes for {expected} parties, got {got}")]
+    PartyCount { expected: usize, got: usize },
+
+    #[error("Unknown share hash algorithm in shares version {0}")]
+    UnknownHashAlgorithm(String),
+
+    #[error("Failed to serialize iris share: {0}")]
+    SerdeError(#[from] serde_json::Error),
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SharesS3Object {
     pub iris_share_0: String,
@@ -186,6 +241,116 @@ pub struct IrisCodesJSON {
     pub right_mask_code_shares: String, // these are base64 encoded strings
 }
 
+impl IrisCodesJSON {
+    /// Rejects shares whose `IRIS_version` or `IRIS_shares_version` is not in
+    /// `supported`. The hash algorithm suffix of the shares version (see
+    /// [`ShareHashAlgorithm`]) is ignored here.
+    pub fn check_supported_versions(
+        &self,
+        supported: &VersionSet,
+    ) -> Result<(), SharesDecodingError> {
+        if !supported.iris_versions.contains(&self.iris_version) {
+            return Err(SharesDecodingError::UnsupportedVersion {
+                field:   "IRIS_version",
+                version: self.iris_version.clone(),
+            });
+        }
+        let shares_version = self
+            .iris_shares_version
+            .split_once(ShareHashAlgorithm::SEPARATOR)
+            .map_or(self.iris_shares_version.as_str(), |(version, _)| version);
+        if !supported.iris_shares_versions.contains(shares_version) {
+            return Err(SharesDecodingError::UnsupportedVersion {
+                field:   "IRIS_shares_version",
+                version: self.iris_shares_version.clone(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// `IRIS_version`s of the shares this party can process.
+pub const SUPPORTED_IRIS_VERSIONS: &[&str] = &["1.0", "1.1"];
+/// `IRIS_shares_version`s of the shares this party can process.
+pub const SUPPORTED_IRIS_SHARES_VERSIONS: &[&str] = &["1.0", "1.3"];
+
+/// Versions accepted by [`IrisCodesJSON::check_supported_versions`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionSet {
+    pub iris_versions:        HashSet<String>,
+    pub iris_shares_versions: HashSet<String>,
+}
+
+impl VersionSet {
+    pub fn new<I, S>(iris_versions: I, iris_shares_versions: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            iris_versions:        iris_versions.into_iter().map(Into::into).collect(),
+            iris_shares_versions: iris_shares_versions.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl Default for VersionSet {
+    fn default() -> Self {
+        Self::new(
+            SUPPORTED_IRIS_VERSIONS.iter().copied(),
+            SUPPORTED_IRIS_SHARES_VERSIONS.iter().copied(),
+        )
+    }
+}
+
+/// Hash algorithm of the share hashes in [`UniquenessRequest`]. It is encoded
+/// in the `IRIS_shares_version` of the shares as a `+<algorithm>` suffix, e.g.
+/// `1.3+sha3-256`. Versions without a suffix use SHA-256.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShareHashAlgorithm {
+    #[default]
+    Sha256,
+    Sha3_256,
+}
+
+impl ShareHashAlgorithm {
+    const SEPARATOR: char = '+';
+
+    fn name(self) -> &'static str {
+        match self {
+            ShareHashAlgorithm::Sha256 => "sha256",
+            ShareHashAlgorithm::Sha3_256 => "sha3-256",
+        }
+    }
+
+    /// Parses the algorithm from an `IRIS_shares_version`.
+    pub fn from_shares_version(version: &str) -> Result<Self, ValidationError> {
+        match version.split_once(Self::SEPARATOR) {
+            None => Ok(ShareHashAlgorithm::Sha256),
+            Some((_, name)) => [ShareHashAlgorithm::Sha256, ShareHashAlgorithm::Sha3_256]
+                .into_iter()
+                .find(|algorithm| algorithm.name() == name)
+                .ok_or_else(|| ValidationError::UnknownHashAlgorithm(version.to_string())),
+        }
+    }
+
+    /// Appends the algorithm to `version`. SHA-256 leaves it unchanged, so
+    /// that existing versions keep their meaning.
+    pub fn shares_version(self, version: &str) -> String {
+        match self {
+            ShareHashAlgorithm::Sha256 => version.to_string(),
+            _ => format!("{}{}{}", version, Self::SEPARATOR, self.name()),
+        }
+    }
+
+    pub fn hash<T: AsRef<[u8]>>(self, data: T) -> String {
+        match self {
+            ShareHashAlgorithm::Sha256 => calculate_sha256(data),
+            ShareHashAlgorithm::Sha3_256 => calculate_sha3_256(data),
+        }
+    }
+}
+
 impl SharesS3Object {
     pub fn get(&self, party_id: usize) -> Option<&String> {
         match party_id {
@@ -200,10 +365,8 @@ impl SharesS3Object {
 static S3_HTTP_CLIENT: LazyLock<Client> = LazyLock::new(Client::new);
 
 impl UniquenessRequest {
-    pub async fn get_iris_data_by_party_id(
-        &self,
-        party_id: usize,
-    ) -> Result<String, SharesDecodingError> {
+    /// Downloads the shares file from the presigned URL.
+    async fn fetch_shares_file(&self) -> Result<SharesS3Object, SharesDecodingError> {
         // Send a GET request to the presigned URL
         let retry_strategy = FixedInterval::from_millis(200).map(jitter).take(5);
         let response = Retry::spawn(retry_strategy, || async {
@@ -217,22 +380,12 @@ impl UniquenessRequest {
         // Ensure the request was successful
         if response.status().is_success() {
             // Parse the JSON response into the SharesS3Object struct
-            let shares_file: SharesS3Object = match response.json().await {
-                Ok(file) => file,
+            match response.json().await {
+                Ok(file) => Ok(file),
                 Err(e) => {
                     tracing::error!("Failed to parse JSON: {}", e);
-                    return Err(SharesDecodingError::RequestError(e));
+                    Err(SharesDecodingError::RequestError(e))
                 }
-            };
-
-            // Construct the field name dynamically
-            let field_name = format!("iris_share_{}", party_id);
-            // Access the field dynamically
-            if let Some(value) = shares_file.get(party_id) {
-                Ok(value.to_string())
-            } else {
-                tracing::error!("Failed to find field: {}", field_name);
-                Err(SharesDecodingError::SecretStringNotFound)
             }
         } else {
             tracing::error!("Failed to download file: {}", response.status());
@@ -244,6 +397,34 @@ impl UniquenessRequest {
         }
     }
 
+    pub async fn get_iris_data_by_party_id(
+        &self,
+        party_id: usize,
+    ) -> Result<String, SharesDecodingError> {
+        let shares_file = self.fetch_shares_file().await?;
+
+        // Construct the field name dynamically
+        let field_name = format!("iris_share_{}", party_id);
+        // Access the field dynamically
+        if let Some(value) = shares_file.get(party_id) {
+            Ok(value.to_string())
+        } else {
+            tracing::error!("Failed to find field: {}", field_name);
+            Err(SharesDecodingError::SecretStringNotFound)
+        }
+    }
+
+    /// Returns the shares of all three parties. They are stored in a single
+    /// file behind the presigned URL, so this is a single download.
+    pub async fn prefetch_all(&self) -> Result<[String; 3], SharesDecodingError> {
+        let SharesS3Object {
+            iris_share_0,
+            iris_share_1,
+            iris_share_2,
+        } = self.fetch_shares_file().await?;
+        Ok([iris_share_0, iris_share_1, iris_share_2])
+    }
+
     pub fn decrypt_iris_share(
         &self,
         share: String,
@@ -253,24 +434,11 @@ impl UniquenessRequest {
             .decode(share.as_bytes())
             .map_err(|_| SharesDecodingError::Base64DecodeError)?;
 
-        // try decrypting with key_pairs.current_key_pair, if it fails, try decrypting
-        // with key_pairs.previous_key_pair (if it exists, otherwise, return an error)
-        let decrypted = match key_pairs
-            .current_key_pair
-            .open_sealed_box(share_bytes.clone())
-        {
-            Ok(bytes) => Ok(bytes),
-            Err(_) => {
-                match if let Some(key_pair) = key_pairs.previous_key_pair.clone() {
-                    key_pair.open_sealed_box(share_bytes)
-                } else {
-                    Err(SharesDecodingError::PreviousKeyNotFound)
-                } {
-                    Ok(bytes) => Ok(bytes),
-                    Err(_) => Err(SharesDecodingError::SealedBoxOpenError),
-                }
-            }
-        };
+        // try decrypting with the current key pair, and then with the previous one if
+        // it exists
+        let decrypted = key_pairs
+            .open_sealed_box(&share_bytes)
+            .map_err(|_| SharesDecodingError::SealedBoxOpenError);
 
         let iris_share = match decrypted {
             Ok(bytes) => {
@@ -287,16 +455,64 @@ impl UniquenessRequest {
         Ok(iris_share)
     }
 
+    /// Same as [`Self::decrypt_iris_share`], but decodes the share into
+    /// `buffer`, which is cleared first and can be reused across shares, and
+    /// parses the plaintext with `serde_json::from_slice` instead of going
+    /// through a `String`. Invalid UTF-8 is therefore reported as a
+    /// [`SharesDecodingError::SerdeError`].
+    pub fn decrypt_iris_share_into(
+        &self,
+        share: &str,
+        key_pairs: &SharesEncryptionKeyPairs,
+        buffer: &mut Vec<u8>,
+    ) -> Result<IrisCodesJSON, SharesDecodingError> {
+        buffer.clear();
+        STANDARD
+            .decode_vec(share.as_bytes(), buffer)
+            .map_err(|_| SharesDecodingError::Base64DecodeError)?;
+
+        let decrypted = key_pairs
+            .open_sealed_box(buffer)
+            .map_err(|_| SharesDecodingError::SealedBoxOpenError)?;
+
+        serde_json::from_slice(&decrypted).map_err(SharesDecodingError::SerdeError)
+    }
+
+    /// Checks that the request carries one share hash per party.
+    pub fn check_party_count(&self, n_parties: usize) -> Result<(), ValidationError> {
+        if self.iris_shares_file_hashes.len() != n_parties {
+            return Err(ValidationError::PartyCount {
+                expected: n_parties,
+                got:      self.iris_shares_file_hashes.len(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks that the hash of the serialized `share` matches the hash the
+    /// client sent for `party_id`. The hash algorithm is taken from the
+    /// shares version, see [`ShareHashAlgorithm`].
     pub fn validate_iris_share(
         &self,
         party_id: usize,
         share: IrisCodesJSON,
-    ) -> Result<bool, SharesDecodingError> {
-        let stringified_share = serde_json::to_string(&share)
-            .map_err(SharesDecodingError::SerdeError)?
-            .into_bytes();
-
-        Ok(self.iris_shares_file_hashes[party_id] == calculate_sha256(stringified_share))
+    ) -> Result<(), ValidationError> {
+        let expected = self
+            .iris_shares_file_hashes
+            .get(party_id)
+            .ok_or(ValidationError::UnknownParty(party_id))?;
+        let algorithm = ShareHashAlgorithm::from_shares_version(&share.iris_shares_version)?;
+        let stringified_share = serde_json::to_string(&share)?.into_bytes();
+        let got = algorithm.hash(stringified_share);
+
+        if *expected != got {
+            return Err(ValidationError::HashMismatch {
+                party: party_id,
+                expected: expected.clone(),
+                got,
+            });
+        }
+        Ok(())
     }
 }
 
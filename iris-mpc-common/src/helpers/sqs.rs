@@ -0,0 +1,85 @@
+use crate::galois_engine::degree4::GaloisRingIrisCodeShare;
+use thiserror::Error;
+
+/// Obviously corrupt iris shares in a request.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum RequestError {
+    #[error("Iris {share} share belongs to party {got}, expected party {expected}")]
+    WrongParty {
+        share:    &'static str,
+        expected: usize,
+        got:      usize,
+    },
+    #[error("Iris {0} share is all zeros")]
+    AllZero(&'static str),
+}
+
+/// Checks the decoded code and mask shares of one eye of `party_id`
+/// (zero-based) before they reach the GPU. On its own a share is uniformly
+/// random, so without the shares of the other parties only its party and
+/// all-zero coefs, which an honest encoding produces with negligible
+/// probability, can be checked.
+pub fn sanity_check_shares(
+    party_id: usize,
+    code: &GaloisRingIrisCodeShare,
+    mask: &GaloisRingIrisCodeShare,
+) -> Result<(), RequestError> {
+    for (name, share) in [("code", code), ("mask", mask)] {
+        if share.id != party_id + 1 {
+            return Err(RequestError::WrongParty {
+                share:    name,
+                expected: party_id + 1,
+                got:      share.id,
+            });
+        }
+        if share.coefs.iter().all(|&coef| coef == 0) {
+            return Err(RequestError::AllZero(name));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sanity_check_shares, RequestError};
+    use crate::{galois_engine::degree4::GaloisRingIrisCodeShare, iris_db::iris::IrisCode};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn shares() -> [(GaloisRingIrisCodeShare, GaloisRingIrisCodeShare); 3] {
+        let mut rng = StdRng::seed_from_u64(42);
+        let iris = IrisCode::random_rng(&mut rng);
+        let codes = GaloisRingIrisCodeShare::encode_iris_code(&iris.code, &iris.mask, &mut rng);
+        let masks = GaloisRingIrisCodeShare::encode_mask_code(&iris.mask, &mut rng);
+        std::array::from_fn(|i| (codes[i].clone(), masks[i].clone()))
+    }
+
+    #[test]
+    fn test_sanity_check_valid_shares() {
+        for (party_id, (code, mask)) in shares().iter().enumerate() {
+            assert_eq!(sanity_check_shares(party_id, code, mask), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_sanity_check_all_zero_mask() {
+        let [(code, _), ..] = shares();
+        let mask = GaloisRingIrisCodeShare::default_for_party(1);
+        assert_eq!(
+            sanity_check_shares(0, &code, &mask),
+            Err(RequestError::AllZero("mask"))
+        );
+    }
+
+    #[test]
+    fn test_sanity_check_wrong_party() {
+        let [_, (code, mask), _] = shares();
+        assert_eq!(
+            sanity_check_shares(0, &code, &mask),
+            Err(RequestError::WrongParty {
+                share:    "code",
+                expected: 1,
+                got:      2,
+            })
+        );
+    }
+}
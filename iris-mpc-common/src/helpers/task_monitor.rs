@@ -2,10 +2,41 @@
 
 use eyre::Result;
 use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
     ops::{Deref, DerefMut},
     panic,
+    time::Duration,
 };
-use tokio::task::{JoinError, JoinSet};
+use thiserror::Error;
+use tokio::task::{AbortHandle, Id, JoinError, JoinSet};
+
+/// The number of monitored tasks that didn't finish in time after being
+/// aborted.
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("{0} monitored tasks hung even when aborted")]
+pub struct HungTasks(pub usize);
+
+/// Lifetime task counts of a `TaskMonitor`. Tasks spawned or joined directly
+/// through the inner `JoinSet` aren't counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TaskStats {
+    /// Tasks that have been spawned, including those that have finished.
+    pub spawned:  usize,
+    /// Tasks that have exited in any way, including panics and cancellations.
+    pub finished: usize,
+    /// Tasks that have panicked.
+    pub panicked: usize,
+}
+
+impl TaskStats {
+    fn record<T>(&mut self, result: &Result<T, JoinError>) {
+        self.finished += 1;
+        if matches!(result, Err(join_err) if join_err.is_panic()) {
+            self.panicked += 1;
+        }
+    }
+}
 
 /// A long-running async task monitor which checks all its tasks for panics or
 /// hangs when dropped. Designed for ongoing tasks which run until the program
@@ -19,6 +50,12 @@ use tokio::task::{JoinError, JoinSet};
 #[derive(Debug, Default)]
 pub struct TaskMonitor {
     pub tasks: JoinSet<Result<()>>,
+    /// Tasks spawned with `spawn_named()`.
+    named:     HashMap<String, AbortHandle>,
+    /// Tasks aborted with `abort_by_name()`, which are expected to finish.
+    aborted:   HashSet<Id>,
+    /// Lifetime task counts, see `stats()`.
+    stats:     TaskStats,
 }
 
 // Instead of writing trivial wrappers for all the useful JoinSet methods, we
@@ -70,11 +107,99 @@ impl TaskMonitor {
     /// batch or long-running operation.
     pub fn check_tasks(&mut self) {
         // Any finished task is an error, so we just need to check for the first one.
-        if let Some(finished_task) = self.tasks.try_join_next() {
+        if let Some(finished_task) = self.try_join_next_unexpected() {
             Self::panic_with_task_status(finished_task);
         }
     }
 
+    /// Hands off the monitored tasks without aborting them, unlike dropping the
+    /// monitor.
+    pub fn into_join_set(mut self) -> JoinSet<Result<()>> {
+        // Drop still runs, but only aborts the empty set left behind
+        std::mem::take(&mut self.tasks)
+    }
+
+    /// Returns how many tasks have been spawned, have finished, and have
+    /// panicked over the lifetime of this monitor.
+    pub fn stats(&self) -> TaskStats {
+        self.stats
+    }
+
+    /// Spawns `task` on the monitored set, counting it in `stats()`. Shadows
+    /// `JoinSet::spawn()`.
+    pub fn spawn<F>(&mut self, task: F) -> AbortHandle
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.stats.spawned += 1;
+        self.tasks.spawn(task)
+    }
+
+    /// Spawns the blocking `f` on the monitored set, counting it in `stats()`.
+    /// Shadows `JoinSet::spawn_blocking()`.
+    pub fn spawn_blocking<F>(&mut self, f: F) -> AbortHandle
+    where
+        F: FnOnce() -> Result<()> + Send + 'static,
+    {
+        self.stats.spawned += 1;
+        self.tasks.spawn_blocking(f)
+    }
+
+    /// Spawns `task` and registers it under `name`, so it can be aborted with
+    /// `abort_by_name()`. A later task with the same name replaces this one in
+    /// the registry, but keeps it running.
+    ///
+    /// Finished tasks are pruned from the registry first, so it only grows with
+    /// the number of running named tasks.
+    pub fn spawn_named<F>(&mut self, name: impl Into<String>, task: F) -> Id
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.named.retain(|_, handle| !handle.is_finished());
+        let handle = self.spawn(task);
+        let id = handle.id();
+        self.named.insert(name.into(), handle);
+        id
+    }
+
+    /// Aborts the task spawned with `spawn_named()` under `name`. Returns
+    /// whether there was such a task which was still running.
+    ///
+    /// The cancellation of the aborted task is expected, so it isn't reported
+    /// by `check_tasks()`. Its panics still are.
+    pub fn abort_by_name(&mut self, name: &str) -> bool {
+        let Some(handle) = self.named.remove(name) else {
+            return false;
+        };
+        if handle.is_finished() {
+            return false;
+        }
+        handle.abort();
+        self.aborted.insert(handle.id());
+        true
+    }
+
+    /// Like `JoinSet::try_join_next()`, but skips tasks which were aborted
+    /// with `abort_by_name()` and then finished normally or were cancelled.
+    fn try_join_next_unexpected(&mut self) -> Option<Result<Result<()>, JoinError>> {
+        while let Some(result) = self.tasks.try_join_next_with_id() {
+            self.stats.record(&result);
+            let id = match &result {
+                Ok((id, _)) => *id,
+                Err(join_err) => join_err.id(),
+            };
+            let expected = match &result {
+                Ok((_, Ok(()))) => true,
+                Ok((_, Err(_))) => false,
+                Err(join_err) => join_err.is_cancelled(),
+            };
+            if !(self.aborted.remove(&id) && expected) {
+                return Some(result.map(|(_, result)| result));
+            }
+        }
+        None
+    }
+
     /// Checks for panics, cancellations, or early finishes, then aborts all
     /// tasks.
     ///
@@ -95,6 +220,7 @@ impl TaskMonitor {
     pub fn check_tasks_finished(&mut self) {
         // Any hung task is an error, so we need to check they've all finished.
         while let Some(finished_task) = self.tasks.try_join_next() {
+            self.stats.record(&finished_task);
             // If there is a hang (or hang panic) here, try calling abort_all() and waiting
             // before dropping the TaskMonitor.
             Self::resume_panic(finished_task);
@@ -114,6 +240,7 @@ impl TaskMonitor {
     pub fn check_tasks_finished_ignoring_hangs(&mut self) {
         // Any hung task is an error, so we need to check they've all finished.
         while let Some(finished_task) = self.tasks.try_join_next() {
+            self.stats.record(&finished_task);
             // If there is a hang (or hang panic) here, try calling abort_all() and waiting
             // before dropping the TaskMonitor.
             Self::resume_panic(finished_task);
@@ -132,6 +259,7 @@ impl TaskMonitor {
 
         // Any hung task is an error, so we need to check they've all finished.
         while let Some(finished_task) = self.tasks.join_next().await {
+            self.stats.record(&finished_task);
             Self::resume_panic(finished_task);
         }
 
@@ -141,6 +269,28 @@ impl TaskMonitor {
         assert!(self.tasks.is_empty());
     }
 
+    /// Like `abort_and_wait_for_finish()`, but stops waiting after `timeout`,
+    /// returning the number of tasks that are still running instead of
+    /// hanging.
+    pub async fn abort_and_wait_for_finish_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<(), HungTasks> {
+        self.abort_all();
+
+        let (tasks, stats) = (&mut self.tasks, &mut self.stats);
+        let wait = async {
+            while let Some(finished_task) = tasks.join_next().await {
+                stats.record(&finished_task);
+                Self::resume_panic(finished_task);
+            }
+        };
+        match tokio::time::timeout(timeout, wait).await {
+            Ok(()) => Ok(()),
+            Err(_) => Err(HungTasks(self.tasks.len())),
+        }
+    }
+
     /// If `result` is a task panic, resume that panic.
     /// If `result` is an `eyre::Report`, panic with that error.
     ///
@@ -169,3 +319,172 @@ impl TaskMonitor {
         panic!("Monitored task unexpectedly finished without an error");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{HungTasks, TaskMonitor, TaskStats};
+    use std::{
+        panic::AssertUnwindSafe,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use tokio::{sync::oneshot, time::timeout};
+
+    #[tokio::test]
+    async fn test_abort_by_name() {
+        let mut monitor = TaskMonitor::new();
+        monitor.spawn_named("a", std::future::pending());
+        monitor.spawn_named("b", std::future::pending());
+
+        assert!(monitor.abort_by_name("a"));
+        assert!(!monitor.abort_by_name("a"));
+        assert!(!monitor.abort_by_name("c"));
+
+        // Only the aborted task finishes, and its cancellation isn't reported
+        timeout(Duration::from_secs(5), async {
+            while monitor.len() > 1 {
+                monitor.check_tasks();
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(monitor.len(), 1);
+        assert!(monitor.abort_by_name("b"));
+    }
+
+    #[tokio::test]
+    async fn test_spawn_named_prunes_finished() {
+        let mut monitor = TaskMonitor::new();
+        for i in 0..10 {
+            monitor.spawn_named(format!("done-{}", i), async { Ok(()) });
+        }
+        timeout(Duration::from_secs(5), async {
+            while monitor.named.values().any(|handle| !handle.is_finished()) {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+
+        monitor.spawn_named("pending", std::future::pending());
+        assert_eq!(monitor.named.keys().collect::<Vec<_>>(), ["pending"]);
+        assert!(!monitor.abort_by_name("done-0"));
+        assert!(monitor.abort_by_name("pending"));
+    }
+
+    #[tokio::test]
+    async fn test_into_join_set() {
+        let mut monitor = TaskMonitor::new();
+        let (tx, rx) = oneshot::channel::<()>();
+        monitor.spawn(async move {
+            rx.await?;
+            Ok(())
+        });
+
+        let mut tasks = monitor.into_join_set();
+        tx.send(()).unwrap();
+        let result = timeout(Duration::from_secs(5), tasks.join_next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(result, Ok(Ok(()))));
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let mut monitor = TaskMonitor::new();
+        monitor.spawn(async { Ok(()) });
+        monitor.spawn(async { panic!("task panic") });
+        monitor.spawn(std::future::pending());
+        assert_eq!(monitor.stats(), TaskStats {
+            spawned:  3,
+            finished: 0,
+            panicked: 0,
+        });
+
+        // Let the tasks run
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // Reaping the panicked task resumes its panic
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            monitor.check_tasks_finished_ignoring_hangs();
+        }));
+        assert!(result.is_err());
+        monitor.check_tasks_finished_ignoring_hangs();
+        assert_eq!(monitor.stats(), TaskStats {
+            spawned:  3,
+            finished: 2,
+            panicked: 1,
+        });
+    }
+
+    #[tokio::test]
+    async fn test_stats_joined_through_deref() {
+        let mut monitor = TaskMonitor::new();
+        monitor.spawn(async { Ok(()) });
+        monitor.spawn_named("pending", std::future::pending());
+        monitor.spawn_blocking(|| Ok(()));
+
+        // Joining through the inner JoinSet bypasses the finished count, but
+        // doesn't make spawned tasks disappear
+        for _ in 0..2 {
+            let result = timeout(Duration::from_secs(5), monitor.join_next())
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(matches!(result, Ok(Ok(()))));
+        }
+        assert_eq!(monitor.stats(), TaskStats {
+            spawned:  3,
+            finished: 0,
+            panicked: 0,
+        });
+
+        monitor.spawn(async { Ok(()) });
+        assert_eq!(monitor.stats().spawned, 4);
+        assert!(monitor.abort_by_name("pending"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_abort_and_wait_for_finish_timeout() {
+        let mut monitor = TaskMonitor::new();
+        monitor.spawn(std::future::pending());
+        assert_eq!(
+            monitor
+                .abort_and_wait_for_finish_timeout(Duration::from_secs(5))
+                .await,
+            Ok(())
+        );
+
+        // A task that never yields can't be aborted
+        let started = Arc::new(AtomicBool::new(false));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (task_started, hung) = (started.clone(), stop.clone());
+        monitor.spawn(async move {
+            task_started.store(true, Ordering::Relaxed);
+            while !hung.load(Ordering::Relaxed) {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            Ok(())
+        });
+        monitor.spawn(std::future::pending());
+        // A task that is aborted before it first runs is just dropped
+        while !started.load(Ordering::Relaxed) {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            monitor
+                .abort_and_wait_for_finish_timeout(Duration::from_millis(100))
+                .await,
+            Err(HungTasks(1))
+        );
+
+        stop.store(true, Ordering::Relaxed);
+        while let Some(finished_task) = monitor.join_next().await {
+            TaskMonitor::resume_panic(finished_task);
+        }
+    }
+}
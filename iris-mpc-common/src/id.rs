@@ -1,7 +1,9 @@
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 
 /// An enum representing the party ID
-#[derive(std::cmp::Eq, std::cmp::PartialEq, Clone, Copy, Debug)]
+#[derive(std::cmp::Eq, std::cmp::PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
 #[repr(u8)]
 pub enum PartyID {
     /// Party 0
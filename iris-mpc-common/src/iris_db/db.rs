@@ -1,6 +1,8 @@
 use super::iris::IrisCode;
 use rand::{rngs::StdRng, Rng, SeedableRng};
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
 
 #[derive(Default)]
 pub struct IrisDB {
@@ -33,6 +35,16 @@ impl IrisDB {
         Self { db }
     }
 
+    /// Random DB whose masks have a fraction of about `density` set bits, see
+    /// [`IrisCode::random_with_mask_density`].
+    pub fn new_random_with_mask_density<R: Rng>(size: usize, density: f64, rng: &mut R) -> Self {
+        let db = (0..size)
+            .map(|_| IrisCode::random_with_mask_density(rng, density))
+            .collect();
+
+        Self { db }
+    }
+
     /// Only use for testing
     pub fn new_random_par<R: Rng>(size: usize, rng: &mut R) -> Self {
         // Fork out the rngs to be able to use them concurrently
@@ -61,6 +73,32 @@ impl IrisDB {
     }
 }
 
+/// Seed and size of the random DB of an end-to-end test. The servers seed
+/// their DB from it and the client regenerates the same DB from it to check
+/// the results, so both can read it from the same JSON file instead of
+/// having to agree on the values separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TestVectorSeed {
+    pub seed:    u64,
+    pub db_size: usize,
+}
+
+impl TestVectorSeed {
+    pub fn load(path: impl AsRef<Path>) -> eyre::Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> eyre::Result<()> {
+        Ok(fs::write(path, serde_json::to_string(self)?)?)
+    }
+
+    /// The plaintext DB generated from this seed, matching the shares written
+    /// by `Store::init_db_with_random_shares` with the same seed.
+    pub fn db(&self) -> IrisDB {
+        IrisDB::new_random_par(self.db_size, &mut StdRng::seed_from_u64(self.seed))
+    }
+}
+
 #[cfg(test)]
 mod iris_test {
     use super::*;
@@ -82,4 +120,44 @@ mod iris_test {
             assert_eq!(in_db, db.db.iter().any(|x| iris.is_close(x)));
         }
     }
+
+    #[test]
+    fn test_vector_seed_test() {
+        let path =
+            std::env::temp_dir().join(format!("test_vector_seed_{}.json", std::process::id()));
+        TestVectorSeed {
+            seed:    7,
+            db_size: DB_SIZE,
+        }
+        .save(&path)
+        .unwrap();
+
+        let server = TestVectorSeed::load(&path).unwrap().db();
+        let client = TestVectorSeed::load(&path).unwrap().db();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(server.len(), DB_SIZE);
+        assert_eq!(server.db, client.db);
+        assert_eq!(
+            server.db,
+            IrisDB::new_random_par(DB_SIZE, &mut StdRng::seed_from_u64(7)).db
+        );
+
+        assert!(TestVectorSeed::load(&path).is_err());
+    }
+
+    #[test]
+    fn mask_density_test() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for density in [0.0, 0.3, 0.75, 1.0] {
+            let db = IrisDB::new_random_with_mask_density(DB_SIZE, density, &mut rng);
+            let set_bits: usize = db.db.iter().map(|iris| iris.mask.count_ones()).sum();
+            let realized = set_bits as f64 / (DB_SIZE * IrisCode::IRIS_CODE_SIZE) as f64;
+            assert!(
+                (realized - density).abs() < 0.01,
+                "requested mask density {}, got {}",
+                density,
+                realized
+            );
+        }
+    }
 }
@@ -70,6 +70,34 @@ impl IrisCodeArray {
         bytemuck::cast_slice_mut(&mut self.0)
     }
 
+    /// Packs the array into a compact byte representation using one bit per
+    /// element. Element `i` is stored in byte `i / 8` at bit position `i % 8`
+    /// (least significant bit first), resulting in exactly
+    /// `IRIS_CODE_SIZE_BYTES` bytes.
+    pub fn pack_bits(&self) -> Vec<u8> {
+        let mut packed = vec![0u8; Self::IRIS_CODE_SIZE_BYTES];
+        for (i, bit) in self.bits().enumerate() {
+            packed[i / 8] |= (bit as u8) << (i % 8);
+        }
+        packed
+    }
+
+    /// Inverse of [`IrisCodeArray::pack_bits`].
+    pub fn unpack_bits(packed: &[u8]) -> eyre::Result<Self> {
+        if packed.len() != Self::IRIS_CODE_SIZE_BYTES {
+            bail!(
+                "Invalid length for packed iris code: expected {}, got {}",
+                Self::IRIS_CODE_SIZE_BYTES,
+                packed.len()
+            );
+        }
+        let mut code = IrisCodeArray::ZERO;
+        for i in 0..Self::IRIS_CODE_SIZE {
+            code.set_bit(i, (packed[i / 8] >> (i % 8)) & 1 == 1);
+        }
+        Ok(code)
+    }
+
     /// Decode from base64 string compatible with Open IRIS
     pub fn from_base64(s: &str) -> eyre::Result<Self> {
         let decoded_bytes = BASE64_STANDARD.decode(s)?;
@@ -176,6 +204,29 @@ impl IrisCode {
         code
     }
 
+    /// Like [`Self::random_rng`], but every mask bit pair is set with
+    /// probability `density` instead of removing about 10% of the mask.
+    ///
+    /// # Panics
+    ///
+    /// If `density` is not in `[0, 1]`.
+    pub fn random_with_mask_density<R: Rng>(rng: &mut R, density: f64) -> Self {
+        let mut code = IrisCode {
+            code: IrisCodeArray::random_rng(rng),
+            mask: IrisCodeArray::ZERO,
+        };
+
+        let dist = Bernoulli::new(density).expect("mask density must be in [0, 1]");
+        for i in 0..Self::IRIS_CODE_SIZE / 2 {
+            if dist.sample(rng) {
+                code.mask.set_bit(2 * i, true);
+                code.mask.set_bit(2 * i + 1, true);
+            }
+        }
+
+        code
+    }
+
     pub fn get_distance(&self, other: &Self) -> f64 {
         let combined_mask = self.mask & other.mask;
         let combined_mask_len = combined_mask.count_ones();
@@ -254,6 +305,19 @@ mod tests {
         }
     }
 
+    #[test]
+    fn pack_unpack_bits_roundtrip() {
+        let mut rng = rand::thread_rng();
+        let code = IrisCodeArray::random_rng(&mut rng);
+        let packed = code.pack_bits();
+        assert_eq!(packed.len(), IrisCodeArray::IRIS_CODE_SIZE_BYTES);
+        for i in 0..IrisCodeArray::IRIS_CODE_SIZE {
+            assert_eq!((packed[i / 8] >> (i % 8)) & 1 == 1, code.get_bit(i));
+        }
+        assert_eq!(IrisCodeArray::unpack_bits(&packed).unwrap(), code);
+        assert!(IrisCodeArray::unpack_bits(&packed[1..]).is_err());
+    }
+
     #[test]
     fn decode_from_string() {
         let (code_str, rotations) =
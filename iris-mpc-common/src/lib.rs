@@ -1,6 +1,7 @@
 #![allow(clippy::needless_range_loop)]
 pub mod config;
 pub mod error;
+pub mod eye;
 pub mod galois;
 pub mod galois_engine;
 pub mod helpers;
@@ -0,0 +1,58 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::StatusCode;
+use iris_mpc_common::helpers::smpc_request::{
+    IrisCodesJSON, ShareHashAlgorithm, SharesS3Object, UniquenessRequest,
+};
+use sodiumoxide::crypto::{box_::PublicKey, sealedbox};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+/// Serves sealed iris shares the way the client uploads them to S3, so tests
+/// get a ready [`UniquenessRequest`] pointing at them.
+pub struct MockIrisShareStore {
+    server:   MockServer,
+    n_shares: AtomicUsize,
+}
+
+impl MockIrisShareStore {
+    pub async fn start() -> Self {
+        Self {
+            server:   MockServer::start().await,
+            n_shares: AtomicUsize::new(0),
+        }
+    }
+
+    /// Seals `share` for `public_key` as the share of every party, serves the
+    /// shares file at a new presigned URL and returns a request for it. The
+    /// hashes are computed with the algorithm of the shares version.
+    pub async fn serve(&self, share: &IrisCodesJSON, public_key: &PublicKey) -> UniquenessRequest {
+        let id = self.n_shares.fetch_add(1, Ordering::Relaxed);
+        let url_path = format!("/presigned/{}", id);
+
+        let serialized = serde_json::to_string(share).unwrap();
+        let sealed = || STANDARD.encode(sealedbox::seal(serialized.as_bytes(), public_key));
+        let shares_file = SharesS3Object {
+            iris_share_0: sealed(),
+            iris_share_1: sealed(),
+            iris_share_2: sealed(),
+        };
+        Mock::given(method("GET"))
+            .and(path(url_path.clone()))
+            .respond_with(ResponseTemplate::new(StatusCode::OK).set_body_json(shares_file))
+            .mount(&self.server)
+            .await;
+
+        let hash = ShareHashAlgorithm::from_shares_version(&share.iris_shares_version)
+            .unwrap()
+            .hash(&serialized);
+        UniquenessRequest {
+            batch_size:              None,
+            signup_id:               format!("signup_{}", id),
+            s3_presigned_url:        self.server.uri() + &url_path,
+            iris_shares_file_hashes: vec![hash; 3],
+        }
+    }
+}
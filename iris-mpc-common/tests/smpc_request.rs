@@ -1,10 +1,16 @@
+mod common;
+
 mod tests {
+    use crate::common::MockIrisShareStore;
     use base64::{engine::general_purpose::STANDARD, Engine};
     use http::StatusCode;
     use iris_mpc_common::helpers::{
         key_pair::{SharesDecodingError, SharesEncryptionKeyPairs},
-        sha256::calculate_sha256,
-        smpc_request::{IrisCodesJSON, UniquenessRequest},
+        sha256::{calculate_sha256, calculate_sha3_256},
+        smpc_request::{
+            compute_result_hmac, verify_result_hmac, IrisCodesJSON, ShareHashAlgorithm,
+            UniquenessRequest, UniquenessResult, ValidationError, VersionSet,
+        },
     };
     use serde_json::json;
     use sodiumoxide::crypto::{box_::PublicKey, sealedbox};
@@ -46,7 +52,7 @@ mod tests {
             batch_size:              Some(1),
             signup_id:               "signup_mock".to_string(),
             s3_presigned_url:        "https://example.com/mock".to_string(),
-            iris_shares_file_hashes: hashes,
+            iris_shares_file_hashes: hashes.to_vec(),
         }
     }
 
@@ -55,7 +61,7 @@ mod tests {
             batch_size:              None,
             signup_id:               "test_signup_id".to_string(),
             s3_presigned_url:        "https://example.com/package".to_string(),
-            iris_shares_file_hashes: [
+            iris_shares_file_hashes: vec![
                 "hash_0".to_string(),
                 "hash_1".to_string(),
                 "hash_2".to_string(),
@@ -86,7 +92,7 @@ mod tests {
             batch_size:              None,
             signup_id:               "test_signup_id".to_string(),
             s3_presigned_url:        mock_server.uri().clone() + "/test_presign_url",
-            iris_shares_file_hashes: [
+            iris_shares_file_hashes: vec![
                 "hash_0".to_string(),
                 "hash_1".to_string(),
                 "hash_2".to_string(),
@@ -99,9 +105,40 @@ mod tests {
         assert_eq!(result.unwrap(), "share_0_data".to_string());
     }
 
+    #[tokio::test]
+    async fn test_prefetch_all_shares() {
+        let mock_server = MockServer::start().await;
+
+        let response_body = json!({
+            "iris_share_0": "share_0_data",
+            "iris_share_1": "share_1_data",
+            "iris_share_2": "share_2_data"
+        });
+
+        let template = ResponseTemplate::new(StatusCode::OK).set_body_json(response_body);
+
+        Mock::given(method("GET"))
+            .and(path("/test_presign_url"))
+            .respond_with(template)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut smpc_request = get_mock_request();
+        smpc_request.s3_presigned_url = mock_server.uri() + "/test_presign_url";
+
+        let shares = smpc_request.prefetch_all().await.unwrap();
+
+        assert_eq!(shares, [
+            "share_0_data".to_string(),
+            "share_1_data".to_string(),
+            "share_2_data".to_string()
+        ]);
+        mock_server.verify().await;
+    }
+
     #[tokio::test]
     async fn test_decrypt_iris_share_success() {
-        // Mocked base64 encoded JSON string
         let iris_codes_json = IrisCodesJSON {
             iris_version:           "1.0".to_string(),
             iris_shares_version:    "1.3".to_string(),
@@ -114,12 +151,11 @@ mod tests {
         let decoded_public_key = STANDARD.decode(CURRENT_PUBLIC_KEY.as_bytes()).unwrap();
         let shares_encryption_public_key = PublicKey::from_slice(&decoded_public_key).unwrap();
 
-        // convert iris code to JSON string, sealbox and encode as BASE64
-        let json_string = serde_json::to_string(&iris_codes_json).unwrap();
-        let sealed_box = sealedbox::seal(json_string.as_bytes(), &shares_encryption_public_key);
-        let encoded_share = STANDARD.encode(sealed_box);
-
-        let smpc_request = get_mock_request();
+        let store = MockIrisShareStore::start().await;
+        let smpc_request = store
+            .serve(&iris_codes_json, &shares_encryption_public_key)
+            .await;
+        let encoded_share = smpc_request.get_iris_data_by_party_id(0).await.unwrap();
         let key_pair = get_key_pairs(
             PREVIOUS_PRIVATE_KEY.to_string(),
             CURRENT_PRIVATE_KEY.to_string(),
@@ -159,6 +195,39 @@ mod tests {
         assert_eq!(result.unwrap(), iris_codes_json);
     }
 
+    #[tokio::test]
+    async fn test_decrypt_iris_share_into_matches_buffered() {
+        let smpc_request = get_mock_request();
+        let key_pair = get_key_pairs(
+            CURRENT_PRIVATE_KEY.to_string(),
+            PREVIOUS_PRIVATE_KEY.to_string(),
+        );
+
+        // The buffer is reused for shares sealed with either key
+        let mut buffer = Vec::new();
+        for public_key in [CURRENT_PUBLIC_KEY, PREVIOUS_PUBLIC_KEY] {
+            let decoded_public_key = STANDARD.decode(public_key.as_bytes()).unwrap();
+            let shares_encryption_public_key = PublicKey::from_slice(&decoded_public_key).unwrap();
+            let json_string = serde_json::to_string(&mock_iris_codes_json()).unwrap();
+            let sealed_box = sealedbox::seal(json_string.as_bytes(), &shares_encryption_public_key);
+            let encoded_share = STANDARD.encode(sealed_box);
+
+            let streamed = smpc_request
+                .decrypt_iris_share_into(&encoded_share, &key_pair, &mut buffer)
+                .unwrap();
+            let buffered = smpc_request
+                .decrypt_iris_share(encoded_share, key_pair.clone())
+                .unwrap();
+            assert_eq!(streamed, buffered);
+            assert_eq!(streamed, mock_iris_codes_json());
+        }
+
+        assert!(matches!(
+            smpc_request.decrypt_iris_share_into("InvalidBase64String", &key_pair, &mut buffer),
+            Err(SharesDecodingError::Base64DecodeError)
+        ));
+    }
+
     #[tokio::test]
     async fn test_decrypt_iris_share_non_existent_previous_private_key() {
         // Mocked base64 encoded JSON string
@@ -186,6 +255,30 @@ mod tests {
         ));
     }
 
+    #[tokio::test]
+    async fn test_absent_previous_key() {
+        for previous in ["", "  "] {
+            let key_pairs = get_key_pairs(CURRENT_PRIVATE_KEY.to_string(), previous.to_string());
+            assert!(key_pairs.previous_key_pair.is_none());
+        }
+        let key_pairs = get_key_pairs(CURRENT_PRIVATE_KEY.to_string(), "".to_string());
+
+        // Shares for the current key still open without a previous key
+        let decoded_public_key = STANDARD.decode(CURRENT_PUBLIC_KEY.as_bytes()).unwrap();
+        let current_public_key = PublicKey::from_slice(&decoded_public_key).unwrap();
+        let sealed_box = sealedbox::seal(b"share", &current_public_key);
+        assert_eq!(key_pairs.open_sealed_box(&sealed_box).unwrap(), b"share");
+
+        // Shares for any other key fail without attempting a previous key
+        let decoded_public_key = STANDARD.decode(PREVIOUS_PUBLIC_KEY.as_bytes()).unwrap();
+        let previous_public_key = PublicKey::from_slice(&decoded_public_key).unwrap();
+        let sealed_box = sealedbox::seal(b"share", &previous_public_key);
+        assert!(matches!(
+            key_pairs.open_sealed_box(&sealed_box),
+            Err(SharesDecodingError::PreviousKeyNotFound)
+        ));
+    }
+
     #[tokio::test]
     async fn test_decrypt_iris_share_invalid_base64() {
         let invalid_base64 = "InvalidBase64String";
@@ -249,26 +342,25 @@ mod tests {
     #[tokio::test]
     async fn test_validate_iris_share() {
         let mock_iris_codes_json = mock_iris_codes_json();
-        let mock_serialized_iris = serde_json::to_string(&mock_iris_codes_json).unwrap();
-        let mock_hash = calculate_sha256(mock_serialized_iris.into_bytes());
+        let decoded_public_key = STANDARD.decode(CURRENT_PUBLIC_KEY.as_bytes()).unwrap();
+        let shares_encryption_public_key = PublicKey::from_slice(&decoded_public_key).unwrap();
 
-        let smpc_request = get_mock_smpc_request_with_hashes([
-            mock_hash.clone(),
-            "dummy_hash_1".to_string(),
-            "dummy_hash_2".to_string(),
-        ]);
+        let store = MockIrisShareStore::start().await;
+        let smpc_request = store
+            .serve(&mock_iris_codes_json, &shares_encryption_public_key)
+            .await;
 
-        let is_valid = smpc_request
-            .validate_iris_share(0, mock_iris_codes_json)
-            .unwrap();
+        let result = smpc_request.validate_iris_share(0, mock_iris_codes_json);
 
-        assert!(is_valid, "The iris share should be valid");
+        assert!(result.is_ok(), "The iris share should be valid");
     }
 
     #[tokio::test]
     async fn test_validate_iris_share_invalid() {
         // Arrange
         let mock_iris_codes_json = mock_iris_codes_json();
+        let mock_serialized_iris = serde_json::to_string(&mock_iris_codes_json).unwrap();
+        let mock_hash = calculate_sha256(mock_serialized_iris.into_bytes());
         let incorrect_hash = "incorrect_hash_value".to_string();
 
         let smpc_request = get_mock_smpc_request_with_hashes([
@@ -278,11 +370,129 @@ mod tests {
         ]);
 
         // Act
-        let is_valid = smpc_request
-            .validate_iris_share(0, mock_iris_codes_json)
-            .unwrap();
+        let result = smpc_request.validate_iris_share(0, mock_iris_codes_json.clone());
 
         // Assert
-        assert!(!is_valid, "The iris share should be invalid");
+        match result {
+            Err(ValidationError::HashMismatch {
+                party,
+                expected,
+                got,
+            }) => {
+                assert_eq!(party, 0);
+                assert_eq!(expected, "incorrect_hash_value");
+                assert_eq!(got, mock_hash);
+            }
+            other => panic!("Expected a hash mismatch, got {:?}", other),
+        }
+        assert!(matches!(
+            smpc_request.validate_iris_share(5, mock_iris_codes_json),
+            Err(ValidationError::UnknownParty(5))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_validate_iris_share_sha3() {
+        let mut mock_iris_codes_json = mock_iris_codes_json();
+        mock_iris_codes_json.iris_shares_version =
+            ShareHashAlgorithm::Sha3_256.shares_version("1.3");
+        assert_eq!(mock_iris_codes_json.iris_shares_version, "1.3+sha3-256");
+        let mock_serialized_iris = serde_json::to_string(&mock_iris_codes_json).unwrap();
+
+        let smpc_request = get_mock_smpc_request_with_hashes([
+            calculate_sha3_256(mock_serialized_iris.clone().into_bytes()),
+            "dummy_hash_1".to_string(),
+            "dummy_hash_2".to_string(),
+        ]);
+        assert!(smpc_request
+            .validate_iris_share(0, mock_iris_codes_json.clone())
+            .is_ok());
+
+        // A SHA-256 hash does not validate a share that declares SHA3-256
+        let smpc_request = get_mock_smpc_request_with_hashes([
+            calculate_sha256(mock_serialized_iris.into_bytes()),
+            "dummy_hash_1".to_string(),
+            "dummy_hash_2".to_string(),
+        ]);
+        assert!(matches!(
+            smpc_request.validate_iris_share(0, mock_iris_codes_json.clone()),
+            Err(ValidationError::HashMismatch { .. })
+        ));
+
+        mock_iris_codes_json.iris_shares_version = "1.3+md5".to_string();
+        assert!(matches!(
+            smpc_request.validate_iris_share(0, mock_iris_codes_json),
+            Err(ValidationError::UnknownHashAlgorithm(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_party_count() {
+        let mut smpc_request = get_mock_request();
+        assert!(smpc_request.check_party_count(3).is_ok());
+
+        smpc_request.iris_shares_file_hashes.pop();
+        assert!(matches!(
+            smpc_request.check_party_count(3),
+            Err(ValidationError::PartyCount {
+                expected: 3,
+                got:      2,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_check_supported_versions() {
+        let supported = VersionSet::new(["1.0"], ["1.3"]);
+
+        let mut share = mock_iris_codes_json();
+        assert!(share.check_supported_versions(&supported).is_ok());
+        share.iris_shares_version = ShareHashAlgorithm::Sha3_256.shares_version("1.3");
+        assert!(share.check_supported_versions(&supported).is_ok());
+        assert!(share
+            .check_supported_versions(&VersionSet::default())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_check_unsupported_versions() {
+        let supported = VersionSet::new(["1.0"], ["1.3"]);
+
+        let mut share = mock_iris_codes_json();
+        share.iris_version = "9.9".to_string();
+        assert!(matches!(
+            share.check_supported_versions(&supported),
+            Err(SharesDecodingError::UnsupportedVersion { field: "IRIS_version", version }) if version == "9.9"
+        ));
+
+        let mut share = mock_iris_codes_json();
+        share.iris_shares_version = "9.9".to_string();
+        assert!(matches!(
+            share.check_supported_versions(&supported),
+            Err(SharesDecodingError::UnsupportedVersion { field: "IRIS_shares_version", version }) if version == "9.9"
+        ));
+    }
+
+    #[test]
+    fn test_result_hmac() {
+        let key = b"shared secret";
+        let result = UniquenessResult::new(
+            0,
+            Some(42),
+            false,
+            "signup_id".to_string(),
+            None,
+            None,
+            None,
+        );
+        let body = serde_json::to_string(&result).unwrap();
+        let hmac = compute_result_hmac(key, &body);
+        assert!(verify_result_hmac(key, &body, &hmac));
+
+        let tampered_body = body.replace("42", "43");
+        assert_ne!(body, tampered_body);
+        assert!(!verify_result_hmac(key, &tampered_body, &hmac));
+        assert!(!verify_result_hmac(b"other secret", &body, &hmac));
+        assert!(!verify_result_hmac(key, &body, "not hex"));
     }
 }
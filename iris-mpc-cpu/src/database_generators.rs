@@ -1,7 +1,8 @@
-use crate::shares::{ring_impl::RingElement, share::Share};
+use crate::shares::{bit::Bit, ring_impl::RingElement, share::Share};
 use iris_mpc_common::{
     galois_engine::degree4::{GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare},
     iris_db::iris::IrisCode,
+    IRIS_CODE_LENGTH, MASK_CODE_LENGTH,
 };
 use rand::{CryptoRng, Rng, RngCore};
 
@@ -14,6 +15,32 @@ pub struct GaloisRingSharedIris {
     pub mask: GaloisRingTrimmedMaskCodeShare,
 }
 
+impl GaloisRingSharedIris {
+    /// Computes the `trick_dot` of the codes and of the masks with `other` in
+    /// a single pass over both coefficient arrays. The mask dot is the one of
+    /// the trimmed masks, so it still has to be doubled by the caller.
+    pub fn code_and_mask_dot(&self, other: &Self) -> (u16, u16) {
+        const CODE_PER_MASK: usize = IRIS_CODE_LENGTH / MASK_CODE_LENGTH;
+
+        let mut code_dot = 0u16;
+        let mut mask_dot = 0u16;
+        for (((x_code, y_code), x_mask), y_mask) in self
+            .code
+            .coefs
+            .chunks_exact(CODE_PER_MASK)
+            .zip(other.code.coefs.chunks_exact(CODE_PER_MASK))
+            .zip(self.mask.coefs.iter())
+            .zip(other.mask.coefs.iter())
+        {
+            for (x, y) in x_code.iter().zip(y_code) {
+                code_dot = code_dot.wrapping_add(x.wrapping_mul(*y));
+            }
+            mask_dot = mask_dot.wrapping_add(x_mask.wrapping_mul(*y_mask));
+        }
+        (code_dot, mask_dot)
+    }
+}
+
 pub fn create_random_sharing<R: RngCore>(rng: &mut R, input: u16) -> Vec<Share<u16>> {
     let val = RingElement(input);
     let a = rng.gen::<ShareRingPlain>();
@@ -27,6 +54,19 @@ pub fn create_random_sharing<R: RngCore>(rng: &mut R, input: u16) -> Vec<Share<u
     vec![share1, share2, share3]
 }
 
+pub fn create_random_bit_sharing<R: RngCore>(rng: &mut R, input: bool) -> Vec<Share<Bit>> {
+    let val = RingElement(Bit::new(input));
+    let a = rng.gen::<RingElement<Bit>>();
+    let b = rng.gen::<RingElement<Bit>>();
+    let c = val ^ a ^ b;
+
+    let share1 = Share::new(a, c);
+    let share2 = Share::new(b, a);
+    let share3 = Share::new(c, b);
+
+    vec![share1, share2, share3]
+}
+
 pub fn generate_galois_iris_shares<R: Rng + CryptoRng>(
     rng: &mut R,
     iris: IrisCode,
@@ -48,3 +88,23 @@ pub fn generate_galois_iris_shares<R: Rng + CryptoRng>(
         },
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes_prng::AesRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_code_and_mask_dot() {
+        let mut rng = AesRng::seed_from_u64(0);
+        let x = generate_galois_iris_shares(&mut rng, IrisCode::random_rng(&mut rng));
+        let y = generate_galois_iris_shares(&mut rng, IrisCode::random_rng(&mut rng));
+        for (x, y) in x.iter().zip(y.iter()) {
+            assert_eq!(
+                x.code_and_mask_dot(y),
+                (x.code.trick_dot(&y.code), x.mask.trick_dot(&y.mask))
+            );
+        }
+    }
+}
@@ -60,6 +60,9 @@ impl LocalRuntime {
                 }
             })
             .collect();
+        for boot_session in boot_sessions.iter() {
+            boot_session.validate_topology()?;
+        }
 
         let mut jobs = JoinSet::new();
         for (player_id, boot_session) in boot_sessions.iter().enumerate() {
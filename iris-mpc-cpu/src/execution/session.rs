@@ -5,7 +5,10 @@ use crate::{
 };
 use eyre::eyre;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct SessionId(pub u128);
@@ -42,6 +45,42 @@ pub trait SessionHandles {
     fn prev_identity(&self) -> eyre::Result<Identity>;
 }
 
+impl BootSession {
+    /// Checks that the role assignment forms the ring the replicated protocols
+    /// rely on: exactly three distinct identities with the roles 0, 1 and 2,
+    /// one of them being our own. Otherwise `next`/`prev` would silently pair
+    /// up the wrong parties, e.g. breaking the PRF correlation.
+    pub fn validate_topology(&self) -> eyre::Result<()> {
+        const NUM_PARTIES: usize = 3;
+        if self.role_assignments.len() != NUM_PARTIES {
+            return Err(eyre!(
+                "Expected {} parties in the role assignment, got {}",
+                NUM_PARTIES,
+                self.role_assignments.len()
+            ));
+        }
+        for index in 0..NUM_PARTIES {
+            if !self.role_assignments.contains_key(&Role::new(index)) {
+                return Err(eyre!("Role {} is missing from the role assignment", index));
+            }
+        }
+        let identities: HashSet<_> = self.role_assignments.values().collect();
+        if identities.len() != NUM_PARTIES {
+            return Err(eyre!(
+                "Role assignment contains duplicate identities: {:?}",
+                self.role_assignments
+            ));
+        }
+        if !identities.contains(&self.own_identity) {
+            return Err(eyre!(
+                "Own identity {:?} is not part of the role assignment",
+                self.own_identity
+            ));
+        }
+        Ok(())
+    }
+}
+
 impl SessionHandles for BootSession {
     fn session_id(&self) -> SessionId {
         self.session_id
@@ -132,4 +171,59 @@ impl Session {
     pub fn prf_as_mut(&mut self) -> &mut Prf {
         &mut self.setup
     }
+
+    /// See [`BootSession::validate_topology`].
+    pub fn validate_topology(&self) -> eyre::Result<()> {
+        self.boot_session.validate_topology()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{execution::local::LocalRuntime, network::local::LocalNetworkingStore};
+
+    fn boot_session(identities: &[&str], own_identity: &str) -> BootSession {
+        let identities: Vec<Identity> = identities.iter().map(|&id| id.into()).collect();
+        let network = LocalNetworkingStore::from_host_ids(&identities);
+        BootSession {
+            session_id:       SessionId::from(0_u128),
+            role_assignments: Arc::new(
+                identities
+                    .iter()
+                    .enumerate()
+                    .map(|(index, id)| (Role::new(index), id.clone()))
+                    .collect(),
+            ),
+            networking:       Arc::new(network.get_local_network(own_identity.into())),
+            own_identity:     own_identity.into(),
+        }
+    }
+
+    #[test]
+    fn test_validate_topology() {
+        boot_session(&["alice", "bob", "charlie"], "bob")
+            .validate_topology()
+            .unwrap();
+
+        let err = boot_session(&["alice", "bob", "alice"], "bob")
+            .validate_topology()
+            .unwrap_err();
+        assert!(err.to_string().contains("duplicate identities"), "{}", err);
+
+        assert!(boot_session(&["alice", "bob"], "bob")
+            .validate_topology()
+            .is_err());
+        assert!(boot_session(&["alice", "bob", "charlie"], "dave")
+            .validate_topology()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_identity_session_setup() {
+        let runtime = LocalRuntime::new(vec!["alice".into(), "bob".into(), "alice".into()], vec![
+            [0_u8; 16], [1_u8; 16], [2_u8; 16],
+        ]);
+        assert!(runtime.create_player_sessions().await.is_err());
+    }
 }
@@ -3,4 +3,4 @@ pub mod execution;
 pub mod hawkers;
 pub(crate) mod network;
 pub mod protocol;
-pub(crate) mod shares;
+pub mod shares;
@@ -1,4 +1,7 @@
-use crate::shares::{bit::Bit, ring_impl::RingElement};
+use crate::{
+    execution::session::SessionId,
+    shares::{bit::Bit, ring_impl::RingElement},
+};
 use eyre::eyre;
 use serde::{Deserialize, Serialize};
 
@@ -15,6 +18,9 @@ pub enum NetworkValue {
     VecRing16(Vec<RingElement<u16>>),
     VecRing32(Vec<RingElement<u32>>),
     VecRing64(Vec<RingElement<u64>>),
+    // New variants go at the end, so the bincode tags of the existing ones
+    // stay the same across versions.
+    VecRingBit(Vec<RingElement<Bit>>),
 }
 
 impl NetworkValue {
@@ -27,20 +33,57 @@ impl NetworkValue {
     }
 }
 
-impl From<Vec<RingElement<u16>>> for NetworkValue {
-    fn from(value: Vec<RingElement<u16>>) -> Self {
-        NetworkValue::VecRing16(value)
+/// Direction of a message in the ring of parties, as seen by its sender.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Direction {
+    ToNext,
+    ToPrev,
+}
+
+/// A [`NetworkValue`] tagged with the session and the direction it was sent
+/// in, so the receiver can tell which neighbour it came from even if the
+/// network does not keep messages from different senders apart.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct TaggedValue {
+    pub session_id: SessionId,
+    pub direction:  Direction,
+    pub value:      NetworkValue,
+}
+
+impl TaggedValue {
+    pub fn to_network(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_network(serialized: eyre::Result<Vec<u8>>) -> eyre::Result<Self> {
+        bincode::deserialize::<Self>(&serialized?).map_err(|_e| eyre!("failed to parse value"))
     }
 }
 
-impl TryFrom<NetworkValue> for Vec<RingElement<u16>> {
-    type Error = eyre::Error;
-    fn try_from(value: NetworkValue) -> eyre::Result<Self> {
-        match value {
-            NetworkValue::VecRing16(x) => Ok(x),
-            _ => Err(eyre!(
-                "could not convert Network Value into Vec<RingElement<u16>>"
-            )),
+macro_rules! impl_vec_ring_conversion {
+    ($t:ty, $variant:ident) => {
+        impl From<Vec<RingElement<$t>>> for NetworkValue {
+            fn from(value: Vec<RingElement<$t>>) -> Self {
+                NetworkValue::$variant(value)
+            }
         }
-    }
+
+        impl TryFrom<NetworkValue> for Vec<RingElement<$t>> {
+            type Error = eyre::Error;
+            fn try_from(value: NetworkValue) -> eyre::Result<Self> {
+                match value {
+                    NetworkValue::$variant(x) => Ok(x),
+                    _ => Err(eyre!(concat!(
+                        "could not convert Network Value into Vec<RingElement<",
+                        stringify!($t),
+                        ">>"
+                    ))),
+                }
+            }
+        }
+    };
 }
+
+impl_vec_ring_conversion!(u16, VecRing16);
+impl_vec_ring_conversion!(u32, VecRing32);
+impl_vec_ring_conversion!(u64, VecRing64);
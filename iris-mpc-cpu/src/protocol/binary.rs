@@ -583,23 +583,45 @@ pub async fn single_extract_msb_u32<const K: usize>(
     session: &mut Session,
     x: Share<u32>,
 ) -> Result<Share<Bit>, Error> {
-    let (a, b) = extract_msb_u32::<{ u32::BITS as usize }>(session, VecShare::new_vec(vec![x]))
-        .await?
-        .get_at(0)
-        .get_ab();
+    let mut bits = extract_msb_u32_many(session, VecShare::new_vec(vec![x])).await?;
+    Ok(bits.pop().expect("Enough elements present"))
+}
 
-    Ok(Share::new(a.get_bit_as_bit(0), b.get_bit_as_bit(0)))
+/// Extracts the MSBs of a batch of shares in one go and unpacks them into one
+/// bit share per input, in input order.
+pub async fn extract_msb_u32_many(
+    session: &mut Session,
+    x: VecShare<u32>,
+) -> Result<Vec<Share<Bit>>, Error> {
+    let len = x.len();
+    let packed = extract_msb_u32::<{ u32::BITS as usize }>(session, x).await?;
+    Ok((0..len)
+        .map(|i| {
+            let (a, b) = packed.shares[i / 64].get_ab_ref();
+            Share::new(a.get_bit_as_bit(i % 64), b.get_bit_as_bit(i % 64))
+        })
+        .collect())
 }
 
 pub async fn open_bin(session: &mut Session, share: Share<Bit>) -> Result<Bit, Error> {
+    let mut opened = open_bin_many(session, vec![share]).await?;
+    debug_assert_eq!(opened.len(), 1);
+    Ok(Bit::new(opened.pop().expect("Enough elements present")))
+}
+
+/// Opens a batch of binary shares using a single round of communication.
+pub async fn open_bin_many(
+    session: &mut Session,
+    shares: Vec<Share<Bit>>,
+) -> Result<Vec<bool>, Error> {
     // send to next_party
     let next_party = session.next_identity()?;
     let network = session.network().clone();
     let sid = session.session_id();
-    let message = share.b;
+    let message: Vec<_> = shares.iter().map(|s| s.b).collect();
     network
         .send(
-            NetworkValue::RingElementBit(message).to_network(),
+            NetworkValue::VecRingBit(message).to_network(),
             &next_party,
             &sid,
         )
@@ -612,11 +634,84 @@ pub async fn open_bin(session: &mut Session, share: Share<Bit>) -> Result<Bit, E
     let c = {
         let serialized_other_share = network.receive(&prev_party, &sid).await;
         match NetworkValue::from_network(serialized_other_share) {
-            Ok(NetworkValue::RingElementBit(message)) => Ok(message),
-            _ => Err(eyre!("Error in receiving in open_bin operation")),
+            Ok(NetworkValue::VecRingBit(message)) => Ok(message),
+            _ => Err(eyre!("Error in receiving in open_bin_many operation")),
         }
     }?;
+    if c.len() != shares.len() {
+        return Err(eyre!(
+            "Expected a VecRingBit with length {:?} but received with length: {:?}",
+            shares.len(),
+            c.len()
+        ));
+    }
+
+    // xor shares with the received shares
+    Ok(shares
+        .into_iter()
+        .zip(c)
+        .map(|(s, c)| (s.a ^ s.b ^ c).convert().convert())
+        .collect())
+}
 
-    // xor shares with the received share
-    Ok((share.a ^ share.b ^ c).convert())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::local::LocalRuntime;
+    use aes_prng::AesRng;
+    use rand::{RngCore, SeedableRng};
+    use tokio::task::JoinSet;
+
+    fn create_bit_sharing<R: RngCore>(
+        rng: &mut R,
+        input: bool,
+    ) -> (Share<Bit>, Share<Bit>, Share<Bit>) {
+        let a = rng.gen::<RingElement<Bit>>();
+        let b = rng.gen::<RingElement<Bit>>();
+        let c = RingElement(Bit::new(input)) ^ a ^ b;
+
+        let share1 = Share::new(a, c);
+        let share2 = Share::new(b, a);
+        let share3 = Share::new(c, b);
+        (share1, share2, share3)
+    }
+
+    #[tokio::test]
+    async fn test_open_bin_many() {
+        let mut rng = AesRng::seed_from_u64(0_u64);
+        let bits: Vec<bool> = (0..100).map(|_| rng.gen()).collect();
+
+        let mut shares = vec![Vec::new(), Vec::new(), Vec::new()];
+        for bit in bits.iter() {
+            let (s0, s1, s2) = create_bit_sharing(&mut rng, *bit);
+            shares[0].push(s0);
+            shares[1].push(s1);
+            shares[2].push(s2);
+        }
+
+        let runtime = LocalRuntime::replicated_test_config();
+        let ready_sessions = runtime.create_player_sessions().await.unwrap();
+
+        let mut jobs = JoinSet::new();
+        for (index, player) in runtime.identities.iter().enumerate() {
+            let mut player_session = ready_sessions.get(player).unwrap().clone();
+            let own_shares = shares[index].clone();
+            jobs.spawn(async move {
+                let batched = open_bin_many(&mut player_session, own_shares.clone())
+                    .await
+                    .unwrap();
+                let mut looped = Vec::with_capacity(own_shares.len());
+                for share in own_shares {
+                    let bit = open_bin(&mut player_session, share).await.unwrap();
+                    looped.push(bit.convert());
+                }
+                (batched, looped)
+            });
+        }
+        while let Some(res) = jobs.join_next().await {
+            let (batched, looped) = res.unwrap();
+            assert_eq!(batched, looped);
+            assert_eq!(batched, bits);
+        }
+    }
 }
@@ -1,3 +1,5 @@
 pub(crate) mod binary;
 pub mod ops;
 pub(crate) mod prf;
+#[cfg(any(test, feature = "testing"))]
+pub mod reveal;
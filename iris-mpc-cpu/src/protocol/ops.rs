@@ -1,21 +1,61 @@
-use super::binary::single_extract_msb_u32;
+use super::binary::{extract_msb_u32_many, single_extract_msb_u32};
 use crate::{
     database_generators::GaloisRingSharedIris,
     execution::session::{BootSession, Session, SessionHandles},
-    network::value::NetworkValue::{self},
+    network::value::{
+        Direction,
+        NetworkValue::{self},
+        TaggedValue,
+    },
     protocol::{
-        binary::{lift, mul_lift_2k, open_bin},
+        binary::{bit_inject_ot_2round, lift, mul_lift_2k, open_bin, open_bin_many},
         prf::{Prf, PrfSeed},
     },
-    shares::{bit::Bit, ring_impl::RingElement, share::Share, vecshare::VecShare},
+    shares::{
+        bit::Bit, int_ring::IntRing2k, ring_impl::RingElement, share::Share, vecshare::VecShare,
+    },
 };
 use eyre::eyre;
 
+mod sealed {
+    use crate::{network::value::NetworkValue, shares::ring_impl::RingElement};
+
+    /// Conversion of a batch of ring elements to and from the wire format.
+    pub trait NetworkConversion: Sized {
+        fn to_network_value(values: Vec<RingElement<Self>>) -> NetworkValue;
+        fn from_network_value(value: NetworkValue) -> eyre::Result<Vec<RingElement<Self>>>;
+    }
+
+    macro_rules! impl_network_conversion {
+        ($($t:ty),*) => {$(
+            impl NetworkConversion for $t {
+                fn to_network_value(values: Vec<RingElement<Self>>) -> NetworkValue {
+                    NetworkValue::from(values)
+                }
+
+                fn from_network_value(value: NetworkValue) -> eyre::Result<Vec<RingElement<Self>>> {
+                    Vec::<RingElement<Self>>::try_from(value)
+                }
+            }
+        )*};
+    }
+
+    impl_network_conversion!(u16, u32, u64);
+}
+
+/// Rings whose shares can be sent over the network, and thus opened. Sealed,
+/// as only these rings have a wire format.
+pub trait NetworkRing: IntRing2k + sealed::NetworkConversion {}
+
+impl<T: IntRing2k + sealed::NetworkConversion> NetworkRing for T {}
+
 pub(crate) const MATCH_THRESHOLD_RATIO: f64 = iris_mpc_common::iris_db::iris::MATCH_THRESHOLD_RATIO;
 pub(crate) const B_BITS: u64 = 16;
-pub(crate) const B: u64 = 1 << B_BITS;
-pub(crate) const A: u64 = ((1. - 2. * MATCH_THRESHOLD_RATIO) * B as f64) as u64;
-pub(crate) const A_BITS: u32 = u64::BITS - A.leading_zeros();
+/// Largest possible mask dot product, of two masks with all bits set.
+const MAX_MASK_DOT: u64 = iris_mpc_common::IRIS_CODE_LENGTH as u64;
+/// Number of fractional bits of the shared distance computed by
+/// `fixed_point_distance`.
+pub const DISTANCE_FRAC_BITS: u64 = 16;
 
 /// Setup the PRF seeds in the replicated protocol.
 /// Each party sends to the next party a random seed.
@@ -46,29 +86,331 @@ pub async fn setup_replicated_prf(session: &BootSession, my_seed: PrfSeed) -> ey
     Ok(Prf::new(my_seed, other_seed))
 }
 
+/// Returns the threshold constant A = ((1. - 2. * MATCH_THRESHOLD_RATIO) *
+/// 2^scale_bits) used by the threshold comparison at the given fixed-point
+/// scale.
+pub(crate) fn threshold_a(scale_bits: u64) -> u64 {
+    threshold_a_for_ratio(MATCH_THRESHOLD_RATIO, scale_bits)
+}
+
+/// Same as `threshold_a`, but for an arbitrary match threshold ratio.
+pub(crate) fn threshold_a_for_ratio(ratio: f64, scale_bits: u64) -> u64 {
+    ((1. - 2. * ratio) * (1_u64 << scale_bits) as f64) as u64
+}
+
+/// Whether `mask_dot * A - code_dot * 2^scale_bits` fits into the 31 bits of
+/// a signed value in Z_{2^32} for every mask dot up to `MAX_MASK_DOT` and
+/// every code dot in [-mask_dot, mask_dot], so that its MSB is its sign.
+pub(crate) fn scale_fits(scale_bits: u64) -> bool {
+    scale_bits < 31 && MAX_MASK_DOT * (threshold_a(scale_bits) + (1 << scale_bits)) < 1 << 31
+}
+
 /// Takes as input two code and mask dot products between two Irises: i, j.
 /// i.e. code_dot = <i.code, j.code> and mask_dot = <i.mask, j.mask>
 /// Then lifts the two dot products to the larger ring (Z_{2^32}), multiplies
 /// with some predefined constants B = 2^16
 /// A = ((1. - 2. * MATCH_THRESHOLD_RATIO) * B as f64)
 /// and then compares mask_dot * A < code_dot * B.
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(
+        session_id = ?session.session_id(),
+        role = session.own_role().map(|role| role.zero_based()).ok(),
+    )
+)]
 pub async fn compare_threshold(
     session: &mut Session,
     code_dot: Share<u16>,
     mask_dot: Share<u16>,
 ) -> eyre::Result<Share<Bit>> {
-    debug_assert!(A_BITS as u64 <= B_BITS);
+    compare_threshold_with_scale::<B_BITS>(session, code_dot, mask_dot).await
+}
 
-    let y = mul_lift_2k::<B_BITS>(&code_dot);
-    let mut x = lift::<{ B_BITS as usize }>(session, VecShare::new_vec(vec![mask_dot])).await?;
+/// Same as `compare_threshold`, but with the fixed-point scale B =
+/// 2^SCALE_BITS chosen by the caller. A higher scale gives a more precise
+/// threshold, at the cost of less headroom in Z_{2^32}: both mask_dot * A
+/// and code_dot * B have to fit, and with mask dots of up to
+/// `IRIS_CODE_LENGTH` that only holds up to SCALE_BITS = 17. Larger scales
+/// are rejected with an error instead of silently wrapping around.
+pub async fn compare_threshold_with_scale<const SCALE_BITS: u64>(
+    session: &mut Session,
+    code_dot: Share<u16>,
+    mask_dot: Share<u16>,
+) -> eyre::Result<Share<Bit>> {
+    if !scale_fits(SCALE_BITS) {
+        return Err(eyre!(
+            "SCALE_BITS = {} overflows Z_2^32 for mask dots up to {}",
+            SCALE_BITS,
+            MAX_MASK_DOT
+        ));
+    }
+    let a = threshold_a(SCALE_BITS);
+    debug_assert!((u64::BITS - a.leading_zeros()) as u64 <= SCALE_BITS);
+    #[cfg(feature = "debug_checks")]
+    check_mul_lift_headroom::<SCALE_BITS>(session, &[code_dot.clone()]).await?;
+
+    let y = mul_lift_2k::<SCALE_BITS>(&code_dot);
+    let mut x = lift::<{ u16::BITS as usize }>(session, VecShare::new_vec(vec![mask_dot])).await?;
     debug_assert_eq!(x.len(), 1);
     let mut x = x.pop().expect("Enough elements present");
-    x *= A as u32;
+    x *= a as u32;
     x -= y;
 
     single_extract_msb_u32::<32>(session, x).await
 }
 
+/// Batched version of `compare_threshold`: compares every pair of
+/// `code_dots[i]` and `mask_dots[i]`, lifting all mask dots and extracting all
+/// MSBs in one go, so the number of communication rounds doesn't depend on
+/// the batch size.
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(
+        session_id = ?session.session_id(),
+        role = session.own_role().map(|role| role.zero_based()).ok(),
+    )
+)]
+pub async fn compare_threshold_batch(
+    session: &mut Session,
+    code_dots: Vec<Share<u16>>,
+    mask_dots: Vec<Share<u16>>,
+) -> eyre::Result<Vec<Share<Bit>>> {
+    if code_dots.len() != mask_dots.len() {
+        return Err(eyre!(
+            "Got {} code dots but {} mask dots",
+            code_dots.len(),
+            mask_dots.len()
+        ));
+    }
+    let a = threshold_a(B_BITS) as u32;
+    #[cfg(feature = "debug_checks")]
+    check_mul_lift_headroom::<B_BITS>(session, &code_dots).await?;
+
+    let x = lift::<{ u16::BITS as usize }>(session, VecShare::new_vec(mask_dots)).await?;
+    let mut diffs = VecShare::with_capacity(code_dots.len());
+    for (mut x, code_dot) in x.shares.into_iter().zip(code_dots.iter()) {
+        x *= a;
+        x -= mul_lift_2k::<B_BITS>(code_dot);
+        diffs.push(x);
+    }
+    extract_msb_u32_many(session, diffs).await
+}
+
+/// Same as `compare_threshold`, but evaluates the comparison for every ratio
+/// in `ratios` instead of just MATCH_THRESHOLD_RATIO, returning one bit per
+/// ratio. The mask dot is lifted only once and all MSBs are extracted in a
+/// single batch, so this is much cheaper than one `compare_threshold` per
+/// ratio. Every ratio has to be in (0, 0.5].
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(
+        session_id = ?session.session_id(),
+        role = session.own_role().map(|role| role.zero_based()).ok(),
+    )
+)]
+pub async fn compare_thresholds(
+    session: &mut Session,
+    code_dot: Share<u16>,
+    mask_dot: Share<u16>,
+    ratios: &[f64],
+) -> eyre::Result<Vec<Share<Bit>>> {
+    if let Some(ratio) = ratios.iter().find(|&&ratio| !(ratio > 0. && ratio <= 0.5)) {
+        return Err(eyre!("Threshold ratio {} is not in (0, 0.5]", ratio));
+    }
+    #[cfg(feature = "debug_checks")]
+    check_mul_lift_headroom::<B_BITS>(session, &[code_dot.clone()]).await?;
+
+    let y = mul_lift_2k::<B_BITS>(&code_dot);
+    let mut x = lift::<{ u16::BITS as usize }>(session, VecShare::new_vec(vec![mask_dot])).await?;
+    debug_assert_eq!(x.len(), 1);
+    let x = x.pop().expect("Enough elements present");
+
+    let mut diffs = VecShare::with_capacity(ratios.len());
+    for &ratio in ratios {
+        let mut diff = x.clone();
+        diff *= threshold_a_for_ratio(ratio, B_BITS) as u32;
+        diff -= y.clone();
+        diffs.push(diff);
+    }
+    extract_msb_u32_many(session, diffs).await
+}
+
+/// Checks that every signed code dot fits into the 31 - K bits of headroom
+/// that `mul_lift_2k::<K>` leaves in Z_{2^32}, i.e. lies in
+/// [-2^{30 - K}, 2^{30 - K}). Otherwise the shifted dot silently wraps around
+/// and the threshold comparison is wrong. The dots are lifted and both bounds
+/// are compared via their MSBs; only the comparison bits are opened.
+#[cfg(feature = "debug_checks")]
+pub(crate) async fn check_mul_lift_headroom<const K: u64>(
+    session: &mut Session,
+    code_dots: &[Share<u16>],
+) -> eyre::Result<()> {
+    debug_assert!(K < 31);
+    let bound = 1_u32 << (30 - K);
+    let role = session.own_role()?;
+
+    let lifted = batch_signed_lift(session, VecShare::new_vec(code_dots.to_vec())).await?;
+    let mut diffs = VecShare::with_capacity(2 * lifted.len());
+    for x in lifted.iter() {
+        // MSB set iff x < -bound.
+        let mut lower = x.clone();
+        lower.add_assign_const_role(bound, role);
+        diffs.push(lower);
+        // MSB set iff x >= bound.
+        let mut upper = -x;
+        upper.add_assign_const_role(bound - 1, role);
+        diffs.push(upper);
+    }
+    let bits = extract_msb_u32_many(session, diffs).await?;
+    let out_of_range = open_bin_many(session, bits).await?;
+    if let Some(i) = out_of_range.iter().position(|&bit| bit) {
+        return Err(eyre!(
+            "Code dot {} does not fit into the {} bits of headroom left by mul_lift_2k::<{}>",
+            i / 2,
+            31 - K,
+            K
+        ));
+    }
+    Ok(())
+}
+
+/// Multiplies two batches of shares element-wise in one round: each party
+/// computes its local part of the product, masks it with a zero share and
+/// reshares it to the next party.
+async fn mul_many(
+    session: &mut Session,
+    x: &VecShare<u32>,
+    y: &VecShare<u32>,
+) -> eyre::Result<VecShare<u32>> {
+    debug_assert_eq!(x.len(), y.len());
+    let local: Vec<RingElement<u32>> = x
+        .iter()
+        .zip(y.iter())
+        .map(|(x, y)| session.prf_as_mut().gen_zero_share() + x * y)
+        .collect();
+
+    let network = session.network().clone();
+    let sid = session.session_id();
+    network
+        .send(
+            NetworkValue::VecRing32(local.clone()).to_network(),
+            &session.next_identity()?,
+            &sid,
+        )
+        .await?;
+    let reply = network.receive(&session.prev_identity()?, &sid).await;
+    let res_b = match NetworkValue::from_network(reply) {
+        Ok(NetworkValue::VecRing32(element)) => element,
+        _ => return Err(eyre!("Could not deserialize VecRing32")),
+    };
+    if res_b.len() != local.len() {
+        return Err(eyre!(
+            "Expected a VecRing32 with length {:?} but received with length: {:?}",
+            local.len(),
+            res_b.len()
+        ));
+    }
+    Ok(VecShare::new_vec(
+        local
+            .into_iter()
+            .zip(res_b)
+            .map(|(a, b)| Share::new(a, b))
+            .collect(),
+    ))
+}
+
+/// Computes shares of the fractional hamming distance
+/// `0.5 - code_dot / (2 * mask_dot)` of every pair of `code_dots[i]` and
+/// `mask_dots[i]`, as a fixed-point number with DISTANCE_FRAC_BITS fractional
+/// bits in Z_{2^32}, rounded down. Neither dot product is opened.
+///
+/// The distance is `(mask_dot - code_dot) / (2 * mask_dot)`. Both operands are
+/// lifted to Z_{2^32} and divided by restoring division, one quotient bit per
+/// round of MSB extractions, from 2^DISTANCE_FRAC_BITS (a distance of exactly
+/// 1) down to 2^0. The scaled dividend has to stay below 2^31, so the mask dot
+/// must be below 2^14, and it must not be zero.
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(
+        session_id = ?session.session_id(),
+        role = session.own_role().map(|role| role.zero_based()).ok(),
+    )
+)]
+pub async fn fixed_point_distance(
+    session: &mut Session,
+    code_dots: Vec<Share<u16>>,
+    mask_dots: Vec<Share<u16>>,
+) -> eyre::Result<Vec<Share<u32>>> {
+    if code_dots.len() != mask_dots.len() {
+        return Err(eyre!(
+            "Got {} code dots but {} mask dots",
+            code_dots.len(),
+            mask_dots.len()
+        ));
+    }
+    let len = code_dots.len();
+    let role = session.own_role()?;
+
+    // mask_dot - code_dot lies in [0, 2 * mask_dot], so it doesn't wrap around
+    // in Z_{2^16} and both operands can be lifted as unsigned values.
+    let mut pre_lift = VecShare::with_capacity(2 * len);
+    for (code_dot, mask_dot) in code_dots.iter().zip(mask_dots.iter()) {
+        pre_lift.push(mask_dot.clone() - code_dot.clone());
+    }
+    for mask_dot in mask_dots {
+        pre_lift.push(mask_dot);
+    }
+    let lifted = lift::<{ u16::BITS as usize }>(session, pre_lift).await?;
+    let (numerators, masks) = lifted.shares.split_at(len);
+
+    let mut remainders: Vec<Share<u32>> = numerators
+        .iter()
+        .map(|n| n * (1_u32 << DISTANCE_FRAC_BITS))
+        .collect();
+    let denominators: Vec<Share<u32>> = masks.iter().map(|m| m * 2).collect();
+    let mut quotients = vec![Share::<u32>::default(); len];
+    for q in quotients.iter_mut() {
+        q.add_assign_const_role((1_u32 << (DISTANCE_FRAC_BITS + 1)) - 1, role);
+    }
+
+    for i in (0..=DISTANCE_FRAC_BITS).rev() {
+        let shifted = VecShare::new_vec(
+            denominators
+                .iter()
+                .map(|d| d * (1_u32 << i))
+                .collect::<Vec<_>>(),
+        );
+        let diffs = VecShare::new_vec(
+            remainders
+                .iter()
+                .zip(shifted.iter())
+                .map(|(r, d)| r.clone() - d.clone())
+                .collect::<Vec<_>>(),
+        );
+        // The MSB is set iff the shifted denominator doesn't fit, i.e. iff the
+        // quotient bit is 0. In that case the subtraction is undone.
+        let msbs = extract_msb_u32_many(session, diffs.clone()).await?;
+        let msbs = bit_inject_ot_2round(session, VecShare::new_vec(msbs)).await?;
+        let msbs = lift::<{ u16::BITS as usize }>(session, msbs).await?;
+        let restore = mul_many(session, &msbs, &shifted).await?;
+
+        for (((r, diff), restore), (q, msb)) in remainders
+            .iter_mut()
+            .zip(diffs.into_iter())
+            .zip(restore.into_iter())
+            .zip(quotients.iter_mut().zip(msbs.iter()))
+        {
+            *r = diff + restore;
+            *q -= msb * (1_u32 << i);
+        }
+    }
+    Ok(quotients)
+}
+
 pub(crate) async fn batch_signed_lift(
     session: &mut Session,
     mut pre_lift: VecShare<u16>,
@@ -126,15 +468,16 @@ pub(crate) async fn cross_mul_via_lift(
     let next_role = session.identity(&session.own_role()?.next(3))?;
     let prev_role = session.identity(&session.own_role()?.prev(3))?;
 
+    let message = NetworkValue::VecRing32(exchanged_shares_a.clone()).to_network();
+    tracing::trace!(bytes = message.len(), "Sending lifted products");
     network
-        .send(
-            NetworkValue::VecRing32(exchanged_shares_a.clone()).to_network(),
-            next_role,
-            &session.session_id(),
-        )
+        .send(message, next_role, &session.session_id())
         .await?;
 
     let serialized_reply = network.receive(prev_role, &session.session_id()).await;
+    if let Ok(reply) = &serialized_reply {
+        tracing::trace!(bytes = reply.len(), "Received lifted products");
+    }
     let res_b = match NetworkValue::from_network(serialized_reply) {
         Ok(NetworkValue::VecRing32(element)) => element,
         _ => return Err(eyre!("Could not deserialize VecRing16")),
@@ -164,6 +507,14 @@ pub(crate) async fn cross_mul_via_lift(
 /// ie: d1 = dot(c_x, c_y); t1 = dot(m_x, m_y). d2, t2 are replicated shares
 /// that come from an iris code and mask dot product, ie:
 /// d2 = dot(c_u, c_w), t2 = dot(m_u, m_w)
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(
+        session_id = ?session.session_id(),
+        role = session.own_role().map(|role| role.zero_based()).ok(),
+    )
+)]
 pub async fn cross_compare(
     session: &mut Session,
     d1: Share<u16>,
@@ -190,8 +541,7 @@ pub async fn galois_ring_pairwise_distance(
     let mut additive_shares = Vec::with_capacity(2 * pairs.len());
     for pair in pairs.iter() {
         let (x, y) = pair;
-        let code_dot = x.code.trick_dot(&y.code);
-        let mask_dot = x.mask.trick_dot(&y.mask);
+        let (code_dot, mask_dot) = x.code_and_mask_dot(y);
         additive_shares.push(RingElement(code_dot));
         // When applying the trick dot on trimmed masks, we have to multiply with 2 the
         // result The intuition being that a GaloisRingTrimmedMask contains half
@@ -203,6 +553,14 @@ pub async fn galois_ring_pairwise_distance(
 
 /// Converts additive sharing (from trick_dot output) to a replicated sharing by
 /// masking it with a zero sharing
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(
+        session_id = ?session.session_id(),
+        role = session.own_role().map(|role| role.zero_based()).ok(),
+    )
+)]
 pub async fn galois_ring_to_rep3(
     session: &mut Session,
     items: Vec<RingElement<u16>>,
@@ -214,17 +572,14 @@ pub async fn galois_ring_to_rep3(
     // make sure we mask the input with a zero sharing
     let masked_items: Vec<_> = items
         .iter()
-        .map(|x| session.prf_as_mut().gen_zero_share() + x)
+        .zip(session.prf_as_mut().gen_zero_share_vec(items.len()))
+        .map(|(x, zero)| zero + x)
         .collect();
 
     // sending to the next party
-    network
-        .send(
-            NetworkValue::VecRing16(masked_items.clone()).to_network(),
-            &next_party,
-            &sid,
-        )
-        .await?;
+    let message = NetworkValue::VecRing16(masked_items.clone()).to_network();
+    tracing::trace!(bytes = message.len(), "Sending masked shares");
+    network.send(message, &next_party, &sid).await?;
 
     // receiving from previous party
     let network = session.network().clone();
@@ -232,6 +587,9 @@ pub async fn galois_ring_to_rep3(
     let prev_party = session.prev_identity()?;
     let shares_b = {
         let serialized_other_share = network.receive(&prev_party, &sid).await;
+        if let Ok(other_share) = &serialized_other_share {
+            tracing::trace!(bytes = other_share.len(), "Received masked shares");
+        }
         match NetworkValue::from_network(serialized_other_share) {
             Ok(NetworkValue::VecRing16(message)) => Ok(message),
             _ => Err(eyre!("Error in receiving in galois_ring_to_rep3 operation")),
@@ -251,6 +609,14 @@ pub async fn galois_ring_to_rep3(
 /// Convert the partial shamir share result to a replicated sharing and then
 /// Compare the distance using the MATCH_THRESHOLD_RATIO from the
 /// `compare_threshold` function.
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(
+        session_id = ?session.session_id(),
+        role = session.own_role().map(|role| role.zero_based()).ok(),
+    )
+)]
 pub async fn galois_ring_is_match(
     session: &mut Session,
     pairs: &[(GaloisRingSharedIris, GaloisRingSharedIris)],
@@ -275,79 +641,127 @@ pub async fn is_dot_zero(
     Ok(opened.convert())
 }
 
+/// Batched version of `is_dot_zero`, using a constant number of communication
+/// rounds for the whole batch.
+pub async fn is_dot_zero_batch(
+    session: &mut Session,
+    code_dots: Vec<Share<u16>>,
+    mask_dots: Vec<Share<u16>>,
+) -> eyre::Result<Vec<bool>> {
+    let bits = compare_threshold_batch(session, code_dots, mask_dots).await?;
+    open_bin_many(session, bits).await
+}
+
+/// Opens a batch of additive (3-out-of-3) shares, such as the output of
+/// `galois_ring_pairwise_distance`. Each party sends its share to both peers.
+/// The messages are tagged with the session and their direction, and the
+/// received shares are matched to the neighbours by these tags rather than by
+/// the receive call that returned them, so a network mixing up the two
+/// neighbours' messages is detected or, if both arrive, corrected.
+pub async fn open_additive<T>(
+    session: &Session,
+    shares: Vec<RingElement<T>>,
+) -> eyre::Result<Vec<T>>
+where
+    T: NetworkRing,
+{
+    let network = session.network().clone();
+    let sid = session.session_id();
+    let next_party = session.next_identity()?;
+    let prev_party = session.prev_identity()?;
+
+    let value = T::to_network_value(shares.clone());
+    for (direction, receiver) in [
+        (Direction::ToNext, &next_party),
+        (Direction::ToPrev, &prev_party),
+    ] {
+        let message = TaggedValue {
+            session_id: sid,
+            direction,
+            value: value.clone(),
+        };
+        network.send(message.to_network(), receiver, &sid).await?;
+    }
+
+    // The previous party sent its share towards its next party and vice versa.
+    let mut shares_prev = None;
+    let mut shares_next = None;
+    for sender in [&prev_party, &next_party] {
+        let message = TaggedValue::from_network(network.receive(sender, &sid).await)?;
+        if message.session_id != sid {
+            return Err(eyre!(
+                "Received additive shares of session {:?} in session {:?}",
+                message.session_id,
+                sid
+            ));
+        }
+        let slot = match message.direction {
+            Direction::ToNext => &mut shares_prev,
+            Direction::ToPrev => &mut shares_next,
+        };
+        if slot
+            .replace(T::from_network_value(message.value)?)
+            .is_some()
+        {
+            return Err(eyre!(
+                "Received additive shares sent {:?} twice",
+                message.direction
+            ));
+        }
+    }
+    let shares_prev = shares_prev.expect("Both directions received");
+    let shares_next = shares_next.expect("Both directions received");
+    if shares_prev.len() != shares.len() || shares_next.len() != shares.len() {
+        return Err(eyre!("Received additive shares of mismatching length"));
+    }
+
+    Ok(shares
+        .into_iter()
+        .zip(shares_prev)
+        .zip(shares_next)
+        .map(|((x, p), n)| (x + p + n).convert())
+        .collect())
+}
+
+/// Opens a batch of match bits (e.g. the result of matching a query against
+/// every DB entry) in a single round and returns how many of them are set,
+/// along with the index of the first set bit, if any. That index is the one
+/// reported back as the matching DB entry.
+pub async fn count_matches(
+    session: &mut Session,
+    bits: Vec<Share<Bit>>,
+) -> eyre::Result<(u32, Option<usize>)> {
+    let opened = open_bin_many(session, bits).await?;
+    let count = opened.iter().filter(|&&bit| bit).count() as u32;
+    Ok((count, opened.into_iter().position(|bit| bit)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{
-        database_generators::generate_galois_iris_shares,
-        execution::{local::LocalRuntime, player::Identity},
+        database_generators::{create_random_bit_sharing, generate_galois_iris_shares},
+        execution::{
+            local::LocalRuntime,
+            player::Identity,
+            session::{NetworkingImpl, SessionId},
+        },
         hawkers::plaintext_store::PlaintextIris,
-        protocol::ops::NetworkValue::RingElement32,
-        shares::{int_ring::IntRing2k, ring_impl::RingElement},
+        network::Networking,
+        protocol::reveal::{open_many, open_single},
+        shares::ring_impl::RingElement,
     };
     use aes_prng::AesRng;
     use iris_mpc_common::iris_db::db::IrisDB;
     use rand::{Rng, RngCore, SeedableRng};
     use rstest::rstest;
-    use std::collections::HashMap;
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
     use tokio::task::JoinSet;
-
-    async fn open_single(session: &Session, x: Share<u32>) -> eyre::Result<RingElement<u32>> {
-        let network = session.network();
-        let next_role = session.identity(&session.own_role()?.next(3))?;
-        let prev_role = session.identity(&session.own_role()?.prev(3))?;
-        network
-            .send(
-                RingElement32(x.b).to_network(),
-                next_role,
-                &session.session_id(),
-            )
-            .await?;
-        let serialized_reply = network.receive(prev_role, &session.session_id()).await;
-        let missing_share = match NetworkValue::from_network(serialized_reply) {
-            Ok(NetworkValue::RingElement32(element)) => element,
-            _ => return Err(eyre!("Could not deserialize RingElement32")),
-        };
-        let (a, b) = x.get_ab();
-        Ok(a + b + missing_share)
-    }
-
-    async fn open_t_many<T>(session: &Session, shares: Vec<Share<T>>) -> eyre::Result<Vec<T>>
-    where
-        T: IntRing2k,
-        NetworkValue: From<Vec<RingElement<T>>>,
-        Vec<RingElement<T>>: TryFrom<NetworkValue, Error = eyre::Error>,
-    {
-        let next_party = session.next_identity()?;
-        let network = session.network().clone();
-        let sid = session.session_id();
-
-        let shares_b: Vec<_> = shares.iter().map(|s| s.b).collect();
-        let message = shares_b;
-        network
-            .send(NetworkValue::from(message).to_network(), &next_party, &sid)
-            .await?;
-
-        // receiving from previous party
-        let network = session.network().clone();
-        let sid = session.session_id();
-        let prev_party = session.prev_identity()?;
-        let shares_c = {
-            let serialized_other_share = network.receive(&prev_party, &sid).await;
-            let net_message = NetworkValue::from_network(serialized_other_share)?;
-            Vec::<RingElement<T>>::try_from(net_message)
-        }?;
-
-        let res = shares
-            .into_iter()
-            .zip(shares_c)
-            .map(|(s, c)| {
-                let (a, b) = s.get_ab();
-                (a + b + c).convert()
-            })
-            .collect();
-        Ok(res)
-    }
+    use tracing::{span, Subscriber};
+    use tracing_subscriber::{layer::Context, prelude::*, Layer, Registry};
 
     #[tokio::test]
     async fn test_async_prf_setup() {
@@ -489,46 +903,8 @@ mod tests {
         }
         // check first party output is equal to the expected result.
         let t = jobs.join_next().await.unwrap().unwrap();
-        assert_eq!(t.0, RingElement(4));
-        assert_eq!(t.1, RingElement(6));
-    }
-
-    async fn open_additive(session: &Session, x: Vec<RingElement<u16>>) -> eyre::Result<Vec<u16>> {
-        let network = session.network();
-        let next_role = session.identity(&session.own_role()?.next(3))?;
-        let prev_role = session.identity(&session.own_role()?.prev(3))?;
-        network
-            .send(
-                NetworkValue::VecRing16(x.clone()).to_network(),
-                next_role,
-                &session.session_id(),
-            )
-            .await?;
-        network
-            .send(
-                NetworkValue::VecRing16(x.clone()).to_network(),
-                prev_role,
-                &session.session_id(),
-            )
-            .await?;
-
-        let serialized_reply_0 = network.receive(prev_role, &session.session_id()).await;
-        let serialized_reply_1 = network.receive(next_role, &session.session_id()).await;
-
-        let missing_share_0 = match NetworkValue::from_network(serialized_reply_0) {
-            Ok(NetworkValue::VecRing16(element)) => element,
-            _ => return Err(eyre!("Could not deserialize VecRingElement16")),
-        };
-        let missing_share_1 = match NetworkValue::from_network(serialized_reply_1) {
-            Ok(NetworkValue::VecRing16(element)) => element,
-            _ => return Err(eyre!("Could not deserialize VecRingElement16")),
-        };
-        let opened_value: Vec<u16> = x
-            .iter()
-            .enumerate()
-            .map(|(i, v)| (missing_share_0[i] + missing_share_1[i] + v).convert())
-            .collect();
-        Ok(opened_value)
+        assert_eq!(t.0, 4);
+        assert_eq!(t.1, 6);
     }
 
     #[tokio::test]
@@ -560,7 +936,7 @@ mod tests {
                     .unwrap();
                 let opened_x = open_additive(&player_session, x.clone()).await.unwrap();
                 let x_rep = galois_ring_to_rep3(&mut player_session, x).await.unwrap();
-                let opened_x_rep = open_t_many(&player_session, x_rep).await.unwrap();
+                let opened_x_rep = open_many(&player_session, x_rep).await.unwrap();
                 (opened_x, opened_x_rep)
             });
         }
@@ -579,4 +955,480 @@ mod tests {
         assert_eq!(output0.1[0], plain_d1 as u16);
         assert_eq!(output0.1[1], plain_d2);
     }
+
+    /// Hands out the messages of a party's two neighbours swapped, i.e. a
+    /// receive from one neighbour returns what the other one sent.
+    struct SwappedNeighbours {
+        inner: NetworkingImpl,
+        prev:  Identity,
+        next:  Identity,
+    }
+
+    #[async_trait::async_trait]
+    impl Networking for SwappedNeighbours {
+        async fn send(
+            &self,
+            value: Vec<u8>,
+            receiver: &Identity,
+            session_id: &SessionId,
+        ) -> eyre::Result<()> {
+            self.inner.send(value, receiver, session_id).await
+        }
+
+        async fn receive(
+            &self,
+            sender: &Identity,
+            session_id: &SessionId,
+        ) -> eyre::Result<Vec<u8>> {
+            let sender = if *sender == self.prev {
+                &self.next
+            } else {
+                &self.prev
+            };
+            self.inner.receive(sender, session_id).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_open_additive_swapped_delivery() {
+        let mut rng = AesRng::seed_from_u64(0_u64);
+        let secrets = (0..10).map(|_| rng.gen::<u16>()).collect::<Vec<_>>();
+        let mut shares = vec![Vec::new(), Vec::new(), Vec::new()];
+        for &secret in secrets.iter() {
+            let a = RingElement(rng.gen::<u16>());
+            let b = RingElement(rng.gen::<u16>());
+            shares[0].push(a);
+            shares[1].push(b);
+            shares[2].push(RingElement(secret) - a - b);
+        }
+
+        let runtime = LocalRuntime::replicated_test_config();
+        let ready_sessions = runtime.create_player_sessions().await.unwrap();
+
+        let mut jobs = JoinSet::new();
+        for (index, player) in runtime.identities.iter().enumerate() {
+            let mut player_session = ready_sessions.get(player).unwrap().clone();
+            player_session.boot_session.networking = Arc::new(SwappedNeighbours {
+                inner: player_session.network().clone(),
+                prev:  player_session.prev_identity().unwrap(),
+                next:  player_session.next_identity().unwrap(),
+            });
+            let own_shares = shares[index].clone();
+            jobs.spawn(async move { open_additive(&player_session, own_shares).await });
+        }
+        while let Some(res) = jobs.join_next().await {
+            assert_eq!(res.unwrap().unwrap(), secrets);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_count_matches() {
+        let mut rng = AesRng::seed_from_u64(0_u64);
+        let num_entries = 50;
+        let matching = [7, 23, 41];
+
+        let mut shares = vec![Vec::new(), Vec::new(), Vec::new()];
+        for i in 0..num_entries {
+            let bit_shares = create_random_bit_sharing(&mut rng, matching.contains(&i));
+            for (party, share) in bit_shares.into_iter().enumerate() {
+                shares[party].push(share);
+            }
+        }
+
+        let runtime = LocalRuntime::replicated_test_config();
+        let ready_sessions = runtime.create_player_sessions().await.unwrap();
+
+        let mut jobs = JoinSet::new();
+        for (index, player) in runtime.identities.iter().enumerate() {
+            let mut player_session = ready_sessions.get(player).unwrap().clone();
+            let own_shares = shares[index].clone();
+            jobs.spawn(async move {
+                let matches = count_matches(&mut player_session, own_shares[..7].to_vec())
+                    .await
+                    .unwrap();
+                assert_eq!(matches, (0, None));
+                count_matches(&mut player_session, own_shares)
+                    .await
+                    .unwrap()
+            });
+        }
+        while let Some(res) = jobs.join_next().await {
+            let (count, first) = res.unwrap();
+            assert_eq!(count, 3);
+            assert_eq!(first, Some(7));
+        }
+    }
+
+    #[test]
+    fn test_scale_fits() {
+        assert!(scale_fits(B_BITS));
+        assert!(scale_fits(17));
+        assert!(!scale_fits(18));
+        assert!(!scale_fits(20));
+        assert!(!scale_fits(31));
+    }
+
+    #[tokio::test]
+    #[rstest]
+    #[case(16)]
+    #[case(17)]
+    #[case(18)]
+    async fn test_compare_threshold_with_scale(#[case] scale_bits: u64) {
+        let mut rng = AesRng::seed_from_u64(scale_bits);
+        let mask_dot = iris_mpc_common::IRIS_CODE_LENGTH as u16;
+        // With the largest mask dot, the code dots around the threshold
+        // (1 - 2 * MATCH_THRESHOLD_RATIO) * mask_dot = 3200 are decided by it. The
+        // clear (non-)matches stay within the code dot headroom of
+        // `check_mul_lift_headroom` at every tested scale.
+        let inputs = [
+            (8000, true),
+            (8000_u16.wrapping_neg(), false),
+            (3201, true),
+            (3199, false),
+        ];
+
+        let runtime = LocalRuntime::replicated_test_config();
+        let ready_sessions = runtime.create_player_sessions().await.unwrap();
+
+        for (code_dot, expected) in inputs {
+            let code_shares = create_single_sharing(&mut rng, code_dot);
+            let mask_shares = create_single_sharing(&mut rng, mask_dot);
+            let code_shares = [code_shares.0, code_shares.1, code_shares.2];
+            let mask_shares = [mask_shares.0, mask_shares.1, mask_shares.2];
+
+            let mut jobs = JoinSet::new();
+            for (index, player) in runtime.identities.iter().enumerate() {
+                let mut player_session = ready_sessions.get(player).unwrap().clone();
+                let code_share = code_shares[index].clone();
+                let mask_share = mask_shares[index].clone();
+                jobs.spawn(async move {
+                    let session = &mut player_session;
+                    let bit = match scale_bits {
+                        16 => {
+                            compare_threshold_with_scale::<16>(session, code_share, mask_share)
+                                .await
+                        }
+                        17 => {
+                            compare_threshold_with_scale::<17>(session, code_share, mask_share)
+                                .await
+                        }
+                        18 => {
+                            compare_threshold_with_scale::<18>(session, code_share, mask_share)
+                                .await
+                        }
+                        _ => unreachable!(),
+                    };
+                    match bit {
+                        Ok(bit) => Some(open_bin(session, bit).await.unwrap().convert()),
+                        Err(_) => None,
+                    }
+                });
+            }
+            while let Some(res) = jobs.join_next().await {
+                let res = res.unwrap();
+                if scale_fits(scale_bits) {
+                    assert_eq!(res, Some(expected));
+                } else {
+                    assert_eq!(res, None);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_is_dot_zero_batch() {
+        let mut rng = AesRng::seed_from_u64(0_u64);
+        let num_pairs = 70;
+        let mut code_shares = vec![Vec::new(), Vec::new(), Vec::new()];
+        let mut mask_shares = vec![Vec::new(), Vec::new(), Vec::new()];
+        for _ in 0..num_pairs {
+            let mask_dot = rng.gen_range(1_u16..=1000);
+            let code_dot = rng.gen_range(-(mask_dot as i16)..=mask_dot as i16) as u16;
+            let code = create_single_sharing(&mut rng, code_dot);
+            let mask = create_single_sharing(&mut rng, mask_dot);
+            for (party, (code, mask)) in [(code.0, mask.0), (code.1, mask.1), (code.2, mask.2)]
+                .into_iter()
+                .enumerate()
+            {
+                code_shares[party].push(code);
+                mask_shares[party].push(mask);
+            }
+        }
+
+        let runtime = LocalRuntime::replicated_test_config();
+        let ready_sessions = runtime.create_player_sessions().await.unwrap();
+
+        let mut jobs = JoinSet::new();
+        for (index, player) in runtime.identities.iter().enumerate() {
+            let mut player_session = ready_sessions.get(player).unwrap().clone();
+            let code_shares = code_shares[index].clone();
+            let mask_shares = mask_shares[index].clone();
+            jobs.spawn(async move {
+                let batched = is_dot_zero_batch(
+                    &mut player_session,
+                    code_shares.clone(),
+                    mask_shares.clone(),
+                )
+                .await
+                .unwrap();
+                let mut looped = Vec::with_capacity(code_shares.len());
+                for (code, mask) in code_shares.into_iter().zip(mask_shares) {
+                    looped.push(is_dot_zero(&mut player_session, code, mask).await.unwrap());
+                }
+                (batched, looped)
+            });
+        }
+        let mut outputs = Vec::new();
+        while let Some(res) = jobs.join_next().await {
+            let (batched, looped) = res.unwrap();
+            assert_eq!(batched.len(), num_pairs);
+            assert_eq!(batched, looped);
+            outputs.push(batched);
+        }
+        assert!(outputs.windows(2).all(|w| w[0] == w[1]));
+        // Both outcomes should show up among random dots.
+        assert!(outputs[0].contains(&true) && outputs[0].contains(&false));
+
+        let mut session = ready_sessions.get(&runtime.identities[0]).unwrap().clone();
+        let share = create_single_sharing(&mut rng, 0).0;
+        assert!(is_dot_zero_batch(&mut session, vec![share.clone()], vec![])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fixed_point_distance() {
+        let mut rng = AesRng::seed_from_u64(0_u64);
+        let mut plain = (0..6)
+            .map(|_| {
+                let mask_dot = rng.gen_range(1_i16..=12800);
+                (rng.gen_range(-mask_dot..=mask_dot), mask_dot)
+            })
+            .collect::<Vec<_>>();
+        // Distances of exactly 0 and 1.
+        plain.push((500, 500));
+        plain.push((-500, 500));
+
+        let mut code_shares = vec![Vec::new(), Vec::new(), Vec::new()];
+        let mut mask_shares = vec![Vec::new(), Vec::new(), Vec::new()];
+        for &(code_dot, mask_dot) in plain.iter() {
+            let (c0, c1, c2) = create_single_sharing(&mut rng, code_dot as u16);
+            let (m0, m1, m2) = create_single_sharing(&mut rng, mask_dot as u16);
+            for (shares, share) in code_shares.iter_mut().zip([c0, c1, c2]) {
+                shares.push(share);
+            }
+            for (shares, share) in mask_shares.iter_mut().zip([m0, m1, m2]) {
+                shares.push(share);
+            }
+        }
+
+        let runtime = LocalRuntime::replicated_test_config();
+        let ready_sessions = runtime.create_player_sessions().await.unwrap();
+
+        let mut jobs = JoinSet::new();
+        for (index, player) in runtime.identities.iter().enumerate() {
+            let mut player_session = ready_sessions.get(player).unwrap().clone();
+            let code_dots = code_shares[index].clone();
+            let mask_dots = mask_shares[index].clone();
+            jobs.spawn(async move {
+                let distances = fixed_point_distance(&mut player_session, code_dots, mask_dots)
+                    .await
+                    .unwrap();
+                open_many(&player_session, distances).await.unwrap()
+            });
+        }
+        let scale = (1_u64 << DISTANCE_FRAC_BITS) as f64;
+        while let Some(res) = jobs.join_next().await {
+            let opened = res.unwrap();
+            assert_eq!(opened.len(), plain.len());
+            for (&fixed, &(code_dot, mask_dot)) in opened.iter().zip(plain.iter()) {
+                let expected = 0.5 - code_dot as f64 / (2. * mask_dot as f64);
+                assert!(
+                    (expected - fixed as f64 / scale).abs() < 1. / scale,
+                    "{} is not within one step of {}",
+                    fixed,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "debug_checks")]
+    async fn test_mul_lift_headroom_check() {
+        let mut rng = AesRng::seed_from_u64(0_u64);
+        let mask_dot = 12800_u16;
+        // Code dots fit into [-2^14, 2^14) for B_BITS = 16.
+        let cases = [
+            (12800_u16, true),
+            (-16384_i16 as u16, true),
+            (16384, false),
+            (-16385_i16 as u16, false),
+        ];
+
+        let runtime = LocalRuntime::replicated_test_config();
+        let ready_sessions = runtime.create_player_sessions().await.unwrap();
+
+        for (code_dot, fits) in cases {
+            let code_shares = create_single_sharing(&mut rng, code_dot);
+            let mask_shares = create_single_sharing(&mut rng, mask_dot);
+            let code_shares = [code_shares.0, code_shares.1, code_shares.2];
+            let mask_shares = [mask_shares.0, mask_shares.1, mask_shares.2];
+
+            let mut jobs = JoinSet::new();
+            for (index, player) in runtime.identities.iter().enumerate() {
+                let mut player_session = ready_sessions.get(player).unwrap().clone();
+                let code_share = code_shares[index].clone();
+                let mask_share = mask_shares[index].clone();
+                jobs.spawn(async move {
+                    compare_threshold(&mut player_session, code_share, mask_share).await
+                });
+            }
+            while let Some(res) = jobs.join_next().await {
+                assert_eq!(res.unwrap().is_ok(), fits, "code dot {}", code_dot as i16);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compare_thresholds() {
+        let mut rng = AesRng::seed_from_u64(0_u64);
+        let ratios = [0.1, 0.25, MATCH_THRESHOLD_RATIO, 0.4, 0.5];
+        let mask_dot = 1000_u16;
+        let code_dots = (0..8)
+            .map(|_| (rng.gen_range(-1000_i16..=1000)) as u16)
+            .collect::<Vec<_>>();
+
+        let runtime = LocalRuntime::replicated_test_config();
+        let ready_sessions = runtime.create_player_sessions().await.unwrap();
+
+        for code_dot in code_dots {
+            let code_shares = create_single_sharing(&mut rng, code_dot);
+            let mask_shares = create_single_sharing(&mut rng, mask_dot);
+            let code_shares = [code_shares.0, code_shares.1, code_shares.2];
+            let mask_shares = [mask_shares.0, mask_shares.1, mask_shares.2];
+
+            let mut jobs = JoinSet::new();
+            for (index, player) in runtime.identities.iter().enumerate() {
+                let mut player_session = ready_sessions.get(player).unwrap().clone();
+                let code_share = code_shares[index].clone();
+                let mask_share = mask_shares[index].clone();
+                jobs.spawn(async move {
+                    let bits = compare_thresholds(
+                        &mut player_session,
+                        code_share.clone(),
+                        mask_share.clone(),
+                        &ratios,
+                    )
+                    .await
+                    .unwrap();
+                    let batched = open_bin_many(&mut player_session, bits).await.unwrap();
+
+                    let mut single = Vec::with_capacity(ratios.len());
+                    for ratio in ratios {
+                        let bits = compare_thresholds(
+                            &mut player_session,
+                            code_share.clone(),
+                            mask_share.clone(),
+                            &[ratio],
+                        )
+                        .await
+                        .unwrap();
+                        single.extend(open_bin_many(&mut player_session, bits).await.unwrap());
+                    }
+
+                    let bit = compare_threshold(&mut player_session, code_share, mask_share)
+                        .await
+                        .unwrap();
+                    let default = open_bin(&mut player_session, bit).await.unwrap().convert();
+                    (batched, single, default)
+                });
+            }
+
+            let expected = ratios
+                .iter()
+                .map(|&ratio| {
+                    let a = threshold_a_for_ratio(ratio, B_BITS) as u32;
+                    (mask_dot as u32)
+                        .wrapping_mul(a)
+                        .wrapping_sub((code_dot as u32) << B_BITS)
+                        >> 31
+                        == 1
+                })
+                .collect::<Vec<_>>();
+            while let Some(res) = jobs.join_next().await {
+                let (batched, single, default) = res.unwrap();
+                assert_eq!(batched, expected, "code dot {}", code_dot as i16);
+                assert_eq!(single, expected, "code dot {}", code_dot as i16);
+                assert_eq!(default, batched[2], "code dot {}", code_dot as i16);
+            }
+        }
+
+        // Invalid ratios are rejected before anything is sent.
+        let mut session = ready_sessions.get(&runtime.identities[0]).unwrap().clone();
+        let share = create_single_sharing(&mut rng, 0).0;
+        for ratio in [0., 0.6, f64::NAN] {
+            assert!(
+                compare_thresholds(&mut session, share.clone(), share.clone(), &[ratio])
+                    .await
+                    .is_err()
+            );
+        }
+    }
+
+    /// Records the name of every span that is created.
+    #[derive(Clone, Default)]
+    struct SpanNames(Arc<Mutex<Vec<&'static str>>>);
+
+    impl<S: Subscriber> Layer<S> for SpanNames {
+        fn on_new_span(&self, attrs: &span::Attributes<'_>, _id: &span::Id, _ctx: Context<'_, S>) {
+            self.0.lock().unwrap().push(attrs.metadata().name());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_galois_ring_is_match_spans() {
+        let span_names = SpanNames::default();
+        let _guard = tracing::subscriber::set_default(Registry::default().with(span_names.clone()));
+
+        let runtime = LocalRuntime::replicated_test_config();
+        let ready_sessions = runtime.create_player_sessions().await.unwrap();
+        let mut rng = AesRng::seed_from_u64(0_u64);
+
+        let iris = IrisDB::new_random_rng(1, &mut rng).db[0].clone();
+        let first_entry = generate_galois_iris_shares(&mut rng, iris.clone());
+        let second_entry = generate_galois_iris_shares(&mut rng, iris);
+
+        let mut jobs = JoinSet::new();
+        for (index, player) in runtime.identities.iter().enumerate() {
+            let mut player_session = ready_sessions.get(player).unwrap().clone();
+            let mut own_shares = vec![(first_entry[index].clone(), second_entry[index].clone())];
+            own_shares.iter_mut().for_each(|(_x, y)| {
+                y.code.preprocess_iris_code_query_share();
+                y.mask.preprocess_mask_code_query_share();
+            });
+            jobs.spawn(async move {
+                galois_ring_is_match(&mut player_session, &own_shares)
+                    .await
+                    .unwrap()
+            });
+        }
+        while let Some(res) = jobs.join_next().await {
+            assert!(res.unwrap());
+        }
+
+        let span_names = span_names.0.lock().unwrap();
+        for name in [
+            "galois_ring_is_match",
+            "galois_ring_to_rep3",
+            "compare_threshold",
+        ] {
+            assert_eq!(
+                span_names.iter().filter(|span| **span == name).count(),
+                3,
+                "expected one {} span per party",
+                name
+            );
+        }
+    }
 }
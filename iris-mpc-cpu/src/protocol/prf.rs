@@ -1,4 +1,8 @@
-use crate::shares::{int_ring::IntRing2k, ring_impl::RingElement};
+use crate::{
+    execution::session::Session,
+    protocol::ops::setup_replicated_prf,
+    shares::{int_ring::IntRing2k, ring_impl::RingElement},
+};
 use aes_prng::AesRng;
 use rand::{distributions::Standard, prelude::Distribution, Rng, SeedableRng};
 
@@ -27,6 +31,16 @@ impl Prf {
         }
     }
 
+    /// Replaces the PRFs of a running session by exchanging `new_seed` with
+    /// the neighbouring parties, following the same protocol as
+    /// `setup_replicated_prf`. All parties must call this at the same point of
+    /// the session. The session keeps its old PRFs if the exchange fails.
+    pub async fn reseed(session: &mut Session, new_seed: PrfSeed) -> eyre::Result<()> {
+        let prf = setup_replicated_prf(&session.boot_session, new_seed).await?;
+        session.setup = prf;
+        Ok(())
+    }
+
     pub fn get_my_prf(&mut self) -> &mut AesRng {
         &mut self.my_prf
     }
@@ -57,6 +71,17 @@ impl Prf {
         a - b
     }
 
+    /// Generates `n` correlated zero shares at once, drawing all values from
+    /// one PRF before the other instead of alternating per element.
+    pub fn gen_zero_share_vec<T: IntRing2k>(&mut self, n: usize) -> Vec<RingElement<T>>
+    where
+        Standard: Distribution<T>,
+    {
+        let a: Vec<RingElement<T>> = (&mut self.my_prf).sample_iter(Standard).take(n).collect();
+        let b = (&mut self.prev_prf).sample_iter::<RingElement<T>, _>(Standard);
+        a.into_iter().zip(b).map(|(a, b)| a - b).collect()
+    }
+
     pub fn gen_binary_zero_share<T: IntRing2k>(&mut self) -> RingElement<T>
     where
         Standard: Distribution<T>,
@@ -65,3 +90,58 @@ impl Prf {
         a ^ b
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::local::LocalRuntime;
+    use tokio::task::JoinSet;
+
+    #[tokio::test]
+    async fn test_reseed_keeps_zero_shares_correlated() {
+        let runtime = LocalRuntime::replicated_test_config();
+        let ready_sessions = runtime.create_player_sessions().await.unwrap();
+
+        let mut jobs = JoinSet::new();
+        for player in runtime.identities.iter() {
+            let mut player_session = ready_sessions.get(player).unwrap().clone();
+            jobs.spawn(async move {
+                let before = player_session.prf_as_mut().gen_zero_share::<u32>();
+                Prf::reseed(&mut player_session, Prf::gen_seed())
+                    .await
+                    .unwrap();
+                let after = player_session.prf_as_mut().gen_zero_share::<u32>();
+                (before, after)
+            });
+        }
+        let mut before = RingElement(0_u32);
+        let mut after = RingElement(0_u32);
+        while let Some(res) = jobs.join_next().await {
+            let (b, a) = res.unwrap();
+            before += b;
+            after += a;
+        }
+        assert_eq!(before, RingElement(0));
+        assert_eq!(after, RingElement(0));
+    }
+
+    #[tokio::test]
+    async fn test_gen_zero_share_vec() {
+        let runtime = LocalRuntime::replicated_test_config();
+        let ready_sessions = runtime.create_player_sessions().await.unwrap();
+        let n = 100;
+
+        let mut jobs = JoinSet::new();
+        for player in runtime.identities.iter() {
+            let mut player_session = ready_sessions.get(player).unwrap().clone();
+            jobs.spawn(async move { player_session.prf_as_mut().gen_zero_share_vec::<u16>(n) });
+        }
+        let mut sum = vec![RingElement(0_u16); n];
+        while let Some(res) = jobs.join_next().await {
+            let shares = res.unwrap();
+            assert_eq!(shares.len(), n);
+            sum.iter_mut().zip(shares).for_each(|(s, x)| *s += x);
+        }
+        assert!(sum.iter().all(|s| *s == RingElement(0)));
+    }
+}
@@ -0,0 +1,120 @@
+//! Helpers to reconstruct secret-shared values to plaintext.
+//!
+//! Every party learns the opened value, so these must only be used in tests
+//! and debugging code. The module is available to other crates behind the
+//! `testing` feature, so the example below only runs as a doctest with
+//! `--features testing`.
+#![cfg_attr(feature = "testing", doc = "```")]
+#![cfg_attr(not(feature = "testing"), doc = "```ignore")]
+//! use iris_mpc_cpu::{
+//!     execution::local::LocalRuntime,
+//!     protocol::reveal::open_single,
+//!     shares::{ring_impl::RingElement, share::Share},
+//! };
+//! use tokio::task::JoinSet;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> eyre::Result<()> {
+//! let runtime = LocalRuntime::replicated_test_config();
+//! let sessions = runtime.create_player_sessions().await?;
+//!
+//! // 42 = 10 + 12 + 20, where party i holds (x_i, x_{i-1}).
+//! let (a, b, c) = (RingElement(10u32), RingElement(12), RingElement(20));
+//! let shares = [Share::new(a, c), Share::new(b, a), Share::new(c, b)];
+//!
+//! let mut jobs = JoinSet::new();
+//! for (player, share) in runtime.identities.iter().zip(shares) {
+//!     let session = sessions[player].clone();
+//!     jobs.spawn(async move { open_single(&session, share).await });
+//! }
+//! while let Some(opened) = jobs.join_next().await {
+//!     assert_eq!(opened??, 42);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub use super::ops::{open_additive, NetworkRing};
+use crate::{
+    execution::session::{Session, SessionHandles},
+    network::value::NetworkValue,
+    shares::share::Share,
+};
+use eyre::eyre;
+
+/// Opens a single replicated share. Each party sends its `b` component to the
+/// next party and receives the missing component from the previous one.
+pub async fn open_single<T>(session: &Session, share: Share<T>) -> eyre::Result<T>
+where
+    T: NetworkRing,
+{
+    open_many(session, vec![share])
+        .await?
+        .pop()
+        .ok_or_else(|| eyre!("Expected exactly one opened value"))
+}
+
+/// Opens a batch of replicated shares in a single round.
+pub async fn open_many<T>(session: &Session, shares: Vec<Share<T>>) -> eyre::Result<Vec<T>>
+where
+    T: NetworkRing,
+{
+    let network = session.network().clone();
+    let sid = session.session_id();
+    let next_party = session.next_identity()?;
+    let prev_party = session.prev_identity()?;
+
+    let shares_b: Vec<_> = shares.iter().map(|s| s.b).collect();
+    network
+        .send(
+            T::to_network_value(shares_b).to_network(),
+            &next_party,
+            &sid,
+        )
+        .await?;
+
+    let serialized_other_share = network.receive(&prev_party, &sid).await;
+    let shares_c = T::from_network_value(NetworkValue::from_network(serialized_other_share)?)?;
+    if shares_c.len() != shares.len() {
+        return Err(eyre!(
+            "Expected {} shares from previous party, got {}",
+            shares.len(),
+            shares_c.len()
+        ));
+    }
+
+    Ok(shares
+        .into_iter()
+        .zip(shares_c)
+        .map(|(s, c)| {
+            let (a, b) = s.get_ab();
+            (a + b + c).convert()
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{execution::local::LocalRuntime, shares::ring_impl::RingElement};
+    use tokio::task::JoinSet;
+
+    /// Same as the module example, which only runs with the `testing` feature.
+    #[tokio::test]
+    async fn test_open_single() {
+        let runtime = LocalRuntime::replicated_test_config();
+        let sessions = runtime.create_player_sessions().await.unwrap();
+
+        let (a, b, c) = (RingElement(10u32), RingElement(12), RingElement(20));
+        let shares = [Share::new(a, c), Share::new(b, a), Share::new(c, b)];
+
+        let mut jobs = JoinSet::new();
+        for (player, share) in runtime.identities.iter().zip(shares) {
+            let session = sessions[player].clone();
+            jobs.spawn(async move { open_single(&session, share).await });
+        }
+        while let Some(opened) = jobs.join_next().await {
+            assert_eq!(opened.unwrap().unwrap(), 42);
+        }
+    }
+}
@@ -1,6 +1,6 @@
-pub(crate) mod bit;
-pub(crate) mod int_ring;
-pub(crate) mod ring_impl;
-pub(crate) mod share;
+pub mod bit;
+pub mod int_ring;
+pub mod ring_impl;
+pub mod share;
 pub(crate) mod vecshare;
 pub(crate) mod vecshare_bittranspose;
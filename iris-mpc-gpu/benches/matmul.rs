@@ -1,11 +1,19 @@
-use criterion::{criterion_group, criterion_main, Criterion, Throughput};
-use iris_mpc_common::{shamir::P, IRIS_CODE_LENGTH};
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use cudarc::nccl::Id;
+use iris_mpc_common::shamir::P;
 use iris_mpc_gpu::{
-    dot::share_db::{preprocess_query, ShareDB},
-    helpers::device_manager::DeviceManager,
+    dot::share_db::{preprocess_query, ReduceConfig, ShareDB},
+    helpers::{
+        bench::{lock_gpu_clocks, BenchParams},
+        device_manager::DeviceManager,
+    },
 };
 use rand::{rngs::StdRng, Rng, SeedableRng};
-use std::sync::Arc;
+use std::{
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, Instant},
+};
 
 fn random_vec(n: usize, m: usize, max_value: u32) -> Vec<u16> {
     let mut rng = StdRng::seed_from_u64(RNG_SEED);
@@ -15,54 +23,327 @@ fn random_vec(n: usize, m: usize, max_value: u32) -> Vec<u16> {
 }
 
 const RNG_SEED: u64 = 42;
-const DB_SIZE: usize = 10 * (1 << 16);
-const QUERY_SIZE: usize = 1984;
-const WIDTH: usize = 12800;
 
 fn bench_memcpy(c: &mut Criterion) {
     let mut group = c.benchmark_group("bench_memcpy");
+    let params = BenchParams::from_env();
+    let _clocks = lock_gpu_clocks();
+    let BenchParams {
+        db_size,
+        query_size,
+        width,
+    } = params;
 
-    let db = random_vec(DB_SIZE, WIDTH, P as u32); // (길이 12800인 u16 vector) DB_SIZE개
-    let query = random_vec(QUERY_SIZE, WIDTH, P as u32); // (길이 12800인 u16 vector) QUERY_SIZE개
+    let db = random_vec(db_size, width, P as u32); // (길이 width인 u16 vector) db_size개
+    let query = random_vec(query_size, width, P as u32); // (길이 width인 u16 vector) query_size개
     let device_manager = Arc::new(DeviceManager::init());
 
     let mut engine = ShareDB::init(
         0,
         device_manager.clone(),
-        DB_SIZE,
-        QUERY_SIZE,
-        IRIS_CODE_LENGTH,
+        db_size,
+        query_size,
+        width,
         ([0u32; 8], [0u32; 8]),
         vec![],
-    );
+    )
+    .unwrap();
     let preprocessed_query = preprocess_query(&query);
     let streams = device_manager.fork_streams();
     let blass = device_manager.create_cublas(&streams);
-    let mut db_slices = engine.alloc_db(DB_SIZE);
-    let db_sizes = engine.load_full_db(&mut db_slices, &db);
+    let mut db_slices = engine.alloc_db(db_size);
+    let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
 
-    group.throughput(Throughput::Elements((DB_SIZE * QUERY_SIZE / 31) as u64));
+    group.throughput(Throughput::Elements(params.comparisons()));
     group.sample_size(10);
 
-    group.bench_function(format!("matmul {} x {}", DB_SIZE, QUERY_SIZE), |b| {
-        b.iter(|| {
-            let preprocessed_query = device_manager
-                .htod_transfer_query(&preprocessed_query, &streams, QUERY_SIZE, IRIS_CODE_LENGTH)
-                .unwrap();
-            let query_sums = engine.query_sums(&preprocessed_query, &streams, &blass);
-            engine.dot(
+    let mut run = || {
+        let preprocessed_query = device_manager
+            .htod_transfer_query(&preprocessed_query, &streams, query_size, width)
+            .unwrap();
+        let query_sums = engine.query_sums(&preprocessed_query, &streams, &blass);
+        engine
+            .dot(
                 &preprocessed_query,
                 &db_slices.code_gr,
                 &db_sizes,
                 0,
                 &streams,
                 &blass,
-            );
-            engine.dot_reduce(&query_sums, &db_slices.code_sums_gr, &db_sizes, 0, &streams);
-            device_manager.await_streams(&streams);
-        });
+            )
+            .unwrap();
+        engine
+            .dot_reduce(&query_sums, &db_slices.code_sums_gr, &db_sizes, 0, &streams)
+            .unwrap();
+        device_manager.await_streams(&streams);
+    };
+    // Warm up, so the first sample doesn't pay for module loading and caches
+    run();
+
+    group.bench_function(format!("matmul {} x {}", db_size, query_size), |b| {
+        b.iter(&mut run);
     });
 }
 
-criterion_group!(benches, bench_memcpy);
+fn bench_reduce(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bench_reduce");
+    let params = BenchParams::from_env();
+    let _clocks = lock_gpu_clocks();
+    let BenchParams {
+        db_size,
+        query_size,
+        width,
+    } = params;
+
+    let db = random_vec(db_size, width, P as u32);
+    let query = random_vec(query_size, width, P as u32);
+    let device_manager = Arc::new(DeviceManager::init());
+
+    let mut engine = ShareDB::init(
+        0,
+        device_manager.clone(),
+        db_size,
+        query_size,
+        width,
+        ([0u32; 8], [0u32; 8]),
+        vec![],
+    )
+    .unwrap();
+    let preprocessed_query = preprocess_query(&query);
+    let streams = device_manager.fork_streams();
+    let blass = device_manager.create_cublas(&streams);
+    let mut db_slices = engine.alloc_db(db_size);
+    let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
+    let preprocessed_query = device_manager
+        .htod_transfer_query(&preprocessed_query, &streams, query_size, width)
+        .unwrap();
+    let query_sums = engine.query_sums(&preprocessed_query, &streams, &blass);
+    engine
+        .dot(
+            &preprocessed_query,
+            &db_slices.code_gr,
+            &db_sizes,
+            0,
+            &streams,
+            &blass,
+        )
+        .unwrap();
+    device_manager.await_streams(&streams);
+
+    group.throughput(Throughput::Elements((db_size * query_size) as u64));
+    group.sample_size(10);
+
+    for threads_per_block in [128, 256, 512, 1024] {
+        let config = ReduceConfig::new(threads_per_block, 0).unwrap();
+        group.bench_function(
+            format!(
+                "reduce {} x {} ({} threads)",
+                db_size, query_size, threads_per_block
+            ),
+            |b| {
+                b.iter(|| {
+                    engine
+                        .dot_reduce_with_config(
+                            &query_sums,
+                            &db_slices.code_sums_gr,
+                            &db_sizes,
+                            0,
+                            &streams,
+                            &config,
+                        )
+                        .unwrap();
+                    device_manager.await_streams(&streams);
+                });
+            },
+        );
+    }
+}
+
+fn bench_parallel_devices(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bench_parallel_devices");
+    let params = BenchParams::from_env();
+    let _clocks = lock_gpu_clocks();
+    let BenchParams {
+        db_size,
+        query_size,
+        width,
+    } = params;
+
+    let db = random_vec(db_size, width, P as u32);
+    let query = random_vec(query_size, width, P as u32);
+    let device_manager = Arc::new(DeviceManager::init());
+
+    let mut engine = ShareDB::init(
+        0,
+        device_manager.clone(),
+        db_size,
+        query_size,
+        width,
+        ([0u32; 8], [0u32; 8]),
+        vec![],
+    )
+    .unwrap();
+    let preprocessed_query = preprocess_query(&query);
+    let streams = device_manager.fork_streams();
+    let blass = device_manager.create_cublas(&streams);
+    let mut db_slices = engine.alloc_db(db_size);
+    let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
+    let preprocessed_query = device_manager
+        .htod_transfer_query(&preprocessed_query, &streams, query_size, width)
+        .unwrap();
+
+    group.throughput(Throughput::Elements((db_size * query_size) as u64));
+    group.sample_size(10);
+
+    for parallel in [false, true] {
+        engine.set_parallel_devices(parallel);
+        let mut run = || {
+            let query_sums = engine.query_sums(&preprocessed_query, &streams, &blass);
+            engine
+                .dot(
+                    &preprocessed_query,
+                    &db_slices.code_gr,
+                    &db_sizes,
+                    0,
+                    &streams,
+                    &blass,
+                )
+                .unwrap();
+            engine
+                .dot_reduce(&query_sums, &db_slices.code_sums_gr, &db_sizes, 0, &streams)
+                .unwrap();
+            device_manager.await_streams(&streams);
+        };
+        // Warm up, so the first sample doesn't pay for module loading and caches
+        run();
+
+        group.bench_function(
+            format!(
+                "dot {} x {} ({})",
+                db_size,
+                query_size,
+                if parallel { "parallel" } else { "serial" }
+            ),
+            |b| {
+                b.iter(&mut run);
+            },
+        );
+    }
+}
+
+/// Runs `dot`, `dot_reduce`, `reshare_results` and `reconstruct_results` for
+/// three parties in this process, each on its own thread with a third of the
+/// devices, connected via NCCL. Needs at least three devices.
+fn bench_full_pipeline(c: &mut Criterion) {
+    let mut group = c.benchmark_group("full_pipeline");
+    let params = BenchParams::from_env();
+    let _clocks = lock_gpu_clocks();
+    let BenchParams {
+        db_size,
+        query_size,
+        width,
+    } = params;
+
+    let db = Arc::new(random_vec(db_size, width, P as u32));
+    let query = Arc::new(preprocess_query(&random_vec(query_size, width, P as u32)));
+    let device_managers = DeviceManager::init()
+        .split_into_n_chunks(3)
+        .expect("full_pipeline needs at least 3 devices");
+    let ids = (0..device_managers[0].device_count())
+        .map(|_| Id::new().unwrap())
+        .collect::<Vec<_>>();
+    let seeds = [[0u32; 8], [1u32; 8], [2u32; 8]];
+
+    // Every party runs the requested number of iterations when it receives it
+    // and reports back how long that took.
+    let (parties, handles): (Vec<_>, Vec<_>) = device_managers
+        .into_iter()
+        .enumerate()
+        .map(|(party_id, device_manager)| {
+            let (iters_tx, iters_rx) = mpsc::channel::<u64>();
+            let (elapsed_tx, elapsed_rx) = mpsc::channel::<Duration>();
+            let (db, query, ids) = (db.clone(), query.clone(), ids.clone());
+            let handle = thread::spawn(move || {
+                let device_manager = Arc::new(device_manager);
+                let comms = device_manager
+                    .instantiate_network_from_ids(party_id, &ids)
+                    .unwrap();
+                let mut engine = ShareDB::init(
+                    party_id,
+                    device_manager.clone(),
+                    db_size,
+                    query_size,
+                    width,
+                    (seeds[party_id], seeds[(party_id + 2) % 3]),
+                    comms,
+                )
+                .unwrap();
+                let streams = device_manager.fork_streams();
+                let blass = device_manager.create_cublas(&streams);
+                let mut db_slices = engine.alloc_db(db_size);
+                let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
+
+                let mut run = || {
+                    let query = device_manager
+                        .htod_transfer_query(&query, &streams, query_size, width)
+                        .unwrap();
+                    let query_sums = engine.query_sums(&query, &streams, &blass);
+                    engine
+                        .dot(&query, &db_slices.code_gr, &db_sizes, 0, &streams, &blass)
+                        .unwrap();
+                    engine
+                        .dot_reduce(&query_sums, &db_slices.code_sums_gr, &db_sizes, 0, &streams)
+                        .unwrap();
+                    engine.reshare_results(&db_sizes, &streams).unwrap();
+                    device_manager.await_streams(&streams);
+                    black_box(engine.reconstruct_results(&db_sizes).unwrap());
+                };
+                // Warm up, so the first sample doesn't pay for module loading and caches
+                run();
+
+                for iters in iters_rx {
+                    let now = Instant::now();
+                    for _ in 0..iters {
+                        run();
+                    }
+                    elapsed_tx.send(now.elapsed()).unwrap();
+                }
+            });
+            ((iters_tx, elapsed_rx), handle)
+        })
+        .unzip();
+
+    group.throughput(Throughput::Elements(params.comparisons()));
+    group.sample_size(10);
+
+    group.bench_function(
+        format!("dot+reshare+reconstruct {} x {}", db_size, query_size),
+        |b| {
+            b.iter_custom(|iters| {
+                for (iters_tx, _) in &parties {
+                    iters_tx.send(iters).unwrap();
+                }
+                parties
+                    .iter()
+                    .map(|(_, elapsed_rx)| elapsed_rx.recv().unwrap())
+                    .max()
+                    .unwrap()
+            });
+        },
+    );
+
+    // Closing the channels lets the parties finish.
+    drop(parties);
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+criterion_group!(
+    benches,
+    bench_memcpy,
+    bench_reduce,
+    bench_parallel_devices,
+    bench_full_pipeline
+);
 criterion_main!(benches);
@@ -2,17 +2,31 @@
 //! This script establishes a pairwise connection via NCCL between all devices
 //! of two hosts. Each device pair gets its separate NCCL comm channel, with the
 //! host device being rank 0. It also starts a HTTP server on the host on port
-//! 3000 to exchange the NCCL COMM_IDs. Host: NCCL_DEBUG=INFO cargo run
+//! 3000 to exchange the NCCL COMM_IDs, which also serves `/healthz` and
+//! `/readyz` for liveness and readiness probes. Host: NCCL_DEBUG=INFO cargo run
 //! --release --bin nccl 0 Node: NCCL_DEBUG=INFO cargo run --release --bin nccl
-//! {1,2} HOST_IP:3000
+//! {1,2} HOST [PORT], where HOST is a hostname, IPv4 or IPv6 address. If
+//! `COMM_ID_DIR` is set, the COMM_IDs are exchanged through files in that
+//! shared directory instead of over HTTP. `COMM_ID_RUN` then has to be set to
+//! the same identifier of the current run on all hosts.
 
-use axum::{extract::Path, routing::get, Router};
 use cudarc::{
     driver::{CudaDevice, CudaSlice},
     nccl::{Comm, Id},
 };
-use iris_mpc_gpu::helpers::id_wrapper::IdWrapper;
-use std::{env, str::FromStr, sync::LazyLock, time::Instant};
+use iris_mpc_common::helpers::runtime::multi_thread_runtime;
+use iris_mpc_gpu::helpers::id_wrapper::{
+    comm_id_router, comm_id_url, poll_comm_id, poll_comm_id_file, write_comm_ids, CommExchange,
+    COMM_ID_POLL_DEADLINE,
+};
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, LazyLock,
+    },
+    time::Instant,
+};
 
 static COMM_ID: LazyLock<Vec<Id>> = LazyLock::new(|| {
     (0..CudaDevice::count().unwrap())
@@ -21,25 +35,51 @@ static COMM_ID: LazyLock<Vec<Id>> = LazyLock::new(|| {
 });
 
 const DUMMY_DATA_LEN: usize = 5 * (1 << 30);
+const SERVER_PORT: u16 = 3000;
+/// Number of tokio worker threads if `WORKER_THREADS` is not set.
+const DEFAULT_WORKER_THREADS: usize = 12;
+
+fn comm_exchange() -> eyre::Result<CommExchange> {
+    match env::var_os("COMM_ID_DIR") {
+        Some(dir) => {
+            let run = env::var("COMM_ID_RUN")
+                .map_err(|_| eyre::eyre!("COMM_ID_RUN must be set with COMM_ID_DIR"))?;
+            Ok(CommExchange::SharedDir {
+                dir: dir.into(),
+                run,
+            })
+        }
+        None => Ok(CommExchange::Http),
+    }
+}
 
-async fn root(Path(device_id): Path<String>) -> String {
-    let device_id: usize = device_id.parse().unwrap();
-    IdWrapper(COMM_ID[device_id]).to_string()
+fn main() -> eyre::Result<()> {
+    multi_thread_runtime(Some(DEFAULT_WORKER_THREADS))?.block_on(run())
 }
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 12)]
-async fn main() -> eyre::Result<()> {
+async fn run() -> eyre::Result<()> {
     let args = env::args().collect::<Vec<_>>();
     let n_devices = CudaDevice::count().unwrap() as usize;
     let party_id: usize = args[1].parse().unwrap();
+    let peer_port: u16 = args
+        .get(3)
+        .map(|port| port.parse().unwrap())
+        .unwrap_or(SERVER_PORT);
 
+    let exchange = comm_exchange()?;
     let mut server_join_handle = None;
+    let ready = Arc::new(AtomicBool::new(false));
 
-    if party_id == 0 {
+    if let (0, CommExchange::SharedDir { dir, run }) = (party_id, &exchange) {
+        write_comm_ids(dir, run, &COMM_ID)?;
+    } else if party_id == 0 {
+        let ready = ready.clone();
         server_join_handle = Some(tokio::spawn(async move {
             println!("starting server...");
-            let app = Router::new().route("/:device_id", get(root));
-            let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+            let app = comm_id_router(COMM_ID.clone(), ready);
+            let listener = tokio::net::TcpListener::bind(("0.0.0.0", SERVER_PORT))
+                .await
+                .unwrap();
             axum::serve(listener, app).await.unwrap();
         }));
     };
@@ -55,8 +95,15 @@ async fn main() -> eyre::Result<()> {
         let id = if party_id == 0 {
             COMM_ID[i]
         } else {
-            let res = reqwest::blocking::get(format!("http://{}/{}", args[2], i)).unwrap();
-            IdWrapper::from_str(&res.text().unwrap()).unwrap().0
+            match &exchange {
+                CommExchange::Http => {
+                    poll_comm_id(comm_id_url(&args[2], peer_port, i)?, COMM_ID_POLL_DEADLINE)
+                        .await?
+                }
+                CommExchange::SharedDir { dir, run } => {
+                    poll_comm_id_file(dir, run, i, COMM_ID_POLL_DEADLINE).await?
+                }
+            }
         };
 
         // This call to CudaDevice::new is only used in context of a benchmark - not
@@ -78,6 +125,7 @@ async fn main() -> eyre::Result<()> {
         slices2.push(slice2);
         slices3.push(slice3);
     }
+    ready.store(true, Ordering::Release);
 
     for _ in 0..10 {
         let now = Instant::now();
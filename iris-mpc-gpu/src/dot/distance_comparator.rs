@@ -1,12 +1,9 @@
 use super::ROTATIONS;
 use crate::helpers::{
     device_manager::DeviceManager, launch_config_from_elements_and_threads,
-    DEFAULT_LAUNCH_CONFIG_THREADS,
-};
-use cudarc::{
-    driver::{CudaFunction, CudaSlice, CudaStream, CudaView, LaunchAsync},
-    nvrtc::compile_ptx,
+    ptx_cache::compile_ptx_cached, DEFAULT_LAUNCH_CONFIG_THREADS,
 };
+use cudarc::driver::{CudaFunction, CudaSlice, CudaStream, CudaView, LaunchAsync};
 use std::{cmp::min, sync::Arc};
 
 const PTX_SRC: &str = include_str!("kernel.cu");
@@ -35,7 +32,7 @@ pub struct DistanceComparator {
 
 impl DistanceComparator {
     pub fn init(query_length: usize, device_manager: Arc<DeviceManager>) -> Self {
-        let ptx = compile_ptx(PTX_SRC).unwrap();
+        let ptx = compile_ptx_cached(PTX_SRC).unwrap();
         let mut open_kernels: Vec<CudaFunction> = Vec::new();
         let mut merge_db_kernels = Vec::new();
         let mut merge_batch_kernels = Vec::new();
@@ -13,6 +13,15 @@ extern "C" __global__ void xor_assign_u8(U8 *lhs, U8 *rhs, size_t n)
     }
 }
 
+extern "C" __global__ void compute_distance(unsigned short *codes, unsigned short *masks, double *output, size_t n)
+{
+    size_t i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < n)
+    {
+        output[i] = 0.5 - (double)((short)codes[i]) / (2.0 * (double)masks[i]);
+    }
+}
+
 extern "C" __global__ void matmul_correct_and_reduce(int *c, unsigned short *output, int *a0Sums, int *a1Sums, int *b0Sums, int *b1Sums, size_t dbLength, size_t numElements, size_t offset, unsigned short multiplier, unsigned short *rngMasks0, unsigned short *rngMasks1)
 {
     size_t idx = blockIdx.x * blockDim.x + threadIdx.x;
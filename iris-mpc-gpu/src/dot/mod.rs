@@ -1,4 +1,5 @@
 pub mod distance_comparator;
+pub mod multi_share_db;
 pub mod share_db;
 
 pub const IRIS_CODE_LENGTH: usize = 12_800;
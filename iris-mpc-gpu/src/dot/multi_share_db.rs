@@ -0,0 +1,298 @@
+use super::share_db::{shard_lens, ShareDB, ShareDbError, SlicedProcessedDatabase};
+use crate::helpers::query_processor::{CudaVec2DSlicer, CudaVec2DSlicerU32, CudaVec2DSlicerU8};
+use cudarc::{cublas::CudaBlas, driver::CudaStream};
+
+/// Shards a DB over several [`ShareDB`] engines, each running on its own
+/// subset of the devices, e.g. the chunks returned by
+/// [`DeviceManager::split_into_n_chunks`](crate::helpers::device_manager::DeviceManager::split_into_n_chunks).
+///
+/// Engine `i` holds the `i`-th contiguous block of the DB, which the engine
+/// itself distributes round-robin over its devices. Every per-engine argument
+/// (queries, streams, cuBLAS handles, DB sizes) is passed as a slice with one
+/// entry per engine, in the same order as the engines.
+pub struct MultiShareDb {
+    engines: Vec<ShareDB>,
+}
+
+impl MultiShareDb {
+    pub fn new(engines: Vec<ShareDB>) -> Result<Self, ShareDbError> {
+        if engines.is_empty() {
+            return Err(ShareDbError::Dimension(
+                "MultiShareDb needs at least one engine".to_string(),
+            ));
+        }
+        if let Some(engine) = engines.iter().find(|engine| {
+            engine.query_length() != engines[0].query_length()
+                || engine.code_length() != engines[0].code_length()
+        }) {
+            return Err(ShareDbError::Dimension(format!(
+                "engines disagree on the query length ({} vs {}) or code length ({} vs {})",
+                engines[0].query_length(),
+                engine.query_length(),
+                engines[0].code_length(),
+                engine.code_length()
+            )));
+        }
+        Ok(Self { engines })
+    }
+
+    pub fn engines(&self) -> &[ShareDB] {
+        &self.engines
+    }
+
+    pub fn engines_mut(&mut self) -> &mut [ShareDB] {
+        &mut self.engines
+    }
+
+    fn check_engine_count(&self, what: &str, len: usize) -> Result<(), ShareDbError> {
+        if len != self.engines.len() {
+            return Err(ShareDbError::Dimension(format!(
+                "expected {} for {} engines, got {}",
+                what,
+                self.engines.len(),
+                len
+            )));
+        }
+        Ok(())
+    }
+
+    /// Allocates room for `max_db_length` records in total, split evenly over
+    /// the engines.
+    pub fn alloc_db(&self, max_db_length: usize) -> Vec<SlicedProcessedDatabase> {
+        let per_engine = max_db_length.div_ceil(self.engines.len());
+        self.engines
+            .iter()
+            .map(|engine| engine.alloc_db(per_engine))
+            .collect()
+    }
+
+    /// Loads a contiguous block of `db_entries` into each engine. Returns the
+    /// per-device DB sizes of every engine.
+    pub fn load_full_db(
+        &self,
+        dbs: &mut [SlicedProcessedDatabase],
+        db_entries: &[u16],
+    ) -> Result<Vec<Vec<usize>>, ShareDbError> {
+        self.check_engine_count("DBs", dbs.len())?;
+        let code_length = self.engines[0].code_length();
+        if db_entries.len() % code_length != 0 {
+            return Err(ShareDbError::Dimension(format!(
+                "DB of {} entries is not a multiple of the code length {}",
+                db_entries.len(),
+                code_length
+            )));
+        }
+
+        let block_lens = shard_lens(db_entries.len() / code_length, self.engines.len());
+        let mut offset = 0;
+        let mut db_sizes = Vec::with_capacity(self.engines.len());
+        for ((engine, db), len) in self.engines.iter().zip(dbs).zip(block_lens) {
+            let block = &db_entries[offset * code_length..(offset + len) * code_length];
+            db_sizes.push(engine.load_full_db(db, block)?);
+            offset += len;
+        }
+        Ok(db_sizes)
+    }
+
+    pub fn query_sums(
+        &self,
+        queries: &[CudaVec2DSlicerU8],
+        streams: &[Vec<CudaStream>],
+        blass: &[Vec<CudaBlas>],
+    ) -> Result<Vec<CudaVec2DSlicerU32>, ShareDbError> {
+        self.check_engine_count("queries", queries.len())?;
+        self.check_engine_count("streams", streams.len())?;
+        self.check_engine_count("cuBLAS handles", blass.len())?;
+        Ok(self
+            .engines
+            .iter()
+            .enumerate()
+            .map(|(i, engine)| engine.query_sums(&queries[i], &streams[i], &blass[i]))
+            .collect())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn dot<T>(
+        &mut self,
+        queries: &[CudaVec2DSlicer<T>],
+        dbs: &[SlicedProcessedDatabase],
+        db_sizes: &[Vec<usize>],
+        offset: usize,
+        streams: &[Vec<CudaStream>],
+        blass: &[Vec<CudaBlas>],
+    ) -> Result<(), ShareDbError> {
+        self.check_engine_count("queries", queries.len())?;
+        self.check_engine_count("DBs", dbs.len())?;
+        self.check_engine_count("DB sizes", db_sizes.len())?;
+        self.check_engine_count("streams", streams.len())?;
+        self.check_engine_count("cuBLAS handles", blass.len())?;
+        for (i, engine) in self.engines.iter_mut().enumerate() {
+            engine.dot(
+                &queries[i],
+                &dbs[i].code_gr,
+                &db_sizes[i],
+                offset,
+                &streams[i],
+                &blass[i],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn dot_reduce(
+        &mut self,
+        query_sums: &[CudaVec2DSlicerU32],
+        dbs: &[SlicedProcessedDatabase],
+        db_sizes: &[Vec<usize>],
+        offset: usize,
+        streams: &[Vec<CudaStream>],
+    ) -> Result<(), ShareDbError> {
+        self.check_engine_count("query sums", query_sums.len())?;
+        self.check_engine_count("DBs", dbs.len())?;
+        self.check_engine_count("DB sizes", db_sizes.len())?;
+        self.check_engine_count("streams", streams.len())?;
+        for (i, engine) in self.engines.iter_mut().enumerate() {
+            engine.dot_reduce(
+                &query_sums[i],
+                &dbs[i].code_sums_gr,
+                &db_sizes[i],
+                offset,
+                &streams[i],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Concatenates the [`ShareDB::fetch_all_results`] of every engine, so
+    /// that the result of query `q` and DB record `i` ends up at `q * total +
+    /// i`, just like for a single engine holding the whole DB.
+    pub fn fetch_all_results(&self, db_sizes: &[Vec<usize>]) -> Result<Vec<u16>, ShareDbError> {
+        self.check_engine_count("DB sizes", db_sizes.len())?;
+        let query_length = self.engines[0].query_length();
+        let results = self
+            .engines
+            .iter()
+            .zip(db_sizes)
+            .map(|(engine, db_sizes)| engine.fetch_all_results(db_sizes))
+            .collect::<Result<Vec<_>, _>>()?;
+        let totals = db_sizes
+            .iter()
+            .map(|sizes| sizes.iter().sum::<usize>())
+            .collect::<Vec<_>>();
+
+        let mut merged = Vec::with_capacity(totals.iter().sum::<usize>() * query_length);
+        for q in 0..query_length {
+            for (results, &total) in results.iter().zip(&totals) {
+                merged.extend_from_slice(&results[q * total..(q + 1) * total]);
+            }
+        }
+        Ok(merged)
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "gpu_dependent")]
+mod tests {
+    use super::MultiShareDb;
+    use crate::{
+        dot::{
+            share_db::{preprocess_query, ShareDB},
+            IRIS_CODE_LENGTH,
+        },
+        helpers::device_manager::DeviceManager,
+    };
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::sync::Arc;
+
+    const DB_SIZE: usize = 8 * 1000;
+    const QUERY_SIZE: usize = 32;
+    const RNG_SEED: u64 = 42;
+
+    fn engine(device_manager: Arc<DeviceManager>) -> ShareDB {
+        ShareDB::init(
+            0,
+            device_manager,
+            DB_SIZE,
+            QUERY_SIZE,
+            IRIS_CODE_LENGTH,
+            ([0u32; 8], [0u32; 8]),
+            vec![],
+        )
+        .unwrap()
+    }
+
+    /// Splitting the DB over two engines gives the same results as running it
+    /// on a single engine.
+    #[test]
+    fn check_multi_share_db() {
+        let mut rng = StdRng::seed_from_u64(RNG_SEED);
+        let db = (0..DB_SIZE * IRIS_CODE_LENGTH)
+            .map(|_| rng.gen())
+            .collect::<Vec<u16>>();
+        let query = (0..QUERY_SIZE * IRIS_CODE_LENGTH)
+            .map(|_| rng.gen())
+            .collect::<Vec<u16>>();
+        let preprocessed_query = preprocess_query(&query);
+
+        let Ok(chunks) = DeviceManager::init().split_into_n_chunks(2) else {
+            println!("Skipping test, at least 2 devices are required");
+            return;
+        };
+
+        // The whole DB on a single engine
+        let device_manager = Arc::new(DeviceManager::init());
+        let mut single = engine(device_manager.clone());
+        let streams = device_manager.fork_streams();
+        let blass = device_manager.create_cublas(&streams);
+        let queries = device_manager
+            .htod_transfer_query(&preprocessed_query, &streams, QUERY_SIZE, IRIS_CODE_LENGTH)
+            .unwrap();
+        let query_sums = single.query_sums(&queries, &streams, &blass);
+        let mut db_slices = single.alloc_db(DB_SIZE);
+        let db_sizes = single.load_full_db(&mut db_slices, &db).unwrap();
+        single
+            .dot(&queries, &db_slices.code_gr, &db_sizes, 0, &streams, &blass)
+            .unwrap();
+        single
+            .dot_reduce(&query_sums, &db_slices.code_sums_gr, &db_sizes, 0, &streams)
+            .unwrap();
+        device_manager.await_streams(&streams);
+        let expected = single.fetch_all_results(&db_sizes).unwrap();
+
+        // The same DB split over two engines
+        let device_managers = chunks.into_iter().map(Arc::new).collect::<Vec<_>>();
+        let mut multi =
+            MultiShareDb::new(device_managers.iter().cloned().map(engine).collect()).unwrap();
+        let streams = device_managers
+            .iter()
+            .map(|dm| dm.fork_streams())
+            .collect::<Vec<_>>();
+        let blass = device_managers
+            .iter()
+            .zip(&streams)
+            .map(|(dm, streams)| dm.create_cublas(streams))
+            .collect::<Vec<_>>();
+        let queries = device_managers
+            .iter()
+            .zip(&streams)
+            .map(|(dm, streams)| {
+                dm.htod_transfer_query(&preprocessed_query, streams, QUERY_SIZE, IRIS_CODE_LENGTH)
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let query_sums = multi.query_sums(&queries, &streams, &blass).unwrap();
+        let mut dbs = multi.alloc_db(DB_SIZE);
+        let db_sizes = multi.load_full_db(&mut dbs, &db).unwrap();
+        multi
+            .dot(&queries, &dbs, &db_sizes, 0, &streams, &blass)
+            .unwrap();
+        multi
+            .dot_reduce(&query_sums, &dbs, &db_sizes, 0, &streams)
+            .unwrap();
+        for (dm, streams) in device_managers.iter().zip(&streams) {
+            dm.await_streams(streams);
+        }
+
+        assert_eq!(multi.fetch_all_results(&db_sizes).unwrap(), expected);
+    }
+}
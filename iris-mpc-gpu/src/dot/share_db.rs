@@ -1,8 +1,10 @@
 use crate::{
+    dot::IRIS_CODE_LENGTH,
     helpers::{
         comm::NcclComm,
         device_manager::DeviceManager,
         launch_config_from_elements_and_threads,
+        ptx_cache::compile_ptx_cached,
         query_processor::{
             CudaVec2DSlicer, CudaVec2DSlicerRawPointer, CudaVec2DSlicerU32, CudaVec2DSlicerU8,
             StreamAwareCudaSlice,
@@ -15,45 +17,123 @@ use crate::{
 use core::panic;
 use cudarc::{
     cublas::{
-        result::gemm_ex,
+        result::{gemm_ex, CublasError},
         sys::{self, lib},
         CudaBlas,
     },
     driver::{
-        result::{self, malloc_async, malloc_managed},
-        sys::{CUdeviceptr, CUmemAttach_flags},
-        CudaFunction, CudaSlice, CudaStream, CudaView, DevicePtr, DeviceSlice, LaunchAsync,
+        result::{self, event, malloc_async, malloc_managed},
+        sys::{CUdeviceptr, CUevent, CUevent_flags, CUmemAttach_flags, CUresult},
+        CudaFunction, CudaSlice, CudaStream, CudaView, DevicePtr, DeviceSlice, DriverError,
+        LaunchAsync,
     },
-    nccl,
-    nvrtc::compile_ptx,
+    nccl::{self, result::NcclError},
+    nvrtc::CompileError,
 };
+use futures::{Stream, TryStreamExt};
+use iris_mpc_common::eye::Eye;
 use itertools::{izip, Itertools};
 use rayon::prelude::*;
+use ring::{
+    digest,
+    hkdf::{Salt, HKDF_SHA256},
+};
 use std::{
     ffi::{c_void, CStr},
+    fs::File,
+    future::Future,
     mem,
+    ops::Range,
+    path::Path,
+    pin::pin,
     sync::Arc,
 };
+use thiserror::Error;
 
 const PTX_SRC: &str = include_str!("kernel.cu");
 const REDUCE_FUNCTION_NAME: &str = "matmul_correct_and_reduce";
 const XOR_ASSIGN_U8_NAME: &str = "xor_assign_u8";
+const COMPUTE_DISTANCE_NAME: &str = "compute_distance";
+/// Number of 8-bit limbs a `u16` share is split into for the cuBLAS int8
+/// matmuls. The products of limbs `i` and `j` are scaled by `1 << (LIMB_BITS *
+/// (i + j))` and dropped if `i + j >= LIMBS`, since they vanish mod 2^16.
 const LIMBS: usize = 2;
+const LIMB_BITS: usize = 8;
+const _: () = assert!(LIMBS * LIMB_BITS >= u16::BITS as usize);
+const _: () = assert!(LIMB_BITS * (LIMBS - 1) < i32::BITS as usize - 1);
+/// Number of records per copy when hashing the DB.
+const DIGEST_CHUNK_RECORDS: usize = 1 << 14;
+const DEVICE_SEED_SALT: &[u8] = b"iris-mpc ShareDB device seed";
+/// Number of entries in one row of a code, as laid out by
+/// `GaloisRingIrisCodeShare`. A rotation by one shifts every row by 4 entries.
+const ROTATION_ROW_LENGTH: usize = 800;
 
-pub fn preprocess_query(query: &[u16]) -> Vec<Vec<u8>> {
-    let mut result = vec![];
-    for _ in 0..LIMBS {
-        result.push(vec![0u8; query.len()]);
-    }
+/// Errors returned by the public [`ShareDB`] methods.
+#[derive(Error, Debug)]
+pub enum ShareDbError {
+    #[error("CUDA error: {0}")]
+    Cuda(#[from] DriverError),
+    #[error("failed to compile kernels: {0}")]
+    Compile(#[from] CompileError),
+    #[error("NCCL error: {0:?}")]
+    Nccl(NcclError),
+    #[error("cuBLAS error: {0}")]
+    Cublas(#[from] CublasError),
+    #[error("exchanging results with peer {peer} on device {device} failed: {error:?}")]
+    CommExchange {
+        peer:   usize,
+        device: usize,
+        error:  NcclError,
+    },
+    #[error("dimension mismatch: {0}")]
+    Dimension(String),
+    #[error("missing ShareDB parameter: {0}")]
+    MissingParameter(&'static str),
+    #[error("failed to start the device thread pool: {0}")]
+    ThreadPool(#[from] rayon::ThreadPoolBuildError),
+    #[error("failed to transfer the queries: {0}")]
+    QueryTransfer(eyre::Report),
+    #[cfg(feature = "debug_checks")]
+    #[error("reshared results differ from the previous party on device {device} at byte {offset}")]
+    ReshareMismatch { device: usize, offset: usize },
+}
 
-    for (idx, &entry) in query.iter().enumerate() {
-        for i in 0..LIMBS {
-            let tmp = (entry as u32 >> (i * 8)) as u8;
-            result[i][idx] = (tmp as i32 - 128) as u8;
-        }
+impl From<NcclError> for ShareDbError {
+    fn from(error: NcclError) -> Self {
+        Self::Nccl(error)
     }
+}
+
+/// Returns limb `i` of `x`, shifted from `0..256` to the `i8` range.
+fn limb(x: u16, i: usize) -> i8 {
+    let tmp = (x as u32 >> (i * LIMB_BITS)) as u8;
+    (tmp as i32 - 128) as i8
+}
+
+/// Splits every entry of `query` into [`LIMBS`] limbs, see [`limb`].
+pub fn preprocess_query(query: &[u16]) -> Vec<Vec<u8>> {
+    (0..LIMBS)
+        .map(|i| query.iter().map(|&entry| limb(entry, i) as u8).collect())
+        .collect()
+}
 
-    result.to_vec()
+/// Inverts [`preprocess_query`]: shifts every limb back to `0..256` and
+/// recombines them into the original `u16` entries.
+pub fn reconstruct_query(limbs: &[Vec<u8>]) -> Vec<u16> {
+    assert_eq!(limbs.len(), LIMBS, "expected {} limbs", LIMBS);
+    (0..limbs[0].len())
+        .map(|idx| {
+            limbs.iter().enumerate().fold(0u16, |acc, (i, limb)| {
+                let tmp = (limb[idx] as i8 as i32 + 128) as u16;
+                acc | tmp.wrapping_shl((i * LIMB_BITS) as u32)
+            })
+        })
+        .collect()
+}
+
+/// The per-limb device pointers of `db` on device `idx`.
+fn limb_ptrs(db: &CudaVec2DSlicerRawPointer, idx: usize) -> [u64; LIMBS] {
+    [db.limb_0[idx], db.limb_1[idx]]
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -71,15 +151,41 @@ pub fn gemm(
     alpha: i32,
     beta: i32,
 ) {
+    // Try to fetch more information in case of an error
+    if let Err(e) = try_gemm(
+        handle, a, b, c, a_offset, b_offset, c_offset, m, n, k, alpha, beta,
+    ) {
+        let c_str = unsafe { CStr::from_ptr(lib().cublasGetStatusString(e.0)) };
+        panic!("CUBLAS error: {:?}", c_str.to_str());
+    }
+}
+
+/// Same as [`gemm`], but returns the cuBLAS error instead of panicking.
+#[allow(clippy::too_many_arguments)]
+pub fn try_gemm(
+    handle: &CudaBlas,
+    a: CUdeviceptr,
+    b: CUdeviceptr,
+    c: CUdeviceptr,
+    a_offset: u64,
+    b_offset: u64,
+    c_offset: u64,
+    m: usize,
+    n: usize,
+    k: usize,
+    alpha: i32,
+    beta: i32,
+) -> Result<(), CublasError> {
     // https://docs.nvidia.com/cuda/cublas/#cublasgemmex:
     // "CUBLAS_COMPUTE_32I and CUBLAS_COMPUTE_32I_PEDANTIC compute types are only supported with A, B being 4-byte aligned and lda, ldb being multiples of 4."
-    assert!(m % 4 == 0, "m must be a multiple of 4");
+    // lda and ldb are both k, so m can be anything, e.g. a DB chunk of one record.
+    assert!(k % 4 == 0, "k must be a multiple of 4");
     // We don't enforce the following, since we use it for n=1 and emperial testing
     // shows that it works. assert!(n % 4 == 0, "n must be a multiple of 4");
     assert!(a % 4 == 0, "a must be aligned to 4 bytes");
     assert!(b % 4 == 0, "b must be aligned to 4 bytes");
     unsafe {
-        let status = gemm_ex(
+        gemm_ex(
             *handle.handle(),
             sys::cublasOperation_t::CUBLAS_OP_T,
             sys::cublasOperation_t::CUBLAS_OP_N,
@@ -99,28 +205,74 @@ pub fn gemm(
             m as i32,
             sys::cublasComputeType_t::CUBLAS_COMPUTE_32I_PEDANTIC,
             sys::cublasGemmAlgo_t::CUBLAS_GEMM_DEFAULT,
-        );
+        )
+    }
+}
+
+/// Launch parameters of the reduce kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReduceConfig {
+    threads_per_block: u32,
+    shared_mem_bytes:  u32,
+}
+
+impl Default for ReduceConfig {
+    fn default() -> Self {
+        Self {
+            threads_per_block: DEFAULT_LAUNCH_CONFIG_THREADS,
+            shared_mem_bytes:  0,
+        }
+    }
+}
 
-        // Try to fetch more information in case of an error
-        if let Err(e) = status {
-            let c_str = CStr::from_ptr(lib().cublasGetStatusString(e.0));
-            panic!("CUBLAS error: {:?}", c_str.to_str());
+impl ReduceConfig {
+    /// `threads_per_block` must be a non-zero multiple of the warp size (32)
+    /// and at most 1024.
+    pub fn new(threads_per_block: u32, shared_mem_bytes: u32) -> eyre::Result<Self> {
+        if threads_per_block == 0 || threads_per_block % 32 != 0 || threads_per_block > 1024 {
+            eyre::bail!(
+                "threads_per_block must be a non-zero multiple of 32 and at most 1024, got {}",
+                threads_per_block
+            );
         }
+        Ok(Self {
+            threads_per_block,
+            shared_mem_bytes,
+        })
+    }
+
+    pub fn threads_per_block(&self) -> u32 {
+        self.threads_per_block
+    }
+
+    pub fn shared_mem_bytes(&self) -> u32 {
+        self.shared_mem_bytes
     }
 }
 
 pub struct SlicedProcessedDatabase {
-    pub code_gr:      CudaVec2DSlicerRawPointer,
-    pub code_sums_gr: CudaVec2DSlicerU32,
+    pub code_gr:       CudaVec2DSlicerRawPointer,
+    pub code_sums_gr:  CudaVec2DSlicerU32,
+    /// Number of records the allocation can hold across all devices.
+    pub max_db_length: usize,
 }
 
+/// Dot product engine over a DB that is distributed round-robin over the
+/// devices of its [`DeviceManager`].
+///
+/// The engine runs no background tasks. Its device buffers and thread pool are
+/// released when it is dropped, and serving the NCCL comm ids to the peers is
+/// left to the caller, e.g. with
+/// [`comm_id_router`](crate::helpers::id_wrapper::comm_id_router).
 pub struct ShareDB {
     peer_id:               usize,
     is_remote:             bool,
     query_length:          usize,
+    max_query_length:      usize,
     device_manager:        Arc<DeviceManager>,
     kernels:               Vec<CudaFunction>,
     xor_assign_u8_kernels: Vec<CudaFunction>,
+    distance_kernels:      Vec<CudaFunction>,
     rngs:                  Vec<(ChaChaCudaRng, ChaChaCudaRng)>,
     comms:                 Vec<Arc<NcclComm>>,
     ones:                  Vec<CudaSlice<u8>>,
@@ -128,11 +280,267 @@ pub struct ShareDB {
     pub results:           Vec<CudaSlice<u8>>,
     pub results_peer:      Vec<CudaSlice<u8>>,
     code_length:           usize,
+    device_pool:           rayon::ThreadPool,
+    parallel_devices:      bool,
+    rotation_tables:       Vec<Vec<usize>>,
+}
+
+/// A CUDA event that is destroyed when dropped.
+struct OwnedEvent(CUevent);
+
+// SAFETY: the handle is only passed to the driver API, which may be called from
+// any thread.
+unsafe impl Send for OwnedEvent {}
+
+impl Drop for OwnedEvent {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = event::destroy(self.0);
+        }
+    }
+}
+
+/// Number of records on each device when `total` records are distributed
+/// round-robin over `n_shards` devices.
+pub(crate) fn shard_lens(total: usize, n_shards: usize) -> Vec<usize> {
+    (0..n_shards)
+        .map(|i| total / n_shards + usize::from(i < total % n_shards))
+        .collect()
+}
+
+/// Runs `f` for every device on `pool`, or serially on the calling thread if
+/// there is no pool. `f` gets the device index and the item of that device and
+/// has to bind the device itself.
+fn run_per_device<T, R>(
+    pool: Option<&rayon::ThreadPool>,
+    items: Vec<T>,
+    f: impl Fn(usize, T) -> R + Sync,
+) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+{
+    match pool {
+        Some(pool) => pool.install(|| {
+            items
+                .into_par_iter()
+                .enumerate()
+                .map(|(idx, item)| f(idx, item))
+                .collect()
+        }),
+        None => items
+            .into_iter()
+            .enumerate()
+            .map(|(idx, item)| f(idx, item))
+            .collect(),
+    }
+}
+
+/// Derives independent seeds for `n_devices` devices from one seed pair with
+/// HKDF-SHA256, for [`ShareDB::init_with_device_seeds`]. This is opt-in: the
+/// resulting keystreams differ from those of [`ShareDB::init`], so all
+/// parties have to switch at the same time.
+pub fn derive_device_seeds(
+    chacha_seeds: ([u32; 8], [u32; 8]),
+    n_devices: usize,
+) -> Vec<([u32; 8], [u32; 8])> {
+    (0..n_devices)
+        .map(|idx| {
+            (
+                derive_device_seed(chacha_seeds.0, idx),
+                derive_device_seed(chacha_seeds.1, idx),
+            )
+        })
+        .collect()
+}
+
+/// Derives the seed of device `device_index` from a seed shared by all
+/// devices.
+fn derive_device_seed(seed: [u32; 8], device_index: usize) -> [u32; 8] {
+    let prk = Salt::new(HKDF_SHA256, DEVICE_SEED_SALT).extract(bytemuck::cast_slice(&seed));
+    let info = (device_index as u64).to_be_bytes();
+    let okm = prk.expand(&[info.as_slice()], HKDF_SHA256).unwrap();
+    let mut result = [0u32; 8];
+    okm.fill(bytemuck::cast_slice_mut(&mut result)).unwrap();
+    result
+}
+
+/// Nonce of the RNGs of device `device_index` in an engine for `eye`, so that
+/// the engines of both eyes never share a keystream, even with the same seeds.
+/// The nonce of the left eye is just the device index.
+fn rng_nonce(eye: Eye, device_index: usize) -> u64 {
+    ((eye as u64) << 32) | device_index as u64
+}
+
+/// Builder for [`ShareDB`]. The device manager and the DB and query lengths
+/// are required. Everything else defaults to a local engine of party 0 for
+/// iris codes, with zero seeds and no peers.
+#[derive(Default)]
+pub struct ShareDbBuilder {
+    peer_id:        usize,
+    device_manager: Option<Arc<DeviceManager>>,
+    max_db_length:  Option<usize>,
+    query_length:   Option<usize>,
+    code_length:    Option<usize>,
+    seeds:          Seeds,
+    comms:          Vec<Arc<NcclComm>>,
+    rotations:      Vec<isize>,
+    eye:            Eye,
+}
+
+#[derive(Default)]
+enum Seeds {
+    #[default]
+    Zero,
+    Shared(([u32; 8], [u32; 8])),
+    PerDevice(Vec<([u32; 8], [u32; 8])>),
+}
+
+impl ShareDbBuilder {
+    pub fn peer_id(mut self, peer_id: usize) -> Self {
+        self.peer_id = peer_id;
+        self
+    }
+
+    pub fn device_manager(mut self, device_manager: Arc<DeviceManager>) -> Self {
+        self.device_manager = Some(device_manager);
+        self
+    }
+
+    pub fn max_db_length(mut self, max_db_length: usize) -> Self {
+        self.max_db_length = Some(max_db_length);
+        self
+    }
+
+    pub fn query_length(mut self, query_length: usize) -> Self {
+        self.query_length = Some(query_length);
+        self
+    }
+
+    /// Defaults to [`IRIS_CODE_LENGTH`]; use
+    /// [`MASK_CODE_LENGTH`](crate::dot::MASK_CODE_LENGTH) for mask DBs.
+    pub fn code_length(mut self, code_length: usize) -> Self {
+        self.code_length = Some(code_length);
+        self
+    }
+
+    /// Seeds used by every device, as in [`ShareDB::init`].
+    pub fn seeds(mut self, chacha_seeds: ([u32; 8], [u32; 8])) -> Self {
+        self.seeds = Seeds::Shared(chacha_seeds);
+        self
+    }
+
+    /// One pair of seeds per device, as in [`ShareDB::init_with_device_seeds`].
+    pub fn device_seeds(mut self, chacha_seeds: Vec<([u32; 8], [u32; 8])>) -> Self {
+        self.seeds = Seeds::PerDevice(chacha_seeds);
+        self
+    }
+
+    /// Makes the engine exchange results with the other parties over `comms`,
+    /// one communicator per device.
+    pub fn remote(mut self, comms: Vec<Arc<NcclComm>>) -> Self {
+        self.comms = comms;
+        self
+    }
+
+    /// Precomputes the permutations for rotating queries by each of the
+    /// `rotations`, for [`ShareDB::dot_rotations`]. Positive rotations shift
+    /// to the right, like `GaloisRingIrisCodeShare::all_rotations`.
+    pub fn rotations(mut self, rotations: Vec<isize>) -> Self {
+        self.rotations = rotations;
+        self
+    }
+
+    /// Eye the engine holds the DB of. Defaults to [`Eye::Left`]. Only affects
+    /// the RNG nonces, see [`rng_nonce`].
+    pub fn eye(mut self, eye: Eye) -> Self {
+        self.eye = eye;
+        self
+    }
+
+    pub fn build(self) -> Result<ShareDB, ShareDbError> {
+        let device_manager = self
+            .device_manager
+            .ok_or(ShareDbError::MissingParameter("device_manager"))?;
+        let max_db_length = self
+            .max_db_length
+            .ok_or(ShareDbError::MissingParameter("max_db_length"))?;
+        let query_length = self
+            .query_length
+            .ok_or(ShareDbError::MissingParameter("query_length"))?;
+        let code_length = self.code_length.unwrap_or(IRIS_CODE_LENGTH);
+
+        let mut engine = match self.seeds {
+            Seeds::PerDevice(device_seeds) => ShareDB::init_with_device_seeds(
+                self.peer_id,
+                device_manager,
+                max_db_length,
+                query_length,
+                code_length,
+                device_seeds,
+                self.comms,
+            ),
+            seeds => ShareDB::init(
+                self.peer_id,
+                device_manager,
+                max_db_length,
+                query_length,
+                code_length,
+                match seeds {
+                    Seeds::Shared(chacha_seeds) => chacha_seeds,
+                    _ => ([0u32; 8], [0u32; 8]),
+                },
+                self.comms,
+            ),
+        }?;
+        engine.rotation_tables = rotation_tables(code_length, &self.rotations)?;
+        if self.eye != Eye::Left {
+            for (idx, (chacha1, chacha2)) in engine.rngs.iter_mut().enumerate() {
+                chacha1.set_nonce(rng_nonce(self.eye, idx))?;
+                chacha2.set_nonce(rng_nonce(self.eye, idx))?;
+            }
+        }
+        Ok(engine)
+    }
+}
+
+/// For every rotation, the index of the entry of the unrotated code that ends
+/// up at each position of the rotated one.
+fn rotation_tables(
+    code_length: usize,
+    rotations: &[isize],
+) -> Result<Vec<Vec<usize>>, ShareDbError> {
+    if !rotations.is_empty() && code_length % ROTATION_ROW_LENGTH != 0 {
+        return Err(ShareDbError::Dimension(format!(
+            "code length {} is not a multiple of the row length {}",
+            code_length, ROTATION_ROW_LENGTH
+        )));
+    }
+    Ok(rotations
+        .iter()
+        .map(|&rotation| {
+            let shift = (rotation * 4).rem_euclid(ROTATION_ROW_LENGTH as isize) as usize;
+            (0..code_length)
+                .map(|i| {
+                    let row_start = i - i % ROTATION_ROW_LENGTH;
+                    row_start
+                        + (i % ROTATION_ROW_LENGTH + ROTATION_ROW_LENGTH - shift)
+                            % ROTATION_ROW_LENGTH
+                })
+                .collect()
+        })
+        .collect())
 }
 
 impl ShareDB {
+    pub fn builder() -> ShareDbBuilder {
+        ShareDbBuilder::default()
+    }
+
+    /// Same as [`Self::init_with_device_seeds`], with every device using
+    /// `chacha_seeds`. The keystreams of the devices only differ by their
+    /// nonce; see [`derive_device_seeds`] for independent seeds per device.
     #[allow(clippy::too_many_arguments)]
-    #[allow(clippy::arc_with_non_send_sync)]
     pub fn init(
         peer_id: usize,
         device_manager: Arc<DeviceManager>,
@@ -141,37 +549,65 @@ impl ShareDB {
         code_length: usize,
         chacha_seeds: ([u32; 8], [u32; 8]),
         comms: Vec<Arc<NcclComm>>,
-    ) -> Self {
-        let n_devices = device_manager.device_count();
-        let ptx = compile_ptx(PTX_SRC).unwrap();
-
-        let mut kernels = Vec::new();
-
-        for i in 0..n_devices {
-            let dev = device_manager.device(i);
-            dev.load_ptx(ptx.clone(), REDUCE_FUNCTION_NAME, &[REDUCE_FUNCTION_NAME])
-                .unwrap();
-            let function = dev
-                .get_func(REDUCE_FUNCTION_NAME, REDUCE_FUNCTION_NAME)
-                .unwrap();
+    ) -> Result<Self, ShareDbError> {
+        let device_seeds = vec![chacha_seeds; device_manager.device_count()];
+        Self::init_with_device_seeds(
+            peer_id,
+            device_manager,
+            max_db_length,
+            query_length,
+            code_length,
+            device_seeds,
+            comms,
+        )
+    }
 
-            kernels.push(function);
+    /// Initializes the engine with one pair of ChaCha seeds per device.
+    #[allow(clippy::too_many_arguments)]
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub fn init_with_device_seeds(
+        peer_id: usize,
+        device_manager: Arc<DeviceManager>,
+        max_db_length: usize,
+        query_length: usize,
+        code_length: usize,
+        chacha_seeds: Vec<([u32; 8], [u32; 8])>,
+        comms: Vec<Arc<NcclComm>>,
+    ) -> Result<Self, ShareDbError> {
+        let n_devices = device_manager.device_count();
+        if n_devices == 0 {
+            return Err(ShareDbError::Dimension(
+                "the device manager has no devices".to_string(),
+            ));
+        }
+        if chacha_seeds.len() != n_devices {
+            return Err(ShareDbError::Dimension(format!(
+                "expected {} seed pairs, one per device, got {}",
+                n_devices,
+                chacha_seeds.len()
+            )));
         }
+        let ptx = compile_ptx_cached(PTX_SRC)?;
 
-        let xor_assign_u8_kernels = (0..n_devices)
-            .map(|i| {
-                let dev = device_manager.device(i);
-                dev.load_ptx(ptx.clone(), XOR_ASSIGN_U8_NAME, &[XOR_ASSIGN_U8_NAME])
-                    .unwrap();
-                dev.get_func(XOR_ASSIGN_U8_NAME, XOR_ASSIGN_U8_NAME)
-                    .unwrap()
-            })
-            .collect_vec();
+        let load_kernels = |name: &'static str| {
+            (0..n_devices)
+                .map(|i| {
+                    let dev = device_manager.device(i);
+                    dev.load_ptx(ptx.clone(), name, &[name])?;
+                    dev.get_func(name, name)
+                        .ok_or(DriverError(CUresult::CUDA_ERROR_NOT_FOUND))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        let kernels = load_kernels(REDUCE_FUNCTION_NAME)?;
+        let xor_assign_u8_kernels = load_kernels(XOR_ASSIGN_U8_NAME)?;
+        let distance_kernels = load_kernels(COMPUTE_DISTANCE_NAME)?;
 
         let ones = vec![1u8; code_length];
         let ones = (0..n_devices)
-            .map(|idx| device_manager.device(idx).htod_sync_copy(&ones).unwrap())
-            .collect::<Vec<_>>();
+            .map(|idx| device_manager.device(idx).htod_sync_copy(&ones))
+            .collect::<Result<Vec<_>, _>>()?;
 
         // TODO: depending on the batch size, intermediate_results can get quite big, we
         // can perform the gemm in chunks to limit this
@@ -182,18 +618,16 @@ impl ShareDB {
 
         for idx in 0..n_devices {
             unsafe {
-                intermediate_results.push(device_manager.device(idx).alloc(results_len).unwrap());
+                intermediate_results.push(device_manager.device(idx).alloc(results_len)?);
                 results.push(
                     device_manager
                         .device(idx)
-                        .alloc(results_len * std::mem::size_of::<u16>())
-                        .unwrap(),
+                        .alloc(results_len * std::mem::size_of::<u16>())?,
                 );
                 results_peer.push(
                     device_manager
                         .device(idx)
-                        .alloc(results_len * std::mem::size_of::<u16>())
-                        .unwrap(),
+                        .alloc(results_len * std::mem::size_of::<u16>())?,
                 );
             }
         }
@@ -202,23 +636,29 @@ impl ShareDB {
         let rng_buf_size: usize =
             (max_db_length * query_length * mem::size_of::<u16>()).div_ceil(64) * 64;
         let mut rngs = vec![];
-        for idx in 0..n_devices {
-            let (seed0, seed1) = chacha_seeds;
+        for (idx, &(seed0, seed1)) in chacha_seeds.iter().enumerate() {
             let mut chacha1 =
                 ChaChaCudaRng::init(rng_buf_size, device_manager.device(idx).clone(), seed0);
-            chacha1.get_mut_chacha().set_nonce(idx as u64);
+            chacha1.set_nonce(rng_nonce(Eye::Left, idx))?;
             let mut chacha2 =
                 ChaChaCudaRng::init(rng_buf_size, device_manager.device(idx).clone(), seed1);
-            chacha2.get_mut_chacha().set_nonce(idx as u64);
+            chacha2.set_nonce(rng_nonce(Eye::Left, idx))?;
             rngs.push((chacha1, chacha2));
         }
 
-        Self {
+        let device_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(n_devices)
+            .thread_name(|idx| format!("share-db-device-{}", idx))
+            .build()?;
+
+        Ok(Self {
             peer_id,
             query_length,
+            max_query_length: query_length,
             device_manager,
             kernels,
             xor_assign_u8_kernels,
+            distance_kernels,
             rngs,
             is_remote: !comms.is_empty(),
             comms,
@@ -227,11 +667,70 @@ impl ShareDB {
             results,
             results_peer,
             code_length,
+            device_pool,
+            parallel_devices: true,
+            rotation_tables: vec![],
+        })
+    }
+
+    /// Enables or disables launching the per-device work of `query_sums`,
+    /// `dot` and the `dot_reduce` variants concurrently from one thread per
+    /// device. Enabled by default.
+    pub fn set_parallel_devices(&mut self, parallel: bool) {
+        self.parallel_devices = parallel;
+    }
+
+    fn device_pool(&self) -> Option<&rayon::ThreadPool> {
+        self.parallel_devices.then_some(&self.device_pool)
+    }
+
+    fn check_device_count(&self, what: &str, len: usize) -> Result<(), ShareDbError> {
+        let n_devices = self.device_manager.device_count();
+        if len < n_devices {
+            return Err(ShareDbError::Dimension(format!(
+                "expected {} for {} devices, got {}",
+                what, n_devices, len
+            )));
+        }
+        Ok(())
+    }
+
+    /// Sets the number of queries in the following batches, so that a last
+    /// batch smaller than the `query_length` the engine was created with does
+    /// not have to be padded. The queries are the first `query_length` rows
+    /// of the query buffers, and only their columns of the results are
+    /// computed, reshared and fetched.
+    pub fn set_query_length(&mut self, query_length: usize) -> Result<(), ShareDbError> {
+        if query_length > self.max_query_length {
+            return Err(ShareDbError::Dimension(format!(
+                "query length {} exceeds the maximum query length {}",
+                query_length, self.max_query_length
+            )));
         }
+        self.query_length = query_length;
+        Ok(())
     }
 
+    pub fn query_length(&self) -> usize {
+        self.query_length
+    }
+
+    pub fn code_length(&self) -> usize {
+        self.code_length
+    }
+
+    /// Returns the number of ChaCha blocks (64 bytes each) the masking RNGs
+    /// of `device_id` have produced so far. Both RNGs of a device advance in
+    /// lockstep, so this is the counter of either of them.
+    pub fn rng_blocks_consumed(&self, device_id: usize) -> u64 {
+        self.rngs[device_id].0.get_chacha().get_counter()
+    }
+
+    /// Allocates room for `max_db_length` records. If that is not a multiple
+    /// of the device count, the first devices hold one record more than the
+    /// others, just like [`Self::load_full_db`] distributes them.
     pub fn alloc_db(&self, max_db_length: usize) -> SlicedProcessedDatabase {
-        let max_size = max_db_length / self.device_manager.device_count();
+        let max_size = max_db_length.div_ceil(self.device_manager.device_count());
         let (db0_sums, (db1_sums, (db0, db1))) = self
             .device_manager
             .devices()
@@ -263,7 +762,7 @@ impl ShareDB {
         }
 
         SlicedProcessedDatabase {
-            code_gr:      CudaVec2DSlicerRawPointer {
+            code_gr: CudaVec2DSlicerRawPointer {
                 limb_0: db0,
                 limb_1: db1,
             },
@@ -271,6 +770,7 @@ impl ShareDB {
                 limb_0: db0_sums,
                 limb_1: db1_sums,
             },
+            max_db_length,
         }
     }
 
@@ -283,42 +783,39 @@ impl ShareDB {
     ) {
         assert!(record.len() == code_length);
 
-        let a0_host = record
-            .iter()
-            .map(|&x| ((x as i8) as i32 - 128) as i8)
-            .collect::<Vec<_>>();
-
-        let a1_host = record
-            .iter()
-            .map(|&x: &u16| ((x >> 8) as i32 - 128) as i8)
-            .collect::<Vec<_>>();
-
         let device_index = index % n_shards;
         let device_db_index = index / n_shards;
 
-        unsafe {
-            std::ptr::copy(
-                a0_host.as_ptr() as *const _,
-                (db.limb_0[device_index] + (device_db_index * code_length) as u64) as *mut _,
-                code_length,
-            );
-
-            std::ptr::copy(
-                a1_host.as_ptr() as *const _,
-                (db.limb_1[device_index] + (device_db_index * code_length) as u64) as *mut _,
-                code_length,
-            );
-        };
+        for (i, ptr) in limb_ptrs(db, device_index).into_iter().enumerate() {
+            let host = record.iter().map(|&x| limb(x, i)).collect::<Vec<_>>();
+            unsafe {
+                std::ptr::copy(
+                    host.as_ptr() as *const _,
+                    (ptr + (device_db_index * code_length) as u64) as *mut _,
+                    code_length,
+                );
+            }
+        }
     }
 
     pub fn preprocess_db(&self, db: &mut SlicedProcessedDatabase, db_lens: &[usize]) {
+        let rows = db_lens.iter().map(|&len| 0..len).collect::<Vec<_>>();
+        self.preprocess_db_rows(db, &rows);
+    }
+
+    /// Recomputes the code sums of the given rows of every device.
+    fn preprocess_db_rows(&self, db: &mut SlicedProcessedDatabase, rows: &[Range<usize>]) {
         let code_len = self.code_length;
         for device_index in 0..self.device_manager.device_count() {
+            if rows[device_index].is_empty() {
+                continue;
+            }
             for (limbs, sum_slices) in [
                 (&db.code_gr.limb_0, &mut db.code_sums_gr.limb_0),
                 (&db.code_gr.limb_1, &mut db.code_sums_gr.limb_1),
             ] {
-                let sums = (0..db_lens[device_index])
+                let sums = rows[device_index]
+                    .clone()
                     .into_par_iter()
                     .map(|idx| {
                         let slice: &[i8] = unsafe {
@@ -335,38 +832,319 @@ impl ShareDB {
                     .device(device_index)
                     .bind_to_thread()
                     .unwrap();
+                let offset = (rows[device_index].start * mem::size_of::<u32>()) as u64;
                 unsafe {
-                    result::memcpy_htod_sync(sum_slices[device_index].cu_device_ptr, &sums)
-                        .unwrap();
+                    result::memcpy_htod_sync(
+                        sum_slices[device_index].cu_device_ptr + offset,
+                        &sums,
+                    )
+                    .unwrap();
                 }
             }
         }
     }
 
     #[allow(clippy::type_complexity)]
-    pub fn load_full_db(&self, db: &mut SlicedProcessedDatabase, db_entries: &[u16]) -> Vec<usize> {
-        assert!(db_entries.len() % self.code_length == 0);
+    pub fn load_full_db(
+        &self,
+        db: &mut SlicedProcessedDatabase,
+        db_entries: &[u16],
+    ) -> Result<Vec<usize>, ShareDbError> {
+        self.load_full_db_with_progress(db, db_entries, |_, _| {})
+    }
+
+    /// Same as [`Self::load_full_db`], but calls `progress(rows_done,
+    /// total_rows)` after the records of each device have been loaded.
+    pub fn load_full_db_with_progress(
+        &self,
+        db: &mut SlicedProcessedDatabase,
+        db_entries: &[u16],
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<Vec<usize>, ShareDbError> {
+        if db_entries.len() % self.code_length != 0 {
+            return Err(ShareDbError::Dimension(format!(
+                "DB of {} entries is not a multiple of the code length {}",
+                db_entries.len(),
+                self.code_length
+            )));
+        }
+
+        let code_length = self.code_length;
+        let n_shards = self.device_manager.device_count();
+        let total = db_entries.len() / self.code_length;
+        if total > db.max_db_length {
+            return Err(ShareDbError::Dimension(format!(
+                "DB of {} records does not fit into an allocation for {} records",
+                total, db.max_db_length
+            )));
+        }
+        let db_lens = shard_lens(total, n_shards);
+
+        let mut done = 0;
+        for device_index in 0..n_shards {
+            tracing::info_span!("load_full_db", device_index).in_scope(|| {
+                db_entries
+                    .par_chunks(code_length)
+                    .enumerate()
+                    .skip(device_index)
+                    .step_by(n_shards)
+                    .for_each(|(idx, chunk)| {
+                        Self::load_single_record(idx, &db.code_gr, chunk, n_shards, code_length);
+                    });
+            });
+            done += db_lens[device_index];
+            progress(done, total);
+        }
+
+        tracing::info_span!("preprocess_db").in_scope(|| self.preprocess_db(db, &db_lens));
+
+        Ok(db_lens)
+    }
+
+    /// Writes `new_entries` into the existing allocation after the
+    /// `current_len` records that are already loaded, and only computes the
+    /// sums of the new rows. Returns the new number of records per device.
+    pub fn append_db(
+        &self,
+        db: &mut SlicedProcessedDatabase,
+        new_entries: &[u16],
+        current_len: usize,
+    ) -> Result<Vec<usize>, ShareDbError> {
+        if new_entries.len() % self.code_length != 0 {
+            return Err(ShareDbError::Dimension(format!(
+                "{} new entries are not a multiple of the code length {}",
+                new_entries.len(),
+                self.code_length
+            )));
+        }
 
         let code_length = self.code_length;
         let n_shards = self.device_manager.device_count();
-        db_entries
-            .par_chunks(self.code_length)
+        let new_len = current_len + new_entries.len() / code_length;
+        let old_lens = shard_lens(current_len, n_shards);
+        let new_lens = shard_lens(new_len, n_shards);
+        if new_len > db.max_db_length {
+            return Err(ShareDbError::Dimension(format!(
+                "DB of {} records does not fit into an allocation for {} records",
+                new_len, db.max_db_length
+            )));
+        }
+
+        new_entries
+            .par_chunks(code_length)
             .enumerate()
             .for_each(|(idx, chunk)| {
-                Self::load_single_record(idx, &db.code_gr, chunk, n_shards, code_length);
+                Self::load_single_record(
+                    current_len + idx,
+                    &db.code_gr,
+                    chunk,
+                    n_shards,
+                    code_length,
+                );
             });
 
-        // Calculate the number of entries per shard
-        let mut db_lens = vec![db_entries.len() / self.code_length / n_shards; n_shards];
-        for i in 0..db_lens.len() {
-            if i < (db_entries.len() / self.code_length) % n_shards {
-                db_lens[i] += 1;
+        let rows = izip!(old_lens, &new_lens)
+            .map(|(old, &new)| old..new)
+            .collect::<Vec<_>>();
+        tracing::info_span!("preprocess_db").in_scope(|| self.preprocess_db_rows(db, &rows));
+
+        Ok(new_lens)
+    }
+
+    /// Appends the records of `rows` in order after the `current_len` records
+    /// that are already loaded, in batches of `batch_size` records via
+    /// [`Self::append_db`], e.g. the shares streamed from the store by
+    /// `Store::stream_shares_in_range`. Returns the new number of records per
+    /// device.
+    pub async fn load_db_from_stream<R, E>(
+        &self,
+        db: &mut SlicedProcessedDatabase,
+        current_len: usize,
+        batch_size: usize,
+        rows: impl Stream<Item = Result<R, E>>,
+    ) -> eyre::Result<Vec<usize>>
+    where
+        R: AsRef<[u16]>,
+        E: Into<eyre::Report>,
+    {
+        if batch_size == 0 {
+            return Err(ShareDbError::Dimension("batch size must not be zero".to_string()).into());
+        }
+
+        let mut rows = pin!(rows);
+        let mut db_lens = shard_lens(current_len, self.device_manager.device_count());
+        let mut len = current_len;
+        let mut batch = Vec::with_capacity(batch_size * self.code_length);
+        loop {
+            let row = rows.try_next().await.map_err(Into::into)?;
+            if let Some(row) = &row {
+                let row = row.as_ref();
+                if row.len() != self.code_length {
+                    return Err(ShareDbError::Dimension(format!(
+                        "record {} has {} entries instead of the code length {}",
+                        len + batch.len() / self.code_length,
+                        row.len(),
+                        self.code_length
+                    ))
+                    .into());
+                }
+                batch.extend_from_slice(row);
+            }
+            if batch.len() == batch_size * self.code_length || (row.is_none() && !batch.is_empty())
+            {
+                db_lens = self.append_db(db, &batch, len)?;
+                len += batch.len() / self.code_length;
+                batch.clear();
+            }
+            if row.is_none() {
+                return Ok(db_lens);
+            }
+        }
+    }
+
+    /// Overwrites the record at `index` with zeros and updates its sums. The
+    /// results of a zeroed record are zero for every query, and a zero mask
+    /// never passes the threshold, so calling this on both the code and the
+    /// mask DB removes the record from all future matches.
+    pub fn mark_deleted(
+        &self,
+        db: &mut SlicedProcessedDatabase,
+        index: usize,
+    ) -> Result<(), ShareDbError> {
+        if index >= db.max_db_length {
+            return Err(ShareDbError::Dimension(format!(
+                "record {} is out of bounds for a DB of {} records",
+                index, db.max_db_length
+            )));
+        }
+
+        let n_shards = self.device_manager.device_count();
+        Self::load_single_record(
+            index,
+            &db.code_gr,
+            &vec![0u16; self.code_length],
+            n_shards,
+            self.code_length,
+        );
+
+        let rows = (0..n_shards)
+            .map(|device_index| {
+                let row = index / n_shards;
+                if device_index == index % n_shards {
+                    row..row + 1
+                } else {
+                    row..row
+                }
+            })
+            .collect::<Vec<_>>();
+        self.preprocess_db_rows(db, &rows);
+
+        Ok(())
+    }
+
+    /// Hashes the first `db_sizes[device_id]` records of `device_id`, both
+    /// the code limbs and their sums on the device, into a 64-bit digest. The
+    /// sums are copied back in chunks of bounded size. Two loads of the same
+    /// records give the same digest, so parties can compare the digests of
+    /// repeated loads, or check the lengths they are computed over, without
+    /// revealing their shares.
+    pub fn db_digest(
+        &self,
+        db: &SlicedProcessedDatabase,
+        db_sizes: &[usize],
+        device_id: usize,
+    ) -> Result<u64, ShareDbError> {
+        self.check_device_count("DB sizes", db_sizes.len())?;
+        if device_id >= db_sizes.len() {
+            return Err(ShareDbError::Dimension(format!(
+                "device {} out of range for {} devices",
+                device_id,
+                db_sizes.len()
+            )));
+        }
+        let len = db_sizes[device_id];
+        let capacity = db
+            .max_db_length
+            .div_ceil(self.device_manager.device_count());
+        if len > capacity {
+            return Err(ShareDbError::Dimension(format!(
+                "{} records exceed the allocation of device {}",
+                len, device_id
+            )));
+        }
+
+        self.device_manager.device(device_id).bind_to_thread()?;
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        ctx.update(&(len as u64).to_le_bytes());
+        for limb in [db.code_gr.limb_0[device_id], db.code_gr.limb_1[device_id]] {
+            // SAFETY: the codes are in managed memory, which the host can read
+            // directly, and the first `len` records have been loaded.
+            let codes: &[u8] =
+                unsafe { std::slice::from_raw_parts(limb as *const _, len * self.code_length) };
+            ctx.update(codes);
+        }
+
+        let mut sums = vec![0u32; DIGEST_CHUNK_RECORDS.min(len)];
+        for limb in [
+            &db.code_sums_gr.limb_0[device_id],
+            &db.code_sums_gr.limb_1[device_id],
+        ] {
+            for start in (0..len).step_by(DIGEST_CHUNK_RECORDS) {
+                let chunk = &mut sums[..DIGEST_CHUNK_RECORDS.min(len - start)];
+                let offset = (start * mem::size_of::<u32>()) as u64;
+                unsafe { result::memcpy_dtoh_sync(chunk, limb.cu_device_ptr + offset)? };
+                ctx.update(bytemuck::cast_slice(chunk));
             }
         }
 
-        self.preprocess_db(db, &db_lens);
+        let digest = ctx.finish();
+        Ok(u64::from_le_bytes(
+            digest.as_ref()[..8]
+                .try_into()
+                .expect("SHA-256 is 32 bytes"),
+        ))
+    }
+
+    /// Loads the DB from a memory-mapped file of `db_length` native-endian
+    /// `u16` codes of `code_length` elements each, without copying the file
+    /// into an intermediate buffer. The mapped bytes are used as is, so the
+    /// file has to be written on a host of the same endianness.
+    pub fn load_full_db_mmap(
+        &self,
+        db: &mut SlicedProcessedDatabase,
+        path: &Path,
+        db_length: usize,
+        max_db_length: usize,
+    ) -> eyre::Result<Vec<usize>> {
+        if db_length > max_db_length {
+            eyre::bail!(
+                "DB length {} exceeds the maximum DB length {}",
+                db_length,
+                max_db_length
+            );
+        }
+
+        let file = File::open(path)?;
+        let expected_len = db_length * self.code_length * mem::size_of::<u16>();
+        let file_len = file.metadata()?.len() as usize;
+        if file_len != expected_len {
+            eyre::bail!(
+                "Expected a DB file with length {} but found length {}",
+                expected_len,
+                file_len
+            );
+        }
+        if db_length == 0 {
+            return Ok(vec![0; self.device_manager.device_count()]);
+        }
+
+        // SAFETY: the file is only read and is expected not to be modified while
+        // it is mapped.
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        let db_entries: &[u16] = bytemuck::try_cast_slice(&mmap)
+            .map_err(|e| eyre::eyre!("Invalid DB file layout: {}", e))?;
 
-        db_lens
+        Ok(self.load_full_db(db, db_entries)?)
     }
 
     pub fn query_sums(
@@ -375,75 +1153,69 @@ impl ShareDB {
         streams: &[CudaStream],
         blass: &[CudaBlas],
     ) -> CudaVec2DSlicerU32 {
-        let mut query1_sums = vec![];
-        let mut query0_sums = vec![];
-
-        for idx in 0..self.device_manager.device_count() {
-            let device = self.device_manager.device(idx);
-            device.bind_to_thread().unwrap();
+        let query_length = self.query_length;
+        let code_length = self.code_length;
+        let n_devices = self.device_manager.device_count();
+        let (query0_sums, query1_sums): (Vec<_>, Vec<_>) =
+            run_per_device(self.device_pool(), vec![(); n_devices], |idx, _| {
+                let device = self.device_manager.device(idx);
+                device.bind_to_thread().unwrap();
 
-            let query0 = &query_ptrs.limb_0[idx];
-            let query1 = &query_ptrs.limb_1[idx];
+                let query0 = &query_ptrs.limb_0[idx];
+                let query1 = &query_ptrs.limb_1[idx];
 
-            let query0_sum = unsafe {
-                malloc_async(
+                let query0_sum = unsafe {
+                    malloc_async(streams[idx].stream, query_length * mem::size_of::<u32>()).unwrap()
+                };
+                let slice0_sum = StreamAwareCudaSlice::<u32>::upgrade_ptr_stream(
+                    query0_sum,
                     streams[idx].stream,
-                    self.query_length * mem::size_of::<u32>(),
-                )
-                .unwrap()
-            };
-            let slice0_sum = StreamAwareCudaSlice::<u32>::upgrade_ptr_stream(
-                query0_sum,
-                streams[idx].stream,
-                self.query_length,
-            );
+                    query_length,
+                );
 
-            let query1_sum = unsafe {
-                malloc_async(
-                    streams[idx].stream,
-                    self.query_length * mem::size_of::<u32>(),
-                )
-                .unwrap()
-            };
+                let query1_sum = unsafe {
+                    malloc_async(streams[idx].stream, query_length * mem::size_of::<u32>()).unwrap()
+                };
 
-            let slice1_sum = StreamAwareCudaSlice::<u32>::upgrade_ptr_stream(
-                query1_sum,
-                streams[idx].stream,
-                self.query_length,
-            );
+                let slice1_sum = StreamAwareCudaSlice::<u32>::upgrade_ptr_stream(
+                    query1_sum,
+                    streams[idx].stream,
+                    query_length,
+                );
 
-            gemm(
-                &blass[idx],
-                *query0.device_ptr(),
-                *self.ones[idx].device_ptr(),
-                query0_sum,
-                0,
-                0,
-                0,
-                self.query_length,
-                1,
-                self.code_length,
-                1,
-                0,
-            );
-            gemm(
-                &blass[idx],
-                *query1.device_ptr(),
-                *self.ones[idx].device_ptr(),
-                query1_sum,
-                0,
-                0,
-                0,
-                self.query_length,
-                1,
-                self.code_length,
-                1,
-                0,
-            );
+                gemm(
+                    &blass[idx],
+                    *query0.device_ptr(),
+                    *self.ones[idx].device_ptr(),
+                    query0_sum,
+                    0,
+                    0,
+                    0,
+                    query_length,
+                    1,
+                    code_length,
+                    1,
+                    0,
+                );
+                gemm(
+                    &blass[idx],
+                    *query1.device_ptr(),
+                    *self.ones[idx].device_ptr(),
+                    query1_sum,
+                    0,
+                    0,
+                    0,
+                    query_length,
+                    1,
+                    code_length,
+                    1,
+                    0,
+                );
 
-            query0_sums.push(slice0_sum);
-            query1_sums.push(slice1_sum);
-        }
+                (slice0_sum, slice1_sum)
+            })
+            .into_iter()
+            .unzip();
         CudaVec2DSlicer {
             limb_0: query0_sums,
             limb_1: query1_sums,
@@ -458,41 +1230,255 @@ impl ShareDB {
         offset: usize,
         streams: &[CudaStream],
         blass: &[CudaBlas],
-    ) {
-        for idx in 0..self.device_manager.device_count() {
-            self.device_manager.device(idx).bind_to_thread().unwrap();
-            let query0 = &queries.limb_0[idx];
-            let query1 = &queries.limb_1[idx];
-
-            // Prepare randomness to mask results
-            if self.is_remote {
-                let len: usize = (chunk_sizes[idx] * self.query_length).div_ceil(64) * 64;
-                self.rngs[idx].0.fill_rng_no_host_copy(len, &streams[idx]);
-                self.rngs[idx].1.fill_rng_no_host_copy(len, &streams[idx]);
-            }
+    ) -> Result<(), ShareDbError> {
+        let query_length = self.query_length;
+        self.dot_queries(
+            queries,
+            db,
+            chunk_sizes,
+            offset,
+            streams,
+            blass,
+            0..query_length,
+            true,
+        )
+    }
+
+    /// Same as [`Self::dot`], but launches the products of at most
+    /// `segment_length` queries at a time and yields to the async runtime in
+    /// between, so that other tasks get to run while a large batch is being
+    /// enqueued. The results are the same as for [`Self::dot`].
+    #[allow(clippy::too_many_arguments)]
+    pub async fn dot_segmented<T>(
+        &mut self,
+        queries: &CudaVec2DSlicer<T>,
+        db: &CudaVec2DSlicerRawPointer,
+        chunk_sizes: &[usize],
+        offset: usize,
+        streams: &[CudaStream],
+        blass: &[CudaBlas],
+        segment_length: usize,
+    ) -> Result<(), ShareDbError> {
+        if segment_length == 0 {
+            return Err(ShareDbError::Dimension(
+                "the segment length must be positive".to_string(),
+            ));
+        }
+        for start in (0..self.query_length).step_by(segment_length) {
+            let end = (start + segment_length).min(self.query_length);
+            self.dot_queries(
+                queries,
+                db,
+                chunk_sizes,
+                offset,
+                streams,
+                blass,
+                start..end,
+                start == 0,
+            )?;
+            tokio::task::yield_now().await;
+        }
+        Ok(())
+    }
+
+    /// Computes the products of every query with every query of the same
+    /// batch, e.g. to deduplicate a batch before matching it against the DB.
+    /// Like for insert queries, the `raw_queries` (as they would be stored in
+    /// the DB) take the place of the DB and are multiplied with the
+    /// preprocessed `queries`. The raw queries are split into contiguous
+    /// chunks across the devices, so device `d` holds the products with raw
+    /// queries `d * chunk..` laid out like the results of
+    /// [`Self::dot_reduce`]. Returns the chunk sizes to fetch them with.
+    #[allow(clippy::too_many_arguments)]
+    pub fn self_dot<T>(
+        &mut self,
+        queries: &CudaVec2DSlicer<T>,
+        raw_queries: &CudaVec2DSlicer<T>,
+        query_sums: &CudaVec2DSlicerU32,
+        raw_query_sums: &CudaVec2DSlicerU32,
+        streams: &[CudaStream],
+        blass: &[CudaBlas],
+    ) -> Result<Vec<usize>, ShareDbError> {
+        let n_devices = self.device_manager.device_count();
+        let chunk = self.query_length.div_ceil(n_devices);
+        let len = self.query_length * chunk;
+        if let Some(buffer) = self
+            .results
+            .iter()
+            .find(|buffer| len * mem::size_of::<u16>() > buffer.len())
+        {
+            return Err(ShareDbError::Dimension(format!(
+                "{} x {} query results do not fit into a result buffer of {} bytes",
+                self.query_length,
+                chunk,
+                buffer.len()
+            )));
+        }
+
+        let starts = (0..n_devices)
+            .map(|idx| (idx * chunk).min(self.query_length))
+            .collect_vec();
+        let chunk_sizes = starts
+            .iter()
+            .map(|&start| chunk.min(self.query_length - start))
+            .collect_vec();
+        let shift = |ptrs: Vec<u64>, size: usize| {
+            ptrs.into_iter()
+                .zip(&starts)
+                .map(|(ptr, &start)| ptr + (start * size) as u64)
+                .collect_vec()
+        };
+        let raw_queries = CudaVec2DSlicerRawPointer::from(raw_queries);
+        let raw_queries = CudaVec2DSlicerRawPointer {
+            limb_0: shift(raw_queries.limb_0, self.code_length),
+            limb_1: shift(raw_queries.limb_1, self.code_length),
+        };
+        let raw_query_sums = CudaVec2DSlicerRawPointer::from(raw_query_sums);
+        let raw_query_sums = CudaVec2DSlicerRawPointer {
+            limb_0: shift(raw_query_sums.limb_0, mem::size_of::<u32>()),
+            limb_1: shift(raw_query_sums.limb_1, mem::size_of::<u32>()),
+        };
+
+        self.dot(queries, &raw_queries, &chunk_sizes, 0, streams, blass)?;
+        self.reduce_queries(
+            query_sums,
+            &raw_query_sums,
+            &chunk_sizes,
+            0,
+            streams,
+            1,
+            &ReduceConfig::default(),
+        )?;
+        Ok(chunk_sizes)
+    }
+
+    /// Rotates each of `queries` by every rotation the engine was built with,
+    /// see [`ShareDbBuilder::rotations`]. Rotation `r` of query `q` ends up at
+    /// query index `q * n_rotations + r`.
+    pub fn rotate_queries(&self, queries: &[u16]) -> Result<Vec<u16>, ShareDbError> {
+        if self.rotation_tables.is_empty() {
+            return Err(ShareDbError::Dimension(
+                "the engine was built without rotations".to_string(),
+            ));
+        }
+        if queries.len() % self.code_length != 0 {
+            return Err(ShareDbError::Dimension(format!(
+                "{} query entries are not a multiple of the code length {}",
+                queries.len(),
+                self.code_length
+            )));
+        }
+        Ok(queries
+            .chunks(self.code_length)
+            .flat_map(|query| {
+                self.rotation_tables
+                    .iter()
+                    .flat_map(move |table| table.iter().map(move |&i| query[i]))
+            })
+            .collect())
+    }
+
+    /// Like [`Self::dot`], but for every rotation of the plain `queries` the
+    /// engine was built with, laid out as in [`Self::rotate_queries`]. Returns
+    /// the sums of the rotated queries for [`Self::dot_reduce`].
+    #[allow(clippy::too_many_arguments)]
+    pub fn dot_rotations(
+        &mut self,
+        queries: &[u16],
+        db: &CudaVec2DSlicerRawPointer,
+        chunk_sizes: &[usize],
+        offset: usize,
+        streams: &[CudaStream],
+        blass: &[CudaBlas],
+    ) -> Result<CudaVec2DSlicerU32, ShareDbError> {
+        let rotated = self.rotate_queries(queries)?;
+        if rotated.len() / self.code_length > self.query_length {
+            return Err(ShareDbError::Dimension(format!(
+                "{} rotated queries exceed the query length {}",
+                rotated.len() / self.code_length,
+                self.query_length
+            )));
+        }
+        let rotated = self
+            .device_manager
+            .htod_transfer_query(
+                &preprocess_query(&rotated),
+                streams,
+                self.query_length,
+                self.code_length,
+            )
+            .map_err(ShareDbError::QueryTransfer)?;
+        let query_sums = self.query_sums(&rotated, streams, blass);
+        self.dot(&rotated, db, chunk_sizes, offset, streams, blass)?;
+        Ok(query_sums)
+    }
+
+    /// Computes the products of the DB with the queries in `query_range`.
+    /// The masking randomness covers the whole batch and is only generated if
+    /// `fill_rngs` is set, so that it is generated once per batch.
+    #[allow(clippy::too_many_arguments)]
+    fn dot_queries<T>(
+        &mut self,
+        queries: &CudaVec2DSlicer<T>,
+        db: &CudaVec2DSlicerRawPointer,
+        chunk_sizes: &[usize],
+        offset: usize,
+        streams: &[CudaStream],
+        blass: &[CudaBlas],
+        query_range: Range<usize>,
+        fill_rngs: bool,
+    ) -> Result<(), ShareDbError> {
+        self.check_device_count("chunk sizes", chunk_sizes.len())?;
+        let queries = CudaVec2DSlicerRawPointer::from(queries);
+        let query_ptrs = (0..queries.limb_0.len())
+            .map(|idx| limb_ptrs(&queries, idx))
+            .collect_vec();
+        let query_length = self.query_length;
+        let code_length = self.code_length;
+        let is_remote = self.is_remote;
+        let device_manager = &self.device_manager;
+        let intermediate_results = &self.intermediate_results;
+        let pool = self.parallel_devices.then_some(&self.device_pool);
+        let rngs = self.rngs.iter_mut().collect_vec();
+
+        let dot_device =
+            |idx: usize, rngs: &mut (ChaChaCudaRng, ChaChaCudaRng)| -> Result<(), ShareDbError> {
+                if chunk_sizes[idx] == 0 {
+                    return Ok(());
+                }
+                device_manager.device(idx).bind_to_thread()?;
+
+                // Prepare randomness to mask results
+                if is_remote && fill_rngs {
+                    let len: usize = (chunk_sizes[idx] * query_length).div_ceil(64) * 64;
+                    rngs.0.fill_rng_no_host_copy(len, &streams[idx]);
+                    rngs.1.fill_rng_no_host_copy(len, &streams[idx]);
+                }
 
-            for (i, d) in [db.limb_0[idx], db.limb_1[idx]].into_iter().enumerate() {
-                for (j, q) in [query0, query1].iter().enumerate() {
-                    if i + j >= LIMBS {
-                        continue;
+                for (i, d) in limb_ptrs(db, idx).into_iter().enumerate() {
+                    for (j, &q) in query_ptrs[idx].iter().enumerate() {
+                        if i + j >= LIMBS {
+                            continue;
+                        }
+                        try_gemm(
+                            &blass[idx],
+                            d,
+                            q,
+                            *intermediate_results[idx].device_ptr(),
+                            (offset * code_length) as u64,
+                            (query_range.start * code_length) as u64,
+                            (query_range.start * chunk_sizes[idx] * mem::size_of::<i32>()) as u64,
+                            chunk_sizes[idx],
+                            query_range.len(),
+                            code_length,
+                            1 << (LIMB_BITS * (i + j)),
+                            if i + j == 0 { 0 } else { 1 },
+                        )?;
                     }
-                    gemm(
-                        &blass[idx],
-                        d,
-                        *q.device_ptr(),
-                        *self.intermediate_results[idx].device_ptr(),
-                        (offset * self.code_length) as u64,
-                        0,
-                        0,
-                        chunk_sizes[idx],
-                        self.query_length,
-                        self.code_length,
-                        1 << (8 * (i + j)),
-                        if i + j == 0 { 0 } else { 1 },
-                    );
                 }
-            }
-        }
+                Ok(())
+            };
+        run_per_device(pool, rngs, dot_device).into_iter().collect()
     }
 
     pub fn dot_reduce_and_multiply(
@@ -503,44 +1489,101 @@ impl ShareDB {
         offset: usize,
         streams: &[CudaStream],
         multiplier: u16,
-    ) {
-        for idx in 0..self.device_manager.device_count() {
-            assert!(
-                self.rngs[idx].0.cuda_slice().is_some() && self.rngs[idx].1.cuda_slice().is_some()
-            );
+    ) -> Result<(), ShareDbError> {
+        self.dot_reduce_and_multiply_with_config(
+            query_sums,
+            db_sums,
+            chunk_sizes,
+            offset,
+            streams,
+            multiplier,
+            &ReduceConfig::default(),
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn dot_reduce_and_multiply_with_config(
+        &mut self,
+        query_sums: &CudaVec2DSlicerU32,
+        db_sums: &CudaVec2DSlicerU32,
+        chunk_sizes: &[usize],
+        offset: usize,
+        streams: &[CudaStream],
+        multiplier: u16,
+        config: &ReduceConfig,
+    ) -> Result<(), ShareDbError> {
+        self.reduce_queries(
+            query_sums,
+            &db_sums.into(),
+            chunk_sizes,
+            offset,
+            streams,
+            multiplier,
+            config,
+        )
+    }
+
+    /// [`Self::dot_reduce_and_multiply_with_config`] on the raw pointers of
+    /// the DB sums.
+    #[allow(clippy::too_many_arguments)]
+    fn reduce_queries(
+        &mut self,
+        query_sums: &CudaVec2DSlicerU32,
+        db_sums: &CudaVec2DSlicerRawPointer,
+        chunk_sizes: &[usize],
+        offset: usize,
+        streams: &[CudaStream],
+        multiplier: u16,
+        config: &ReduceConfig,
+    ) -> Result<(), ShareDbError> {
+        self.check_device_count("chunk sizes", chunk_sizes.len())?;
+        let query_length = self.query_length;
+        let device_manager = &self.device_manager;
+        let kernels = &self.kernels;
+        let intermediate_results = &self.intermediate_results;
+        let rngs = &self.rngs;
+        let pool = self.parallel_devices.then_some(&self.device_pool);
+        let results = self.results.iter_mut().collect_vec();
+
+        let reduce_device = |idx: usize, result: &mut CudaSlice<u8>| -> Result<(), ShareDbError> {
+            if chunk_sizes[idx] == 0 {
+                return Ok(());
+            }
+            assert!(rngs[idx].0.cuda_slice().is_some() && rngs[idx].1.cuda_slice().is_some());
 
-            let num_elements = chunk_sizes[idx] * self.query_length;
-            let threads_per_block = DEFAULT_LAUNCH_CONFIG_THREADS; // ON CHANGE: sync with kernel
-            let cfg = launch_config_from_elements_and_threads(
+            let num_elements = chunk_sizes[idx] * query_length;
+            let mut cfg = launch_config_from_elements_and_threads(
                 num_elements as u32,
-                threads_per_block,
-                &self.device_manager.devices()[idx],
+                config.threads_per_block,
+                &device_manager.devices()[idx],
             );
+            cfg.shared_mem_bytes = config.shared_mem_bytes;
 
             unsafe {
-                self.kernels[idx]
-                    .clone()
-                    .launch_on_stream(
-                        &streams[idx],
-                        cfg,
-                        (
-                            &self.intermediate_results[idx],
-                            &mut self.results[idx],
-                            *db_sums.limb_0[idx].device_ptr(),
-                            *db_sums.limb_1[idx].device_ptr(),
-                            *query_sums.limb_0[idx].device_ptr(),
-                            *query_sums.limb_1[idx].device_ptr(),
-                            chunk_sizes[idx] as u64,
-                            (chunk_sizes[idx] * self.query_length) as u64,
-                            offset as u64,
-                            multiplier,
-                            self.rngs[idx].0.cuda_slice().unwrap(),
-                            self.rngs[idx].1.cuda_slice().unwrap(),
-                        ),
-                    )
-                    .unwrap();
+                kernels[idx].clone().launch_on_stream(
+                    &streams[idx],
+                    cfg,
+                    (
+                        &intermediate_results[idx],
+                        result,
+                        db_sums.limb_0[idx],
+                        db_sums.limb_1[idx],
+                        *query_sums.limb_0[idx].device_ptr(),
+                        *query_sums.limb_1[idx].device_ptr(),
+                        chunk_sizes[idx] as u64,
+                        (chunk_sizes[idx] * query_length) as u64,
+                        offset as u64,
+                        multiplier,
+                        rngs[idx].0.cuda_slice().unwrap(),
+                        rngs[idx].1.cuda_slice().unwrap(),
+                    ),
+                )?;
             }
-        }
+            Ok(())
+        };
+        run_per_device(pool, results, reduce_device)
+            .into_iter()
+            .collect()
     }
 
     pub fn dot_reduce(
@@ -550,8 +1593,71 @@ impl ShareDB {
         chunk_sizes: &[usize],
         offset: usize,
         streams: &[CudaStream],
-    ) {
-        self.dot_reduce_and_multiply(query_sums, db_sums, chunk_sizes, offset, streams, 1);
+    ) -> Result<(), ShareDbError> {
+        self.dot_reduce_and_multiply(query_sums, db_sums, chunk_sizes, offset, streams, 1)
+    }
+
+    pub fn dot_reduce_with_config(
+        &mut self,
+        query_sums: &CudaVec2DSlicerU32,
+        db_sums: &CudaVec2DSlicerU32,
+        chunk_sizes: &[usize],
+        offset: usize,
+        streams: &[CudaStream],
+        config: &ReduceConfig,
+    ) -> Result<(), ShareDbError> {
+        self.dot_reduce_and_multiply_with_config(
+            query_sums,
+            db_sums,
+            chunk_sizes,
+            offset,
+            streams,
+            1,
+            config,
+        )
+    }
+
+    /// Computes the fractional hamming distance `0.5 - code / (2 * mask)` from
+    /// the reconstructed code and mask dot products on each device. `codes`
+    /// holds the signed code dot products as `u16` and `masks` the plain
+    /// (not doubled) mask dot products.
+    pub fn compute_distance(
+        &self,
+        codes: &[CudaView<u16>],
+        masks: &[CudaView<u16>],
+        lens: &[usize],
+        streams: &[CudaStream],
+    ) -> Vec<CudaSlice<f64>> {
+        (0..self.device_manager.device_count())
+            .map(|idx| {
+                assert!(codes[idx].len() >= lens[idx] && masks[idx].len() >= lens[idx]);
+                let mut output = self
+                    .device_manager
+                    .device(idx)
+                    .alloc_zeros(lens[idx])
+                    .unwrap();
+                if lens[idx] == 0 {
+                    return output;
+                }
+                let cfg = launch_config_from_elements_and_threads(
+                    lens[idx] as u32,
+                    DEFAULT_LAUNCH_CONFIG_THREADS,
+                    &self.device_manager.devices()[idx],
+                );
+
+                unsafe {
+                    self.distance_kernels[idx]
+                        .clone()
+                        .launch_on_stream(
+                            &streams[idx],
+                            cfg,
+                            (&codes[idx], &masks[idx], &mut output, lens[idx]),
+                        )
+                        .unwrap();
+                }
+                output
+            })
+            .collect()
     }
 
     fn single_xor_assign_u8(
@@ -651,7 +1757,13 @@ impl ShareDB {
         );
     }
 
-    pub fn reshare_results(&mut self, db_sizes: &[usize], streams: &[CudaStream]) {
+    pub fn reshare_results(
+        &mut self,
+        db_sizes: &[usize],
+        streams: &[CudaStream],
+    ) -> Result<(), ShareDbError> {
+        self.check_device_count("DB sizes", db_sizes.len())?;
+        self.check_device_count("NCCL comms", self.comms.len())?;
         let next_peer = (self.peer_id + 1) % 3;
         let prev_peer = (self.peer_id + 2) % 3;
 
@@ -664,37 +1776,319 @@ impl ShareDB {
 
         let send = &send_bufs;
 
-        nccl::group_start().unwrap();
+        nccl::group_start()?;
         for idx in 0..self.device_manager.device_count() {
             let len = db_sizes[idx] * self.query_length * 2;
             let send_len = len >> 2;
             let send_view = send[idx].slice(..send_len);
             self.comms[idx]
                 .send_view(&send_view, next_peer, &streams[idx])
-                .unwrap();
+                .map_err(|error| ShareDbError::CommExchange {
+                    peer: next_peer,
+                    device: idx,
+                    error,
+                })?;
 
             let mut recv_view = self.results_peer[idx].slice(..len);
             self.comms[idx]
                 .receive_view(&mut recv_view, prev_peer, &streams[idx])
-                .unwrap();
+                .map_err(|error| ShareDbError::CommExchange {
+                    peer: prev_peer,
+                    device: idx,
+                    error,
+                })?;
         }
-        nccl::group_end().unwrap();
+        nccl::group_end()?;
         for idx in 0..self.device_manager.device_count() {
             let len = db_sizes[idx] * self.query_length * 2;
             self.otp_decrypt_rng_result(len, idx, streams);
         }
+        Ok(())
     }
 
-    pub fn fetch_results(&self, results: &mut [u16], db_sizes: &[usize], device_id: usize) {
-        unsafe {
-            let res_trans =
-                self.results[device_id].transmute(db_sizes[device_id] * self.query_length);
+    /// Checks that the results held by this party after
+    /// [`Self::reshare_results`] form a valid replicated sharing, i.e. that
+    /// `results_peer` equals the `results` of the previous party. All three
+    /// parties have to call this together.
+    #[cfg(feature = "debug_checks")]
+    pub fn verify_reshare(&self, db_sizes: &[usize]) -> Result<(), ShareDbError> {
+        self.check_device_count("DB sizes", db_sizes.len())?;
+        self.check_device_count("NCCL comms", self.comms.len())?;
+        let next_peer = (self.peer_id + 1) % 3;
+        let prev_peer = (self.peer_id + 2) % 3;
+        let n_devices = self.device_manager.device_count();
+        let lens = (0..n_devices)
+            .map(|idx| db_sizes[idx] * self.query_length * mem::size_of::<u16>())
+            .collect_vec();
 
-            self.device_manager
-                .device(device_id)
-                .dtoh_sync_copy_into(&res_trans.unwrap(), results)
-                .unwrap();
+        let mut streams = Vec::with_capacity(n_devices);
+        let mut received = Vec::with_capacity(n_devices);
+        for idx in 0..n_devices {
+            let device = self.device_manager.device(idx);
+            device.synchronize()?;
+            streams.push(device.fork_default_stream()?);
+            received.push(device.alloc_zeros::<u8>(lens[idx])?);
+        }
+
+        nccl::group_start()?;
+        for idx in 0..n_devices {
+            let send_view = self.results[idx].slice(..lens[idx]);
+            self.comms[idx]
+                .send_view(&send_view, next_peer, &streams[idx])
+                .map_err(|error| ShareDbError::CommExchange {
+                    peer: next_peer,
+                    device: idx,
+                    error,
+                })?;
+
+            let mut recv_view = received[idx].slice(..);
+            self.comms[idx]
+                .receive_view(&mut recv_view, prev_peer, &streams[idx])
+                .map_err(|error| ShareDbError::CommExchange {
+                    peer: prev_peer,
+                    device: idx,
+                    error,
+                })?;
+        }
+        nccl::group_end()?;
+
+        for idx in 0..n_devices {
+            let device = self.device_manager.device(idx);
+            device.synchronize()?;
+            let theirs = device.dtoh_sync_copy(&received[idx])?;
+            let mine = device.dtoh_sync_copy(&self.results_peer[idx].slice(..lens[idx]))?;
+            if let Some(offset) = izip!(&mine, &theirs).position(|(a, b)| a != b) {
+                return Err(ShareDbError::ReshareMismatch {
+                    device: idx,
+                    offset,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of `u16` results of `device_id`, after checking that they fit
+    /// into the result buffer of the device.
+    fn result_len(&self, db_sizes: &[usize], device_id: usize) -> Result<usize, ShareDbError> {
+        if device_id >= self.device_manager.device_count() || device_id >= db_sizes.len() {
+            return Err(ShareDbError::Dimension(format!(
+                "device {} out of range for {} devices and {} DB sizes",
+                device_id,
+                self.device_manager.device_count(),
+                db_sizes.len()
+            )));
+        }
+        let len = db_sizes[device_id] * self.query_length;
+        let buffer_len = self.results[device_id].len();
+        if len * mem::size_of::<u16>() > buffer_len {
+            return Err(ShareDbError::Dimension(format!(
+                "{} DB entries x {} queries need {} bytes, but the result buffer on device {} \
+                 holds {} bytes",
+                db_sizes[device_id],
+                self.query_length,
+                len * mem::size_of::<u16>(),
+                device_id,
+                buffer_len
+            )));
+        }
+        Ok(len)
+    }
+
+    pub fn fetch_results(
+        &self,
+        results: &mut [u16],
+        db_sizes: &[usize],
+        device_id: usize,
+    ) -> Result<(), ShareDbError> {
+        let len = self.result_len(db_sizes, device_id)?;
+        if results.len() != len {
+            return Err(ShareDbError::Dimension(format!(
+                "result buffer of length {} does not match {} DB entries x {} queries on device {}",
+                results.len(),
+                db_sizes[device_id],
+                self.query_length,
+                device_id
+            )));
+        }
+        // SAFETY: All bit patterns are valid u16 values
+        let res_trans = unsafe { self.results[device_id].transmute(len) }.expect("len is checked");
+
+        self.device_manager
+            .device(device_id)
+            .dtoh_sync_copy_into(&res_trans, results)?;
+        Ok(())
+    }
+
+    /// Copies the raw `i32` accumulations of the last [`Self::dot`] on
+    /// `device_id` back to the host, before [`Self::dot_reduce`] turns them
+    /// into results. They are laid out like the results of
+    /// [`Self::fetch_results`].
+    #[cfg(feature = "debug_checks")]
+    pub fn debug_fetch_intermediate(
+        &self,
+        device_id: usize,
+        db_sizes: &[usize],
+    ) -> Result<Vec<i32>, ShareDbError> {
+        let len = self.result_len(db_sizes, device_id)?;
+        let device = self.device_manager.device(device_id);
+        device.synchronize()?;
+        Ok(device.dtoh_sync_copy(&self.intermediate_results[device_id].slice(..len))?)
+    }
+
+    /// Fetches the results of all devices and merges them into a single
+    /// query-major buffer in DB order, i.e. the result of query `q` and DB
+    /// record `i` ends up at `q * total + i`, with `total` the sum of
+    /// `db_sizes`. Assumes the records were distributed round-robin over the
+    /// devices, as done by [`Self::load_full_db`].
+    pub fn fetch_all_results(&self, db_sizes: &[usize]) -> Result<Vec<u16>, ShareDbError> {
+        self.check_device_count("DB sizes", db_sizes.len())?;
+        let n_shards = self.device_manager.device_count();
+        let db_sizes = &db_sizes[..n_shards];
+        let mut per_device = Vec::with_capacity(n_shards);
+        for device_id in 0..n_shards {
+            let mut results = vec![0u16; self.result_len(db_sizes, device_id)?];
+            self.fetch_results(&mut results, db_sizes, device_id)?;
+            per_device.push(results);
+        }
+        Ok(self.merge_device_results(db_sizes, &per_device))
+    }
+
+    /// Merges per-device results into the layout of
+    /// [`Self::fetch_all_results`].
+    fn merge_device_results(&self, db_sizes: &[usize], per_device: &[Vec<u16>]) -> Vec<u16> {
+        let n_shards = per_device.len();
+        let total: usize = db_sizes.iter().sum();
+        let mut merged = vec![0u16; total * self.query_length];
+        for (device_id, (&db_size, results)) in izip!(db_sizes, per_device).enumerate() {
+            for (q, row_results) in results.chunks(db_size.max(1)).enumerate() {
+                for (row, &result) in row_results.iter().enumerate() {
+                    merged[q * total + row * n_shards + device_id] = result;
+                }
+            }
+        }
+        merged
+    }
+
+    /// Opens the results to all parties. After [`Self::reshare_results`], the
+    /// parties hold a replicated sharing `x = x_0 + x_1 + x_2 (mod 2^16)`,
+    /// where party `i` holds `x_i` in `results` and `x_{i-1}` in
+    /// `results_peer`. Each party sends `x_i` to the previous party and
+    /// receives the missing `x_{i+1}` from the next one, then adds up all
+    /// three shares. All three parties have to call this together.
+    ///
+    /// The plaintext results are laid out like those of
+    /// [`Self::fetch_all_results`]. This reveals the results, so it is meant
+    /// for tests and debugging only.
+    pub fn reconstruct_results(&self, db_sizes: &[usize]) -> Result<Vec<u16>, ShareDbError> {
+        self.check_device_count("DB sizes", db_sizes.len())?;
+        self.check_device_count("NCCL comms", self.comms.len())?;
+        let next_peer = (self.peer_id + 1) % 3;
+        let prev_peer = (self.peer_id + 2) % 3;
+        let n_devices = self.device_manager.device_count();
+        let db_sizes = &db_sizes[..n_devices];
+        let lens = (0..n_devices)
+            .map(|idx| self.result_len(db_sizes, idx))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut streams = Vec::with_capacity(n_devices);
+        let mut received = Vec::with_capacity(n_devices);
+        for idx in 0..n_devices {
+            let device = self.device_manager.device(idx);
+            device.synchronize()?;
+            streams.push(device.fork_default_stream()?);
+            received.push(device.alloc_zeros::<u8>(lens[idx] * mem::size_of::<u16>())?);
+        }
+
+        nccl::group_start()?;
+        for idx in 0..n_devices {
+            let send_view = self.results[idx].slice(..lens[idx] * mem::size_of::<u16>());
+            self.comms[idx]
+                .send_view(&send_view, prev_peer, &streams[idx])
+                .map_err(|error| ShareDbError::CommExchange {
+                    peer: prev_peer,
+                    device: idx,
+                    error,
+                })?;
+
+            let mut recv_view = received[idx].slice(..);
+            self.comms[idx]
+                .receive_view(&mut recv_view, next_peer, &streams[idx])
+                .map_err(|error| ShareDbError::CommExchange {
+                    peer: next_peer,
+                    device: idx,
+                    error,
+                })?;
+        }
+        nccl::group_end()?;
+
+        let mut per_device = Vec::with_capacity(n_devices);
+        for idx in 0..n_devices {
+            let device = self.device_manager.device(idx);
+            device.synchronize()?;
+            let len = lens[idx];
+            let mut mine = vec![0u16; len];
+            self.fetch_results(&mut mine, db_sizes, idx)?;
+            // SAFETY: All bit patterns are valid u16 values
+            let prev =
+                unsafe { self.results_peer[idx].transmute::<u16>(len) }.expect("len is checked");
+            let prev = device.dtoh_sync_copy(&prev)?;
+            // SAFETY: All bit patterns are valid u16 values
+            let next = unsafe { received[idx].transmute::<u16>(len) }.expect("len is checked");
+            let next = device.dtoh_sync_copy(&next)?;
+            per_device.push(
+                izip!(mine, prev, next)
+                    .map(|(a, b, c)| a.wrapping_add(b).wrapping_add(c))
+                    .collect_vec(),
+            );
+        }
+        Ok(self.merge_device_results(db_sizes, &per_device))
+    }
+
+    /// Like [`Self::fetch_results`], but does not block the async runtime
+    /// while the device is busy. The results are copied into a buffer of their
+    /// own on `streams[device_id]` right away, so that the returned future
+    /// does not borrow the engine; it waits for that copy and then copies the
+    /// buffer back, both on a blocking thread.
+    pub fn fetch_results_async(
+        &self,
+        db_sizes: &[usize],
+        device_id: usize,
+        streams: &[CudaStream],
+    ) -> Result<impl Future<Output = Result<Vec<u16>, ShareDbError>>, ShareDbError> {
+        let len = self.result_len(db_sizes, device_id)?;
+        self.check_device_count("streams", streams.len())?;
+
+        let device = self.device_manager.device(device_id);
+        device.bind_to_thread()?;
+        let stream = streams[device_id].stream;
+        let bytes = len * mem::size_of::<u16>();
+        let copy = StreamAwareCudaSlice::<u16>::upgrade_ptr_stream(
+            unsafe { malloc_async(stream, bytes)? },
+            stream,
+            len,
+        );
+        unsafe {
+            result::memcpy_dtod_async(
+                copy.cu_device_ptr,
+                *self.results[device_id].device_ptr(),
+                bytes,
+                stream,
+            )?;
         }
+        let done = OwnedEvent(event::create(CUevent_flags::CU_EVENT_DISABLE_TIMING)?);
+        unsafe { event::record(done.0, stream)? };
+
+        Ok(async move {
+            tokio::task::spawn_blocking(move || -> Result<_, ShareDbError> {
+                device.bind_to_thread()?;
+                unsafe { event::synchronize(done.0)? };
+                let mut results = vec![0u16; len];
+                unsafe { result::memcpy_dtoh_sync(&mut results, copy.cu_device_ptr)? };
+                Ok(results)
+            })
+            .await
+            .expect("fetching the results panicked")
+        })
     }
 
     pub fn result_chunk_shares<'a>(&'a self, db_sizes: &[usize]) -> Vec<ChunkShareView<'a, u16>> {
@@ -722,21 +2116,32 @@ impl ShareDB {
 #[cfg(test)]
 #[cfg(feature = "gpu_dependent")]
 mod tests {
-    use super::{preprocess_query, ShareDB};
+    use super::{
+        derive_device_seeds, limb, preprocess_query, reconstruct_query, ReduceConfig, ShareDB,
+        ShareDbError, PTX_SRC,
+    };
     use crate::{
         dot::{IRIS_CODE_LENGTH, MASK_CODE_LENGTH},
-        helpers::device_manager::DeviceManager,
+        helpers::{comm::NcclComm, device_manager::DeviceManager, ptx_cache::compile_count},
     };
+    use cudarc::{driver::result, nccl::Id};
     use float_eq::assert_float_eq;
     use iris_mpc_common::{
+        eye::Eye,
         galois_engine::degree4::{GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare},
         iris_db::db::IrisDB,
     };
-    use itertools::Itertools;
+    use itertools::{izip, Itertools};
     use ndarray::Array2;
     use num_traits::FromPrimitive;
     use rand::{rngs::StdRng, Rng, SeedableRng};
-    use std::sync::Arc;
+    use std::{
+        io::Write,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
 
     const WIDTH: usize = 12_800;
     const QUERY_SIZE: usize = 32;
@@ -778,6 +2183,18 @@ mod tests {
         res.into_iter().flatten().collect::<Vec<_>>()
     }
 
+    /// Single-rank NCCL comms, so that the engine masks its results like a
+    /// remote party without needing any peers.
+    fn single_rank_comms(device_manager: &DeviceManager) -> Vec<Arc<NcclComm>> {
+        device_manager
+            .devices()
+            .iter()
+            .map(|dev| {
+                Arc::new(NcclComm::from_rank(dev.clone(), 0, 1, Id::new().unwrap()).unwrap())
+            })
+            .collect()
+    }
+
     /// Test to verify the matmul operation for random matrices in the field
     #[test]
     fn check_matmul() {
@@ -796,7 +2213,8 @@ mod tests {
             IRIS_CODE_LENGTH,
             ([0u32; 8], [0u32; 8]),
             vec![],
-        );
+        )
+        .unwrap();
         let preprocessed_query = preprocess_query(&query);
         let streams = device_manager.fork_streams();
         let blass = device_manager.create_cublas(&streams);
@@ -805,17 +2223,21 @@ mod tests {
             .unwrap();
         let query_sums = engine.query_sums(&preprocessed_query, &streams, &blass);
         let mut db_slices = engine.alloc_db(DB_SIZE);
-        let db_sizes = engine.load_full_db(&mut db_slices, &db);
+        let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
 
-        engine.dot(
-            &preprocessed_query,
-            &db_slices.code_gr,
-            &db_sizes,
-            0,
-            &streams,
-            &blass,
-        );
-        engine.dot_reduce(&query_sums, &db_slices.code_sums_gr, &db_sizes, 0, &streams);
+        engine
+            .dot(
+                &preprocessed_query,
+                &db_slices.code_gr,
+                &db_sizes,
+                0,
+                &streams,
+                &blass,
+            )
+            .unwrap();
+        engine
+            .dot_reduce(&query_sums, &db_slices.code_sums_gr, &db_sizes, 0, &streams)
+            .unwrap();
         device_manager.await_streams(&streams);
 
         let a_nda = random_ndarray::<u16>(shard_db(&db, n_devices), DB_SIZE, WIDTH);
@@ -830,7 +2252,9 @@ mod tests {
         }
 
         for device_idx in 0..n_devices {
-            engine.fetch_results(&mut gpu_result, &db_sizes, device_idx);
+            engine
+                .fetch_results(&mut gpu_result, &db_sizes, device_idx)
+                .unwrap();
             let selected_elements: Vec<u16> = vec_column_major
                 .chunks(DB_SIZE)
                 .flat_map(|chunk| {
@@ -846,6 +2270,68 @@ mod tests {
         }
     }
 
+    /// Checks that devices without any records are skipped, e.g. when the DB
+    /// has fewer records than there are devices.
+    #[test]
+    fn check_tiny_db() {
+        const TINY_DB_SIZE: usize = 2;
+        let db = random_vec(TINY_DB_SIZE, WIDTH, u16::MAX as u32);
+        let query = random_vec(QUERY_SIZE, WIDTH, u16::MAX as u32);
+        let device_manager = Arc::new(DeviceManager::init());
+
+        let mut engine = ShareDB::init(
+            0,
+            device_manager.clone(),
+            TINY_DB_SIZE,
+            QUERY_SIZE,
+            IRIS_CODE_LENGTH,
+            ([0u32; 8], [0u32; 8]),
+            vec![],
+        )
+        .unwrap();
+        let streams = device_manager.fork_streams();
+        let blass = device_manager.create_cublas(&streams);
+        let preprocessed_query = device_manager
+            .htod_transfer_query(
+                &preprocess_query(&query),
+                &streams,
+                QUERY_SIZE,
+                IRIS_CODE_LENGTH,
+            )
+            .unwrap();
+        let query_sums = engine.query_sums(&preprocessed_query, &streams, &blass);
+        let mut db_slices = engine.alloc_db(TINY_DB_SIZE);
+        let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
+
+        engine
+            .dot(
+                &preprocessed_query,
+                &db_slices.code_gr,
+                &db_sizes,
+                0,
+                &streams,
+                &blass,
+            )
+            .unwrap();
+        engine
+            .dot_reduce(&query_sums, &db_slices.code_sums_gr, &db_sizes, 0, &streams)
+            .unwrap();
+        device_manager.await_streams(&streams);
+
+        let expected = query
+            .chunks(WIDTH)
+            .flat_map(|q| {
+                db.chunks(WIDTH).map(move |record| {
+                    record
+                        .iter()
+                        .zip(q)
+                        .fold(0u16, |acc, (&a, &b)| acc.wrapping_add(a.wrapping_mul(b)))
+                })
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(engine.fetch_all_results(&db_sizes).unwrap(), expected);
+    }
+
     /// Checks that the result of a matmul of the original data equals the
     /// reconstructed result of individual matmuls on the shamir shares.
     #[test]
@@ -897,7 +2383,8 @@ mod tests {
                 IRIS_CODE_LENGTH,
                 ([0u32; 8], [0u32; 8]),
                 vec![],
-            );
+            )
+            .unwrap();
             let preprocessed_query = preprocess_query(&querys);
             let streams = device_manager.fork_streams();
             let blass = device_manager.create_cublas(&streams);
@@ -906,19 +2393,25 @@ mod tests {
                 .unwrap();
             let query_sums = engine.query_sums(&preprocessed_query, &streams, &blass);
             let mut db_slices = engine.alloc_db(DB_SIZE);
-            let db_sizes = engine.load_full_db(&mut db_slices, &codes_db);
+            let db_sizes = engine.load_full_db(&mut db_slices, &codes_db).unwrap();
 
-            engine.dot(
-                &preprocessed_query,
-                &db_slices.code_gr,
-                &db_sizes,
-                0,
-                &streams,
-                &blass,
-            );
-            engine.dot_reduce(&query_sums, &db_slices.code_sums_gr, &db_sizes, 0, &streams);
+            engine
+                .dot(
+                    &preprocessed_query,
+                    &db_slices.code_gr,
+                    &db_sizes,
+                    0,
+                    &streams,
+                    &blass,
+                )
+                .unwrap();
+            engine
+                .dot_reduce(&query_sums, &db_slices.code_sums_gr, &db_sizes, 0, &streams)
+                .unwrap();
             device_manager.await_streams(&streams);
-            engine.fetch_results(&mut gpu_result[i], &db_sizes, 0);
+            engine
+                .fetch_results(&mut gpu_result[i], &db_sizes, 0)
+                .unwrap();
         }
 
         for i in 0..DB_SIZE * QUERY_SIZE / n_devices {
@@ -1011,7 +2504,8 @@ mod tests {
                 IRIS_CODE_LENGTH,
                 ([0u32; 8], [0u32; 8]),
                 vec![],
-            );
+            )
+            .unwrap();
             let mut masks_engine = ShareDB::init(
                 party_id,
                 device_manager.clone(),
@@ -1020,7 +2514,8 @@ mod tests {
                 MASK_CODE_LENGTH,
                 ([0u32; 8], [0u32; 8]),
                 vec![],
-            );
+            )
+            .unwrap();
 
             let code_query = preprocess_query(&code_queries);
             let mask_query = preprocess_query(&mask_queries);
@@ -1036,50 +2531,66 @@ mod tests {
             let code_query_sums = codes_engine.query_sums(&code_query, &streams, &blass);
             let mask_query_sums = masks_engine.query_sums(&mask_query, &streams, &blass);
             let mut code_db_slices = codes_engine.alloc_db(DB_SIZE);
-            let db_sizes = codes_engine.load_full_db(&mut code_db_slices, &codes_db);
+            let db_sizes = codes_engine
+                .load_full_db(&mut code_db_slices, &codes_db)
+                .unwrap();
             let mut mask_db_slices = masks_engine.alloc_db(DB_SIZE);
-            let mask_db_sizes = masks_engine.load_full_db(&mut mask_db_slices, &masks_db);
+            let mask_db_sizes = masks_engine
+                .load_full_db(&mut mask_db_slices, &masks_db)
+                .unwrap();
 
             assert_eq!(db_sizes, mask_db_sizes);
 
-            codes_engine.dot(
-                &code_query,
-                &code_db_slices.code_gr,
-                &db_sizes,
-                0,
-                &streams,
-                &blass,
-            );
-            masks_engine.dot(
-                &mask_query,
-                &mask_db_slices.code_gr,
-                &db_sizes,
-                0,
-                &streams,
-                &blass,
-            );
+            codes_engine
+                .dot(
+                    &code_query,
+                    &code_db_slices.code_gr,
+                    &db_sizes,
+                    0,
+                    &streams,
+                    &blass,
+                )
+                .unwrap();
+            masks_engine
+                .dot(
+                    &mask_query,
+                    &mask_db_slices.code_gr,
+                    &db_sizes,
+                    0,
+                    &streams,
+                    &blass,
+                )
+                .unwrap();
 
-            codes_engine.dot_reduce(
-                &code_query_sums,
-                &code_db_slices.code_sums_gr,
-                &db_sizes,
-                0,
-                &streams,
-            );
-            masks_engine.dot_reduce_and_multiply(
-                &mask_query_sums,
-                &mask_db_slices.code_sums_gr,
-                &db_sizes,
-                0,
-                &streams,
-                2,
-            );
+            codes_engine
+                .dot_reduce(
+                    &code_query_sums,
+                    &code_db_slices.code_sums_gr,
+                    &db_sizes,
+                    0,
+                    &streams,
+                )
+                .unwrap();
+            masks_engine
+                .dot_reduce_and_multiply(
+                    &mask_query_sums,
+                    &mask_db_slices.code_sums_gr,
+                    &db_sizes,
+                    0,
+                    &streams,
+                    2,
+                )
+                .unwrap();
 
             device_manager.await_streams(&streams);
 
             // TODO: fetch results also for other devices
-            codes_engine.fetch_results(&mut results_codes[party_id], &db_sizes, 0);
-            masks_engine.fetch_results(&mut results_masks[party_id], &db_sizes, 0);
+            codes_engine
+                .fetch_results(&mut results_codes[party_id], &db_sizes, 0)
+                .unwrap();
+            masks_engine
+                .fetch_results(&mut results_masks[party_id], &db_sizes, 0)
+                .unwrap();
         }
 
         // Reconstruct the results
@@ -1109,4 +2620,1791 @@ mod tests {
             assert_float_eq!(dists[i], reference_dists[i * n_devices], abs <= 1e-6);
         }
     }
+
+    /// Checks that the load progress callback reports monotonically
+    /// increasing row counts ending at the total.
+    #[test]
+    fn check_load_full_db_progress() {
+        let db = random_vec(DB_SIZE, WIDTH, u16::MAX as u32);
+        let device_manager = Arc::new(DeviceManager::init());
+
+        let engine = ShareDB::init(
+            0,
+            device_manager.clone(),
+            DB_SIZE,
+            QUERY_SIZE,
+            IRIS_CODE_LENGTH,
+            ([0u32; 8], [0u32; 8]),
+            vec![],
+        )
+        .unwrap();
+        let mut db_slices = engine.alloc_db(DB_SIZE);
+        let mut calls = vec![];
+        let db_sizes = engine
+            .load_full_db_with_progress(&mut db_slices, &db, |done, total| {
+                calls.push((done, total))
+            })
+            .unwrap();
+
+        assert_eq!(calls.len(), device_manager.device_count());
+        assert!(calls.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(calls.iter().all(|&(_, total)| total == DB_SIZE));
+        assert_eq!(calls.last().unwrap().0, DB_SIZE);
+        assert_eq!(db_sizes.iter().sum::<usize>(), DB_SIZE);
+    }
+
+    /// Checks that mismatching buffer sizes are reported as dimension errors
+    /// instead of panicking.
+    #[test]
+    fn check_dimension_errors() {
+        let device_manager = Arc::new(DeviceManager::init());
+        let engine = ShareDB::init(
+            0,
+            device_manager.clone(),
+            DB_SIZE,
+            QUERY_SIZE,
+            IRIS_CODE_LENGTH,
+            ([0u32; 8], [0u32; 8]),
+            vec![],
+        )
+        .unwrap();
+        let mut db_slices = engine.alloc_db(DB_SIZE);
+
+        let db = vec![0u16; IRIS_CODE_LENGTH + 1];
+        match engine.load_full_db(&mut db_slices, &db) {
+            Err(ShareDbError::Dimension(msg)) => {
+                assert!(msg.contains(&IRIS_CODE_LENGTH.to_string()), "{}", msg)
+            }
+            other => panic!("expected a dimension error, got {:?}", other),
+        }
+
+        let mut results = vec![0u16; QUERY_SIZE];
+        match engine.fetch_results(&mut results, &[2], 0) {
+            Err(ShareDbError::Dimension(msg)) => assert!(msg.contains("device 0"), "{}", msg),
+            other => panic!("expected a dimension error, got {:?}", other),
+        }
+    }
+
+    /// A DB size that is not a multiple of the device count leaves one more
+    /// record on the first devices, and a DB larger than the allocation is
+    /// rejected instead of overflowing it.
+    #[test]
+    fn check_uneven_db_size() {
+        const RECORDS_PER_DEVICE: usize = 10;
+        let device_manager = Arc::new(DeviceManager::init());
+        let n_devices = device_manager.device_count();
+        let db_size = n_devices * RECORDS_PER_DEVICE + 1;
+        let db = random_vec(db_size, WIDTH, u16::MAX as u32);
+
+        let engine = ShareDB::init(
+            0,
+            device_manager.clone(),
+            db_size,
+            QUERY_SIZE,
+            IRIS_CODE_LENGTH,
+            ([0u32; 8], [0u32; 8]),
+            vec![],
+        )
+        .unwrap();
+        let mut db_slices = engine.alloc_db(db_size);
+        let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
+
+        let mut expected_sizes = vec![RECORDS_PER_DEVICE; n_devices];
+        expected_sizes[0] += 1;
+        assert_eq!(db_sizes, expected_sizes);
+
+        // The last record is the extra row of the first device
+        let last_record = &db[(db_size - 1) * WIDTH..];
+        let stored: &[i8] = unsafe {
+            std::slice::from_raw_parts(
+                (db_slices.code_gr.limb_0[0] + (RECORDS_PER_DEVICE * WIDTH) as u64) as *const _,
+                WIDTH,
+            )
+        };
+        assert_eq!(
+            stored,
+            last_record.iter().map(|&x| limb(x, 0)).collect::<Vec<_>>()
+        );
+
+        let mut small_db_slices = engine.alloc_db(db_size - 1);
+        match engine.load_full_db(&mut small_db_slices, &db) {
+            Err(ShareDbError::Dimension(msg)) => {
+                assert!(msg.contains(&db_size.to_string()), "{}", msg)
+            }
+            other => panic!("expected a dimension error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn check_reconstruct_query() {
+        let mut rng = StdRng::seed_from_u64(RNG_SEED);
+        for _ in 0..100 {
+            let len = rng.gen_range(0..1000);
+            let query = (0..len).map(|_| rng.gen::<u16>()).collect::<Vec<_>>();
+            assert_eq!(reconstruct_query(&preprocess_query(&query)), query);
+        }
+        let edges = vec![0, 1, 0x7f, 0x80, 0xff, 0x100, 0x7fff, 0x8000, u16::MAX];
+        assert_eq!(reconstruct_query(&preprocess_query(&edges)), edges);
+    }
+
+    /// Checks that loading a DB in two parts with `append_db` leaves the same
+    /// device contents as loading it at once.
+    #[test]
+    fn check_append_db() {
+        const INITIAL: usize = DB_SIZE / 2 + 3;
+        let db = random_vec(DB_SIZE, WIDTH, u16::MAX as u32);
+        let device_manager = Arc::new(DeviceManager::init());
+        let n_devices = device_manager.device_count();
+
+        let engine = ShareDB::init(
+            0,
+            device_manager.clone(),
+            DB_SIZE,
+            QUERY_SIZE,
+            IRIS_CODE_LENGTH,
+            ([0u32; 8], [0u32; 8]),
+            vec![],
+        )
+        .unwrap();
+
+        let mut db_slices = engine.alloc_db(DB_SIZE);
+        let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
+
+        let (initial, appended) = db.split_at(INITIAL * WIDTH);
+        let mut appended_db_slices = engine.alloc_db(DB_SIZE);
+        engine
+            .load_full_db(&mut appended_db_slices, initial)
+            .unwrap();
+        let appended_db_sizes = engine
+            .append_db(&mut appended_db_slices, appended, INITIAL)
+            .unwrap();
+        assert_eq!(db_sizes, appended_db_sizes);
+
+        // The allocation has no room for more records
+        assert!(matches!(
+            engine.append_db(&mut appended_db_slices, &db[..WIDTH], DB_SIZE),
+            Err(ShareDbError::Dimension(_))
+        ));
+
+        for device_idx in 0..n_devices {
+            let len = db_sizes[device_idx] * IRIS_CODE_LENGTH;
+            for (a, b) in [
+                (
+                    &db_slices.code_gr.limb_0,
+                    &appended_db_slices.code_gr.limb_0,
+                ),
+                (
+                    &db_slices.code_gr.limb_1,
+                    &appended_db_slices.code_gr.limb_1,
+                ),
+            ] {
+                let (a, b): (&[i8], &[i8]) = unsafe {
+                    (
+                        std::slice::from_raw_parts(a[device_idx] as *const _, len),
+                        std::slice::from_raw_parts(b[device_idx] as *const _, len),
+                    )
+                };
+                assert_eq!(a, b);
+            }
+
+            device_manager.device(device_idx).bind_to_thread().unwrap();
+            for (a, b) in [
+                (
+                    &db_slices.code_sums_gr.limb_0,
+                    &appended_db_slices.code_sums_gr.limb_0,
+                ),
+                (
+                    &db_slices.code_sums_gr.limb_1,
+                    &appended_db_slices.code_sums_gr.limb_1,
+                ),
+            ] {
+                let mut a_host = vec![0u32; db_sizes[device_idx]];
+                let mut b_host = vec![0u32; db_sizes[device_idx]];
+                unsafe {
+                    result::memcpy_dtoh_sync(&mut a_host, a[device_idx].cu_device_ptr).unwrap();
+                    result::memcpy_dtoh_sync(&mut b_host, b[device_idx].cu_device_ptr).unwrap();
+                }
+                assert_eq!(a_host, b_host);
+            }
+        }
+    }
+
+    /// The limb encoding must match the original hard-coded 2-limb one.
+    #[test]
+    fn check_limb_encoding() {
+        assert_eq!(preprocess_query(&[0x1234, 0, 0xffff, 0x00ff]), vec![
+            vec![180, 128, 127, 127],
+            vec![146, 128, 127, 128]
+        ]);
+
+        let query = random_vec(1, 1 << 16, u16::MAX as u32 + 1);
+        let limbs = preprocess_query(&query);
+        for (idx, &x) in query.iter().enumerate() {
+            assert_eq!(limbs[0][idx], ((x as u8) as i32 - 128) as u8);
+            assert_eq!(limbs[1][idx], ((x >> 8) as i32 - 128) as u8);
+            assert_eq!(limb(x, 0), ((x as i8) as i32 - 128) as i8);
+            assert_eq!(limb(x, 1), ((x >> 8) as i32 - 128) as i8);
+        }
+    }
+
+    /// Checks that the DB digest only depends on the loaded records.
+    #[test]
+    fn check_db_digest() {
+        let db = random_vec(DB_SIZE, WIDTH, u16::MAX as u32);
+        let device_manager = Arc::new(DeviceManager::init());
+        let n_devices = device_manager.device_count();
+
+        let engine = ShareDB::init(
+            0,
+            device_manager.clone(),
+            DB_SIZE,
+            QUERY_SIZE,
+            IRIS_CODE_LENGTH,
+            ([0u32; 8], [0u32; 8]),
+            vec![],
+        )
+        .unwrap();
+
+        let mut db_slices = engine.alloc_db(DB_SIZE);
+        let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
+        let mut other_db_slices = engine.alloc_db(DB_SIZE);
+        let other_db_sizes = engine.load_full_db(&mut other_db_slices, &db).unwrap();
+        let mut truncated_db_slices = engine.alloc_db(DB_SIZE);
+        let truncated_db_sizes = engine
+            .load_full_db(
+                &mut truncated_db_slices,
+                &db[..(DB_SIZE - n_devices) * WIDTH],
+            )
+            .unwrap();
+
+        for device_idx in 0..n_devices {
+            let digest = engine.db_digest(&db_slices, &db_sizes, device_idx).unwrap();
+            assert_eq!(
+                digest,
+                engine
+                    .db_digest(&other_db_slices, &other_db_sizes, device_idx)
+                    .unwrap()
+            );
+            assert_ne!(
+                digest,
+                engine
+                    .db_digest(&truncated_db_slices, &truncated_db_sizes, device_idx)
+                    .unwrap()
+            );
+        }
+    }
+
+    /// Checks that a batch with fewer queries gives the columns of those
+    /// queries of a full batch.
+    #[test]
+    fn check_partial_query_batch() {
+        const PARTIAL_QUERY_SIZE: usize = QUERY_SIZE / 2;
+        let db = random_vec(DB_SIZE, WIDTH, u16::MAX as u32);
+        let query = random_vec(QUERY_SIZE, WIDTH, u16::MAX as u32);
+        let device_manager = Arc::new(DeviceManager::init());
+        let n_devices = device_manager.device_count();
+
+        let mut engine = ShareDB::init(
+            0,
+            device_manager.clone(),
+            DB_SIZE,
+            QUERY_SIZE,
+            IRIS_CODE_LENGTH,
+            ([0u32; 8], [0u32; 8]),
+            vec![],
+        )
+        .unwrap();
+        assert!(engine.set_query_length(QUERY_SIZE + 1).is_err());
+
+        let streams = device_manager.fork_streams();
+        let blass = device_manager.create_cublas(&streams);
+        let preprocessed_query = device_manager
+            .htod_transfer_query(
+                &preprocess_query(&query),
+                &streams,
+                QUERY_SIZE,
+                IRIS_CODE_LENGTH,
+            )
+            .unwrap();
+        let mut db_slices = engine.alloc_db(DB_SIZE);
+        let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
+
+        let mut results = vec![];
+        for query_length in [QUERY_SIZE, PARTIAL_QUERY_SIZE] {
+            engine.set_query_length(query_length).unwrap();
+            let query_sums = engine.query_sums(&preprocessed_query, &streams, &blass);
+            engine
+                .dot(
+                    &preprocessed_query,
+                    &db_slices.code_gr,
+                    &db_sizes,
+                    0,
+                    &streams,
+                    &blass,
+                )
+                .unwrap();
+            engine
+                .dot_reduce(&query_sums, &db_slices.code_sums_gr, &db_sizes, 0, &streams)
+                .unwrap();
+            device_manager.await_streams(&streams);
+
+            results.push(
+                (0..n_devices)
+                    .map(|d| {
+                        let mut result = vec![0u16; db_sizes[d] * query_length];
+                        engine.fetch_results(&mut result, &db_sizes, d).unwrap();
+                        result
+                    })
+                    .collect_vec(),
+            );
+        }
+
+        // Results are stored query by query, so the partial batch is a prefix
+        for (full, partial) in results[0].iter().zip(&results[1]) {
+            assert_eq!(&full[..partial.len()], &partial[..]);
+        }
+    }
+
+    /// Checks the products of the query shares with each other against the
+    /// plain mask products.
+    #[test]
+    fn check_self_dot() {
+        let mut rng = StdRng::seed_from_u64(RNG_SEED);
+        let device_manager = Arc::new(DeviceManager::init());
+        let db = IrisDB::new_random_par(QUERY_SIZE, &mut rng);
+
+        let mut gpu_results = vec![vec![0u16; QUERY_SIZE * QUERY_SIZE]; 3];
+        for (i, gpu_result) in gpu_results.iter_mut().enumerate() {
+            let (raw_queries, queries): (Vec<_>, Vec<_>) = db
+                .db
+                .iter()
+                .map(|iris| {
+                    let mut shares = GaloisRingIrisCodeShare::encode_mask_code(
+                        &iris.mask,
+                        &mut StdRng::seed_from_u64(RNG_SEED),
+                    );
+                    let raw = shares[i].coefs;
+                    shares[i].preprocess_iris_code_query_share();
+                    (raw, shares[i].coefs)
+                })
+                .unzip();
+            let raw_queries = raw_queries.into_iter().flatten().collect_vec();
+            let queries = queries.into_iter().flatten().collect_vec();
+
+            let mut engine = ShareDB::init(
+                0,
+                device_manager.clone(),
+                DB_SIZE,
+                QUERY_SIZE,
+                IRIS_CODE_LENGTH,
+                ([0u32; 8], [0u32; 8]),
+                vec![],
+            )
+            .unwrap();
+            let streams = device_manager.fork_streams();
+            let blass = device_manager.create_cublas(&streams);
+            let queries = device_manager
+                .htod_transfer_query(
+                    &preprocess_query(&queries),
+                    &streams,
+                    QUERY_SIZE,
+                    IRIS_CODE_LENGTH,
+                )
+                .unwrap();
+            let raw_queries = device_manager
+                .htod_transfer_query(
+                    &preprocess_query(&raw_queries),
+                    &streams,
+                    QUERY_SIZE,
+                    IRIS_CODE_LENGTH,
+                )
+                .unwrap();
+            let query_sums = engine.query_sums(&queries, &streams, &blass);
+            let raw_query_sums = engine.query_sums(&raw_queries, &streams, &blass);
+            let chunk_sizes = engine
+                .self_dot(
+                    &queries,
+                    &raw_queries,
+                    &query_sums,
+                    &raw_query_sums,
+                    &streams,
+                    &blass,
+                )
+                .unwrap();
+            device_manager.await_streams(&streams);
+            assert_eq!(chunk_sizes.iter().sum::<usize>(), QUERY_SIZE);
+
+            // Device `d` holds the columns of its chunk of raw queries
+            let mut start = 0;
+            for (device_idx, &chunk_size) in chunk_sizes.iter().enumerate() {
+                if chunk_size == 0 {
+                    continue;
+                }
+                let mut results = vec![0u16; QUERY_SIZE * chunk_size];
+                engine
+                    .fetch_results(&mut results, &chunk_sizes, device_idx)
+                    .unwrap();
+                for (q, row) in results.chunks(chunk_size).enumerate() {
+                    gpu_result[q * QUERY_SIZE + start..][..chunk_size].copy_from_slice(row);
+                }
+                start += chunk_size;
+            }
+        }
+
+        for (idx, ((a, b), c)) in gpu_results[0]
+            .iter()
+            .zip(&gpu_results[1])
+            .zip(&gpu_results[2])
+            .enumerate()
+        {
+            let (q, r) = (idx / QUERY_SIZE, idx % QUERY_SIZE);
+            assert_eq!(
+                a.wrapping_add(*b).wrapping_add(*c),
+                (db.db[q].mask & db.db[r].mask).count_ones() as u16
+            );
+        }
+    }
+
+    /// Checks that the async fetch returns the same results as the blocking
+    /// one.
+    #[tokio::test]
+    async fn check_fetch_results_async() {
+        let db = random_vec(DB_SIZE, WIDTH, u16::MAX as u32);
+        let query = random_vec(QUERY_SIZE, WIDTH, u16::MAX as u32);
+        let device_manager = Arc::new(DeviceManager::init());
+        let n_devices = device_manager.device_count();
+
+        let mut engine = ShareDB::init(
+            0,
+            device_manager.clone(),
+            DB_SIZE,
+            QUERY_SIZE,
+            IRIS_CODE_LENGTH,
+            ([0u32; 8], [0u32; 8]),
+            vec![],
+        )
+        .unwrap();
+        let streams = device_manager.fork_streams();
+        let blass = device_manager.create_cublas(&streams);
+        let preprocessed_query = device_manager
+            .htod_transfer_query(
+                &preprocess_query(&query),
+                &streams,
+                QUERY_SIZE,
+                IRIS_CODE_LENGTH,
+            )
+            .unwrap();
+        let mut db_slices = engine.alloc_db(DB_SIZE);
+        let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
+        let query_sums = engine.query_sums(&preprocessed_query, &streams, &blass);
+        engine
+            .dot(
+                &preprocessed_query,
+                &db_slices.code_gr,
+                &db_sizes,
+                0,
+                &streams,
+                &blass,
+            )
+            .unwrap();
+        engine
+            .dot_reduce(&query_sums, &db_slices.code_sums_gr, &db_sizes, 0, &streams)
+            .unwrap();
+
+        for device_idx in 0..n_devices {
+            let results = engine
+                .fetch_results_async(&db_sizes, device_idx, &streams)
+                .unwrap()
+                .await
+                .unwrap();
+
+            let mut expected = vec![0u16; db_sizes[device_idx] * QUERY_SIZE];
+            engine
+                .fetch_results(&mut expected, &db_sizes, device_idx)
+                .unwrap();
+            assert_eq!(results, expected);
+        }
+    }
+
+    /// Checks that streaming the records in batches loads the same DB as
+    /// loading it at once.
+    #[tokio::test]
+    async fn check_load_db_from_stream() {
+        const BATCH_SIZE: usize = 7;
+        let db = random_vec(DB_SIZE, WIDTH, u16::MAX as u32);
+        let device_manager = Arc::new(DeviceManager::init());
+
+        let engine = ShareDB::init(
+            0,
+            device_manager.clone(),
+            DB_SIZE,
+            QUERY_SIZE,
+            IRIS_CODE_LENGTH,
+            ([0u32; 8], [0u32; 8]),
+            vec![],
+        )
+        .unwrap();
+
+        let mut db_slices = engine.alloc_db(DB_SIZE);
+        let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
+
+        let mut streamed_db_slices = engine.alloc_db(DB_SIZE);
+        let rows = futures::stream::iter(db.chunks(WIDTH).map(Ok::<_, eyre::Report>));
+        let streamed_db_sizes = engine
+            .load_db_from_stream(&mut streamed_db_slices, 0, BATCH_SIZE, rows)
+            .await
+            .unwrap();
+        assert_eq!(db_sizes, streamed_db_sizes);
+
+        for device_idx in 0..device_manager.device_count() {
+            assert_eq!(
+                engine.db_digest(&db_slices, &db_sizes, device_idx).unwrap(),
+                engine
+                    .db_digest(&streamed_db_slices, &streamed_db_sizes, device_idx)
+                    .unwrap()
+            );
+        }
+
+        // Records of the wrong length are rejected
+        let rows = futures::stream::iter([Ok::<_, eyre::Report>(&db[..WIDTH - 1])]);
+        assert!(engine
+            .load_db_from_stream(&mut streamed_db_slices, 0, BATCH_SIZE, rows)
+            .await
+            .is_err());
+    }
+
+    /// Checks that the segmented dot gives the same results as the whole-batch
+    /// one and lets other tasks run between the segments.
+    #[tokio::test]
+    async fn check_dot_segmented() {
+        const SEGMENT_LENGTH: usize = 12;
+        let db = random_vec(DB_SIZE, WIDTH, u16::MAX as u32);
+        let query = random_vec(QUERY_SIZE, WIDTH, u16::MAX as u32);
+        let device_manager = Arc::new(DeviceManager::init());
+
+        let mut results = vec![];
+        let mut yields = 0;
+        for segmented in [false, true] {
+            let mut engine = ShareDB::init(
+                0,
+                device_manager.clone(),
+                DB_SIZE,
+                QUERY_SIZE,
+                IRIS_CODE_LENGTH,
+                ([0u32; 8], [0u32; 8]),
+                vec![],
+            )
+            .unwrap();
+            let streams = device_manager.fork_streams();
+            let blass = device_manager.create_cublas(&streams);
+            let preprocessed_query = device_manager
+                .htod_transfer_query(
+                    &preprocess_query(&query),
+                    &streams,
+                    QUERY_SIZE,
+                    IRIS_CODE_LENGTH,
+                )
+                .unwrap();
+            let mut db_slices = engine.alloc_db(DB_SIZE);
+            let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
+            let query_sums = engine.query_sums(&preprocessed_query, &streams, &blass);
+
+            if segmented {
+                let counter = Arc::new(AtomicUsize::new(0));
+                let ticker = tokio::spawn({
+                    let counter = counter.clone();
+                    async move {
+                        loop {
+                            counter.fetch_add(1, Ordering::Relaxed);
+                            tokio::task::yield_now().await;
+                        }
+                    }
+                });
+                engine
+                    .dot_segmented(
+                        &preprocessed_query,
+                        &db_slices.code_gr,
+                        &db_sizes,
+                        0,
+                        &streams,
+                        &blass,
+                        SEGMENT_LENGTH,
+                    )
+                    .await
+                    .unwrap();
+                ticker.abort();
+                yields = counter.load(Ordering::Relaxed);
+            } else {
+                engine
+                    .dot(
+                        &preprocessed_query,
+                        &db_slices.code_gr,
+                        &db_sizes,
+                        0,
+                        &streams,
+                        &blass,
+                    )
+                    .unwrap();
+            }
+            engine
+                .dot_reduce(&query_sums, &db_slices.code_sums_gr, &db_sizes, 0, &streams)
+                .unwrap();
+            device_manager.await_streams(&streams);
+            results.push(engine.fetch_all_results(&db_sizes).unwrap());
+        }
+
+        assert_eq!(results[0], results[1]);
+        // The other task ran between the segments
+        assert!(
+            yields >= QUERY_SIZE.div_ceil(SEGMENT_LENGTH) - 1,
+            "{}",
+            yields
+        );
+    }
+
+    /// Checks that rotating by 0 gives the plain queries and a nonzero
+    /// rotation the manually rotated ones.
+    #[test]
+    fn check_dot_rotations() {
+        const ROTATIONS: [isize; 2] = [0, 3];
+        let n_queries = QUERY_SIZE / ROTATIONS.len();
+        let db = random_vec(DB_SIZE, WIDTH, u16::MAX as u32);
+        let query = random_vec(n_queries, WIDTH, u16::MAX as u32);
+        let device_manager = Arc::new(DeviceManager::init());
+
+        let engine = |rotations: Vec<isize>| {
+            ShareDB::builder()
+                .device_manager(device_manager.clone())
+                .max_db_length(DB_SIZE)
+                .query_length(QUERY_SIZE)
+                .rotations(rotations)
+                .build()
+                .unwrap()
+        };
+
+        // Every query followed by its copy rotated right by 3
+        let manually_rotated = query
+            .chunks(WIDTH)
+            .flat_map(|q| {
+                let mut rotated = q.to_vec();
+                rotated
+                    .chunks_exact_mut(800)
+                    .for_each(|row| row.rotate_right(3 * 4));
+                q.iter().copied().chain(rotated).collect_vec()
+            })
+            .collect_vec();
+
+        let mut rotated_engine = engine(ROTATIONS.to_vec());
+        assert_eq!(
+            rotated_engine.rotate_queries(&query).unwrap(),
+            manually_rotated
+        );
+
+        let mut plain_engine = engine(vec![]);
+        let mut results = vec![];
+        for rotated in [true, false] {
+            let share_db = if rotated {
+                &mut rotated_engine
+            } else {
+                &mut plain_engine
+            };
+            let streams = device_manager.fork_streams();
+            let blass = device_manager.create_cublas(&streams);
+            let mut db_slices = share_db.alloc_db(DB_SIZE);
+            let db_sizes = share_db.load_full_db(&mut db_slices, &db).unwrap();
+            let query_sums = if rotated {
+                share_db
+                    .dot_rotations(&query, &db_slices.code_gr, &db_sizes, 0, &streams, &blass)
+                    .unwrap()
+            } else {
+                let queries = device_manager
+                    .htod_transfer_query(
+                        &preprocess_query(&manually_rotated),
+                        &streams,
+                        QUERY_SIZE,
+                        IRIS_CODE_LENGTH,
+                    )
+                    .unwrap();
+                let query_sums = share_db.query_sums(&queries, &streams, &blass);
+                share_db
+                    .dot(&queries, &db_slices.code_gr, &db_sizes, 0, &streams, &blass)
+                    .unwrap();
+                query_sums
+            };
+            share_db
+                .dot_reduce(&query_sums, &db_slices.code_sums_gr, &db_sizes, 0, &streams)
+                .unwrap();
+            device_manager.await_streams(&streams);
+            results.push(share_db.fetch_all_results(&db_sizes).unwrap());
+        }
+        assert_eq!(results[0], results[1]);
+
+        assert!(matches!(
+            plain_engine.rotate_queries(&query),
+            Err(ShareDbError::Dimension(_))
+        ));
+    }
+
+    /// Checks that the engines of the two eyes draw different randomness on
+    /// the same device, even with the same seeds, and that the left one keeps
+    /// the default stream.
+    #[test]
+    fn check_eye_rng_streams() {
+        let device_manager = Arc::new(DeviceManager::init());
+        let masking_stream = |eye: Option<Eye>| {
+            let builder = ShareDB::builder()
+                .device_manager(device_manager.clone())
+                .max_db_length(DB_SIZE)
+                .query_length(QUERY_SIZE)
+                .seeds(([1u32; 8], [2u32; 8]));
+            let mut engine = match eye {
+                Some(eye) => builder.eye(eye),
+                None => builder,
+            }
+            .build()
+            .unwrap();
+            (0..device_manager.device_count())
+                .map(|idx| {
+                    let rng = &mut engine.rngs[idx].0;
+                    rng.fill_rng();
+                    rng.data().unwrap().to_vec()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let default = masking_stream(None);
+        let left = masking_stream(Some(Eye::Left));
+        let right = masking_stream(Some(Eye::Right));
+        assert_eq!(default, left);
+        for (left, right) in left.iter().zip(&right) {
+            assert_ne!(left, right);
+        }
+    }
+
+    /// Checks that an engine from the builder behaves like one from the
+    /// positional `init`.
+    #[test]
+    fn check_builder() {
+        let db = random_vec(DB_SIZE, WIDTH, u16::MAX as u32);
+        let query = random_vec(QUERY_SIZE, WIDTH, u16::MAX as u32);
+        let device_manager = Arc::new(DeviceManager::init());
+        let n_devices = device_manager.device_count();
+        let seeds = ([1u32; 8], [2u32; 8]);
+
+        assert!(matches!(
+            ShareDB::builder()
+                .device_manager(device_manager.clone())
+                .query_length(QUERY_SIZE)
+                .build(),
+            Err(ShareDbError::MissingParameter("max_db_length"))
+        ));
+
+        let engines = [
+            ShareDB::init(
+                0,
+                device_manager.clone(),
+                DB_SIZE,
+                QUERY_SIZE,
+                IRIS_CODE_LENGTH,
+                seeds,
+                vec![],
+            )
+            .unwrap(),
+            ShareDB::builder()
+                .device_manager(device_manager.clone())
+                .max_db_length(DB_SIZE)
+                .query_length(QUERY_SIZE)
+                .seeds(seeds)
+                .build()
+                .unwrap(),
+        ];
+
+        let streams = device_manager.fork_streams();
+        let blass = device_manager.create_cublas(&streams);
+        let preprocessed_query = device_manager
+            .htod_transfer_query(
+                &preprocess_query(&query),
+                &streams,
+                QUERY_SIZE,
+                IRIS_CODE_LENGTH,
+            )
+            .unwrap();
+
+        let results = engines
+            .into_iter()
+            .map(|mut engine| {
+                let mut db_slices = engine.alloc_db(DB_SIZE);
+                let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
+                let query_sums = engine.query_sums(&preprocessed_query, &streams, &blass);
+                engine
+                    .dot(
+                        &preprocessed_query,
+                        &db_slices.code_gr,
+                        &db_sizes,
+                        0,
+                        &streams,
+                        &blass,
+                    )
+                    .unwrap();
+                engine
+                    .dot_reduce(&query_sums, &db_slices.code_sums_gr, &db_sizes, 0, &streams)
+                    .unwrap();
+                device_manager.await_streams(&streams);
+
+                (0..n_devices)
+                    .map(|d| {
+                        let mut result = vec![0u16; db_sizes[d] * QUERY_SIZE];
+                        engine.fetch_results(&mut result, &db_sizes, d).unwrap();
+                        result
+                    })
+                    .collect_vec()
+            })
+            .collect_vec();
+        assert_eq!(results[0], results[1]);
+    }
+
+    /// Checks that a deleted record has a zero code and mask distance to every
+    /// query, so it can never match, not even against itself.
+    #[test]
+    fn check_mark_deleted() {
+        const DELETED: usize = 0;
+        let mut rng = StdRng::seed_from_u64(RNG_SEED);
+        let device_manager = Arc::new(DeviceManager::init());
+        let n_devices = device_manager.device_count();
+        let db = IrisDB::new_random_par(DB_SIZE, &mut rng);
+
+        let mut results_codes = vec![vec![0u16; DB_SIZE / n_devices * QUERY_SIZE]; 3];
+        let mut results_masks = vec![vec![0u16; DB_SIZE / n_devices * QUERY_SIZE]; 3];
+        let mut db_sizes = vec![];
+
+        for party_id in 0..3 {
+            let codes_db = db
+                .db
+                .iter()
+                .flat_map(|iris| {
+                    GaloisRingIrisCodeShare::encode_iris_code(
+                        &iris.code,
+                        &iris.mask,
+                        &mut StdRng::seed_from_u64(RNG_SEED),
+                    )[party_id]
+                        .coefs
+                })
+                .collect::<Vec<_>>();
+            let masks_db = db
+                .db
+                .iter()
+                .flat_map(|iris| {
+                    let mask: GaloisRingTrimmedMaskCodeShare =
+                        GaloisRingIrisCodeShare::encode_mask_code(
+                            &iris.mask,
+                            &mut StdRng::seed_from_u64(RNG_SEED),
+                        )[party_id]
+                            .clone()
+                            .into();
+                    mask.coefs
+                })
+                .collect::<Vec<_>>();
+            let code_queries = db.db[0..QUERY_SIZE]
+                .iter()
+                .flat_map(|iris| {
+                    let mut shares = GaloisRingIrisCodeShare::encode_iris_code(
+                        &iris.code,
+                        &iris.mask,
+                        &mut StdRng::seed_from_u64(RNG_SEED),
+                    );
+                    shares[party_id].preprocess_iris_code_query_share();
+                    shares[party_id].coefs
+                })
+                .collect::<Vec<_>>();
+            let mask_queries = db.db[0..QUERY_SIZE]
+                .iter()
+                .flat_map(|iris| {
+                    let mut shares = GaloisRingIrisCodeShare::encode_mask_code(
+                        &iris.mask,
+                        &mut StdRng::seed_from_u64(RNG_SEED),
+                    );
+                    shares[party_id].preprocess_iris_code_query_share();
+                    let mask: GaloisRingTrimmedMaskCodeShare = shares[party_id].clone().into();
+                    mask.coefs
+                })
+                .collect::<Vec<_>>();
+
+            let mut codes_engine = ShareDB::init(
+                party_id,
+                device_manager.clone(),
+                DB_SIZE,
+                QUERY_SIZE,
+                IRIS_CODE_LENGTH,
+                ([0u32; 8], [0u32; 8]),
+                vec![],
+            )
+            .unwrap();
+            let mut masks_engine = ShareDB::init(
+                party_id,
+                device_manager.clone(),
+                DB_SIZE,
+                QUERY_SIZE,
+                MASK_CODE_LENGTH,
+                ([0u32; 8], [0u32; 8]),
+                vec![],
+            )
+            .unwrap();
+
+            let streams = device_manager.fork_streams();
+            let blass = device_manager.create_cublas(&streams);
+            let code_query = device_manager
+                .htod_transfer_query(
+                    &preprocess_query(&code_queries),
+                    &streams,
+                    QUERY_SIZE,
+                    IRIS_CODE_LENGTH,
+                )
+                .unwrap();
+            let mask_query = device_manager
+                .htod_transfer_query(
+                    &preprocess_query(&mask_queries),
+                    &streams,
+                    QUERY_SIZE,
+                    MASK_CODE_LENGTH,
+                )
+                .unwrap();
+            let code_query_sums = codes_engine.query_sums(&code_query, &streams, &blass);
+            let mask_query_sums = masks_engine.query_sums(&mask_query, &streams, &blass);
+            let mut code_db_slices = codes_engine.alloc_db(DB_SIZE);
+            db_sizes = codes_engine
+                .load_full_db(&mut code_db_slices, &codes_db)
+                .unwrap();
+            let mut mask_db_slices = masks_engine.alloc_db(DB_SIZE);
+            masks_engine
+                .load_full_db(&mut mask_db_slices, &masks_db)
+                .unwrap();
+
+            codes_engine
+                .mark_deleted(&mut code_db_slices, DELETED)
+                .unwrap();
+            masks_engine
+                .mark_deleted(&mut mask_db_slices, DELETED)
+                .unwrap();
+            assert!(codes_engine
+                .mark_deleted(&mut code_db_slices, DB_SIZE)
+                .is_err());
+
+            codes_engine
+                .dot(
+                    &code_query,
+                    &code_db_slices.code_gr,
+                    &db_sizes,
+                    0,
+                    &streams,
+                    &blass,
+                )
+                .unwrap();
+            masks_engine
+                .dot(
+                    &mask_query,
+                    &mask_db_slices.code_gr,
+                    &db_sizes,
+                    0,
+                    &streams,
+                    &blass,
+                )
+                .unwrap();
+            codes_engine
+                .dot_reduce(
+                    &code_query_sums,
+                    &code_db_slices.code_sums_gr,
+                    &db_sizes,
+                    0,
+                    &streams,
+                )
+                .unwrap();
+            masks_engine
+                .dot_reduce_and_multiply(
+                    &mask_query_sums,
+                    &mask_db_slices.code_sums_gr,
+                    &db_sizes,
+                    0,
+                    &streams,
+                    2,
+                )
+                .unwrap();
+            device_manager.await_streams(&streams);
+
+            let device_idx = DELETED % n_devices;
+            codes_engine
+                .fetch_results(&mut results_codes[party_id], &db_sizes, device_idx)
+                .unwrap();
+            masks_engine
+                .fetch_results(&mut results_masks[party_id], &db_sizes, device_idx)
+                .unwrap();
+        }
+
+        let row = DELETED / n_devices;
+        for query_idx in 0..QUERY_SIZE {
+            let i = query_idx * db_sizes[DELETED % n_devices] + row;
+            let code = results_codes[0][i]
+                .wrapping_add(results_codes[1][i])
+                .wrapping_add(results_codes[2][i]);
+            let mask = results_masks[0][i]
+                .wrapping_add(results_masks[1][i])
+                .wrapping_add(results_masks[2][i]);
+            assert_eq!((code, mask), (0, 0));
+        }
+    }
+
+    /// Checks that the kernel source is only compiled once, no matter how many
+    /// engines are constructed.
+    #[test]
+    fn check_ptx_compiled_once() {
+        let device_manager = Arc::new(DeviceManager::init());
+        let engines = (0..2)
+            .map(|_| {
+                ShareDB::init(
+                    0,
+                    device_manager.clone(),
+                    DB_SIZE,
+                    QUERY_SIZE,
+                    IRIS_CODE_LENGTH,
+                    ([0u32; 8], [0u32; 8]),
+                    vec![],
+                )
+                .unwrap()
+            })
+            .collect_vec();
+        assert_eq!(engines.len(), 2);
+        assert_eq!(compile_count(PTX_SRC), 1);
+    }
+
+    /// Checks that launching the per-device work from the thread pool gives
+    /// the same results as the serial path.
+    #[test]
+    fn check_parallel_devices() {
+        let db = random_vec(DB_SIZE, WIDTH, u16::MAX as u32);
+        let query = random_vec(QUERY_SIZE, WIDTH, u16::MAX as u32);
+        let device_manager = Arc::new(DeviceManager::init());
+        let n_devices = device_manager.device_count();
+
+        let mut engine = ShareDB::init(
+            0,
+            device_manager.clone(),
+            DB_SIZE,
+            QUERY_SIZE,
+            IRIS_CODE_LENGTH,
+            ([0u32; 8], [0u32; 8]),
+            vec![],
+        )
+        .unwrap();
+        let streams = device_manager.fork_streams();
+        let blass = device_manager.create_cublas(&streams);
+        let preprocessed_query = device_manager
+            .htod_transfer_query(
+                &preprocess_query(&query),
+                &streams,
+                QUERY_SIZE,
+                IRIS_CODE_LENGTH,
+            )
+            .unwrap();
+        let mut db_slices = engine.alloc_db(DB_SIZE);
+        let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
+
+        let mut results = vec![];
+        for parallel in [false, true] {
+            engine.set_parallel_devices(parallel);
+            let query_sums = engine.query_sums(&preprocessed_query, &streams, &blass);
+            engine
+                .dot(
+                    &preprocessed_query,
+                    &db_slices.code_gr,
+                    &db_sizes,
+                    0,
+                    &streams,
+                    &blass,
+                )
+                .unwrap();
+            engine
+                .dot_reduce(&query_sums, &db_slices.code_sums_gr, &db_sizes, 0, &streams)
+                .unwrap();
+            device_manager.await_streams(&streams);
+
+            results.push(
+                (0..n_devices)
+                    .map(|d| {
+                        let mut result = vec![0u16; db_sizes[d] * QUERY_SIZE];
+                        engine.fetch_results(&mut result, &db_sizes, d).unwrap();
+                        result
+                    })
+                    .collect_vec(),
+            );
+        }
+        assert_eq!(results[0], results[1]);
+    }
+
+    /// Checks that `dot` advances the RNG counter by the rounded-up amount of
+    /// masking randomness it draws.
+    #[test]
+    fn check_rng_blocks_consumed() {
+        let db = random_vec(DB_SIZE, WIDTH, u16::MAX as u32);
+        let query = random_vec(QUERY_SIZE, WIDTH, u16::MAX as u32);
+        let device_manager = Arc::new(DeviceManager::init());
+        let n_devices = device_manager.device_count();
+
+        let mut engine = ShareDB::init(
+            0,
+            device_manager.clone(),
+            DB_SIZE,
+            QUERY_SIZE,
+            IRIS_CODE_LENGTH,
+            ([0u32; 8], [0u32; 8]),
+            single_rank_comms(&device_manager),
+        )
+        .unwrap();
+        let preprocessed_query = preprocess_query(&query);
+        let streams = device_manager.fork_streams();
+        let blass = device_manager.create_cublas(&streams);
+        let preprocessed_query = device_manager
+            .htod_transfer_query(&preprocessed_query, &streams, QUERY_SIZE, IRIS_CODE_LENGTH)
+            .unwrap();
+        let mut db_slices = engine.alloc_db(DB_SIZE);
+        let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
+
+        let before = (0..n_devices)
+            .map(|i| engine.rng_blocks_consumed(i))
+            .collect::<Vec<_>>();
+        engine
+            .dot(
+                &preprocessed_query,
+                &db_slices.code_gr,
+                &db_sizes,
+                0,
+                &streams,
+                &blass,
+            )
+            .unwrap();
+        device_manager.await_streams(&streams);
+
+        for i in 0..n_devices {
+            let bytes = (db_sizes[i] * QUERY_SIZE).div_ceil(64) * 64;
+            assert_eq!(
+                engine.rng_blocks_consumed(i) - before[i],
+                (bytes / 64) as u64
+            );
+        }
+    }
+
+    /// Checks that repeated remote `dot`s refill the masking randomness in
+    /// place instead of allocating device memory for it.
+    #[test]
+    fn check_repeated_dot_memory() {
+        let db = random_vec(DB_SIZE, WIDTH, u16::MAX as u32);
+        let query = random_vec(QUERY_SIZE, WIDTH, u16::MAX as u32);
+        let device_manager = Arc::new(DeviceManager::init());
+        let n_devices = device_manager.device_count();
+
+        let mut engine = ShareDB::init(
+            0,
+            device_manager.clone(),
+            DB_SIZE,
+            QUERY_SIZE,
+            IRIS_CODE_LENGTH,
+            ([0u32; 8], [0u32; 8]),
+            single_rank_comms(&device_manager),
+        )
+        .unwrap();
+        let streams = device_manager.fork_streams();
+        let blass = device_manager.create_cublas(&streams);
+        let preprocessed_query = device_manager
+            .htod_transfer_query(
+                &preprocess_query(&query),
+                &streams,
+                QUERY_SIZE,
+                IRIS_CODE_LENGTH,
+            )
+            .unwrap();
+        let mut db_slices = engine.alloc_db(DB_SIZE);
+        let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
+
+        let mut dot = || {
+            engine
+                .dot(
+                    &preprocessed_query,
+                    &db_slices.code_gr,
+                    &db_sizes,
+                    0,
+                    &streams,
+                    &blass,
+                )
+                .unwrap();
+            device_manager.await_streams(&streams);
+        };
+        let free_memory = || {
+            (0..n_devices)
+                .map(|idx| {
+                    device_manager.device(idx).bind_to_thread().unwrap();
+                    result::mem_get_info().unwrap().0
+                })
+                .collect_vec()
+        };
+
+        // The first run may set up cuBLAS workspaces
+        dot();
+        let before = free_memory();
+        for _ in 0..10 {
+            dot();
+        }
+        assert_eq!(free_memory(), before);
+    }
+
+    #[test]
+    fn check_derive_device_seeds() {
+        let seeds = ([1u32; 8], [2u32; 8]);
+        let derived = derive_device_seeds(seeds, 4);
+        assert_eq!(derived, derive_device_seeds(seeds, 4));
+        assert_eq!(derived[..2], derive_device_seeds(seeds, 2)[..]);
+        let all = derived.iter().flat_map(|&(a, b)| [a, b]).collect_vec();
+        assert!(all.iter().all_unique());
+        assert!(!all.contains(&seeds.0) && !all.contains(&seeds.1));
+    }
+
+    /// Checks that per-device seeds give every device its own masking stream,
+    /// while the masks of the three parties still cancel out.
+    #[test]
+    fn check_device_seeds() {
+        let db = random_vec(DB_SIZE, WIDTH, u16::MAX as u32);
+        let query = random_vec(QUERY_SIZE, WIDTH, u16::MAX as u32);
+        let device_manager = Arc::new(DeviceManager::init());
+        let n_devices = device_manager.device_count();
+        let streams = device_manager.fork_streams();
+        let blass = device_manager.create_cublas(&streams);
+        let preprocessed_query = device_manager
+            .htod_transfer_query(
+                &preprocess_query(&query),
+                &streams,
+                QUERY_SIZE,
+                IRIS_CODE_LENGTH,
+            )
+            .unwrap();
+
+        // party_seeds[party][device], party i shares its first seed with party i + 1
+        let mut rng = StdRng::seed_from_u64(RNG_SEED);
+        let party_seeds = (0..3)
+            .map(|_| (0..n_devices).map(|_| rng.gen::<[u32; 8]>()).collect_vec())
+            .collect_vec();
+
+        // results[party][device], party 3 is an unmasked reference
+        let mut results = vec![];
+        for party_id in 0..4 {
+            let (seeds, comms) = if party_id < 3 {
+                let seeds = (0..n_devices)
+                    .map(|d| (party_seeds[party_id][d], party_seeds[(party_id + 2) % 3][d]))
+                    .collect();
+                (seeds, single_rank_comms(&device_manager))
+            } else {
+                (vec![([0u32; 8], [0u32; 8]); n_devices], vec![])
+            };
+            let mut engine = ShareDB::init_with_device_seeds(
+                party_id % 3,
+                device_manager.clone(),
+                DB_SIZE,
+                QUERY_SIZE,
+                IRIS_CODE_LENGTH,
+                seeds,
+                comms,
+            )
+            .unwrap();
+            let query_sums = engine.query_sums(&preprocessed_query, &streams, &blass);
+            let mut db_slices = engine.alloc_db(DB_SIZE);
+            let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
+            engine
+                .dot(
+                    &preprocessed_query,
+                    &db_slices.code_gr,
+                    &db_sizes,
+                    0,
+                    &streams,
+                    &blass,
+                )
+                .unwrap();
+            engine
+                .dot_reduce(&query_sums, &db_slices.code_sums_gr, &db_sizes, 0, &streams)
+                .unwrap();
+            device_manager.await_streams(&streams);
+
+            results.push(
+                (0..n_devices)
+                    .map(|d| {
+                        let mut result = vec![0u16; db_sizes[d] * QUERY_SIZE];
+                        engine.fetch_results(&mut result, &db_sizes, d).unwrap();
+                        result
+                    })
+                    .collect_vec(),
+            );
+        }
+
+        let masks = (0..3)
+            .map(|p| {
+                (0..n_devices)
+                    .map(|d| {
+                        izip!(&results[p][d], &results[3][d])
+                            .map(|(&x, &y)| x.wrapping_sub(y))
+                            .collect_vec()
+                    })
+                    .collect_vec()
+            })
+            .collect_vec();
+
+        for d in 0..n_devices {
+            for i in 0..masks[0][d].len() {
+                let sum = masks[0][d][i]
+                    .wrapping_add(masks[1][d][i])
+                    .wrapping_add(masks[2][d][i]);
+                assert_eq!(sum, 0);
+            }
+        }
+        for d in 1..n_devices {
+            assert_ne!(masks[0][0], masks[0][d]);
+        }
+    }
+
+    /// Checks that fetching more results than the device buffer holds, e.g.
+    /// with stale DB sizes, returns an error instead of panicking.
+    #[test]
+    fn check_fetch_results_bounds() {
+        let device_manager = Arc::new(DeviceManager::init());
+        let n_devices = device_manager.device_count();
+        let engine = ShareDB::init(
+            0,
+            device_manager.clone(),
+            DB_SIZE,
+            QUERY_SIZE,
+            IRIS_CODE_LENGTH,
+            ([0u32; 8], [0u32; 8]),
+            vec![],
+        )
+        .unwrap();
+
+        let db_sizes = vec![DB_SIZE * 2; n_devices];
+        let mut results = vec![0u16; DB_SIZE * 2 * QUERY_SIZE];
+        match engine.fetch_results(&mut results, &db_sizes, 0) {
+            Err(ShareDbError::Dimension(msg)) => assert!(msg.contains("result buffer"), "{}", msg),
+            other => panic!("expected a dimension error, got {:?}", other),
+        }
+    }
+
+    /// Checks the intermediate GEMM results against a plaintext `i32` matmul
+    /// of the limbs.
+    #[test]
+    #[cfg(feature = "debug_checks")]
+    fn check_debug_fetch_intermediate() {
+        use super::{LIMBS, LIMB_BITS};
+
+        let device_manager = Arc::new(DeviceManager::init());
+        let n_devices = device_manager.device_count();
+        let db_size = 4 * n_devices;
+        let db = random_vec(db_size, WIDTH, u16::MAX as u32);
+        let query = random_vec(QUERY_SIZE, WIDTH, u16::MAX as u32);
+
+        let mut engine = ShareDB::init(
+            0,
+            device_manager.clone(),
+            db_size,
+            QUERY_SIZE,
+            IRIS_CODE_LENGTH,
+            ([0u32; 8], [0u32; 8]),
+            vec![],
+        )
+        .unwrap();
+        let streams = device_manager.fork_streams();
+        let blass = device_manager.create_cublas(&streams);
+        let preprocessed_query = device_manager
+            .htod_transfer_query(
+                &preprocess_query(&query),
+                &streams,
+                QUERY_SIZE,
+                IRIS_CODE_LENGTH,
+            )
+            .unwrap();
+        let mut db_slices = engine.alloc_db(db_size);
+        let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
+        engine
+            .dot(
+                &preprocessed_query,
+                &db_slices.code_gr,
+                &db_sizes,
+                0,
+                &streams,
+                &blass,
+            )
+            .unwrap();
+
+        let dot = |a: &[u16], b: &[u16]| {
+            let mut sum = 0i32;
+            for i in 0..LIMBS {
+                for j in 0..LIMBS - i {
+                    let limbs = a
+                        .iter()
+                        .zip(b)
+                        .map(|(&x, &y)| limb(x, i) as i32 * limb(y, j) as i32)
+                        .fold(0i32, i32::wrapping_add);
+                    sum = sum.wrapping_add(limbs.wrapping_shl((LIMB_BITS * (i + j)) as u32));
+                }
+            }
+            sum
+        };
+
+        for device_idx in 0..n_devices {
+            let records = db
+                .chunks(WIDTH)
+                .skip(device_idx)
+                .step_by(n_devices)
+                .collect_vec();
+            let expected = query
+                .chunks(WIDTH)
+                .flat_map(|q| records.iter().map(move |record| dot(record, q)))
+                .collect_vec();
+            assert_eq!(
+                engine
+                    .debug_fetch_intermediate(device_idx, &db_sizes)
+                    .unwrap(),
+                expected
+            );
+        }
+    }
+
+    /// Checks that `reconstruct_results` after `reshare_results` matches
+    /// adding up the three parties' fetched results by hand, as done in
+    /// `check_shared_distances`. Needs at least three devices.
+    #[test]
+    fn check_reconstruct_results() {
+        let device_managers = DeviceManager::init()
+            .split_into_n_chunks(3)
+            .expect("have at least 3 devices");
+        let n_devices = device_managers[0].device_count();
+        let ids = (0..n_devices).map(|_| Id::new().unwrap()).collect_vec();
+        let seeds = [[0u32; 8], [1u32; 8], [2u32; 8]];
+
+        let handles = device_managers
+            .into_iter()
+            .enumerate()
+            .map(|(party_id, device_manager)| {
+                let ids = ids.clone();
+                std::thread::spawn(move || {
+                    let device_manager = Arc::new(device_manager);
+                    let comms = device_manager
+                        .instantiate_network_from_ids(party_id, &ids)
+                        .unwrap();
+                    let mut engine = ShareDB::init(
+                        party_id,
+                        device_manager.clone(),
+                        DB_SIZE,
+                        QUERY_SIZE,
+                        IRIS_CODE_LENGTH,
+                        (seeds[party_id], seeds[(party_id + 2) % 3]),
+                        comms,
+                    )
+                    .unwrap();
+                    let streams = device_manager.fork_streams();
+                    let db_sizes = vec![DB_SIZE / n_devices; n_devices];
+
+                    let mut rng = StdRng::seed_from_u64(RNG_SEED + party_id as u64);
+                    for idx in 0..n_devices {
+                        let data = (0..db_sizes[idx] * QUERY_SIZE * 2)
+                            .map(|_| rng.gen::<u8>())
+                            .collect_vec();
+                        device_manager
+                            .device(idx)
+                            .htod_sync_copy_into(
+                                &data,
+                                &mut engine.results[idx].slice_mut(..data.len()),
+                            )
+                            .unwrap();
+                    }
+
+                    engine.reshare_results(&db_sizes, &streams).unwrap();
+                    device_manager.await_streams(&streams);
+                    (
+                        engine.fetch_all_results(&db_sizes).unwrap(),
+                        engine.reconstruct_results(&db_sizes).unwrap(),
+                    )
+                })
+            })
+            .collect_vec();
+
+        let (fetched, reconstructed): (Vec<_>, Vec<_>) = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .unzip();
+
+        let manual = izip!(&fetched[0], &fetched[1], &fetched[2])
+            .map(|(a, b, c)| a.wrapping_add(*b).wrapping_add(*c))
+            .collect_vec();
+        for (party_id, reconstructed) in reconstructed.iter().enumerate() {
+            assert_eq!(reconstructed, &manual, "party {}", party_id);
+        }
+    }
+
+    /// Checks that `verify_reshare` accepts correctly reshared results and
+    /// detects a corrupted `results_peer`. Needs at least three devices.
+    #[test]
+    #[cfg(feature = "debug_checks")]
+    fn check_verify_reshare() {
+        let device_managers = DeviceManager::init()
+            .split_into_n_chunks(3)
+            .expect("have at least 3 devices");
+        let n_devices = device_managers[0].device_count();
+        let ids = (0..n_devices).map(|_| Id::new().unwrap()).collect_vec();
+        let seeds = [[0u32; 8], [1u32; 8], [2u32; 8]];
+
+        let handles = device_managers
+            .into_iter()
+            .enumerate()
+            .map(|(party_id, device_manager)| {
+                let ids = ids.clone();
+                std::thread::spawn(move || {
+                    let device_manager = Arc::new(device_manager);
+                    let comms = device_manager
+                        .instantiate_network_from_ids(party_id, &ids)
+                        .unwrap();
+                    let mut engine = ShareDB::init(
+                        party_id,
+                        device_manager.clone(),
+                        DB_SIZE,
+                        QUERY_SIZE,
+                        IRIS_CODE_LENGTH,
+                        (seeds[party_id], seeds[(party_id + 2) % 3]),
+                        comms,
+                    )
+                    .unwrap();
+                    let streams = device_manager.fork_streams();
+                    let db_sizes = vec![DB_SIZE / n_devices; n_devices];
+
+                    let mut rng = StdRng::seed_from_u64(RNG_SEED + party_id as u64);
+                    for idx in 0..n_devices {
+                        let data = (0..db_sizes[idx] * QUERY_SIZE * 2)
+                            .map(|_| rng.gen::<u8>())
+                            .collect_vec();
+                        device_manager
+                            .device(idx)
+                            .htod_sync_copy_into(
+                                &data,
+                                &mut engine.results[idx].slice_mut(..data.len()),
+                            )
+                            .unwrap();
+                    }
+
+                    engine.reshare_results(&db_sizes, &streams).unwrap();
+                    device_manager.await_streams(&streams);
+                    let valid = engine.verify_reshare(&db_sizes);
+
+                    if party_id == 1 {
+                        let device = device_manager.device(0);
+                        let mut byte = device
+                            .dtoh_sync_copy(&engine.results_peer[0].slice(..1))
+                            .unwrap();
+                        byte[0] ^= 1;
+                        device
+                            .htod_sync_copy_into(&byte, &mut engine.results_peer[0].slice_mut(..1))
+                            .unwrap();
+                    }
+                    let corrupted = engine.verify_reshare(&db_sizes);
+                    (valid, corrupted)
+                })
+            })
+            .collect_vec();
+
+        for (party_id, handle) in handles.into_iter().enumerate() {
+            let (valid, corrupted) = handle.join().unwrap();
+            assert!(valid.is_ok(), "party {}: {:?}", party_id, valid);
+            if party_id == 1 {
+                assert!(matches!(
+                    corrupted,
+                    Err(ShareDbError::ReshareMismatch { device: 0, .. })
+                ));
+            } else {
+                assert!(corrupted.is_ok(), "party {}: {:?}", party_id, corrupted);
+            }
+        }
+    }
+
+    /// Checks that loading the DB from a memory-mapped file produces the same
+    /// device slices as loading it from memory.
+    #[test]
+    fn check_load_full_db_mmap() {
+        let db = random_vec(DB_SIZE, WIDTH, u16::MAX as u32);
+        let device_manager = Arc::new(DeviceManager::init());
+        let n_devices = device_manager.device_count();
+
+        let engine = ShareDB::init(
+            0,
+            device_manager.clone(),
+            DB_SIZE,
+            QUERY_SIZE,
+            IRIS_CODE_LENGTH,
+            ([0u32; 8], [0u32; 8]),
+            vec![],
+        )
+        .unwrap();
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(bytemuck::cast_slice(&db)).unwrap();
+        file.flush().unwrap();
+
+        let mut db_slices = engine.alloc_db(DB_SIZE);
+        let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
+        let mut mmap_db_slices = engine.alloc_db(DB_SIZE);
+        let mmap_db_sizes = engine
+            .load_full_db_mmap(&mut mmap_db_slices, file.path(), DB_SIZE, DB_SIZE)
+            .unwrap();
+        assert_eq!(db_sizes, mmap_db_sizes);
+
+        // A file with the wrong length is rejected
+        assert!(engine
+            .load_full_db_mmap(&mut mmap_db_slices, file.path(), DB_SIZE - 1, DB_SIZE)
+            .is_err());
+
+        for device_idx in 0..n_devices {
+            let len = db_sizes[device_idx] * IRIS_CODE_LENGTH;
+            for (a, b) in [
+                (&db_slices.code_gr.limb_0, &mmap_db_slices.code_gr.limb_0),
+                (&db_slices.code_gr.limb_1, &mmap_db_slices.code_gr.limb_1),
+            ] {
+                let (a, b): (&[i8], &[i8]) = unsafe {
+                    (
+                        std::slice::from_raw_parts(a[device_idx] as *const _, len),
+                        std::slice::from_raw_parts(b[device_idx] as *const _, len),
+                    )
+                };
+                assert_eq!(a, b);
+            }
+        }
+    }
+
+    /// Checks that the reduce kernel produces the same results for different
+    /// block sizes.
+    #[test]
+    fn check_reduce_config() {
+        let db = random_vec(DB_SIZE, WIDTH, u16::MAX as u32);
+        let query = random_vec(QUERY_SIZE, WIDTH, u16::MAX as u32);
+        let device_manager = Arc::new(DeviceManager::init());
+        let n_devices = device_manager.device_count();
+
+        assert!(ReduceConfig::new(100, 0).is_err());
+        assert!(ReduceConfig::new(0, 0).is_err());
+
+        let mut engine = ShareDB::init(
+            0,
+            device_manager.clone(),
+            DB_SIZE,
+            QUERY_SIZE,
+            IRIS_CODE_LENGTH,
+            ([0u32; 8], [0u32; 8]),
+            vec![],
+        )
+        .unwrap();
+        let preprocessed_query = preprocess_query(&query);
+        let streams = device_manager.fork_streams();
+        let blass = device_manager.create_cublas(&streams);
+        let preprocessed_query = device_manager
+            .htod_transfer_query(&preprocessed_query, &streams, QUERY_SIZE, IRIS_CODE_LENGTH)
+            .unwrap();
+        let query_sums = engine.query_sums(&preprocessed_query, &streams, &blass);
+        let mut db_slices = engine.alloc_db(DB_SIZE);
+        let db_sizes = engine.load_full_db(&mut db_slices, &db).unwrap();
+
+        let mut results = vec![];
+        for config in [
+            ReduceConfig::default(),
+            ReduceConfig::new(128, 0).unwrap(),
+            ReduceConfig::new(512, 0).unwrap(),
+        ] {
+            engine
+                .dot(
+                    &preprocessed_query,
+                    &db_slices.code_gr,
+                    &db_sizes,
+                    0,
+                    &streams,
+                    &blass,
+                )
+                .unwrap();
+            engine
+                .dot_reduce_with_config(
+                    &query_sums,
+                    &db_slices.code_sums_gr,
+                    &db_sizes,
+                    0,
+                    &streams,
+                    &config,
+                )
+                .unwrap();
+            device_manager.await_streams(&streams);
+
+            let mut gpu_result = vec![0u16; DB_SIZE / n_devices * QUERY_SIZE];
+            engine.fetch_results(&mut gpu_result, &db_sizes, 0).unwrap();
+            results.push(gpu_result);
+        }
+
+        assert_eq!(results[0], results[1]);
+        assert_eq!(results[0], results[2]);
+    }
+
+    /// Checks the on-GPU distance computation against the host reference.
+    #[test]
+    fn check_compute_distance() {
+        let mut rng = StdRng::seed_from_u64(RNG_SEED);
+        let device_manager = Arc::new(DeviceManager::init());
+        let n_devices = device_manager.device_count();
+        let len = DB_SIZE / n_devices;
+
+        let engine = ShareDB::init(
+            0,
+            device_manager.clone(),
+            DB_SIZE,
+            QUERY_SIZE,
+            IRIS_CODE_LENGTH,
+            ([0u32; 8], [0u32; 8]),
+            vec![],
+        )
+        .unwrap();
+        let streams = device_manager.fork_streams();
+
+        let mut codes = vec![];
+        let mut masks = vec![];
+        let mut codes_gpu = vec![];
+        let mut masks_gpu = vec![];
+        for idx in 0..n_devices {
+            let mask = (0..len)
+                .map(|_| rng.gen_range(1..=MASK_CODE_LENGTH as i16))
+                .collect::<Vec<_>>();
+            let code = mask
+                .iter()
+                .map(|&m| rng.gen_range(-m..=m))
+                .collect::<Vec<_>>();
+            let mask = mask.into_iter().map(|m| m as u16).collect::<Vec<_>>();
+            let code = code.into_iter().map(|c| c as u16).collect::<Vec<_>>();
+            let device = device_manager.device(idx);
+            codes_gpu.push(device.htod_sync_copy(&code).unwrap());
+            masks_gpu.push(device.htod_sync_copy(&mask).unwrap());
+            codes.push(code);
+            masks.push(mask);
+        }
+
+        let dists = engine.compute_distance(
+            &codes_gpu.iter().map(|c| c.slice(..)).collect_vec(),
+            &masks_gpu.iter().map(|m| m.slice(..)).collect_vec(),
+            &vec![len; n_devices],
+            &streams,
+        );
+        device_manager.await_streams(&streams);
+
+        for idx in 0..n_devices {
+            let gpu_dists = device_manager
+                .device(idx)
+                .dtoh_sync_copy(&dists[idx])
+                .unwrap();
+            for ((&code, &mask), &dist) in codes[idx].iter().zip(&masks[idx]).zip(&gpu_dists) {
+                let reference = 0.5f64 - (code as i16) as f64 / (2f64 * mask as f64);
+                assert_float_eq!(dist, reference, abs <= 1e-6);
+            }
+        }
+    }
 }
@@ -0,0 +1,207 @@
+//! Helpers shared by the criterion benches, behind the `bench` feature.
+
+use crate::dot::ROTATIONS;
+use std::{env, str::FromStr};
+
+/// Env var overriding [`BenchParams::db_size`].
+pub const DB_SIZE_ENV: &str = "DB_SIZE";
+/// Env var overriding [`BenchParams::query_size`].
+pub const QUERY_SIZE_ENV: &str = "QUERY_SIZE";
+/// Env var overriding [`BenchParams::width`].
+pub const WIDTH_ENV: &str = "WIDTH";
+/// Env var with the graphics clock in MHz to lock all GPUs to while benching,
+/// see [`lock_gpu_clocks`].
+pub const LOCK_GPU_CLOCK_ENV: &str = "LOCK_GPU_CLOCK_MHZ";
+
+/// Problem size of a matmul bench.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchParams {
+    /// Number of DB entries.
+    pub db_size:    usize,
+    /// Number of query rows, i.e. queries times [`ROTATIONS`].
+    pub query_size: usize,
+    /// Length of each entry in `u16`s, used as the code length of the
+    /// `ShareDB`.
+    pub width:      usize,
+}
+
+impl Default for BenchParams {
+    fn default() -> Self {
+        Self {
+            db_size:    10 * (1 << 16),
+            query_size: 31 * 64,
+            width:      12_800,
+        }
+    }
+}
+
+impl BenchParams {
+    /// Reads the params from [`DB_SIZE_ENV`], [`QUERY_SIZE_ENV`] and
+    /// [`WIDTH_ENV`], falling back to the defaults for unset variables.
+    /// Panics on values that are not positive integers, so a typo doesn't
+    /// silently benchmark the default size.
+    pub fn from_env() -> Self {
+        Self::from_lookup(|name| env::var(name).ok())
+    }
+
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Self {
+        let default = Self::default();
+        Self {
+            db_size:    parse_or(DB_SIZE_ENV, lookup(DB_SIZE_ENV), default.db_size),
+            query_size: parse_or(QUERY_SIZE_ENV, lookup(QUERY_SIZE_ENV), default.query_size),
+            width:      parse_or(WIDTH_ENV, lookup(WIDTH_ENV), default.width),
+        }
+    }
+
+    /// Number of query/DB entry comparisons per matmul, counting all
+    /// rotations of a query as one comparison. Query sizes that are not a
+    /// multiple of [`ROTATIONS`] count the partial query as a whole one.
+    pub fn comparisons(&self) -> u64 {
+        (self.db_size * self.query_size.div_ceil(ROTATIONS)) as u64
+    }
+}
+
+/// Graphics clocks locked by [`lock_gpu_clocks_to`], reset to their defaults
+/// when dropped.
+#[derive(Default)]
+pub struct ClockLock {
+    #[cfg(feature = "nvml")]
+    nvml:   Option<nvml_wrapper::Nvml>,
+    locked: Vec<u32>,
+}
+
+impl ClockLock {
+    /// Indices of the devices whose clocks are locked, in NVML order.
+    pub fn locked_devices(&self) -> &[u32] {
+        &self.locked
+    }
+}
+
+impl Drop for ClockLock {
+    fn drop(&mut self) {
+        #[cfg(feature = "nvml")]
+        if let Some(nvml) = &self.nvml {
+            for &idx in &self.locked {
+                if let Err(e) = nvml
+                    .device_by_index(idx)
+                    .and_then(|mut device| device.reset_gpu_locked_clocks())
+                {
+                    tracing::warn!("Failed to reset the clocks of GPU {}: {}", idx, e);
+                }
+            }
+        }
+    }
+}
+
+/// Locks the graphics clocks of all GPUs to [`LOCK_GPU_CLOCK_ENV`] if it is
+/// set, so boost clocks and thermal throttling don't add variance to the
+/// measurements. See [`lock_gpu_clocks_to`].
+pub fn lock_gpu_clocks() -> ClockLock {
+    match env::var(LOCK_GPU_CLOCK_ENV) {
+        Ok(mhz) => lock_gpu_clocks_to(parse_or(LOCK_GPU_CLOCK_ENV, Some(mhz), 0)),
+        Err(_) => ClockLock::default(),
+    }
+}
+
+/// Locks the graphics clocks of all GPUs to `mhz` via NVML, which usually
+/// needs root. Devices that can't be locked are logged and skipped, so the
+/// bench still runs, just with less stable clocks. Without the `nvml`
+/// feature this does nothing.
+#[cfg(feature = "nvml")]
+pub fn lock_gpu_clocks_to(mhz: u32) -> ClockLock {
+    use nvml_wrapper::enums::device::GpuLockedClocksSetting;
+
+    let nvml = match nvml_wrapper::Nvml::init() {
+        Ok(nvml) => nvml,
+        Err(e) => {
+            tracing::warn!("NVML is not available, not locking GPU clocks: {}", e);
+            return ClockLock::default();
+        }
+    };
+    let mut locked = vec![];
+    for idx in 0..nvml.device_count().unwrap_or(0) {
+        let res = nvml.device_by_index(idx).and_then(|mut device| {
+            device.set_gpu_locked_clocks(GpuLockedClocksSetting::Numeric {
+                min_clock_mhz: mhz,
+                max_clock_mhz: mhz,
+            })
+        });
+        match res {
+            Ok(()) => locked.push(idx),
+            Err(e) => tracing::warn!("Failed to lock the clocks of GPU {}: {}", idx, e),
+        }
+    }
+    ClockLock {
+        nvml: Some(nvml),
+        locked,
+    }
+}
+
+/// Fallback of [`lock_gpu_clocks_to`] without the `nvml` feature, which only
+/// logs that the clocks are left alone.
+#[cfg(not(feature = "nvml"))]
+pub fn lock_gpu_clocks_to(mhz: u32) -> ClockLock {
+    tracing::warn!(
+        "Not locking GPU clocks to {} MHz, the nvml feature is disabled",
+        mhz
+    );
+    ClockLock::default()
+}
+
+fn parse_or<T: FromStr + PartialOrd + Default>(name: &str, value: Option<String>, default: T) -> T {
+    match value {
+        Some(value) => match value.trim().parse() {
+            Ok(value) if value > T::default() => value,
+            _ => panic!("{} must be a positive integer, got {:?}", name, value),
+        },
+        None => default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn params(vars: &[(&str, &str)]) -> BenchParams {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        BenchParams::from_lookup(|name| vars.get(name).map(|v| v.to_string()))
+    }
+
+    #[test]
+    fn test_env_override() {
+        assert_eq!(params(&[]), BenchParams::default());
+        assert_eq!(params(&[]).comparisons(), 10 * (1 << 16) * 64);
+
+        let p = params(&[(QUERY_SIZE_ENV, "33"), (WIDTH_ENV, " 1024 ")]);
+        assert_eq!(p, BenchParams {
+            db_size:    BenchParams::default().db_size,
+            query_size: 33,
+            width:      1024,
+        });
+        assert_eq!(p.comparisons(), p.db_size as u64 * 2);
+
+        let p = params(&[(DB_SIZE_ENV, "1000"), (QUERY_SIZE_ENV, "31")]);
+        assert_eq!(p.comparisons(), 1000);
+    }
+
+    #[cfg(not(feature = "nvml"))]
+    #[test]
+    fn test_lock_gpu_clocks_fallback() {
+        let lock = lock_gpu_clocks_to(1_000);
+        assert!(lock.locked_devices().is_empty());
+        drop(lock);
+    }
+
+    #[test]
+    #[should_panic(expected = "DB_SIZE must be a positive integer")]
+    fn test_env_override_invalid() {
+        params(&[(DB_SIZE_ENV, "10k")]);
+    }
+
+    #[test]
+    #[should_panic(expected = "WIDTH must be a positive integer")]
+    fn test_env_override_zero() {
+        params(&[(WIDTH_ENV, "0")]);
+    }
+}
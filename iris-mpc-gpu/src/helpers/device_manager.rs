@@ -11,52 +11,122 @@ use cudarc::{
             stream::{synchronize, wait_event},
         },
         sys::{CUevent, CUevent_flags},
-        CudaDevice, CudaSlice, CudaStream, DevicePtr, DeviceRepr,
+        CudaDevice, CudaSlice, CudaStream, DevicePtr, DeviceRepr, DriverError,
     },
     nccl::Id,
 };
-use std::{sync::Arc, thread::sleep, time::Duration};
+use std::{
+    sync::{mpsc, Arc},
+    thread::sleep,
+    time::{Duration, Instant},
+};
 
 pub const NCCL_START_WAIT_TIME: Duration = Duration::from_secs(5);
 pub const NCCL_START_RETRIES: usize = 5;
 
+/// NVML metrics of a single device. Every field is `None` if NVML or the
+/// respective query is not available.
+#[cfg(feature = "nvml")]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceStats {
+    /// Percent of time over the last sample period a kernel was running.
+    pub utilization:        Option<u32>,
+    /// GPU temperature in degrees Celsius.
+    pub temperature:        Option<u32>,
+    pub memory_used_bytes:  Option<u64>,
+    pub memory_total_bytes: Option<u64>,
+}
+
+#[cfg(feature = "nvml")]
+static NVML: std::sync::OnceLock<Option<nvml_wrapper::Nvml>> = std::sync::OnceLock::new();
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeviceManagerError {
+    #[error(
+        "no CUDA devices found ({0}), check that an NVIDIA GPU and driver are available and \
+         CUDA_VISIBLE_DEVICES is not empty"
+    )]
+    NoCudaDevices(String),
+    #[error(
+        "only {connected} of {expected} NCCL comms were established before the deadline, check \
+         that all peers are up"
+    )]
+    PeersNotReady { connected: usize, expected: usize },
+}
+
+/// An NCCL comm being handed from the thread that established it to the
+/// caller.
+struct SendComm(NcclComm);
+
+// SAFETY: the comm is only handed over once fully initialized and is not used
+// concurrently; NCCL allows using a comm from a different thread than the one
+// that created it.
+unsafe impl Send for SendComm {}
+
 #[derive(Debug, Clone)]
 pub struct DeviceManager {
     devices: Vec<Arc<CudaDevice>>,
 }
 
 impl DeviceManager {
+    /// Like [`Self::try_init`], but panics if no device can be initialized.
     pub fn init() -> Self {
-        let mut devices = vec![];
-        for i in 0..CudaDevice::count().unwrap() {
-            devices.push(CudaDevice::new(i as usize).unwrap());
-        }
-
-        tracing::info!("Found {} devices", devices.len());
+        Self::try_init().unwrap()
+    }
 
-        Self { devices }
+    /// Initializes all visible CUDA devices. Fails with
+    /// [`DeviceManagerError::NoCudaDevices`] if there are none, e.g. on a
+    /// machine without an NVIDIA driver.
+    pub fn try_init() -> eyre::Result<Self> {
+        Self::try_init_with(CudaDevice::count(), CudaDevice::new)
     }
 
     pub fn init_with_streams() -> Self {
+        Self::try_init_with(CudaDevice::count(), CudaDevice::new_with_stream).unwrap()
+    }
+
+    fn try_init_with(
+        count: Result<i32, DriverError>,
+        new_device: impl Fn(usize) -> Result<Arc<CudaDevice>, DriverError>,
+    ) -> eyre::Result<Self> {
+        let count = match count {
+            Ok(0) => {
+                return Err(
+                    DeviceManagerError::NoCudaDevices("device count is zero".to_string()).into(),
+                )
+            }
+            Ok(count) => count,
+            Err(e) => return Err(DeviceManagerError::NoCudaDevices(e.to_string()).into()),
+        };
         let mut devices = vec![];
-        for i in 0..CudaDevice::count().unwrap() {
-            devices.push(CudaDevice::new_with_stream(i as usize).unwrap());
+        for i in 0..count {
+            devices.push(new_device(i as usize)?);
         }
 
         tracing::info!("Found {} devices", devices.len());
 
-        Self { devices }
+        Ok(Self { devices })
     }
 
     /// Splits the devices into n chunks, returning a device manager for each
     /// chunk.
     /// If too few devices are present, returns the original device manager.
+    /// If the device count is not a multiple of `n`, the remaining devices are
+    /// left unused.
     pub fn split_into_n_chunks(self, n: usize) -> Result<Vec<DeviceManager>, DeviceManager> {
         let n_devices = self.devices.len();
         let chunk_size = n_devices / n;
         if chunk_size == 0 {
             return Err(self);
         }
+        if n_devices % n != 0 {
+            tracing::warn!(
+                "Splitting {} devices into {} chunks leaves {} of them unused",
+                n_devices,
+                n,
+                n_devices % n
+            );
+        }
         let mut ret = vec![];
         for i in 0..n {
             ret.push(DeviceManager {
@@ -103,26 +173,25 @@ impl DeviceManager {
     }
 
     pub fn record_event(&self, streams: &[CudaStream], events: &[CUevent]) {
-        for idx in 0..self.devices.len() {
-            unsafe {
-                self.devices[idx].bind_to_thread().unwrap();
-                event::record(events[idx], streams[idx].stream).unwrap();
-            };
-        }
+        self.for_each_device(|idx, _| {
+            unsafe { event::record(events[idx], streams[idx].stream)? };
+            Ok(())
+        })
+        .unwrap();
     }
 
     pub fn await_event(&self, streams: &[CudaStream], events: &[CUevent]) {
-        for idx in 0..self.devices.len() {
+        self.for_each_device(|idx, _| {
             unsafe {
-                self.devices[idx].bind_to_thread().unwrap();
                 wait_event(
                     streams[idx].stream,
                     events[idx],
                     cudarc::driver::sys::CUevent_wait_flags::CU_EVENT_WAIT_DEFAULT,
-                )
-                .unwrap();
+                )?
             };
-        }
+            Ok(())
+        })
+        .unwrap();
     }
 
     pub fn htod_transfer_query(
@@ -192,6 +261,50 @@ impl DeviceManager {
         self.devices.len()
     }
 
+    /// Binds each device to the current thread in order and calls `f` with its
+    /// index. Stops at and returns the first error.
+    pub fn for_each_device(
+        &self,
+        f: impl Fn(usize, &Arc<CudaDevice>) -> eyre::Result<()>,
+    ) -> eyre::Result<()> {
+        for (idx, device) in self.devices.iter().enumerate() {
+            device.bind_to_thread()?;
+            f(idx, device)?;
+        }
+        Ok(())
+    }
+
+    /// Like [`Self::for_each_device`], but runs `f` for all devices at once,
+    /// each on its own thread bound to the respective device. Returns the
+    /// outputs in device order, or the error of the lowest failing device.
+    pub fn for_each_device_par<T: Send>(
+        &self,
+        f: impl Fn(usize, &Arc<CudaDevice>) -> eyre::Result<T> + Sync,
+    ) -> eyre::Result<Vec<T>> {
+        let f = &f;
+        std::thread::scope(|s| {
+            let handles = self
+                .devices
+                .iter()
+                .enumerate()
+                .map(|(idx, device)| {
+                    s.spawn(move || {
+                        device.bind_to_thread()?;
+                        f(idx, device)
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                })
+                .collect()
+        })
+    }
+
     pub fn htod_copy_into<T: DeviceRepr + Unpin>(
         &self,
         src: Vec<T>,
@@ -248,6 +361,55 @@ impl DeviceManager {
         ids
     }
 
+    /// Reads utilization, temperature and memory usage of every device from
+    /// NVML. Devices are matched by their PCI bus id, as NVML does not
+    /// necessarily enumerate them in CUDA order. Metrics that can't be read
+    /// are returned as `None` instead of failing.
+    #[cfg(feature = "nvml")]
+    pub fn device_stats(&self) -> Vec<DeviceStats> {
+        use cudarc::driver::sys::CUdevice_attribute::{
+            CU_DEVICE_ATTRIBUTE_PCI_BUS_ID, CU_DEVICE_ATTRIBUTE_PCI_DEVICE_ID,
+            CU_DEVICE_ATTRIBUTE_PCI_DOMAIN_ID,
+        };
+        use nvml_wrapper::enum_wrappers::device::TemperatureSensor;
+
+        let nvml = NVML.get_or_init(|| {
+            nvml_wrapper::Nvml::init()
+                .inspect_err(|e| tracing::warn!("NVML is not available: {}", e))
+                .ok()
+        });
+
+        self.devices
+            .iter()
+            .map(|dev| {
+                let Some(nvml) = nvml else {
+                    return DeviceStats::default();
+                };
+                let bus_id = (|| {
+                    Ok::<_, result::DriverError>(format!(
+                        "{:08x}:{:02x}:{:02x}.0",
+                        dev.attribute(CU_DEVICE_ATTRIBUTE_PCI_DOMAIN_ID)?,
+                        dev.attribute(CU_DEVICE_ATTRIBUTE_PCI_BUS_ID)?,
+                        dev.attribute(CU_DEVICE_ATTRIBUTE_PCI_DEVICE_ID)?,
+                    ))
+                })();
+                let Some(device) = bus_id
+                    .ok()
+                    .and_then(|bus_id| nvml.device_by_pci_bus_id(bus_id).ok())
+                else {
+                    return DeviceStats::default();
+                };
+                let memory = device.memory_info().ok();
+                DeviceStats {
+                    utilization:        device.utilization_rates().ok().map(|u| u.gpu),
+                    temperature:        device.temperature(TemperatureSensor::Gpu).ok(),
+                    memory_used_bytes:  memory.as_ref().map(|m| m.used),
+                    memory_total_bytes: memory.as_ref().map(|m| m.total),
+                }
+            })
+            .collect()
+    }
+
     // TODO: check if we can do this nicer, atm we only use the arc to clone it, so
     // a Rc would do.
     #[allow(clippy::arc_with_non_send_sync)]
@@ -256,26 +418,206 @@ impl DeviceManager {
         peer_id: usize,
         ids: &[Id],
     ) -> eyre::Result<Vec<Arc<NcclComm>>> {
-        let n_devices = self.devices.len();
-        let mut comms = Vec::with_capacity(n_devices);
+        (0..self.devices.len())
+            .map(|i| Ok(Arc::new(connect_device(&self.devices[i], peer_id, ids[i])?)))
+            .collect()
+    }
 
-        for i in 0..n_devices {
-            self.devices[i].bind_to_thread().unwrap();
-
-            let mut connected = false;
-            for _ in 0..NCCL_START_RETRIES {
-                if let Ok(c) = NcclComm::from_rank(self.devices[i].clone(), peer_id, 3, ids[i]) {
-                    comms.push(Arc::new(c));
-                    connected = true;
-                    break;
+    /// Like [`Self::instantiate_network_from_ids`], but fails with
+    /// [`DeviceManagerError::PeersNotReady`] if not all comms are established
+    /// within `deadline`, e.g. because a peer is down, instead of blocking
+    /// forever. The comms are established on a background thread, which is
+    /// left behind in the pending NCCL init if the deadline passes. That init
+    /// cannot be cancelled and still holds on to the devices and `ids`, so a
+    /// retry would race it: the caller must not retry, but exit the process.
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub fn instantiate_network_from_ids_with_deadline(
+        &self,
+        peer_id: usize,
+        ids: &[Id],
+        deadline: Duration,
+    ) -> eyre::Result<Vec<Arc<NcclComm>>> {
+        let devices = self.devices.clone();
+        let ids = ids.to_vec();
+        let comms = connect_with_deadline(devices.len(), deadline, move |i| {
+            connect_device(&devices[i], peer_id, ids[i]).map(SendComm)
+        })?;
+        Ok(comms.into_iter().map(|c| Arc::new(c.0)).collect())
+    }
+}
+
+fn connect_device(device: &Arc<CudaDevice>, peer_id: usize, id: Id) -> eyre::Result<NcclComm> {
+    device.bind_to_thread().unwrap();
+    for _ in 0..NCCL_START_RETRIES {
+        if let Ok(c) = NcclComm::from_rank(device.clone(), peer_id, 3, id) {
+            return Ok(c);
+        }
+        sleep(NCCL_START_WAIT_TIME);
+    }
+    eyre::bail!("Failed to establish NCCL connection");
+}
+
+/// Calls `connect` for `0..n` in order on a background thread, failing with
+/// [`DeviceManagerError::PeersNotReady`] if not all calls returned within
+/// `deadline`.
+fn connect_with_deadline<T: Send + 'static>(
+    n: usize,
+    deadline: Duration,
+    connect: impl Fn(usize) -> eyre::Result<T> + Send + 'static,
+) -> eyre::Result<Vec<T>> {
+    let deadline = Instant::now() + deadline;
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for i in 0..n {
+            let res = connect(i);
+            let failed = res.is_err();
+            if tx.send(res).is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    let mut connected = Vec::with_capacity(n);
+    while connected.len() < n {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(timeout) {
+            Ok(res) => connected.push(res?),
+            Err(_) => {
+                return Err(DeviceManagerError::PeersNotReady {
+                    connected: connected.len(),
+                    expected:  n,
                 }
-                sleep(NCCL_START_WAIT_TIME);
+                .into())
             }
+        }
+    }
+    Ok(connected)
+}
 
-            if !connected {
-                eyre::bail!("Failed to establish NCCL connection");
+#[cfg(test)]
+mod tests {
+    use super::{connect_with_deadline, DeviceManager, DeviceManagerError};
+    use cudarc::driver::{sys::CUresult, CudaDevice, DriverError};
+    #[cfg(feature = "gpu_dependent")]
+    use std::sync::{Barrier, Mutex};
+    use std::{
+        sync::mpsc,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn check_try_init_without_devices() {
+        let err = DeviceManager::try_init_with(Ok(0), CudaDevice::new).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DeviceManagerError>(),
+            Some(DeviceManagerError::NoCudaDevices(_))
+        ));
+
+        let err = DeviceManager::try_init_with(
+            Err(DriverError(CUresult::CUDA_ERROR_NO_DEVICE)),
+            CudaDevice::new,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DeviceManagerError>(),
+            Some(DeviceManagerError::NoCudaDevices(_))
+        ));
+    }
+
+    #[test]
+    fn check_connect_with_deadline() {
+        let comms = connect_with_deadline(3, Duration::from_secs(5), Ok).unwrap();
+        assert_eq!(comms, vec![0, 1, 2]);
+
+        // The second device's peer does not show up before the deadline.
+        let (release, released) = mpsc::channel::<()>();
+        let start = Instant::now();
+        let err = connect_with_deadline(3, Duration::from_millis(200), move |i| {
+            if i == 1 {
+                released.recv().unwrap();
             }
+            Ok(i)
+        })
+        .unwrap_err();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(matches!(
+            err.downcast_ref::<DeviceManagerError>(),
+            Some(DeviceManagerError::PeersNotReady {
+                connected: 1,
+                expected:  3,
+            })
+        ));
+        assert_eq!(
+            err.to_string(),
+            "only 1 of 3 NCCL comms were established before the deadline, check that all peers \
+             are up"
+        );
+        // Let the background thread finish instead of leaking it.
+        release.send(()).unwrap();
+
+        let err = connect_with_deadline(3, Duration::from_secs(5), |i| {
+            eyre::ensure!(i != 1, "connection refused");
+            Ok(i)
+        })
+        .unwrap_err();
+        assert_eq!(err.to_string(), "connection refused");
+    }
+
+    #[cfg(feature = "gpu_dependent")]
+    #[test]
+    fn check_for_each_device() {
+        let device_manager = DeviceManager::init();
+        let visited = Mutex::new(vec![]);
+        device_manager
+            .for_each_device(|idx, device| {
+                assert_eq!(device.ordinal(), idx);
+                visited.lock().unwrap().push(idx);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            visited.into_inner().unwrap(),
+            (0..device_manager.device_count()).collect::<Vec<_>>()
+        );
+
+        let err = device_manager.for_each_device(|idx, _| {
+            if idx == 0 {
+                eyre::bail!("device {} failed", idx);
+            }
+            Ok(())
+        });
+        if device_manager.device_count() > 0 {
+            assert!(err.is_err());
         }
-        Ok(comms)
+    }
+
+    #[cfg(feature = "gpu_dependent")]
+    #[test]
+    fn check_for_each_device_par() {
+        let device_manager = DeviceManager::init();
+        // Every thread waits for all others, so this only finishes if the closures
+        // run concurrently
+        let barrier = Barrier::new(device_manager.device_count());
+        let results = device_manager
+            .for_each_device_par(|idx, device| {
+                barrier.wait();
+                assert_eq!(device.ordinal(), idx);
+                Ok(idx * 2)
+            })
+            .unwrap();
+        assert_eq!(
+            results,
+            (0..device_manager.device_count())
+                .map(|idx| idx * 2)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[cfg(all(feature = "nvml", feature = "gpu_dependent"))]
+    #[test]
+    fn check_device_stats() {
+        let device_manager = DeviceManager::init();
+        let stats = device_manager.device_stats();
+        assert_eq!(stats.len(), device_manager.device_count());
     }
 }
@@ -1,9 +1,41 @@
-use axum::extract::Path;
+use axum::{extract::Path, http::StatusCode, routing::get, Router};
 use cudarc::nccl::Id;
-use std::str::FromStr;
+use reqwest::Url;
+use std::{
+    fs,
+    net::Ipv6Addr,
+    path::{Path as FsPath, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Default timeout for fetching a comm id from a peer.
+pub const COMM_ID_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default deadline for a peer's comm id exchange server to come up.
+pub const COMM_ID_POLL_DEADLINE: Duration = Duration::from_secs(60);
+const COMM_ID_POLL_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const COMM_ID_POLL_MAX_BACKOFF: Duration = Duration::from_secs(2);
 
 pub struct IdWrapper(pub Id);
 
+/// How peers obtain the NCCL comm ids generated by peer 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommExchange {
+    /// Peer 0 serves the ids via [`comm_id_router`], the others fetch them via
+    /// [`poll_comm_id`].
+    Http,
+    /// Peer 0 writes the ids into a directory shared by all peers via
+    /// [`write_comm_ids`], the others wait for them via [`poll_comm_id_file`].
+    /// For clusters where HTTP between peers is blocked. `run` identifies the
+    /// current run and has to be the same on all peers, so ids left over by a
+    /// previous run are not picked up.
+    SharedDir { dir: PathBuf, run: String },
+}
+
 impl FromStr for IdWrapper {
     type Err = hex::FromHexError;
 
@@ -29,7 +61,343 @@ impl std::fmt::Display for IdWrapper {
     }
 }
 
-pub async fn http_root(ids: Vec<Id>, Path(device_id): Path<String>) -> String {
-    let device_id: usize = device_id.parse().unwrap();
-    IdWrapper(ids[device_id]).to_string()
+/// Serves the hex-encoded NCCL comm id for the requested device. Returns
+/// `400 Bad Request` if the device id is not a number or is out of range for
+/// `ids`, instead of panicking inside the server task.
+pub async fn http_root(ids: Vec<Id>, Path(device_id): Path<String>) -> Result<String, StatusCode> {
+    let device_id: usize = device_id.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+    let id = ids.get(device_id).ok_or(StatusCode::BAD_REQUEST)?;
+    Ok(IdWrapper(*id).to_string())
+}
+
+/// Builds the router of the comm id exchange server. Besides the comm ids
+/// under `/:device_id`, it serves `/healthz`, which returns `200 OK` as soon
+/// as the server is up, and `/readyz`, which returns `503 Service
+/// Unavailable` until `ready` is set once all comms are established.
+pub fn comm_id_router(ids: Vec<Id>, ready: Arc<AtomicBool>) -> Router {
+    Router::new()
+        .route("/healthz", get(|| async {})) // implicit 200 return
+        .route(
+            "/readyz",
+            get(move || async move {
+                if ready.load(Ordering::Acquire) {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }
+            }),
+        )
+        .route("/:device_id", get(move |path| http_root(ids.clone(), path)))
+}
+
+/// Builds the URL under which a peer serves the comm id for `device_id`.
+/// `peer` may be a hostname, an IPv4 address or an IPv6 address (with or
+/// without brackets).
+pub fn comm_id_url(peer: &str, port: u16, device_id: usize) -> eyre::Result<Url> {
+    let mut url = Url::parse("http://localhost")?;
+    match peer.parse::<Ipv6Addr>() {
+        Ok(ip) => url
+            .set_ip_host(ip.into())
+            .map_err(|_| eyre::eyre!("Invalid peer address: {}", peer))?,
+        Err(_) => url.set_host(Some(peer))?,
+    }
+    url.set_port(Some(port))
+        .map_err(|_| eyre::eyre!("Invalid peer port: {}", port))?;
+    url.set_path(&device_id.to_string());
+    Ok(url)
+}
+
+/// Fetches the comm id served under `url` by a peer's comm id exchange
+/// server. Fails instead of hanging if the peer doesn't answer within
+/// `timeout`, and surfaces non-success responses and malformed ids as errors.
+pub async fn fetch_comm_id(url: Url, timeout: Duration) -> eyre::Result<Id> {
+    let client = reqwest::Client::builder().timeout(timeout).build()?;
+    let res = client.get(url).send().await?.error_for_status()?;
+    Ok(IdWrapper::from_str(&res.text().await?)?.0)
+}
+
+/// Like [`fetch_comm_id`], but retries with exponential backoff until the
+/// peer's server responds, so non-leader peers don't have to guess how long
+/// the leader takes to start. Returns the last error once `deadline` has
+/// passed.
+pub async fn poll_comm_id(url: Url, deadline: Duration) -> eyre::Result<Id> {
+    let deadline = Instant::now() + deadline;
+    let mut backoff = COMM_ID_POLL_INITIAL_BACKOFF;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match fetch_comm_id(url.clone(), remaining.min(COMM_ID_FETCH_TIMEOUT)).await {
+            Ok(id) => return Ok(id),
+            Err(e) if Instant::now() + backoff >= deadline => {
+                return Err(e.wrap_err(format!("Peer at {} did not respond in time", url)));
+            }
+            Err(e) => {
+                tracing::debug!("Comm id not available yet at {}: {}", url, e);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(COMM_ID_POLL_MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+fn comm_id_path(dir: &FsPath, device_id: usize) -> PathBuf {
+    dir.join(format!("{}.id", device_id))
+}
+
+/// Writes the hex-encoded comm id of every device into `dir`, one file per
+/// device, each tagged with `run`. Comm id files of previous runs are removed
+/// first.
+pub fn write_comm_ids(dir: &FsPath, run: &str, ids: &[Id]) -> eyre::Result<()> {
+    eyre::ensure!(
+        !run.is_empty() && !run.contains('\n'),
+        "Invalid comm id run: {:?}",
+        run
+    );
+    fs::create_dir_all(dir)?;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "id") {
+            fs::remove_file(path)?;
+        }
+    }
+    for (device_id, id) in ids.iter().enumerate() {
+        let path = comm_id_path(dir, device_id);
+        // Write to a temporary file first, so a peer never reads a partially
+        // written id
+        let tmp = path.with_extension(format!("id.{}.tmp", std::process::id()));
+        fs::write(&tmp, format!("{}\n{}", run, IdWrapper(*id)))?;
+        fs::rename(&tmp, &path)?;
+    }
+    Ok(())
+}
+
+/// Reads the comm id for `device_id` written by [`write_comm_ids`]. Fails if
+/// the file is missing, malformed or was written for a run other than `run`.
+pub fn read_comm_id(dir: &FsPath, run: &str, device_id: usize) -> eyre::Result<Id> {
+    let path = comm_id_path(dir, device_id);
+    let content = fs::read_to_string(&path)
+        .map_err(|e| eyre::eyre!("Failed to read comm id {}: {}", path.display(), e))?;
+    let (file_run, id) = content
+        .split_once('\n')
+        .ok_or_else(|| eyre::eyre!("Comm id {} has no run", path.display()))?;
+    eyre::ensure!(
+        file_run == run,
+        "Comm id {} is from run {:?}, expected {:?}",
+        path.display(),
+        file_run,
+        run
+    );
+    Ok(IdWrapper::from_str(id.trim())?.0)
+}
+
+/// Like [`read_comm_id`], but retries with exponential backoff until peer 0
+/// has written the comm id of this run, as [`poll_comm_id`] does for HTTP.
+/// Returns the last error once `deadline` has passed.
+pub async fn poll_comm_id_file(
+    dir: &FsPath,
+    run: &str,
+    device_id: usize,
+    deadline: Duration,
+) -> eyre::Result<Id> {
+    let deadline = Instant::now() + deadline;
+    let mut backoff = COMM_ID_POLL_INITIAL_BACKOFF;
+    loop {
+        match read_comm_id(dir, run, device_id) {
+            Ok(id) => return Ok(id),
+            Err(e) if Instant::now() + backoff >= deadline => {
+                return Err(e.wrap_err("Comm id was not written in time"));
+            }
+            Err(e) => {
+                tracing::debug!("Comm id not available yet: {}", e);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(COMM_ID_POLL_MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn serve_ids(ids: Vec<Id>) -> String {
+        serve_router(comm_id_router(ids, Default::default())).await
+    }
+
+    async fn serve_router(app: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_http_root_invalid_device_id() {
+        let ids = vec![Id::uninit([1; 128]), Id::uninit([2; 128])];
+        let base = serve_ids(ids.clone()).await;
+
+        let res = reqwest::get(format!("{}/1", base)).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let id = IdWrapper::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(id.0.internal(), ids[1].internal());
+
+        for device_id in ["2", "1000", "-1", "abc"] {
+            let res = reqwest::get(format!("{}/{}", base, device_id))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health_endpoints() {
+        let ready = Arc::new(AtomicBool::new(false));
+        let base = serve_router(comm_id_router(vec![Id::uninit([1; 128])], ready.clone())).await;
+
+        let res = reqwest::get(format!("{}/healthz", base)).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let res = reqwest::get(format!("{}/readyz", base)).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+
+        ready.store(true, Ordering::Release);
+        let res = reqwest::get(format!("{}/readyz", base)).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let res = reqwest::get(format!("{}/0", base)).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_comm_id_timeout() {
+        let ids = vec![Id::uninit([3; 128])];
+        let base = serve_ids(ids.clone()).await;
+        let id = fetch_comm_id(
+            format!("{}/0", base).parse().unwrap(),
+            COMM_ID_FETCH_TIMEOUT,
+        )
+        .await
+        .unwrap();
+        assert_eq!(id.internal(), ids[0].internal());
+        assert!(fetch_comm_id(
+            format!("{}/1", base).parse().unwrap(),
+            COMM_ID_FETCH_TIMEOUT
+        )
+        .await
+        .is_err());
+
+        let slow = serve_router(Router::new().route(
+            "/:device_id",
+            get(|| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                IdWrapper(Id::uninit([3; 128])).to_string()
+            }),
+        ))
+        .await;
+        let err = fetch_comm_id(
+            format!("{}/0", slow).parse().unwrap(),
+            Duration::from_millis(100),
+        )
+        .await
+        .unwrap_err();
+        assert!(err
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(|e| e.is_timeout()));
+    }
+
+    #[tokio::test]
+    async fn test_poll_comm_id_late_server() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let ids = vec![Id::uninit([4; 128])];
+        let served = ids.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+            axum::serve(listener, comm_id_router(served, Default::default()))
+                .await
+                .unwrap()
+        });
+
+        let url: Url = format!("http://{}/0", addr).parse().unwrap();
+        assert!(poll_comm_id(url.clone(), Duration::from_secs(1))
+            .await
+            .is_err());
+        let id = poll_comm_id(url, Duration::from_secs(10)).await.unwrap();
+        assert_eq!(id.internal(), ids[0].internal());
+    }
+
+    #[test]
+    fn test_shared_dir_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let ids = vec![Id::uninit([5; 128]), Id::uninit([0x7f; 128])];
+        let path = &dir.path().join("comm_ids");
+
+        assert!(read_comm_id(path, "run-1", 0).is_err());
+        write_comm_ids(path, "run-1", &ids).unwrap();
+        for (device_id, id) in ids.iter().enumerate() {
+            assert_eq!(
+                read_comm_id(path, "run-1", device_id).unwrap().internal(),
+                id.internal()
+            );
+        }
+        assert!(read_comm_id(path, "run-1", ids.len()).is_err());
+        assert!(read_comm_id(path, "run-2", 0).is_err());
+
+        // A new run with fewer devices removes all ids of the previous one.
+        write_comm_ids(path, "run-2", &ids[..1]).unwrap();
+        assert!(read_comm_id(path, "run-2", 0).is_ok());
+        assert!(!comm_id_path(path, 1).exists());
+
+        fs::write(comm_id_path(path, 0), "run-2\nnot hex").unwrap();
+        assert!(read_comm_id(path, "run-2", 0).is_err());
+        fs::write(comm_id_path(path, 0), IdWrapper(ids[0]).to_string()).unwrap();
+        assert!(read_comm_id(path, "run-2", 0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_poll_comm_id_file_late_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+        let stale = vec![Id::uninit([6; 128])];
+        let ids = vec![Id::uninit([7; 128])];
+        write_comm_ids(&path, "old", &stale).unwrap();
+
+        let writer_path = path.clone();
+        let written = ids.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            write_comm_ids(&writer_path, "new", &written).unwrap();
+        });
+
+        assert!(poll_comm_id_file(&path, "new", 0, Duration::from_secs(1))
+            .await
+            .is_err());
+        let id = poll_comm_id_file(&path, "new", 0, Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(id.internal(), ids[0].internal());
+    }
+
+    #[test]
+    fn test_comm_id_url() {
+        assert_eq!(
+            comm_id_url("::1", 3000, 2).unwrap().as_str(),
+            "http://[::1]:3000/2"
+        );
+        assert_eq!(
+            comm_id_url("[fd00::1]", 3000, 0).unwrap().as_str(),
+            "http://[fd00::1]:3000/0"
+        );
+        assert_eq!(
+            comm_id_url("10.0.0.1", 3000, 1).unwrap().as_str(),
+            "http://10.0.0.1:3000/1"
+        );
+        assert_eq!(
+            comm_id_url("party-0.smpc.svc.cluster.local", 3000, 7)
+                .unwrap()
+                .as_str(),
+            "http://party-0.smpc.svc.cluster.local:3000/7"
+        );
+        assert!(comm_id_url("bad host", 3000, 0).is_err());
+    }
 }
@@ -7,9 +7,12 @@ use cudarc::driver::{
 };
 use std::sync::Arc;
 
+#[cfg(feature = "bench")]
+pub mod bench;
 pub mod comm;
 pub mod device_manager;
 pub mod id_wrapper;
+pub mod ptx_cache;
 pub mod query_processor;
 
 pub(crate) const DEFAULT_LAUNCH_CONFIG_THREADS: u32 = 256;
@@ -0,0 +1,131 @@
+use cudarc::nvrtc::{compile_ptx, CompileError, Ptx};
+use ring::digest::{digest, SHA256};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+/// Directory to persist compiled PTX in across restarts. The on-disk cache is
+/// disabled if unset. It only saves the NVRTC compilation: the driver still
+/// JIT-compiles the cached PTX for the device's architecture on every start.
+pub const PTX_CACHE_DIR_ENV: &str = "SMPC__PTX_CACHE_DIR";
+
+/// Compiled PTX, keyed by the CUDA source it was compiled from.
+static PTX_CACHE: OnceLock<Mutex<HashMap<String, Ptx>>> = OnceLock::new();
+
+#[cfg(test)]
+static COMPILE_COUNTS: OnceLock<Mutex<HashMap<String, usize>>> = OnceLock::new();
+#[cfg(test)]
+static DISK_HITS: OnceLock<Mutex<HashMap<String, usize>>> = OnceLock::new();
+
+#[cfg(test)]
+fn count(counts: &OnceLock<Mutex<HashMap<String, usize>>>, src: &str) {
+    *counts
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .entry(src.to_string())
+        .or_default() += 1;
+}
+
+/// Compiles `src` to PTX, or returns the result of an earlier compilation of
+/// the same source in this process or, if [`PTX_CACHE_DIR_ENV`] is set, in an
+/// earlier process.
+///
+/// The lock is held while compiling, so concurrent callers asking for the same
+/// source wait for the first compilation instead of repeating it.
+pub fn compile_ptx_cached(src: &str) -> Result<Ptx, CompileError> {
+    let mut cache = PTX_CACHE
+        .get_or_init(Default::default)
+        .lock()
+        .expect("PTX cache lock poisoned");
+    if let Some(ptx) = cache.get(src) {
+        return Ok(ptx.clone());
+    }
+
+    let cache_dir = std::env::var_os(PTX_CACHE_DIR_ENV).map(PathBuf::from);
+    let ptx = load_or_compile(src, cache_dir.as_deref())?;
+    cache.insert(src.to_string(), ptx.clone());
+    Ok(ptx)
+}
+
+/// Path of the cached PTX for `src` in `dir`. PTX is compiled without an
+/// architecture flag and JIT-compiled by the driver when loaded, so the
+/// source alone identifies it.
+fn cache_path(dir: &Path, src: &str) -> PathBuf {
+    let hash = digest(&SHA256, src.as_bytes());
+    dir.join(format!("{}.ptx", hex::encode(hash.as_ref())))
+}
+
+/// Reads the PTX for `src` from `dir`, or compiles and stores it there. Failing
+/// to read or write the cache only costs a recompilation, so such errors are
+/// logged and otherwise ignored.
+fn load_or_compile(src: &str, dir: Option<&Path>) -> Result<Ptx, CompileError> {
+    let path = dir.map(|dir| cache_path(dir, src));
+    if let Some(path) = &path {
+        if let Ok(ptx) = fs::read_to_string(path) {
+            #[cfg(test)]
+            count(&DISK_HITS, src);
+            tracing::debug!("Loaded PTX from {}", path.display());
+            return Ok(Ptx::from_src(ptx));
+        }
+    }
+
+    let ptx = compile_ptx(src)?;
+    #[cfg(test)]
+    count(&COMPILE_COUNTS, src);
+
+    if let Some(path) = &path {
+        // Write to a temporary file first, so a concurrently starting process never
+        // reads a partially written cache entry
+        let tmp = path.with_extension(format!("ptx.{}.tmp", std::process::id()));
+        if let Err(e) = fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| fs::write(&tmp, ptx.to_src()))
+            .and_then(|_| fs::rename(&tmp, path))
+        {
+            tracing::warn!("Failed to write PTX cache {}: {}", path.display(), e);
+        }
+    }
+    Ok(ptx)
+}
+
+/// Number of times `src` was actually handed to NVRTC.
+#[cfg(test)]
+pub(crate) fn compile_count(src: &str) -> usize {
+    COMPILE_COUNTS
+        .get()
+        .and_then(|counts| counts.lock().unwrap().get(src).copied())
+        .unwrap_or(0)
+}
+
+/// Number of times the PTX of `src` was read from the on-disk cache.
+#[cfg(test)]
+pub(crate) fn disk_hit_count(src: &str) -> usize {
+    DISK_HITS
+        .get()
+        .and_then(|counts| counts.lock().unwrap().get(src).copied())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+#[cfg(feature = "gpu_dependent")]
+mod tests {
+    use super::{compile_count, disk_hit_count, load_or_compile};
+
+    const SRC: &str = "extern \"C\" __global__ void ptx_cache_test(int *x) { *x = 1; }";
+
+    /// The second compilation against the same directory, as on a restart, is
+    /// served from disk instead of NVRTC.
+    #[test]
+    fn check_disk_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = load_or_compile(SRC, Some(dir.path())).unwrap();
+        assert_eq!((compile_count(SRC), disk_hit_count(SRC)), (1, 0));
+
+        let second = load_or_compile(SRC, Some(dir.path())).unwrap();
+        assert_eq!((compile_count(SRC), disk_hit_count(SRC)), (1, 1));
+        assert_eq!(first.to_src(), second.to_src());
+    }
+}
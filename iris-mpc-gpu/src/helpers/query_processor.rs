@@ -165,7 +165,7 @@ impl DeviceCompactQuery {
         offset: usize,
         streams: &[CudaStream],
         blass: &[CudaBlas],
-    ) {
+    ) -> eyre::Result<()> {
         code_engine.dot(
             &self.code_query,
             &(&self.code_query_insert).into(),
@@ -173,7 +173,7 @@ impl DeviceCompactQuery {
             offset,
             streams,
             blass,
-        );
+        )?;
 
         mask_engine.dot(
             &self.mask_query,
@@ -182,7 +182,8 @@ impl DeviceCompactQuery {
             offset,
             streams,
             blass,
-        );
+        )?;
+        Ok(())
     }
 
     // TODO(Dragos) function signature can be compressed if there's a large refactor
@@ -199,7 +200,7 @@ impl DeviceCompactQuery {
         offset: usize,
         streams: &[CudaStream],
         blass: &[CudaBlas],
-    ) {
+    ) -> eyre::Result<()> {
         code_engine.dot(
             &self.code_query,
             &sliced_code_db.code_gr,
@@ -207,7 +208,7 @@ impl DeviceCompactQuery {
             offset,
             streams,
             blass,
-        );
+        )?;
         mask_engine.dot(
             &self.mask_query,
             &sliced_mask_db.code_gr,
@@ -215,7 +216,8 @@ impl DeviceCompactQuery {
             offset,
             streams,
             blass,
-        );
+        )?;
+        Ok(())
     }
 }
 pub struct DeviceCompactSums {
@@ -233,14 +235,14 @@ impl DeviceCompactSums {
         db_sizes: &[usize],
         offset: usize,
         streams: &[CudaStream],
-    ) {
+    ) -> eyre::Result<()> {
         code_engine.dot_reduce(
             &self.code_query,
             &self.code_query_insert,
             db_sizes,
             offset,
             streams,
-        );
+        )?;
         mask_engine.dot_reduce_and_multiply(
             &self.mask_query,
             &self.mask_query_insert,
@@ -248,7 +250,8 @@ impl DeviceCompactSums {
             offset,
             streams,
             2,
-        );
+        )?;
+        Ok(())
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -261,14 +264,14 @@ impl DeviceCompactSums {
         database_sizes: &[usize],
         offset: usize,
         streams: &[CudaStream],
-    ) {
+    ) -> eyre::Result<()> {
         code_engine.dot_reduce(
             &self.code_query,
             &sliced_code_db.code_sums_gr,
             database_sizes,
             offset,
             streams,
-        );
+        )?;
         mask_engine.dot_reduce_and_multiply(
             &self.mask_query,
             &sliced_mask_db.code_sums_gr,
@@ -276,6 +279,7 @@ impl DeviceCompactSums {
             offset,
             streams,
             2,
-        );
+        )?;
+        Ok(())
     }
 }
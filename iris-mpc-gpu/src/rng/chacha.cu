@@ -29,7 +29,7 @@
 #define uint32_t unsigned int
 #define uint64_t unsigned long long
 
-#define THREADS_PER_BLOCK 256 // needs to be kept in sync with the kernel launch
+#define THREADS_PER_BLOCK 256 // keep in sync with THREADS_PER_BLOCK in chacha.rs
 
 /* Left rotation of n by d bits */
 #define ROTL32(n, d) (n << d) | (n >> (32 - d))
@@ -1,12 +1,18 @@
-use crate::helpers::{launch_config_from_elements_and_threads, DEFAULT_LAUNCH_CONFIG_THREADS};
-use cudarc::{
-    driver::{
-        CudaDevice, CudaFunction, CudaSlice, CudaStream, CudaViewMut, DeviceSlice, LaunchAsync,
-    },
-    nvrtc::compile_ptx,
+use crate::helpers::{launch_config_from_elements_and_threads, ptx_cache::compile_ptx_cached};
+use cudarc::driver::{
+    CudaDevice, CudaFunction, CudaSlice, CudaStream, CudaViewMut, DeviceSlice, DriverError,
+    LaunchAsync,
 };
 use std::sync::Arc;
 
+/// Number of `u32`s in one ChaCha keystream block. Every kernel thread
+/// produces one block, and the counter advances by one per block.
+pub const U32_PER_KS_CALL: usize = 16;
+/// Threads per block the fill kernels are launched with. Has to match
+/// `THREADS_PER_BLOCK` in `chacha.cu`, which sizes the shared memory holding
+/// the per-thread states by it.
+pub const THREADS_PER_BLOCK: u32 = 256;
+
 pub(super) struct ChachaCommon {
     /// the current state of the chacha rng
     pub(super) chacha_ctx:    ChaChaCtx,
@@ -40,15 +46,34 @@ impl ChachaCommon {
         buf: &mut CudaViewMut<u32>,
         stream: &CudaStream,
         fill_kernel: &CudaFunction,
+    ) {
+        let num_ks_calls = buf.len() / U32_PER_KS_CALL;
+        self.fill_rng_into_at(buf, stream, fill_kernel, self.chacha_ctx.get_counter());
+        // increment the state counter of the ChaChaRng with the number of produced
+        // blocks
+        self.advance_counter(num_ks_calls as u64);
+    }
+
+    /// Like [`Self::fill_rng_into`], but starts at keystream block
+    /// `start_block` instead of the current counter, which is left unchanged.
+    pub fn fill_rng_into_at(
+        &self,
+        buf: &mut CudaViewMut<u32>,
+        stream: &CudaStream,
+        fill_kernel: &CudaFunction,
+        start_block: u64,
     ) {
         let len = buf.len();
-        assert!(len % 16 == 0, "buffer length must be a multiple of 16");
-        let num_ks_calls = len / 16; // we produce 16 u32s per kernel call
-        let threads_per_block = DEFAULT_LAUNCH_CONFIG_THREADS; // ON CHANGE: sync with kernel
+        assert!(
+            len % U32_PER_KS_CALL == 0,
+            "buffer length must be a multiple of {}",
+            U32_PER_KS_CALL
+        );
+        let num_ks_calls = len / U32_PER_KS_CALL;
 
         let cfg = launch_config_from_elements_and_threads(
             num_ks_calls as u32,
-            threads_per_block,
+            THREADS_PER_BLOCK,
             &self.state_gpu_buf.device(),
         );
 
@@ -61,18 +86,13 @@ impl ChachaCommon {
                     (
                         &mut *buf,
                         &self.state_gpu_buf,
-                        self.chacha_ctx.state[12], // first part of counter
-                        self.chacha_ctx.state[13], // second part of counter
+                        start_block as u32,         // first part of counter
+                        (start_block >> 32) as u32, // second part of counter
                         len,
                     ),
                 )
                 .unwrap();
         }
-        // increment the state counter of the ChaChaRng with the number of produced
-        // blocks
-        // one call to KS produces 16 u32, so we increase the counter by the number of
-        // KS calls
-        self.advance_counter(num_ks_calls as u64);
     }
 }
 
@@ -88,11 +108,12 @@ impl ChaChaCudaRng {
     // takes number of bytes to produce, buffer has u32 datatype so will produce
     // buf_size/4 u32s
     pub fn init(buf_size_bytes: usize, dev: Arc<CudaDevice>, seed: [u32; 8]) -> Self {
-        let ptx = compile_ptx(ChachaCommon::CHACHA_PTX_SRC).unwrap();
+        let ptx = compile_ptx_cached(ChachaCommon::CHACHA_PTX_SRC).unwrap();
 
         assert!(
-            buf_size_bytes % 64 == 0,
-            "buf_size must be a multiple of 64 atm"
+            buf_size_bytes % (U32_PER_KS_CALL * 4) == 0,
+            "buf_size must be a multiple of {} atm",
+            U32_PER_KS_CALL * 4
         );
 
         dev.load_ptx(ptx.clone(), ChachaCommon::CHACHA_FILL_FUNCTION_NAME, &[
@@ -148,14 +169,23 @@ impl ChaChaCudaRng {
             .unwrap();
     }
 
+    /// Refills the first `buf_size_bytes` of the device buffer allocated in
+    /// [`Self::init`] in place, without copying them to the host. Nothing is
+    /// allocated, so `buf_size_bytes` must not exceed the size it was
+    /// initialized with.
     pub fn fill_rng_no_host_copy(&mut self, buf_size_bytes: usize, stream: &CudaStream) {
-        assert!(self.rng_chunk.is_some());
-
-        let mut buf = self
+        let rng_chunk = self
             .rng_chunk
             .as_mut()
-            .unwrap()
-            .slice_mut(0..buf_size_bytes / 4);
+            .expect("the RNG was initialized without a buffer");
+        assert!(
+            buf_size_bytes / 4 <= rng_chunk.len(),
+            "{} bytes exceed the RNG buffer of {} bytes",
+            buf_size_bytes,
+            rng_chunk.len() * 4
+        );
+
+        let mut buf = rng_chunk.slice_mut(0..buf_size_bytes / 4);
 
         self.chacha
             .fill_rng_into(&mut buf, stream, &self.fill_kernel);
@@ -165,22 +195,53 @@ impl ChaChaCudaRng {
         self.chacha.fill_rng_into(buf, stream, &self.fill_kernel);
     }
 
+    /// Fills `buf` starting at keystream block `start_block`, where a block is
+    /// [`U32_PER_KS_CALL`] `u32`s, without touching the internal counter. Fills
+    /// of disjoint block ranges don't overlap, so they can be issued on
+    /// different streams at the same time. Advancing the counter past the
+    /// used blocks is up to the caller.
+    pub fn fill_rng_into_at(
+        &self,
+        buf: &mut CudaViewMut<u32>,
+        stream: &CudaStream,
+        start_block: u64,
+    ) {
+        self.chacha
+            .fill_rng_into_at(buf, stream, &self.fill_kernel, start_block);
+    }
+
     pub fn data(&self) -> Option<&[u32]> {
         self.output_buffer.as_deref()
     }
 
+    pub fn get_chacha(&self) -> &ChaChaCtx {
+        &self.chacha.chacha_ctx
+    }
+
     pub fn get_mut_chacha(&mut self) -> &mut ChaChaCtx {
         &mut self.chacha.chacha_ctx
     }
 
+    /// Sets the nonce of the keystream. Unlike setting it on
+    /// [`Self::get_mut_chacha`], this also updates the state the kernels read
+    /// it from.
+    pub fn set_nonce(&mut self, nonce: u64) -> Result<(), DriverError> {
+        self.chacha.chacha_ctx.set_nonce(nonce);
+        self.dev.htod_sync_copy_into(
+            &self.chacha.chacha_ctx.state,
+            &mut self.chacha.state_gpu_buf,
+        )
+    }
+
     pub fn cuda_slice(&self) -> Option<&CudaSlice<u32>> {
         self.rng_chunk.as_ref()
     }
     pub fn set_cuda_slice(&mut self, slice: CudaSlice<u32>) {
         assert!(self.rng_chunk.is_none());
         assert!(
-            slice.len() % 16 == 0,
-            "slice length must be a multiple of 16"
+            slice.len() % U32_PER_KS_CALL == 0,
+            "slice length must be a multiple of {}",
+            U32_PER_KS_CALL
         );
         self.rng_chunk = Some(slice);
     }
@@ -242,14 +303,123 @@ impl ChaChaCtx {
     pub fn get_nonce(&self) -> u64 {
         self.get_value(Self::NONCE_START_IDX)
     }
+
+    /// CPU reference of the fill kernels: writes the keystream starting at the
+    /// current counter into `buf`, without advancing the counter.
+    pub fn fill_reference(&self, buf: &mut [u32]) {
+        for (i, chunk) in buf.chunks_mut(U32_PER_KS_CALL).enumerate() {
+            let block = self.keystream_block(self.get_counter() + i as u64);
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+    }
+
+    /// Computes the ChaCha12 block for `counter`, using the key and nonce of
+    /// this context.
+    fn keystream_block(&self, counter: u64) -> [u32; 16] {
+        let mut state = self.state;
+        state[Self::COUNTER_START_IDX] = counter as u32;
+        state[Self::COUNTER_START_IDX + 1] = (counter >> 32) as u32;
+
+        let mut block = state;
+        // 6 double rounds
+        for _ in 0..6 {
+            quarter_round(&mut block, 0, 4, 8, 12);
+            quarter_round(&mut block, 1, 5, 9, 13);
+            quarter_round(&mut block, 2, 6, 10, 14);
+            quarter_round(&mut block, 3, 7, 11, 15);
+            quarter_round(&mut block, 0, 5, 10, 15);
+            quarter_round(&mut block, 1, 6, 11, 12);
+            quarter_round(&mut block, 2, 7, 8, 13);
+            quarter_round(&mut block, 3, 4, 9, 14);
+        }
+        for (x, s) in block.iter_mut().zip(state) {
+            *x = x.wrapping_add(s);
+        }
+        block
+    }
+}
+
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(16);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(12);
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(8);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(7);
 }
 
 #[cfg(test)]
-#[cfg(feature = "gpu_dependent")]
 mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_kernel_threads_per_block() {
+        assert!(ChachaCommon::CHACHA_PTX_SRC
+            .contains(&format!("#define THREADS_PER_BLOCK {}", THREADS_PER_BLOCK)));
+    }
+
+    /// ChaCha12 with an all-zero key and nonce, from the test vectors in
+    /// draft-strombergson-chacha-test-vectors.
+    #[test]
+    fn test_reference_known_answer() {
+        const EXPECTED: [u32; 16] = [
+            0x6a9af49b, 0x53f95507, 0x12ce1f81, 0xd583265f, 0xbbc32904, 0x1474e049, 0xa589007e,
+            0x5f15ae2e, 0x79f86405, 0xc0e37ad2, 0x3428e82c, 0x798cfaac, 0x2c9f623a, 0x1969dea0,
+            0x2fe80b61, 0xbe261341,
+        ];
+        let mut buf = [0u32; 2 * U32_PER_KS_CALL];
+        ChaChaCtx::init([0u32; 8], 0, 0).fill_reference(&mut buf);
+        assert_eq!(buf[..U32_PER_KS_CALL], EXPECTED);
+
+        // The second block is the first one of the next counter
+        let mut next = [0u32; U32_PER_KS_CALL];
+        ChaChaCtx::init([0u32; 8], 1, 0).fill_reference(&mut next);
+        assert_eq!(buf[U32_PER_KS_CALL..], next);
+    }
+
+    #[cfg(feature = "gpu_dependent")]
+    #[test]
+    fn test_kernel_matches_reference() {
+        const LEN: usize = 4 * U32_PER_KS_CALL;
+        const START_BLOCK: u64 = (1 << 32) - 2;
+        let seed = [0x01234567, 1, 2, 3, 4, 5, 6, 0x89abcdef];
+        let dev = CudaDevice::new(0).unwrap();
+        let stream = dev.fork_default_stream().unwrap();
+        let rng = ChaChaCudaRng::init_empty(dev.clone(), seed);
+        let mut buf = dev.alloc_zeros::<u32>(LEN).unwrap();
+        // Start right below a carry into the upper counter word
+        rng.fill_rng_into_at(&mut buf.slice_mut(..), &stream, START_BLOCK);
+        dev.wait_for(&stream).unwrap();
+
+        let mut expected = vec![0u32; LEN];
+        ChaChaCtx::init(seed, START_BLOCK, 0).fill_reference(&mut expected);
+        assert_eq!(dev.dtoh_sync_copy(&buf).unwrap(), expected);
+    }
+
+    #[cfg(feature = "gpu_dependent")]
+    #[test]
+    fn test_counter_advance() {
+        const LEN: usize = 1000 * U32_PER_KS_CALL;
+        let dev = CudaDevice::new(0).unwrap();
+        let stream = dev.fork_default_stream().unwrap();
+        let mut rng = ChaChaCudaRng::init_empty(dev.clone(), [0u32; 8]);
+        let mut buf = dev.alloc_zeros::<u32>(LEN).unwrap();
+        rng.fill_rng_into(&mut buf.slice_mut(..), &stream);
+        assert_eq!(
+            rng.get_chacha().get_counter(),
+            (LEN / U32_PER_KS_CALL) as u64
+        );
+        rng.fill_rng_into(&mut buf.slice_mut(..LEN / 2), &stream);
+        assert_eq!(
+            rng.get_chacha().get_counter(),
+            (LEN / U32_PER_KS_CALL + LEN / 2 / U32_PER_KS_CALL) as u64
+        );
+    }
+
+    #[cfg(feature = "gpu_dependent")]
     #[test]
     fn test_chacha_rng() {
         // This call to CudaDevice::new is only used in context of a test - not used in
@@ -263,4 +433,41 @@ mod tests {
         rng.fill_rng();
         assert!(&data[..] != rng.data().unwrap());
     }
+
+    #[cfg(feature = "gpu_dependent")]
+    #[test]
+    fn test_fill_rng_into_at() {
+        const LEN: usize = U32_PER_KS_CALL * 1024;
+        let dev = CudaDevice::new(0).unwrap();
+        let stream1 = dev.fork_default_stream().unwrap();
+        let stream2 = dev.fork_default_stream().unwrap();
+
+        let mut sequential = ChaChaCudaRng::init_empty(dev.clone(), [0u32; 8]);
+        let mut expected = dev.alloc_zeros::<u32>(2 * LEN).unwrap();
+        sequential.fill_rng_into(&mut expected.slice_mut(..LEN), &stream1);
+        sequential.fill_rng_into(&mut expected.slice_mut(LEN..), &stream1);
+        dev.wait_for(&stream1).unwrap();
+
+        // Fill the two halves on different streams, in reverse order
+        let explicit = ChaChaCudaRng::init_empty(dev.clone(), [0u32; 8]);
+        let mut buf = dev.alloc_zeros::<u32>(2 * LEN).unwrap();
+        explicit.fill_rng_into_at(
+            &mut buf.slice_mut(LEN..),
+            &stream2,
+            (LEN / U32_PER_KS_CALL) as u64,
+        );
+        explicit.fill_rng_into_at(&mut buf.slice_mut(..LEN), &stream1, 0);
+        dev.wait_for(&stream1).unwrap();
+        dev.wait_for(&stream2).unwrap();
+
+        assert_eq!(
+            dev.dtoh_sync_copy(&buf).unwrap(),
+            dev.dtoh_sync_copy(&expected).unwrap()
+        );
+        assert_eq!(explicit.get_chacha().get_counter(), 0);
+        assert_eq!(
+            sequential.get_chacha().get_counter(),
+            (2 * LEN / U32_PER_KS_CALL) as u64
+        );
+    }
 }
@@ -1,10 +1,11 @@
-use super::chacha::ChachaCommon;
-use cudarc::{
-    driver::{CudaDevice, CudaFunction, CudaStream, CudaViewMut},
-    nvrtc::compile_ptx,
-};
+use super::chacha::{ChachaCommon, U32_PER_KS_CALL};
+use crate::helpers::{dtoh_on_stream_sync, ptx_cache::compile_ptx_cached};
+use cudarc::driver::{CudaDevice, CudaFunction, CudaStream, CudaViewMut, DriverError};
 use std::sync::Arc;
 
+/// Number of `u32`s [`ChaChaCudaCorrRng::self_check`] generates per party.
+pub const SELF_CHECK_LEN: usize = U32_PER_KS_CALL * 1024;
+
 pub struct ChaChaCudaCorrRng {
     fill_kernel: CudaFunction,
     xor_kernel:  CudaFunction,
@@ -16,7 +17,7 @@ impl ChaChaCudaCorrRng {
     // takes number of bytes to produce, buffer has u32 datatype so will produce
     // buf_size/4 u32s
     pub fn init(dev: Arc<CudaDevice>, seed1: [u32; 8], seed2: [u32; 8]) -> Self {
-        let ptx = compile_ptx(ChachaCommon::CHACHA_PTX_SRC).unwrap();
+        let ptx = compile_ptx_cached(ChachaCommon::CHACHA_PTX_SRC).unwrap();
 
         dev.load_ptx(ptx.clone(), ChachaCommon::CHACHA_FILL_FUNCTION_NAME, &[
             ChachaCommon::CHACHA_FILL_FUNCTION_NAME,
@@ -61,12 +62,47 @@ impl ChaChaCudaCorrRng {
     }
 
     pub fn advance_by_bytes(&mut self, bytes: u64) {
-        assert!(bytes % 64 == 0, "bytes must be a multiple of 64");
-        let num_ks_calls = bytes / 64; // we produce 16 u32s per kernel call
-                                       // one call to KS produces 16 u32s
+        let block_bytes = (U32_PER_KS_CALL * 4) as u64;
+        assert!(
+            bytes % block_bytes == 0,
+            "bytes must be a multiple of {}",
+            block_bytes
+        );
+        let num_ks_calls = bytes / block_bytes;
         self.chacha1.advance_counter(num_ks_calls);
         self.chacha2.advance_counter(num_ks_calls);
     }
+
+    /// Checks that the correlated randomness of this party and its two peers
+    /// XORs to zero, which only holds if each party's second seed is the first
+    /// seed of the next one. Meant for startup diagnostics with access to all
+    /// three RNGs, e.g. to catch a seed misconfiguration before it corrupts
+    /// the masking. The counters of all three RNGs are left unchanged.
+    pub fn self_check(
+        &mut self,
+        peer_a: &mut Self,
+        peer_b: &mut Self,
+        stream: &CudaStream,
+    ) -> Result<bool, DriverError> {
+        let dev = self.chacha1.state_gpu_buf.device();
+        let mut buf = dev.alloc_zeros::<u32>(SELF_CHECK_LEN)?;
+        let mut xor = vec![0u32; SELF_CHECK_LEN];
+        for rng in [self, peer_a, peer_b] {
+            let counters = (
+                rng.chacha1.chacha_ctx.get_counter(),
+                rng.chacha2.chacha_ctx.get_counter(),
+            );
+            rng.fill_rng_into(&mut buf.slice_mut(..), stream);
+            rng.chacha1.chacha_ctx.set_counter(counters.0);
+            rng.chacha2.chacha_ctx.set_counter(counters.1);
+
+            let data = dtoh_on_stream_sync(&buf, &dev, stream)?;
+            for (x, y) in xor.iter_mut().zip(data) {
+                *x ^= y;
+            }
+        }
+        Ok(xor.iter().all(|&x| x == 0))
+    }
 }
 
 #[cfg(test)]
@@ -119,4 +155,26 @@ mod tests {
             assert_eq!(a ^ b ^ c, 0);
         }
     }
+
+    #[test]
+    fn test_self_check() {
+        // This call to CudaDevice::new is only used in context of a test - not used in
+        // the server binary
+        let dev = CudaDevice::new(0).unwrap();
+        let stream = dev.fork_default_stream().unwrap();
+        let seed1 = [0u32; 8];
+        let seed2 = [1u32; 8];
+        let seed3 = [2u32; 8];
+        let mut rng1 = ChaChaCudaCorrRng::init(dev.clone(), seed1, seed2);
+        let mut rng2 = ChaChaCudaCorrRng::init(dev.clone(), seed2, seed3);
+        let mut rng3 = ChaChaCudaCorrRng::init(dev.clone(), seed3, seed1);
+        assert!(rng1.self_check(&mut rng2, &mut rng3, &stream).unwrap());
+        // The check doesn't consume any randomness
+        assert_eq!(rng1.chacha1.chacha_ctx.get_counter(), 0);
+        assert_eq!(rng3.chacha2.chacha_ctx.get_counter(), 0);
+
+        // The last party got the seeds the wrong way around
+        let mut rng3 = ChaChaCudaCorrRng::init(dev.clone(), seed1, seed3);
+        assert!(!rng1.self_check(&mut rng2, &mut rng3, &stream).unwrap());
+    }
 }
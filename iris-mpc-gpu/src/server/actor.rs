@@ -221,7 +221,7 @@ impl ServerActor {
             IRIS_CODE_LENGTH,
             next_chacha_seeds(chacha_seeds)?,
             comms.clone(),
-        );
+        )?;
 
         let masks_engine = ShareDB::init(
             party_id,
@@ -231,7 +231,7 @@ impl ServerActor {
             MASK_CODE_LENGTH,
             next_chacha_seeds(chacha_seeds)?,
             comms.clone(),
-        );
+        )?;
 
         let left_code_db_slices = codes_engine.alloc_db(max_db_size);
         let left_mask_db_slices = masks_engine.alloc_db(max_db_size);
@@ -247,7 +247,7 @@ impl ServerActor {
             IRIS_CODE_LENGTH,
             next_chacha_seeds(chacha_seeds)?,
             comms.clone(),
-        );
+        )?;
 
         let batch_masks_engine = ShareDB::init(
             party_id,
@@ -257,7 +257,7 @@ impl ServerActor {
             MASK_CODE_LENGTH,
             next_chacha_seeds(chacha_seeds)?,
             comms.clone(),
-        );
+        )?;
 
         // Phase 2 Setup
         let phase2_chunk_size = n_queries * DB_CHUNK_SIZE;
@@ -308,10 +308,10 @@ impl ServerActor {
         let results = distance_comparator.prepare_results();
         let batch_results = distance_comparator.prepare_results();
 
-        let db_match_list_left =
-            distance_comparator.prepare_db_match_list(max_db_size / device_manager.device_count());
-        let db_match_list_right =
-            distance_comparator.prepare_db_match_list(max_db_size / device_manager.device_count());
+        let db_match_list_left = distance_comparator
+            .prepare_db_match_list(max_db_size.div_ceil(device_manager.device_count()));
+        let db_match_list_right = distance_comparator
+            .prepare_db_match_list(max_db_size.div_ceil(device_manager.device_count()));
         let batch_match_list_left = distance_comparator.prepare_db_match_list(n_queries);
         let batch_match_list_right = distance_comparator.prepare_db_match_list(n_queries);
 
@@ -381,7 +381,7 @@ impl ServerActor {
         left: &IrisCodeDbSlice,
         right: &IrisCodeDbSlice,
         db_size: usize,
-    ) {
+    ) -> eyre::Result<()> {
         assert!(
             [left.0.len(), right.0.len(),]
                 .iter()
@@ -406,22 +406,23 @@ impl ServerActor {
 
         let db_lens1 = self
             .codes_engine
-            .load_full_db(&mut self.left_code_db_slices, left.0);
+            .load_full_db(&mut self.left_code_db_slices, left.0)?;
         let db_lens2 = self
             .masks_engine
-            .load_full_db(&mut self.left_mask_db_slices, left.1);
+            .load_full_db(&mut self.left_mask_db_slices, left.1)?;
         let db_lens3 = self
             .codes_engine
-            .load_full_db(&mut self.right_code_db_slices, right.0);
+            .load_full_db(&mut self.right_code_db_slices, right.0)?;
         let db_lens4 = self
             .masks_engine
-            .load_full_db(&mut self.right_mask_db_slices, right.1);
+            .load_full_db(&mut self.right_mask_db_slices, right.1)?;
 
         assert_eq!(db_lens1, db_lens2);
         assert_eq!(db_lens1, db_lens3);
         assert_eq!(db_lens1, db_lens4);
 
         self.current_db_sizes = db_lens1;
+        Ok(())
     }
 
     pub fn load_single_record(
@@ -604,7 +605,7 @@ impl ServerActor {
             &compact_device_sums_left,
             &mut events,
             Eye::Left,
-        );
+        )?;
 
         ///////////////////////////////////////////////////////////////////
         // COMPARE RIGHT EYE QUERIES
@@ -650,7 +651,7 @@ impl ServerActor {
             &compact_device_sums_right,
             &mut events,
             Eye::Right,
-        );
+        )?;
 
         ///////////////////////////////////////////////////////////////////
         // MERGE LEFT & RIGHT results
@@ -921,7 +922,7 @@ impl ServerActor {
         compact_device_sums: &DeviceCompactSums,
         events: &mut HashMap<&str, Vec<Vec<CUevent>>>,
         eye_db: Eye,
-    ) {
+    ) -> eyre::Result<()> {
         let batch_streams = &self.streams[0];
         let batch_cublas = &self.cublas_handles[0];
 
@@ -949,7 +950,7 @@ impl ServerActor {
                 0,
                 batch_streams,
                 batch_cublas,
-            );
+            )?;
             tracing::info!(party_id = self.party_id, "compute_dot_reducers start");
 
             compact_device_sums.compute_dot_reducers(
@@ -958,7 +959,7 @@ impl ServerActor {
                 &self.query_db_size,
                 0,
                 batch_streams,
-            );
+            )?;
             tracing::info!(party_id = self.party_id, "batch_dot end");
         });
 
@@ -970,10 +971,10 @@ impl ServerActor {
             {
                 tracing::info!(party_id = self.party_id, "batch_reshare start");
                 self.batch_codes_engine
-                    .reshare_results(&self.query_db_size, batch_streams);
+                    .reshare_results(&self.query_db_size, batch_streams)?;
                 tracing::info!(party_id = self.party_id, "batch_reshare masks start");
                 self.batch_masks_engine
-                    .reshare_results(&self.query_db_size, batch_streams);
+                    .reshare_results(&self.query_db_size, batch_streams)?;
                 tracing::info!(party_id = self.party_id, "batch_reshare end");
             }
         );
@@ -1081,7 +1082,7 @@ impl ServerActor {
                     offset,
                     request_streams,
                     request_cublas_handles,
-                );
+                )?;
             });
 
             // wait for the exchange result buffers to be ready
@@ -1102,7 +1103,7 @@ impl ServerActor {
                         &dot_chunk_size,
                         offset,
                         request_streams,
-                    );
+                    )?;
                 }
             );
 
@@ -1116,9 +1117,9 @@ impl ServerActor {
                 "db_reshare",
                 {
                     self.codes_engine
-                        .reshare_results(&dot_chunk_size, request_streams);
+                        .reshare_results(&dot_chunk_size, request_streams)?;
                     self.masks_engine
-                        .reshare_results(&dot_chunk_size, request_streams);
+                        .reshare_results(&dot_chunk_size, request_streams)?;
                 }
             );
 
@@ -1211,6 +1212,7 @@ impl ServerActor {
         for dst in &[&self.results, &self.batch_results, &self.final_results] {
             reset_slice(self.device_manager.devices(), dst, 0xff, &self.streams[0]);
         }
+        Ok(())
     }
 
     fn sync_batch_entries(&mut self, valid_entries: &[bool]) -> eyre::Result<Vec<bool>> {
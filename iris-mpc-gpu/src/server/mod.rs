@@ -3,8 +3,9 @@ pub mod sync_nccl;
 
 use crate::dot::{share_db::preprocess_query, IRIS_CODE_LENGTH, MASK_CODE_LENGTH, ROTATIONS};
 pub use actor::{get_dummy_shares_for_deletion, ServerActor, ServerActorHandle};
-use iris_mpc_common::galois_engine::degree4::{
-    GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare,
+use iris_mpc_common::{
+    eye::Eye,
+    galois_engine::degree4::{GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare},
 };
 use std::collections::HashSet;
 use tokio::sync::oneshot;
@@ -177,8 +178,3 @@ pub struct ServerJobResult {
     pub store_right:             BatchQueryEntries,
     pub deleted_ids:             Vec<u32>,
 }
-
-enum Eye {
-    Left,
-    Right,
-}
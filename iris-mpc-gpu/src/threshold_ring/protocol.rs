@@ -1,7 +1,8 @@
 use crate::{
     helpers::{
         comm::NcclComm, device_manager::DeviceManager, dtoh_on_stream_sync, htod_on_stream_sync,
-        launch_config_from_elements_and_threads, DEFAULT_LAUNCH_CONFIG_THREADS,
+        launch_config_from_elements_and_threads, ptx_cache::compile_ptx_cached,
+        DEFAULT_LAUNCH_CONFIG_THREADS,
     },
     rng::chacha_corr::ChaChaCudaCorrRng,
     threshold_ring::cuda::PTX_SRC,
@@ -12,7 +13,7 @@ use cudarc::{
         DeviceSlice, LaunchAsync,
     },
     nccl::result,
-    nvrtc::{self, Ptx},
+    nvrtc::Ptx,
 };
 use itertools::{izip, Itertools};
 use std::{ops::Range, sync::Arc};
@@ -380,7 +381,7 @@ impl Circuits {
         let mut kernels = Vec::with_capacity(n_devices);
         let mut rngs = Vec::with_capacity(n_devices);
 
-        let ptx = nvrtc::compile_ptx(PTX_SRC).unwrap();
+        let ptx = compile_ptx_cached(PTX_SRC).unwrap();
         for i in 0..n_devices {
             let dev = device_manager.device(i);
             let kernel = Kernels::new(dev.clone(), ptx.clone());
@@ -122,7 +122,9 @@ mod e2e_test {
                 false,
             ) {
                 Ok((mut actor, handle)) => {
-                    actor.load_full_db(&(&db0.0, &db0.1), &(&db0.0, &db0.1), DB_SIZE);
+                    actor
+                        .load_full_db(&(&db0.0, &db0.1), &(&db0.0, &db0.1), DB_SIZE)
+                        .unwrap();
                     tx0.send(Ok(handle)).unwrap();
                     actor
                 }
@@ -149,7 +151,9 @@ mod e2e_test {
                 false,
             ) {
                 Ok((mut actor, handle)) => {
-                    actor.load_full_db(&(&db1.0, &db1.1), &(&db1.0, &db1.1), DB_SIZE);
+                    actor
+                        .load_full_db(&(&db1.0, &db1.1), &(&db1.0, &db1.1), DB_SIZE)
+                        .unwrap();
                     tx1.send(Ok(handle)).unwrap();
                     actor
                 }
@@ -176,7 +180,9 @@ mod e2e_test {
                 false,
             ) {
                 Ok((mut actor, handle)) => {
-                    actor.load_full_db(&(&db2.0, &db2.1), &(&db2.0, &db2.1), DB_SIZE);
+                    actor
+                        .load_full_db(&(&db2.0, &db2.1), &(&db2.0, &db2.1), DB_SIZE)
+                        .unwrap();
                     tx2.send(Ok(handle)).unwrap();
                     actor
                 }
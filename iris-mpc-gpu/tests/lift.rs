@@ -1,20 +1,55 @@
 #[cfg(feature = "gpu_dependent")]
 mod lift_test {
-    use cudarc::driver::{CudaDevice, CudaStream};
+    use cudarc::{
+        driver::{CudaDevice, CudaStream},
+        nccl::Id,
+    };
     use iris_mpc_common::iris_db::iris::IrisCodeArray;
     use iris_mpc_gpu::{
-        helpers::{device_manager::DeviceManager, dtoh_on_stream_sync, htod_on_stream_sync},
+        helpers::{
+            comm::NcclComm, device_manager::DeviceManager, dtoh_on_stream_sync, htod_on_stream_sync,
+        },
         threshold_ring::protocol::{ChunkShare, ChunkShareView, Circuits},
     };
     use itertools::izip;
     use rand::{rngs::StdRng, Rng, SeedableRng};
-    use static_assertions::const_assert;
     use std::{env, sync::Arc};
     use tokio::time::Instant;
 
     // ceil(930 * 125_000 / 2048) * 2048
-    // const INPUTS_PER_GPU_SIZE: usize = 116_250_624;
-    const INPUTS_PER_GPU_SIZE: usize = 12_507_136;
+    // const DEFAULT_INPUTS_PER_GPU_SIZE: usize = 116_250_624;
+    const DEFAULT_INPUTS_PER_GPU_SIZE: usize = 12_507_136;
+
+    /// Inputs must be a multiple of 2048: mod 16 for randomness, mod 64 for
+    /// chunk size.
+    fn validate_inputs_per_gpu(inputs_per_gpu: usize) -> eyre::Result<usize> {
+        if inputs_per_gpu == 0 || inputs_per_gpu % 2048 != 0 {
+            eyre::bail!(
+                "Inputs per GPU must be a non-zero multiple of 2048, got {}",
+                inputs_per_gpu
+            );
+        }
+        Ok(inputs_per_gpu)
+    }
+
+    /// Reads the number of inputs per GPU from `SMPC__INPUTS_PER_GPU`, falling
+    /// back to [`DEFAULT_INPUTS_PER_GPU_SIZE`].
+    fn inputs_per_gpu_from_env() -> eyre::Result<usize> {
+        let inputs_per_gpu = match env::var("SMPC__INPUTS_PER_GPU") {
+            Ok(value) => value.parse()?,
+            Err(_) => DEFAULT_INPUTS_PER_GPU_SIZE,
+        };
+        validate_inputs_per_gpu(inputs_per_gpu)
+    }
+
+    /// Whether `SMPC__NONZERO_MASKS` asks for shares that force the lift
+    /// corrections to be set.
+    fn nonzero_masks_from_env() -> eyre::Result<bool> {
+        match env::var("SMPC__NONZERO_MASKS") {
+            Ok(value) => Ok(value.parse()?),
+            Err(_) => Ok(false),
+        }
+    }
 
     fn to_view<T>(inp: &[ChunkShare<T>]) -> Vec<ChunkShareView<T>> {
         let mut res = Vec::with_capacity(inp.len());
@@ -30,10 +65,31 @@ mod lift_test {
             .collect::<Vec<_>>()
     }
 
-    fn rep_share<R: Rng>(value: u16, id: usize, rng: &mut R) -> (u16, u16) {
-        let a = rng.gen();
-        let b = rng.gen();
-        let c = value - a - b;
+    /// Samples additive shares `a + b + c` of `value` whose integer sum
+    /// overflows 16 bits once or twice, so that both lift corrections are
+    /// exercised.
+    fn nonzero_mask_shares<R: Rng>(value: u16, rng: &mut R) -> (u16, u16, u16) {
+        let value = value as u32;
+        let carries = rng.gen_range(1..=2u32);
+        let sum = if carries == 1 {
+            rng.gen_range(value + 1..=value + 0x10000)
+        } else {
+            rng.gen_range(value + 0x10001..=0x1fffe)
+        };
+        let a = rng.gen_range(sum.saturating_sub(0xffff)..=sum.min(0xffff));
+        let b = sum - a;
+        let c = value + (carries << 16) - sum;
+        (a as u16, b as u16, c as u16)
+    }
+
+    fn rep_share<R: Rng>(value: u16, id: usize, nonzero_masks: bool, rng: &mut R) -> (u16, u16) {
+        let (a, b, c) = if nonzero_masks {
+            nonzero_mask_shares(value, rng)
+        } else {
+            let a = rng.gen();
+            let b = rng.gen();
+            (a, b, value - a - b)
+        };
 
         match id {
             0 => (a, c),
@@ -43,11 +99,16 @@ mod lift_test {
         }
     }
 
-    fn rep_share_vec<R: Rng>(value: &[u16], id: usize, rng: &mut R) -> (Vec<u16>, Vec<u16>) {
+    fn rep_share_vec<R: Rng>(
+        value: &[u16],
+        id: usize,
+        nonzero_masks: bool,
+        rng: &mut R,
+    ) -> (Vec<u16>, Vec<u16>) {
         let mut a = Vec::with_capacity(value.len());
         let mut b = Vec::with_capacity(value.len());
         for v in value.iter() {
-            let (a_, b_) = rep_share(*v, id, rng);
+            let (a_, b_) = rep_share(*v, id, nonzero_masks, rng);
             a.push(a_);
             b.push(b_);
         }
@@ -59,6 +120,7 @@ mod lift_test {
         b: &[u16],
         devices: &[Arc<CudaDevice>],
         streams: &[CudaStream],
+        inputs_per_gpu: usize,
     ) -> Vec<ChunkShare<u16>> {
         debug_assert_eq!(a.len(), b.len());
 
@@ -67,8 +129,8 @@ mod lift_test {
         for (dev, stream, a, b) in izip!(
             devices,
             streams,
-            a.chunks(INPUTS_PER_GPU_SIZE),
-            b.chunks(INPUTS_PER_GPU_SIZE)
+            a.chunks(inputs_per_gpu),
+            b.chunks(inputs_per_gpu)
         ) {
             let a_ = htod_on_stream_sync(a, dev, stream).unwrap();
             let b_ = htod_on_stream_sync(b, dev, stream).unwrap();
@@ -87,7 +149,8 @@ mod lift_test {
         x: &mut [ChunkShareView<u32>],
         corrections: &mut [ChunkShareView<u16>],
         streams: &[CudaStream],
-    ) -> Vec<u32> {
+        inputs_per_gpu: usize,
+    ) -> (Vec<u32>, [usize; 2]) {
         let n_devices = x.len();
         let mut res_a = Vec::with_capacity(n_devices);
         let mut res_b = Vec::with_capacity(n_devices);
@@ -126,32 +189,36 @@ mod lift_test {
             corr_c.push(dtoh_on_stream_sync(&corr.a, &devices[idx], &streams[idx]).unwrap());
         }
 
-        let mut result = Vec::with_capacity(n_devices * INPUTS_PER_GPU_SIZE);
+        let mut result = Vec::with_capacity(n_devices * inputs_per_gpu);
+        // Number of elements where the first and second correction is set
+        let mut n_corrections = [0usize; 2];
         for (mut res_a, res_b, res_c, corr_a, corr_b, corr_c) in
             izip!(res_a, res_b, res_c, corr_a, corr_b, corr_c)
         {
-            assert_eq!(res_a.len(), INPUTS_PER_GPU_SIZE);
-            assert_eq!(res_b.len(), INPUTS_PER_GPU_SIZE);
-            assert_eq!(res_c.len(), INPUTS_PER_GPU_SIZE);
-            assert_eq!(corr_a.len(), INPUTS_PER_GPU_SIZE * 2);
-            assert_eq!(corr_b.len(), INPUTS_PER_GPU_SIZE * 2);
-            assert_eq!(corr_c.len(), INPUTS_PER_GPU_SIZE * 2);
+            assert_eq!(res_a.len(), inputs_per_gpu);
+            assert_eq!(res_b.len(), inputs_per_gpu);
+            assert_eq!(res_c.len(), inputs_per_gpu);
+            assert_eq!(corr_a.len(), inputs_per_gpu * 2);
+            assert_eq!(corr_b.len(), inputs_per_gpu * 2);
+            assert_eq!(corr_c.len(), inputs_per_gpu * 2);
 
             for (res_a, res_b, res_c, corr_a1, corr_b1, corr_c1, corr_a2, corr_b2, corr_c2) in izip!(
                 &mut res_a,
                 res_b,
                 res_c,
-                corr_a.iter().take(INPUTS_PER_GPU_SIZE),
-                corr_b.iter().take(INPUTS_PER_GPU_SIZE),
-                corr_c.iter().take(INPUTS_PER_GPU_SIZE),
-                corr_a.iter().skip(INPUTS_PER_GPU_SIZE),
-                corr_b.iter().skip(INPUTS_PER_GPU_SIZE),
-                corr_c.iter().skip(INPUTS_PER_GPU_SIZE),
+                corr_a.iter().take(inputs_per_gpu),
+                corr_b.iter().take(inputs_per_gpu),
+                corr_c.iter().take(inputs_per_gpu),
+                corr_a.iter().skip(inputs_per_gpu),
+                corr_b.iter().skip(inputs_per_gpu),
+                corr_c.iter().skip(inputs_per_gpu),
             ) {
                 let corr1 = *corr_a1 + corr_b1 + corr_c1;
                 let corr2 = *corr_a2 + corr_b2 + corr_c2;
                 assert!(corr1 == 0 || corr1 == 1);
                 assert!(corr2 == 0 || corr2 == 1);
+                n_corrections[0] += corr1 as usize;
+                n_corrections[1] += corr2 as usize;
                 let mut res = *res_a + res_b + res_c;
                 res -= (corr1 as u32) << 16;
                 res -= (corr2 as u32) << 17;
@@ -160,45 +227,41 @@ mod lift_test {
             result.extend(res_a);
         }
 
-        assert_eq!(result.len(), n_devices * INPUTS_PER_GPU_SIZE);
-        result
+        assert_eq!(result.len(), n_devices * inputs_per_gpu);
+        (result, n_corrections)
     }
 
-    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
-    #[ignore]
-    async fn test_lift() -> eyre::Result<()> {
+    /// Runs the lift for one party and checks the opened result against the
+    /// plaintext reference. Returns whether all iterations passed.
+    fn run_lift(
+        party_id: usize,
+        device_manager: Arc<DeviceManager>,
+        comms: Vec<Arc<NcclComm>>,
+        inputs_per_gpu: usize,
+        nonzero_masks: bool,
+    ) -> bool {
         use itertools::Itertools;
 
-        const_assert!(
-            INPUTS_PER_GPU_SIZE % (2048) == 0,
-            // Mod 16 for randomness, mod 64 for chunk size
-        );
         // TODO
         let mut rng = StdRng::seed_from_u64(42);
 
-        let party_id: usize = env::var("SMPC__PARTY_ID")
-            .expect("SMPC__PARTY_ID environment variable not set")
-            .parse()
-            .expect("SMPC__PARTY_ID must be a valid usize");
-        let n_devices = CudaDevice::count()? as usize;
+        let n_devices = device_manager.device_count();
 
         // Get inputs
-        let mask_dots = sample_mask_dots(INPUTS_PER_GPU_SIZE * n_devices, &mut rng);
+        let mask_dots = sample_mask_dots(inputs_per_gpu * n_devices, &mut rng);
 
-        let (mask_share_a, mask_share_b) = rep_share_vec(&mask_dots, party_id, &mut rng);
+        let (mask_share_a, mask_share_b) =
+            rep_share_vec(&mask_dots, party_id, nonzero_masks, &mut rng);
         let real_result = real_result_msb(mask_dots);
         println!("Random shared inputs generated!");
 
         // Get Circuit Party
-        let device_manager = Arc::new(DeviceManager::init());
-        let ids = device_manager.get_ids_from_magic(0);
-        let comms = device_manager.instantiate_network_from_ids(party_id, &ids)?;
         let mut party = Circuits::new(
             party_id,
-            INPUTS_PER_GPU_SIZE,
-            INPUTS_PER_GPU_SIZE / 64,
+            inputs_per_gpu,
+            inputs_per_gpu / 64,
             ([party_id as u32; 8], [((party_id + 2) % 3) as u32; 8]),
-            device_manager.clone(),
+            device_manager,
             comms,
         );
         let devices = party.get_devices();
@@ -208,15 +271,22 @@ mod lift_test {
             .collect::<Vec<_>>();
 
         // Import to GPU
-        let mask_gpu = to_gpu(&mask_share_a, &mask_share_b, &devices, &streams);
+        let mask_gpu = to_gpu(
+            &mask_share_a,
+            &mask_share_b,
+            &devices,
+            &streams,
+            inputs_per_gpu,
+        );
         println!("Data is on GPUs!");
         println!("Starting tests...");
 
+        let mut all_correct = true;
         for _ in 0..10 {
             // Simulate Masks to be zero for this test
-            let x_ = party.allocate_buffer::<u32>(INPUTS_PER_GPU_SIZE);
+            let x_ = party.allocate_buffer::<u32>(inputs_per_gpu);
             let mut x = to_view(&x_);
-            let correction_ = party.allocate_buffer::<u16>(INPUTS_PER_GPU_SIZE * 2);
+            let correction_ = party.allocate_buffer::<u16>(inputs_per_gpu * 2);
             let mut correction = to_view(&correction_);
             let mask_gpu = mask_gpu.iter().map(|x| x.as_view()).collect_vec();
 
@@ -225,7 +295,13 @@ mod lift_test {
             println!("compute time: {:?}", now.elapsed());
 
             let now = Instant::now();
-            let result = open(&mut party, &mut x, &mut correction, &streams);
+            let (result, n_corrections) = open(
+                &mut party,
+                &mut x,
+                &mut correction,
+                &streams,
+                inputs_per_gpu,
+            );
             party.synchronize_streams(&streams);
             println!("Open and transfer to CPU time: {:?}", now.elapsed());
 
@@ -237,11 +313,117 @@ mod lift_test {
                     break;
                 }
             }
+            if nonzero_masks && n_corrections.contains(&0) {
+                correct = false;
+                println!(
+                    "Test failed: expected both corrections to be set, got {:?}",
+                    n_corrections
+                );
+            }
             if correct {
                 println!("Test passed!");
             }
+            all_correct &= correct;
         }
 
+        all_correct
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[ignore]
+    async fn test_lift() -> eyre::Result<()> {
+        let inputs_per_gpu = inputs_per_gpu_from_env()?;
+        let nonzero_masks = nonzero_masks_from_env()?;
+
+        let party_id: usize = env::var("SMPC__PARTY_ID")
+            .expect("SMPC__PARTY_ID environment variable not set")
+            .parse()
+            .expect("SMPC__PARTY_ID must be a valid usize");
+
+        let device_manager = Arc::new(DeviceManager::init());
+        let ids = device_manager.get_ids_from_magic(0);
+        let comms = device_manager.instantiate_network_from_ids(party_id, &ids)?;
+        assert!(run_lift(
+            party_id,
+            device_manager,
+            comms,
+            inputs_per_gpu,
+            nonzero_masks,
+        ));
+
         Ok(())
     }
+
+    /// Runs all three parties in-process on a single multi-GPU host, each on
+    /// its own third of the local devices, so no peer address or
+    /// `SMPC__PARTY_ID` is needed. There is no single-GPU mode: NCCL does not
+    /// allow several ranks of one communicator on the same GPU, and `Circuits`
+    /// only talks to its peers through NCCL, so this needs at least three
+    /// devices.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 3)]
+    #[ignore]
+    async fn test_lift_local_multi_gpu() -> eyre::Result<()> {
+        let inputs_per_gpu = inputs_per_gpu_from_env()?;
+        let nonzero_masks = nonzero_masks_from_env()?;
+        let device_managers = DeviceManager::init()
+            .split_into_n_chunks(3)
+            .expect("have at least 3 devices");
+        let n_devices = device_managers[0].device_count();
+        let ids = (0..n_devices)
+            .map(|_| Id::new().unwrap())
+            .collect::<Vec<_>>();
+
+        let tasks = device_managers
+            .into_iter()
+            .enumerate()
+            .map(|(party_id, device_manager)| {
+                let ids = ids.clone();
+                tokio::task::spawn_blocking(move || {
+                    let device_manager = Arc::new(device_manager);
+                    let comms = device_manager
+                        .instantiate_network_from_ids(party_id, &ids)
+                        .unwrap();
+                    run_lift(
+                        party_id,
+                        device_manager,
+                        comms,
+                        inputs_per_gpu,
+                        nonzero_masks,
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for task in tasks {
+            assert!(task.await?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_nonzero_mask_shares() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut n_carries = [0usize; 3];
+        for value in sample_mask_dots(10_000, &mut rng) {
+            let (a, b, c) = nonzero_mask_shares(value, &mut rng);
+            assert_eq!(a.wrapping_add(b).wrapping_add(c), value);
+            let sum = a as u32 + b as u32 + c as u32;
+            n_carries[(sum >> 16) as usize] += 1;
+        }
+        assert_eq!(n_carries[0], 0);
+        assert!(n_carries[1] > 0 && n_carries[2] > 0);
+    }
+
+    #[test]
+    fn test_validate_inputs_per_gpu() {
+        assert_eq!(validate_inputs_per_gpu(2048).unwrap(), 2048);
+        assert_eq!(
+            validate_inputs_per_gpu(DEFAULT_INPUTS_PER_GPU_SIZE).unwrap(),
+            DEFAULT_INPUTS_PER_GPU_SIZE
+        );
+        assert!(validate_inputs_per_gpu(0).is_err());
+        assert!(validate_inputs_per_gpu(1024).is_err());
+        assert!(validate_inputs_per_gpu(2048 + 64).is_err());
+    }
 }
@@ -2,10 +2,11 @@ use bytemuck::cast_slice;
 use eyre::{eyre, Result};
 use futures::{
     stream::{self},
-    Stream,
+    Stream, StreamExt,
 };
 use iris_mpc_common::{
     config::Config,
+    eye::Eye,
     galois_engine::degree4::{GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare},
     iris_db::iris::IrisCode,
 };
@@ -62,11 +63,32 @@ impl StoredIris {
     pub fn right_mask(&self) -> &[u16] {
         cast_u8_to_u16(&self.right_mask)
     }
+
+    pub fn code(&self, eye: Eye) -> &[u16] {
+        match eye {
+            Eye::Left => self.left_code(),
+            Eye::Right => self.right_code(),
+        }
+    }
+
+    pub fn mask(&self, eye: Eye) -> &[u16] {
+        match eye {
+            Eye::Left => self.left_mask(),
+            Eye::Right => self.right_mask(),
+        }
+    }
     pub fn id(&self) -> i64 {
         self.id
     }
 }
 
+/// The shares of each iris streamed by [`Store::stream_shares_in_range`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SharePart {
+    Code,
+    Mask,
+}
+
 #[derive(Clone)]
 pub struct StoredIrisRef<'a> {
     pub left_code:  &'a [u16],
@@ -154,6 +176,32 @@ impl Store {
         .bind(i64::try_from(id_range.end).expect("id fits into i64"))
         .fetch(&self.pool)
     }
+
+    /// Streams the code or mask shares of `eye` of the irises with ids in
+    /// `id_range`, e.g. to load them into a GPU engine. The ids have to be
+    /// contiguous, so the `n`-th item is the iris with id `id_range.start +
+    /// n`; a gap is reported as an error.
+    pub fn stream_shares_in_range(
+        &self,
+        id_range: std::ops::Range<u64>,
+        eye: Eye,
+        part: SharePart,
+    ) -> impl Stream<Item = Result<Vec<u16>>> + '_ {
+        let start = id_range.start;
+        self.stream_irises_in_range(id_range)
+            .enumerate()
+            .map(move |(i, iris)| {
+                let iris = iris?;
+                let expected = start + i as u64;
+                if iris.id() as u64 != expected {
+                    return Err(eyre!("expected iris id {}, got {}", expected, iris.id()));
+                }
+                Ok(match part {
+                    SharePart::Code => iris.code(eye).to_vec(),
+                    SharePart::Mask => iris.mask(eye).to_vec(),
+                })
+            })
+    }
     /// Stream irises in parallel, without a particular order.
     pub async fn stream_irises_par(
         &self,
@@ -546,6 +594,26 @@ mod tests {
             assert_eq!(got[i].right_mask(), codes_and_masks[i].right_mask);
         }
 
+        let got: Vec<Vec<u16>> = store
+            .stream_shares_in_range(2..4, Eye::Left, SharePart::Mask)
+            .try_collect()
+            .await?;
+        assert_eq!(got, [
+            codes_and_masks[1].left_mask,
+            codes_and_masks[2].left_mask
+        ]);
+        let got: Vec<Vec<u16>> = store
+            .stream_shares_in_range(1..2, Eye::Right, SharePart::Code)
+            .try_collect()
+            .await?;
+        assert_eq!(got, [codes_and_masks[0].right_code]);
+        // The range starts before the first id
+        assert!(store
+            .stream_shares_in_range(0..2, Eye::Left, SharePart::Code)
+            .try_collect::<Vec<_>>()
+            .await
+            .is_err());
+
         // Clean up on success.
         cleanup(&store, &schema_name).await?;
         Ok(())
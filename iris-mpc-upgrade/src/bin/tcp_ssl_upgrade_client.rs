@@ -72,9 +72,7 @@ async fn main() -> eyre::Result<()> {
         BATCH_TIMEOUT_SECONDS
     };
 
-    if args.party_id > 1 {
-        panic!("Party id must be 0, 1");
-    }
+    args.validate()?;
 
     let mut server1 = prepare_tls_stream_for_writing(&args.server1).await?;
     let mut server2 = prepare_tls_stream_for_writing(&args.server2).await?;
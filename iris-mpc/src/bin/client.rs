@@ -1,9 +1,9 @@
 #![allow(clippy::needless_range_loop)]
-use aws_config::retry::RetryConfig;
-use aws_sdk_sns::{config::Region, Client};
-use aws_sdk_sqs::Client as SqsClient;
+use aws_config::{retry::RetryConfig, ConfigLoader};
+use aws_sdk_sns::{config::Region, types::PublishBatchRequestEntry, Client};
+use aws_sdk_sqs::{types::Message, Client as SqsClient};
 use base64::{engine::general_purpose, Engine};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use eyre::{Context, ContextCompat};
 use iris_mpc_common::{
     galois_engine::degree4::GaloisRingIrisCodeShare,
@@ -11,17 +11,26 @@ use iris_mpc_common::{
         key_pair::download_public_key,
         sha256::calculate_sha256,
         smpc_request::{
-            create_message_type_attribute_map, IrisCodesJSON, UniquenessRequest, UniquenessResult,
-            UNIQUENESS_MESSAGE_TYPE,
+            create_message_type_attribute_map, verify_result_hmac, IrisCodesJSON,
+            UniquenessRequest, UniquenessResult, RESULT_HMAC_ATTRIBUTE, UNIQUENESS_MESSAGE_TYPE,
         },
         sqs_s3_helper::upload_file_and_generate_presigned_url,
     },
-    iris_db::{db::IrisDB, iris::IrisCode},
+    iris_db::{
+        db::{IrisDB, TestVectorSeed},
+        iris::{IrisCode, IrisCodeArray},
+    },
 };
-use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand::{rngs::StdRng, CryptoRng, Rng, SeedableRng};
+use serde::Serialize;
 use serde_json::to_string;
 use sodiumoxide::crypto::{box_::PublicKey, sealedbox};
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::{
     spawn,
     sync::{Mutex, Semaphore},
@@ -34,9 +43,148 @@ const BATCH_SIZE: usize = 64;
 const N_BATCHES: usize = 100;
 const N_QUERIES: usize = BATCH_SIZE * N_BATCHES;
 const WAIT_AFTER_BATCH: Duration = Duration::from_secs(2);
+/// Seed of the random DB, which the servers must generate with the same seed
+/// for the expected results to hold.
 const RNG_SEED_SERVER: u64 = 42;
 const DB_SIZE: usize = 8 * 1_000;
 const ENROLLMENT_REQUEST_TYPE: &str = "enrollment";
+/// Number of parties that send a result for every request.
+const N_PARTIES: usize = 3;
+/// Long polling wait of a single receive, the maximum SQS allows.
+const RECEIVE_WAIT_TIME_SECONDS: i32 = 20;
+/// Maximum number of messages SQS returns from a single receive.
+const RECEIVE_MAX_MESSAGES: i32 = 10;
+/// Maximum number of entries in a single SNS PublishBatch call.
+const SNS_MAX_BATCH_ENTRIES: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl OutputFormat {
+    /// Prints a progress line. In JSON mode it goes to stderr, so that stdout
+    /// only holds the result lines.
+    fn status(self, line: &str) {
+        match self {
+            OutputFormat::Text => println!("{}", line),
+            OutputFormat::Json => eprintln!("{}", line),
+        }
+    }
+}
+
+/// Outcome of a single request, printed as one JSON line in
+/// `--output json` mode.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct ResultLine {
+    request_id: String,
+    expected:   Option<u32>,
+    actual:     Option<u32>,
+    matched:    bool,
+}
+
+impl ResultLine {
+    /// `expected` is the serial id the request should match, or `None` if it
+    /// should be inserted as a new entry.
+    fn new(result: &UniquenessResult, expected: Option<u32>) -> Self {
+        let actual = match (result.is_match, &result.matched_serial_ids) {
+            (true, Some(ids)) if ids.len() == 1 => Some(ids[0]),
+            _ => None,
+        };
+        let matched = result.is_match == expected.is_some() && actual == expected;
+        Self {
+            request_id: result.signup_id.clone(),
+            expected,
+            actual,
+            matched,
+        }
+    }
+}
+
+/// The iris codes of both eyes of one signup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct IrisCodePair {
+    left:  IrisCode,
+    right: IrisCode,
+}
+
+impl IrisCodePair {
+    /// Uses `iris` for both eyes, like the random DB of the servers does.
+    fn both(iris: IrisCode) -> Self {
+        Self {
+            left:  iris.clone(),
+            right: iris,
+        }
+    }
+
+    fn random_rng<R: Rng>(rng: &mut R) -> Self {
+        Self {
+            left:  IrisCode::random_rng(rng),
+            right: IrisCode::random_rng(rng),
+        }
+    }
+
+    /// Secret shares both eyes and returns the shares of each party.
+    ///
+    /// A single `encode_iris_code` / `encode_mask_code` call per eye produces
+    /// the shares of all three parties, so `rng` is used as is and never
+    /// re-seeded per party.
+    fn encode_shares<R: CryptoRng + Rng>(&self, rng: &mut R) -> [IrisCodesJSON; 3] {
+        let [left_code, right_code] = [&self.left, &self.right]
+            .map(|iris| GaloisRingIrisCodeShare::encode_iris_code(&iris.code, &iris.mask, rng));
+        let [left_mask, right_mask] = [&self.left, &self.right]
+            .map(|iris| GaloisRingIrisCodeShare::encode_mask_code(&iris.mask, rng));
+
+        std::array::from_fn(|i| IrisCodesJSON {
+            iris_version:           "1.0".to_string(),
+            iris_shares_version:    "1.3".to_string(),
+            left_iris_code_shares:  left_code[i].to_base64(),
+            left_mask_code_shares:  left_mask[i].to_base64(),
+            right_iris_code_shares: right_code[i].to_base64(),
+            right_mask_code_shares: right_mask[i].to_base64(),
+        })
+    }
+}
+
+/// Groups `entries` into PublishBatch calls of at most
+/// [`SNS_MAX_BATCH_ENTRIES`] entries each, keeping their order.
+fn publish_batches<T>(entries: Vec<T>) -> Vec<Vec<T>> {
+    let mut batches = Vec::with_capacity(entries.len().div_ceil(SNS_MAX_BATCH_ENTRIES));
+    let mut entries = entries.into_iter().peekable();
+    while entries.peek().is_some() {
+        batches.push(entries.by_ref().take(SNS_MAX_BATCH_ENTRIES).collect());
+    }
+    batches
+}
+
+/// Spaces out requests so that at most `rate` of them are sent per second.
+#[derive(Debug)]
+struct Pacer {
+    interval: Option<Duration>,
+    next:     Option<Instant>,
+}
+
+impl Pacer {
+    fn new(rate: Option<f64>) -> Self {
+        Self {
+            interval: rate.map(|rate| Duration::from_secs_f64(1.0 / rate)),
+            next:     None,
+        }
+    }
+
+    /// Reserves the next send slot for `n` requests sent at once and returns
+    /// how long to wait from `now` until it starts. The following slot starts
+    /// `n` intervals later.
+    fn delay(&mut self, now: Instant, n: usize) -> Duration {
+        let Some(interval) = self.interval else {
+            return Duration::ZERO;
+        };
+        let slot = self.next.map_or(now, |next| next.max(now));
+        self.next = Some(slot + interval * n as u32);
+        slot - now
+    }
+}
 
 #[derive(Debug, Parser)]
 struct Opt {
@@ -72,6 +220,298 @@ struct Opt {
 
     #[arg(long, env)]
     random: Option<bool>,
+
+    #[arg(long, env, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    #[arg(long, env, default_value_t = N_QUERIES)]
+    n_queries: usize,
+
+    /// Size of the random DB the server was started with.
+    #[arg(long, env, default_value_t = DB_SIZE)]
+    db_size: usize,
+
+    /// RNG seed the server used to generate its random DB. Must match the
+    /// server for the expected results to hold.
+    #[arg(long, env, default_value_t = RNG_SEED_SERVER)]
+    server_seed: u64,
+
+    /// JSON file with the seed and size of the servers' random DB, as passed
+    /// to the servers. Overrides `--db-size` and `--server-seed`.
+    #[arg(long, env)]
+    test_vector_seed: Option<PathBuf>,
+
+    /// Maximum number of requests published per second. Unlimited if unset.
+    #[arg(long, env, value_parser = parse_rate)]
+    rate: Option<f64>,
+
+    /// Maximum number of requests being prepared and published at once.
+    #[arg(long, env, default_value_t = MAX_CONCURRENT_REQUESTS, value_parser = parse_max_in_flight)]
+    max_in_flight: usize,
+
+    /// File of templates to submit as queries instead of generated ones, see
+    /// `parse_templates` for the format. Overrides `--n-queries`.
+    #[arg(long, env)]
+    templates: Option<PathBuf>,
+
+    /// Shared secret the servers use to HMAC their results. If set, results
+    /// without a valid HMAC are logged and skipped.
+    #[arg(long, env)]
+    result_hmac_key: Option<String>,
+
+    /// Region used for all AWS services instead of the per-service regions.
+    #[arg(long, env)]
+    region: Option<String>,
+
+    /// Custom endpoint for the SNS and SQS clients, e.g. a LocalStack
+    /// instance.
+    #[arg(long, env)]
+    endpoint_url: Option<String>,
+
+    /// Visibility timeout in seconds for received results. Defaults to the
+    /// timeout configured on the queue.
+    #[arg(long, env)]
+    visibility_timeout: Option<i32>,
+
+    /// Seconds from the start after which the client gives up waiting for
+    /// missing results. Waits forever if unset.
+    #[arg(long, env)]
+    receive_timeout: Option<u64>,
+}
+
+/// Starts loading the AWS config for `region` from the environment, with all
+/// clients pointed at `endpoint_url` if it is set.
+fn aws_config_loader(region: String, endpoint_url: Option<&str>) -> ConfigLoader {
+    let loader = aws_config::from_env().region(Region::new(region));
+    match endpoint_url {
+        Some(endpoint_url) => loader.endpoint_url(endpoint_url),
+        None => loader,
+    }
+}
+
+/// The operations of the response queue used by [`receive_results`].
+trait ResultQueue {
+    async fn receive(&self, visibility_timeout: Option<i32>) -> eyre::Result<Vec<Message>>;
+    async fn delete(&self, receipt_handle: &str) -> eyre::Result<()>;
+}
+
+struct SqsResultQueue {
+    client:    SqsClient,
+    queue_url: String,
+}
+
+impl ResultQueue for SqsResultQueue {
+    async fn receive(&self, visibility_timeout: Option<i32>) -> eyre::Result<Vec<Message>> {
+        let output = self
+            .client
+            .receive_message()
+            .max_number_of_messages(RECEIVE_MAX_MESSAGES)
+            .wait_time_seconds(RECEIVE_WAIT_TIME_SECONDS)
+            .message_attribute_names(RESULT_HMAC_ATTRIBUTE)
+            .set_visibility_timeout(visibility_timeout)
+            .queue_url(self.queue_url.clone())
+            .send()
+            .await
+            .context("Failed to receive message")?;
+        Ok(output.messages.unwrap_or_default())
+    }
+
+    async fn delete(&self, receipt_handle: &str) -> eyre::Result<()> {
+        self.client
+            .delete_message()
+            .queue_url(self.queue_url.clone())
+            .receipt_handle(receipt_handle)
+            .send()
+            .await
+            .context("Failed to delete message")?;
+        Ok(())
+    }
+}
+
+/// Checks the results received from the servers against the expected ones.
+struct ResultChecker {
+    output:           OutputFormat,
+    result_hmac_key:  Option<String>,
+    expected_results: Arc<Mutex<HashMap<String, Option<u32>>>>,
+    requests:         Arc<Mutex<HashMap<String, IrisCodePair>>>,
+    responses:        Arc<Mutex<HashMap<u32, IrisCodePair>>>,
+    mismatches:       usize,
+    /// Request ids and node ids of the results checked so far.
+    seen:             HashSet<(String, usize)>,
+}
+
+/// What to do with a result message once it has been looked at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Disposition {
+    /// The first result of a node for an expected request. It was checked and
+    /// can be deleted.
+    Checked,
+    /// A result that is not counted, because it is a duplicate, stale or has
+    /// an invalid HMAC. It can be deleted.
+    Ignored,
+    /// The message could not be parsed. It is left in the queue, so that it
+    /// ends up in the dead-letter queue after enough receives.
+    Malformed,
+}
+
+impl ResultChecker {
+    async fn check(&mut self, body: &str, hmac: Option<&str>) -> eyre::Result<Disposition> {
+        if let Some(key) = &self.result_hmac_key {
+            if !hmac.is_some_and(|hmac| verify_result_hmac(key.as_bytes(), body, hmac)) {
+                eprintln!("Skipping result with missing or invalid HMAC: {}", body);
+                return Ok(Disposition::Ignored);
+            }
+        }
+
+        let result: UniquenessResult = match serde_json::from_str(body) {
+            Ok(result) => result,
+            Err(e) => {
+                tracing::error!("Leaving malformed result in the queue: {}: {}", e, body);
+                return Ok(Disposition::Malformed);
+            }
+        };
+
+        if self.output == OutputFormat::Text {
+            println!("Received result: {:?}", result);
+        }
+
+        let expected_result_option = {
+            let tmp = self.expected_results.lock().await;
+            tmp.get(&result.signup_id).cloned()
+        };
+        let Some(expected_result) = expected_result_option else {
+            eprintln!(
+                "No expected result found for request_id: {}, the SQS message is likely stale, \
+                 clear the queue",
+                result.signup_id
+            );
+            return Ok(Disposition::Ignored);
+        };
+        if !self.seen.insert((result.signup_id.clone(), result.node_id)) {
+            tracing::warn!(
+                "Ignoring duplicate result of node {} for request_id: {}",
+                result.node_id,
+                result.signup_id
+            );
+            return Ok(Disposition::Ignored);
+        }
+        let line = ResultLine::new(&result, expected_result);
+
+        match self.output {
+            OutputFormat::Text => assert!(
+                line.matched,
+                "Unexpected result for request_id {}: expected {:?}, got {:?}",
+                line.request_id, line.expected, line.actual
+            ),
+            OutputFormat::Json => {
+                println!("{}", to_string(&line)?);
+                if !line.matched {
+                    self.mismatches += 1;
+                }
+            }
+        }
+
+        if line.matched && expected_result.is_none() {
+            // New insertion
+            let request = {
+                let tmp = self.requests.lock().await;
+                tmp.get(&result.signup_id).unwrap().clone()
+            };
+            {
+                let mut tmp = self.responses.lock().await;
+                tmp.insert(result.serial_id.unwrap(), request);
+            }
+        }
+
+        Ok(Disposition::Checked)
+    }
+}
+
+/// Long-polls the queue until a result of every party has been checked for
+/// each of the `n_requests` requests, or fails once `deadline` has passed.
+/// Results may arrive in any order and several times; duplicates are deleted
+/// without being checked again. Malformed messages, including ones without a
+/// body, are left in the queue.
+async fn receive_results(
+    queue: &impl ResultQueue,
+    checker: &mut ResultChecker,
+    n_requests: usize,
+    visibility_timeout: Option<i32>,
+    deadline: Option<Instant>,
+) -> eyre::Result<()> {
+    let n_results = n_requests * N_PARTIES;
+    while checker.seen.len() < n_results {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            eyre::bail!(
+                "Timed out waiting for results, got {} of {}",
+                checker.seen.len(),
+                n_results
+            );
+        }
+
+        for msg in queue.receive(visibility_timeout).await? {
+            let Some(body) = msg.body.as_deref() else {
+                tracing::error!(
+                    "Leaving result without a body in the queue: {:?}",
+                    msg.message_id
+                );
+                continue;
+            };
+            let hmac = msg
+                .message_attributes
+                .as_ref()
+                .and_then(|attributes| attributes.get(RESULT_HMAC_ATTRIBUTE))
+                .and_then(|attribute| attribute.string_value());
+
+            match checker.check(body, hmac).await? {
+                Disposition::Checked | Disposition::Ignored => {
+                    queue
+                        .delete(msg.receipt_handle().context("No receipt handle found")?)
+                        .await?;
+                }
+                Disposition::Malformed => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parses newline-delimited templates, each line holding the base64 encoded
+/// iris code and mask separated by whitespace. Empty lines are skipped.
+fn parse_templates(s: &str) -> eyre::Result<Vec<IrisCode>> {
+    s.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let mut parts = line.split_whitespace();
+            let (Some(code), Some(mask), None) = (parts.next(), parts.next(), parts.next()) else {
+                eyre::bail!("Line {}: expected an iris code and a mask", i + 1);
+            };
+            Ok(IrisCode {
+                code: IrisCodeArray::from_base64(code)
+                    .with_context(|| format!("Line {}: invalid iris code", i + 1))?,
+                mask: IrisCodeArray::from_base64(mask)
+                    .with_context(|| format!("Line {}: invalid mask", i + 1))?,
+            })
+        })
+        .collect()
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("rate must be a positive number, got {}", s)),
+    }
+}
+
+fn parse_max_in_flight(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(max_in_flight) if max_in_flight > 0 => Ok(max_in_flight),
+        _ => Err(format!(
+            "max in flight must be a positive integer, got {}",
+            s
+        )),
+    }
 }
 
 #[tokio::main]
@@ -94,8 +534,25 @@ async fn main() -> eyre::Result<()> {
         rng_seed,
         n_repeat,
         random,
+        output,
+        n_queries,
+        db_size,
+        server_seed,
+        test_vector_seed,
+        rate,
+        max_in_flight,
+        templates,
+        result_hmac_key,
+        region,
+        endpoint_url,
+        visibility_timeout,
+        receive_timeout,
     } = Opt::parse();
 
+    let request_topic_region = region.clone().unwrap_or(request_topic_region);
+    let response_queue_region = region.clone().unwrap_or(response_queue_region);
+    let requests_bucket_region = region.unwrap_or(requests_bucket_region);
+
     let mut shares_encryption_public_keys: Vec<PublicKey> = vec![];
 
     for i in 0..3 {
@@ -111,121 +568,86 @@ async fn main() -> eyre::Result<()> {
 
     let n_repeat = n_repeat.unwrap_or(0);
 
-    let region_provider = Region::new(request_topic_region);
+    let templates = match templates {
+        Some(path) => {
+            let contents = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read templates from {}", path.display()))?;
+            Some(Arc::new(parse_templates(&contents)?))
+        }
+        None => None,
+    };
+    let n_queries = templates
+        .as_ref()
+        .map_or(n_queries, |templates| templates.len());
 
-    let requests_sns_config = aws_config::from_env()
-        .region(region_provider)
+    let requests_sns_config = aws_config_loader(request_topic_region, endpoint_url.as_deref())
         .retry_config(RetryConfig::standard().with_max_attempts(5))
         .load()
         .await;
 
     let requests_sns_client = Client::new(&requests_sns_config);
 
-    let db = IrisDB::new_random_par(DB_SIZE, &mut StdRng::seed_from_u64(RNG_SEED_SERVER));
+    let db = match test_vector_seed {
+        Some(path) => TestVectorSeed::load(&path)
+            .with_context(|| format!("Failed to read test vector seed from {}", path.display()))?
+            .db(),
+        None => TestVectorSeed {
+            seed: server_seed,
+            db_size,
+        }
+        .db(),
+    };
 
     let expected_results: Arc<Mutex<HashMap<String, Option<u32>>>> =
         Arc::new(Mutex::new(HashMap::new()));
-    let requests: Arc<Mutex<HashMap<String, IrisCode>>> = Arc::new(Mutex::new(HashMap::new()));
-    let responses: Arc<Mutex<HashMap<u32, IrisCode>>> = Arc::new(Mutex::new(HashMap::new()));
+    let requests: Arc<Mutex<HashMap<String, IrisCodePair>>> = Arc::new(Mutex::new(HashMap::new()));
+    let responses: Arc<Mutex<HashMap<u32, IrisCodePair>>> = Arc::new(Mutex::new(HashMap::new()));
     let db: Arc<Mutex<IrisDB>> = Arc::new(Mutex::new(db));
     let requests_sns_client: Arc<Client> = Arc::new(requests_sns_client);
 
-    let thread_expected_results = expected_results.clone();
-    let thread_requests = requests.clone();
-    let thread_responses = responses.clone();
+    let semaphore = Arc::new(Semaphore::new(max_in_flight));
+    let mut pacer = Pacer::new(rate);
 
-    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_REQUESTS));
+    let mut checker = ResultChecker {
+        output,
+        result_hmac_key,
+        expected_results: expected_results.clone(),
+        requests: requests.clone(),
+        responses: responses.clone(),
+        mismatches: 0,
+        seen: HashSet::new(),
+    };
+    let deadline = receive_timeout.map(|timeout| Instant::now() + Duration::from_secs(timeout));
 
     let recv_thread = spawn(async move {
-        let region_provider = Region::new(response_queue_region);
-        let results_sqs_config = aws_config::from_env().region(region_provider).load().await;
-        let results_sqs_client = SqsClient::new(&results_sqs_config);
-        let mut counter = 0;
-        while counter < N_QUERIES * 3 {
-            // Receive responses
-            let msg = results_sqs_client
-                .receive_message()
-                .max_number_of_messages(1)
-                .queue_url(response_queue_url.clone())
-                .send()
-                .await
-                .context("Failed to receive message")?;
-
-            for msg in msg.messages.unwrap_or_default() {
-                counter += 1;
-
-                let result: UniquenessResult =
-                    serde_json::from_str(&msg.body.context("No body found")?)
-                        .context("Failed to parse message body")?;
-
-                println!("Received result: {:?}", result);
-
-                let expected_result_option = {
-                    let tmp = thread_expected_results.lock().await;
-                    tmp.get(&result.signup_id).cloned()
-                };
-                if expected_result_option.is_none() {
-                    eprintln!(
-                        "No expected result found for request_id: {}, the SQS message is likely \
-                         stale, clear the queue",
-                        result.signup_id
-                    );
-
-                    results_sqs_client
-                        .delete_message()
-                        .queue_url(response_queue_url.clone())
-                        .receipt_handle(msg.receipt_handle.unwrap())
-                        .send()
-                        .await
-                        .context("Failed to delete message")?;
-
-                    continue;
-                }
-                let expected_result = expected_result_option.unwrap();
-
-                if expected_result.is_none() {
-                    // New insertion
-                    assert!(!result.is_match);
-                    let request = {
-                        let tmp = thread_requests.lock().await;
-                        tmp.get(&result.signup_id).unwrap().clone()
-                    };
-                    {
-                        let mut tmp = thread_responses.lock().await;
-                        tmp.insert(result.serial_id.unwrap(), request);
-                    }
-                } else {
-                    // Existing entry
-                    assert!(result.is_match);
-                    assert!(result.matched_serial_ids.is_some());
-                    let matched_ids = result.matched_serial_ids.unwrap();
-                    assert!(matched_ids.len() == 1);
-                    assert_eq!(expected_result.unwrap(), matched_ids[0]);
-                }
-
-                results_sqs_client
-                    .delete_message()
-                    .queue_url(response_queue_url.clone())
-                    .receipt_handle(msg.receipt_handle.unwrap())
-                    .send()
-                    .await
-                    .context("Failed to delete message")?;
-            }
-        }
-        eyre::Ok(())
+        let results_sqs_config = aws_config_loader(response_queue_region, endpoint_url.as_deref())
+            .load()
+            .await;
+        let queue = SqsResultQueue {
+            client:    SqsClient::new(&results_sqs_config),
+            queue_url: response_queue_url,
+        };
+        receive_results(
+            &queue,
+            &mut checker,
+            n_queries,
+            visibility_timeout,
+            deadline,
+        )
+        .await?;
+        eyre::Ok(checker.mismatches)
     });
 
     // Prepare query
-    for batch_idx in 0..N_BATCHES {
+    for batch_idx in 0..n_queries.div_ceil(BATCH_SIZE) {
         let mut handles = Vec::new();
-        for batch_query_idx in 0..BATCH_SIZE {
+        for batch_query_idx in 0..BATCH_SIZE.min(n_queries - batch_idx * BATCH_SIZE) {
             let shares_encryption_public_keys2 = shares_encryption_public_keys.clone();
-            let requests_sns_client2 = requests_sns_client.clone();
             let thread_db2 = db.clone();
+            let templates = templates.clone();
             let thread_expected_results2 = expected_results.clone();
             let thread_requests2 = requests.clone();
             let thread_responses2 = responses.clone();
-            let request_topic_arn = request_topic_arn.clone();
             let requests_bucket_region = requests_bucket_region.clone();
             let requests_bucket_name = requests_bucket_name.clone();
             let semaphore = Arc::clone(&semaphore);
@@ -241,7 +663,19 @@ async fn main() -> eyre::Result<()> {
 
                 let request_id = Uuid::new_v4();
 
-                let template = if random.is_some() {
+                let template = if let Some(templates) = templates {
+                    // Templates loaded from a file
+                    let template = templates[batch_idx * BATCH_SIZE + batch_query_idx].clone();
+                    let db_index = {
+                        let tmp = thread_db2.lock().await;
+                        tmp.db.iter().position(|iris| iris.is_close(&template))
+                    };
+                    {
+                        let mut tmp = thread_expected_results2.lock().await;
+                        tmp.insert(request_id.to_string(), db_index.map(|i| i as u32 + 1));
+                    }
+                    IrisCodePair::both(template)
+                } else if random.is_some() {
                     // Automatic random tests
 
                     let responses_len = {
@@ -253,15 +687,15 @@ async fn main() -> eyre::Result<()> {
 
                     match rng.gen_range(0..options) {
                         0 => {
-                            println!("Sending new iris code");
+                            output.status("Sending new iris code");
                             {
                                 let mut tmp = thread_expected_results2.lock().await;
                                 tmp.insert(request_id.to_string(), None);
                             }
-                            IrisCode::random_rng(&mut rng)
+                            IrisCodePair::random_rng(&mut rng)
                         }
                         1 => {
-                            println!("Sending iris code from db");
+                            output.status("Sending iris code from db");
                             let db_len = {
                                 let tmp = thread_db2.lock().await;
                                 tmp.db.len()
@@ -273,11 +707,11 @@ async fn main() -> eyre::Result<()> {
                             }
                             {
                                 let tmp = thread_db2.lock().await;
-                                tmp.db[db_index].clone()
+                                IrisCodePair::both(tmp.db[db_index].clone())
                             }
                         }
                         2 => {
-                            println!("Sending freshly inserted iris code");
+                            output.status("Sending freshly inserted iris code");
                             let (keys_vec, keys_idx) = {
                                 let tmp = thread_responses2.lock().await;
                                 let keys = tmp.keys().cloned().collect::<Vec<_>>();
@@ -301,13 +735,13 @@ async fn main() -> eyre::Result<()> {
                     if let Some(db_index) = db_index {
                         if batch_query_idx * batch_idx < n_repeat {
                             let tmp = thread_db2.lock().await;
-                            tmp.db[db_index].clone()
+                            IrisCodePair::both(tmp.db[db_index].clone())
                         } else {
-                            IrisCode::random_rng(&mut rng)
+                            IrisCodePair::random_rng(&mut rng)
                         }
                     } else {
                         let mut rng = StdRng::seed_from_u64(1337); // TODO
-                        IrisCode::random_rng(&mut rng)
+                        IrisCodePair::random_rng(&mut rng)
                     }
                 };
 
@@ -316,27 +750,13 @@ async fn main() -> eyre::Result<()> {
                     tmp.insert(request_id.to_string(), template.clone());
                 }
 
-                let shared_code = GaloisRingIrisCodeShare::encode_iris_code(
-                    &template.code,
-                    &template.mask,
-                    &mut rng,
-                );
-                let shared_mask =
-                    GaloisRingIrisCodeShare::encode_mask_code(&template.mask, &mut rng);
+                let shares = template.encode_shares(&mut rng);
 
                 let mut iris_shares_file_hashes: [String; 3] = Default::default();
                 let mut iris_codes_shares_base64: [String; 3] = Default::default();
 
-                for i in 0..3 {
-                    let iris_codes_json = IrisCodesJSON {
-                        iris_version:           "1.0".to_string(),
-                        iris_shares_version:    "1.3".to_string(),
-                        right_iris_code_shares: shared_code[i].to_base64(),
-                        right_mask_code_shares: shared_mask[i].to_base64(),
-                        left_iris_code_shares:  shared_code[i].to_base64(),
-                        left_mask_code_shares:  shared_mask[i].to_base64(),
-                    };
-                    let serialized_iris_codes_json = to_string(&iris_codes_json)
+                for (i, iris_codes_json) in shares.iter().enumerate() {
+                    let serialized_iris_codes_json = to_string(iris_codes_json)
                         .expect("Serialization failed")
                         .clone();
 
@@ -367,46 +787,462 @@ async fn main() -> eyre::Result<()> {
                     Err(e) => {
                         eprintln!("Failed to upload file: {}", e);
                         // ignore the error and continue
-                        return Ok(());
+                        return Ok(None);
                     }
                 };
 
                 let request_message = UniquenessRequest {
-                    batch_size: None,
-                    signup_id: request_id.to_string(),
-                    s3_presigned_url: presigned_url,
-                    iris_shares_file_hashes,
+                    batch_size:              None,
+                    signup_id:               request_id.to_string(),
+                    s3_presigned_url:        presigned_url,
+                    iris_shares_file_hashes: iris_shares_file_hashes.to_vec(),
                 };
 
-                let message_attributes = create_message_type_attribute_map(UNIQUENESS_MESSAGE_TYPE);
-
-                requests_sns_client2
-                    .publish()
-                    .topic_arn(request_topic_arn.clone())
-                    .message_group_id(ENROLLMENT_REQUEST_TYPE)
-                    .message(to_string(&request_message)?)
-                    .set_message_attributes(Some(message_attributes))
-                    .send()
-                    .await?;
-
-                eyre::Ok(())
+                eyre::Ok(Some(to_string(&request_message)?))
             });
             handles.push(handle);
         }
 
         // Wait for all tasks to complete
+        let mut messages = Vec::with_capacity(handles.len());
         for handle in handles {
-            handle.await??;
+            messages.extend(handle.await??);
+        }
+
+        let message_attributes = create_message_type_attribute_map(UNIQUENESS_MESSAGE_TYPE);
+        for batch in publish_batches(messages) {
+            let entries = batch
+                .into_iter()
+                .enumerate()
+                .map(|(i, message)| {
+                    PublishBatchRequestEntry::builder()
+                        .id(i.to_string())
+                        .message(message)
+                        .message_group_id(ENROLLMENT_REQUEST_TYPE)
+                        .set_message_attributes(Some(message_attributes.clone()))
+                        .build()
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            sleep(pacer.delay(Instant::now(), entries.len())).await;
+            let output = requests_sns_client
+                .publish_batch()
+                .topic_arn(request_topic_arn.clone())
+                .set_publish_batch_request_entries(Some(entries))
+                .send()
+                .await?;
+            if !output.failed().is_empty() {
+                eyre::bail!("Failed to publish requests: {:?}", output.failed());
+            }
         }
 
-        println!("Batch {} sent!", batch_idx);
+        output.status(&format!("Batch {} sent!", batch_idx));
 
         // Give it some time to get back results
         sleep(WAIT_AFTER_BATCH).await;
     }
 
     // Receive all messages
-    recv_thread.await??;
+    let mismatches = recv_thread.await??;
+    if mismatches > 0 {
+        eyre::bail!("{} results did not match the expected results", mismatches);
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(
+        is_match: bool,
+        serial_id: Option<u32>,
+        matched: Option<Vec<u32>>,
+    ) -> UniquenessResult {
+        UniquenessResult::new(
+            0,
+            serial_id,
+            is_match,
+            "req-1".to_string(),
+            matched,
+            None,
+            None,
+        )
+    }
+
+    const REQUIRED_ARGS: [&str; 15] = [
+        "client",
+        "--request-topic-arn",
+        "arn",
+        "--request-topic-region",
+        "eu-north-1",
+        "--response-queue-url",
+        "url",
+        "--response-queue-region",
+        "eu-north-1",
+        "--requests-bucket-name",
+        "bucket",
+        "--public-key-base-url",
+        "http://localhost",
+        "--requests-bucket-region",
+        "eu-north-1",
+    ];
+
+    #[test]
+    fn test_opt_sizes() {
+        let opt = Opt::try_parse_from(REQUIRED_ARGS).unwrap();
+        assert_eq!(opt.n_queries, N_QUERIES);
+        assert_eq!(opt.db_size, DB_SIZE);
+        assert_eq!(opt.server_seed, RNG_SEED_SERVER);
+
+        let opt = Opt::try_parse_from(REQUIRED_ARGS.into_iter().chain([
+            "--n-queries",
+            "100",
+            "--db-size",
+            "1000",
+            "--server-seed",
+            "7",
+        ]))
+        .unwrap();
+        assert_eq!(opt.n_queries, 100);
+        assert_eq!(opt.db_size, 1000);
+        assert_eq!(opt.server_seed, 7);
+    }
+
+    #[tokio::test]
+    async fn test_aws_config_endpoint() {
+        let config = aws_config_loader("us-east-1".to_string(), Some("http://localhost:4566"))
+            .load()
+            .await;
+        assert_eq!(config.endpoint_url(), Some("http://localhost:4566"));
+        assert_eq!(config.region(), Some(&Region::new("us-east-1")));
+
+        let config = aws_config_loader("eu-north-1".to_string(), None)
+            .load()
+            .await;
+        assert_eq!(config.endpoint_url(), None);
+
+        let opt = Opt::try_parse_from(REQUIRED_ARGS.into_iter().chain([
+            "--region",
+            "us-east-1",
+            "--endpoint-url",
+            "http://localhost:4566",
+        ]))
+        .unwrap();
+        assert_eq!(opt.region.as_deref(), Some("us-east-1"));
+        assert_eq!(opt.endpoint_url.as_deref(), Some("http://localhost:4566"));
+    }
+
+    /// Hands out the given batches of messages and records deleted receipt
+    /// handles.
+    struct MockQueue {
+        batches: std::sync::Mutex<std::collections::VecDeque<Vec<Message>>>,
+        deleted: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl MockQueue {
+        fn new(batches: Vec<Vec<Message>>) -> Self {
+            Self {
+                batches: std::sync::Mutex::new(batches.into()),
+                deleted: Default::default(),
+            }
+        }
+    }
+
+    impl ResultQueue for MockQueue {
+        async fn receive(&self, _visibility_timeout: Option<i32>) -> eyre::Result<Vec<Message>> {
+            self.batches
+                .lock()
+                .unwrap()
+                .pop_front()
+                .context("Mock queue is empty")
+        }
+
+        async fn delete(&self, receipt_handle: &str) -> eyre::Result<()> {
+            self.deleted
+                .lock()
+                .unwrap()
+                .push(receipt_handle.to_string());
+            Ok(())
+        }
+    }
+
+    fn message(body: &str, receipt_handle: &str) -> Message {
+        Message::builder()
+            .body(body)
+            .receipt_handle(receipt_handle)
+            .build()
+    }
+
+    fn checker(expected: &[(&str, Option<u32>)]) -> ResultChecker {
+        let expected_results = expected
+            .iter()
+            .map(|(id, expected)| (id.to_string(), *expected))
+            .collect();
+        let requests = expected
+            .iter()
+            .map(|(id, _)| (id.to_string(), IrisCodePair::both(IrisCode::default())))
+            .collect();
+        ResultChecker {
+            output:           OutputFormat::Json,
+            result_hmac_key:  None,
+            expected_results: Arc::new(Mutex::new(expected_results)),
+            requests:         Arc::new(Mutex::new(requests)),
+            responses:        Default::default(),
+            mismatches:       0,
+            seen:             HashSet::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_malformed_result_is_kept() {
+        let valid = |node_id| {
+            let mut result = result(false, Some(1), None);
+            result.node_id = node_id;
+            to_string(&result).unwrap()
+        };
+        let no_body = Message::builder().receipt_handle("empty").build();
+        let queue = MockQueue::new(vec![vec![message("{not json", "bad"), no_body], vec![
+            message(&valid(0), "good-0"),
+            message(&valid(1), "good-1"),
+            message(&valid(2), "good-2"),
+        ]]);
+        let mut checker = checker(&[("req-1", None)]);
+
+        receive_results(&queue, &mut checker, 1, Some(30), None)
+            .await
+            .unwrap();
+        assert_eq!(*queue.deleted.lock().unwrap(), vec![
+            "good-0".to_string(),
+            "good-1".to_string(),
+            "good-2".to_string()
+        ]);
+        assert_eq!(checker.mismatches, 0);
+    }
+
+    #[tokio::test]
+    async fn test_receive_out_of_order() {
+        let body = |node_id, signup_id: &str| {
+            to_string(&UniquenessResult::new(
+                node_id,
+                Some(1),
+                false,
+                signup_id.to_string(),
+                None,
+                None,
+                None,
+            ))
+            .unwrap()
+        };
+        let queue = MockQueue::new(vec![
+            vec![
+                message(&body(2, "req-2"), "2-2"),
+                message(&body(0, "req-1"), "1-0"),
+            ],
+            vec![],
+            vec![
+                message(&body(0, "req-1"), "1-0-dup"),
+                message(&body(1, "req-2"), "2-1"),
+                message(&body(2, "req-1"), "1-2"),
+                message(&body(0, "stale"), "stale"),
+            ],
+            vec![
+                message(&body(1, "req-1"), "1-1"),
+                message(&body(0, "req-2"), "2-0"),
+            ],
+        ]);
+        let mut checker = checker(&[("req-1", None), ("req-2", None)]);
+
+        receive_results(&queue, &mut checker, 2, None, None)
+            .await
+            .unwrap();
+        assert_eq!(checker.seen.len(), 6);
+        assert_eq!(checker.mismatches, 0);
+        // Duplicates and stale results are deleted as well
+        assert_eq!(queue.deleted.lock().unwrap().len(), 8);
+
+        // Missing results fail once the deadline has passed
+        let queue = MockQueue::new(vec![vec![message(&body(0, "req-1"), "1-0")]]);
+        let mut checker = self::checker(&[("req-1", None)]);
+        assert!(
+            receive_results(&queue, &mut checker, 1, None, Some(Instant::now()))
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_publish_batches() {
+        let messages = (0..32 * 3).collect::<Vec<_>>();
+        let batches = publish_batches(messages.clone());
+
+        assert_eq!(batches.len(), 10);
+        assert!(batches
+            .iter()
+            .all(|batch| !batch.is_empty() && batch.len() <= SNS_MAX_BATCH_ENTRIES));
+        assert_eq!(batches.concat(), messages);
+        assert!(publish_batches(Vec::<usize>::new()).is_empty());
+    }
+
+    #[test]
+    fn test_encode_shares_both_eyes() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let pair = IrisCodePair::random_rng(&mut rng);
+        let shares = pair.encode_shares(&mut rng);
+
+        for share in &shares {
+            let json: serde_json::Value = serde_json::from_str(&to_string(share).unwrap()).unwrap();
+            for field in [
+                "IRIS_version",
+                "IRIS_shares_version",
+                "left_iris_code_shares",
+                "right_iris_code_shares",
+                "left_mask_code_shares",
+                "right_mask_code_shares",
+            ] {
+                assert!(json.get(field).is_some(), "missing field {}", field);
+            }
+
+            let parsed: IrisCodesJSON = serde_json::from_value(json).unwrap();
+            assert_eq!(&parsed, share);
+            assert_ne!(parsed.left_iris_code_shares, parsed.right_iris_code_shares);
+        }
+
+        // The left and right shares of each party belong to the left and right eye
+        let mut rng = StdRng::seed_from_u64(7);
+        let shares = pair.encode_shares(&mut rng);
+        let mut rng = StdRng::seed_from_u64(7);
+        let [left_code, right_code] = [&pair.left, &pair.right].map(|iris| {
+            GaloisRingIrisCodeShare::encode_iris_code(&iris.code, &iris.mask, &mut rng)
+        });
+        for i in 0..3 {
+            assert_eq!(shares[i].left_iris_code_shares, left_code[i].to_base64());
+            assert_eq!(shares[i].right_iris_code_shares, right_code[i].to_base64());
+        }
+    }
+
+    #[test]
+    fn test_encoded_shares_reconstruct() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let pair = IrisCodePair::random_rng(&mut rng);
+        let shares = pair.encode_shares(&mut rng);
+
+        for (iris, code_shares) in [
+            (
+                &pair.left,
+                shares.clone().map(|share| share.left_iris_code_shares),
+            ),
+            (
+                &pair.right,
+                shares.clone().map(|share| share.right_iris_code_shares),
+            ),
+        ] {
+            let code_shares =
+                code_shares.map(|share| GaloisRingIrisCodeShare::from_base64(&share).unwrap());
+            let reconstructed = GaloisRingIrisCodeShare::reconstruct(&code_shares);
+            assert_eq!(reconstructed.mask, iris.mask);
+            assert_eq!(reconstructed.code, iris.code & iris.mask);
+        }
+    }
+
+    #[test]
+    fn test_parse_templates() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let irises = (0..3)
+            .map(|_| IrisCode::random_rng(&mut rng))
+            .collect::<Vec<_>>();
+        let contents = irises
+            .iter()
+            .map(|iris| {
+                format!(
+                    "{} {}\n",
+                    iris.code.to_base64().unwrap(),
+                    iris.mask.to_base64().unwrap()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let templates = parse_templates(&contents).unwrap();
+        assert_eq!(templates, irises);
+
+        assert!(parse_templates("not-base64 AAAA").is_err());
+        let code = irises[0].code.to_base64().unwrap();
+        assert!(parse_templates(&code).is_err());
+    }
+
+    #[test]
+    fn test_pacer() {
+        let start = Instant::now();
+
+        let mut pacer = Pacer::new(None);
+        assert!((0..10).all(|_| pacer.delay(start, 1) == Duration::ZERO));
+
+        // 10 requests issued at once at 5 per second are spread over ~2 seconds
+        let mut pacer = Pacer::new(Some(5.0));
+        let delays = (0..10).map(|_| pacer.delay(start, 1)).collect::<Vec<_>>();
+        for (i, delay) in delays.iter().enumerate() {
+            assert_eq!(*delay, Duration::from_millis(200) * i as u32);
+        }
+        assert_eq!(
+            delays[9] + Duration::from_millis(200),
+            Duration::from_secs(2)
+        );
+
+        // Once the clock has moved past the reserved slots there is no delay
+        let later = start + Duration::from_secs(10);
+        assert_eq!(pacer.delay(later, 1), Duration::ZERO);
+        assert_eq!(pacer.delay(later, 1), Duration::from_millis(200));
+
+        // A batch of requests takes up one slot per request
+        let mut pacer = Pacer::new(Some(5.0));
+        assert_eq!(pacer.delay(start, 10), Duration::ZERO);
+        assert_eq!(pacer.delay(start, 3), Duration::from_secs(2));
+        assert_eq!(pacer.delay(start, 1), Duration::from_millis(2600));
+
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("-1").is_err());
+        assert_eq!(parse_rate("2.5"), Ok(2.5));
+    }
+
+    #[test]
+    fn test_max_in_flight() {
+        let opt = Opt::try_parse_from(REQUIRED_ARGS).unwrap();
+        assert_eq!(opt.max_in_flight, MAX_CONCURRENT_REQUESTS);
+        let opt =
+            Opt::try_parse_from(REQUIRED_ARGS.into_iter().chain(["--max-in-flight", "4"])).unwrap();
+        assert_eq!(opt.max_in_flight, 4);
+
+        for value in ["0", "-1", "many"] {
+            assert!(Opt::try_parse_from(
+                REQUIRED_ARGS.into_iter().chain(["--max-in-flight", value])
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_result_line_json() {
+        let line = ResultLine::new(&result(true, None, Some(vec![7])), Some(7));
+        assert_eq!(
+            to_string(&line).unwrap(),
+            r#"{"request_id":"req-1","expected":7,"actual":7,"matched":true}"#
+        );
+
+        let line = ResultLine::new(&result(false, Some(9), None), None);
+        assert_eq!(
+            to_string(&line).unwrap(),
+            r#"{"request_id":"req-1","expected":null,"actual":null,"matched":true}"#
+        );
+
+        let line = ResultLine::new(&result(true, None, Some(vec![3])), Some(7));
+        assert_eq!(
+            to_string(&line).unwrap(),
+            r#"{"request_id":"req-1","expected":7,"actual":3,"matched":false}"#
+        );
+
+        // A new entry that unexpectedly matched, and a match on several entries
+        assert!(!ResultLine::new(&result(true, None, Some(vec![3])), None).matched);
+        assert!(!ResultLine::new(&result(true, None, Some(vec![7, 8])), Some(7)).matched);
+    }
+}
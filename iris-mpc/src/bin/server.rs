@@ -16,16 +16,20 @@ use iris_mpc_common::{
         },
         key_pair::SharesEncryptionKeyPairs,
         kms_dh::derive_shared_secret,
+        runtime::multi_thread_runtime,
         shutdown_handler::ShutdownHandler,
         smpc_request::{
-            create_message_type_attribute_map, CircuitBreakerRequest, IdentityDeletionRequest,
-            IdentityDeletionResult, ReceiveRequestError, SQSMessage, UniquenessRequest,
-            UniquenessResult, CIRCUIT_BREAKER_MESSAGE_TYPE, IDENTITY_DELETION_MESSAGE_TYPE,
-            SMPC_MESSAGE_TYPE_ATTRIBUTE, UNIQUENESS_MESSAGE_TYPE,
+            compute_result_hmac, create_message_type_attribute_map, CircuitBreakerRequest,
+            IdentityDeletionRequest, IdentityDeletionResult, ReceiveRequestError, SQSMessage,
+            UniquenessRequest, UniquenessResult, VersionSet, CIRCUIT_BREAKER_MESSAGE_TYPE,
+            IDENTITY_DELETION_MESSAGE_TYPE, RESULT_HMAC_ATTRIBUTE, SMPC_MESSAGE_TYPE_ATTRIBUTE,
+            UNIQUENESS_MESSAGE_TYPE,
         },
+        sqs::sanity_check_shares,
         sync::SyncState,
         task_monitor::TaskMonitor,
     },
+    iris_db::db::TestVectorSeed,
 };
 use iris_mpc_gpu::{
     helpers::device_manager::DeviceManager,
@@ -55,21 +59,22 @@ const REGION: &str = "eu-north-1";
 const RNG_SEED_INIT_DB: u64 = 42;
 const SQS_POLLING_INTERVAL: Duration = Duration::from_secs(1);
 const MAX_CONCURRENT_REQUESTS: usize = 32;
+const N_PARTIES: usize = 3;
 
 static CURRENT_BATCH_SIZE: LazyLock<Mutex<usize>> = LazyLock::new(|| Mutex::new(0));
 
 fn decode_iris_message_shares(
+    party_id: usize,
     code_share: String,
     mask_share: String,
 ) -> eyre::Result<(GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare)> {
     let iris_share = GaloisRingIrisCodeShare::from_base64(&code_share)
         .context("Failed to base64 parse iris code")?;
-    let mask_share: GaloisRingTrimmedMaskCodeShare =
-        GaloisRingIrisCodeShare::from_base64(&mask_share)
-            .context("Failed to base64 parse iris mask")?
-            .into();
+    let mask_share = GaloisRingIrisCodeShare::from_base64(&mask_share)
+        .context("Failed to base64 parse iris mask")?;
+    sanity_check_shares(party_id, &iris_share, &mask_share)?;
 
-    Ok((iris_share, mask_share))
+    Ok((iris_share, mask_share.into()))
 }
 
 #[allow(clippy::type_complexity)]
@@ -267,6 +272,11 @@ async fn receive_batch(
                         let handle = tokio::spawn(async move {
                             let _ = semaphore.acquire().await?;
 
+                            if let Err(e) = smpc_request.check_party_count(N_PARTIES) {
+                                tracing::error!("Invalid uniqueness request: {}", e);
+                                eyre::bail!("Invalid uniqueness request: {}", e);
+                            }
+
                             let base_64_encoded_message_payload =
                                 match smpc_request.get_iris_data_by_party_id(party_id).await {
                                     Ok(iris_message_share) => iris_message_share,
@@ -287,22 +297,28 @@ async fn receive_batch(
                                 }
                             };
 
-                            match smpc_request
+                            if let Err(e) =
+                                iris_message_share.check_supported_versions(&VersionSet::default())
+                            {
+                                tracing::error!("Unsupported iris shares: {}", e);
+                                eyre::bail!("Unsupported iris shares: {}", e);
+                            }
+
+                            if let Err(e) = smpc_request
                                 .validate_iris_share(party_id, iris_message_share.clone())
                             {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    tracing::error!("Failed to validate iris shares: {:?}", e);
-                                    eyre::bail!("Failed to validate iris shares: {:?}", e);
-                                }
+                                tracing::error!("Failed to validate iris shares: {}", e);
+                                eyre::bail!("Failed to validate iris shares: {}", e);
                             }
 
                             let (left_code, left_mask) = decode_iris_message_shares(
+                                party_id,
                                 iris_message_share.left_iris_code_shares,
                                 iris_message_share.left_mask_code_shares,
                             )?;
 
                             let (right_code, right_mask) = decode_iris_message_shares(
+                                party_id,
                                 iris_message_share.right_iris_code_shares,
                                 iris_message_share.right_mask_code_shares,
                             )?;
@@ -542,6 +558,15 @@ async fn send_results_to_sns(
                 construct_message_attributes(&metadata[i].trace_id, &metadata[i].span_id)?;
             message_attributes.extend(trace_attributes);
         }
+        if let Some(key) = &config.result_hmac_key {
+            message_attributes.insert(
+                RESULT_HMAC_ATTRIBUTE.to_string(),
+                MessageAttributeValue::builder()
+                    .data_type("String")
+                    .string_value(compute_result_hmac(key.0.as_bytes(), result_event))
+                    .build()?,
+            );
+        }
         sns_client
             .publish()
             .topic_arn(&config.results_topic_arn)
@@ -555,10 +580,12 @@ async fn send_results_to_sns(
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> eyre::Result<()> {
+fn main() -> eyre::Result<()> {
     dotenvy::dotenv().ok();
+    multi_thread_runtime(None)?.block_on(async_main())
+}
 
+async fn async_main() -> eyre::Result<()> {
     println!("Init config");
     let mut config: Config = Config::load_config("SMPC").unwrap();
     config.overwrite_defaults_with_cli_args(Opt::parse());
@@ -636,19 +663,28 @@ async fn server_main(config: Config) -> eyre::Result<()> {
 
     tracing::info!("Size of the database before init: {}", store_len);
 
+    let (init_db_seed, init_db_size) = match &config.test_vector_seed_file {
+        Some(path) => {
+            let seed = TestVectorSeed::load(path)
+                .with_context(|| format!("Failed to read test vector seed from {}", path))?;
+            (seed.seed, seed.db_size)
+        }
+        None => (RNG_SEED_INIT_DB, config.init_db_size),
+    };
+
     // Seed the persistent storage with random shares if configured and db is still
     // empty.
-    if store_len == 0 && config.init_db_size > 0 {
+    if store_len == 0 && init_db_size > 0 {
         tracing::info!(
             "Initialize persistent iris DB with {} randomly generated shares",
-            config.init_db_size
+            init_db_size
         );
         tracing::info!("Resetting the db: {}", config.clear_db_before_init);
         store
             .init_db_with_random_shares(
-                RNG_SEED_INIT_DB,
+                init_db_seed,
                 party_id,
-                config.init_db_size,
+                init_db_size,
                 config.clear_db_before_init,
             )
             .await?;
@@ -718,7 +754,11 @@ async fn server_main(config: Config) -> eyre::Result<()> {
         let ids = device_manager.get_ids_from_magic(0);
 
         tracing::info!("Starting NCCL");
-        let comms = device_manager.instantiate_network_from_ids(config.party_id, &ids)?;
+        let comms = device_manager.instantiate_network_from_ids_with_deadline(
+            config.party_id,
+            &ids,
+            Duration::from_secs(config.nccl_connect_timeout_secs),
+        )?;
 
         tracing::info!("NCCL: getting sync results");
         let sync_result = match sync_nccl::sync(&comms[0], &my_state) {